@@ -1,6 +1,29 @@
-extern crate protoc_rust;
-
 fn main() {
+    // src/ikcp_bindings.rs is committed, bindgen (and libclang) only runs
+    // when asked for, the output goes to OUT_DIR and src/ikcp.rs picks it up
+    #[cfg(feature = "regenerate-bindings")]
+    generate_bindings();
+
+    // the C kcp is only linked into the client side
+    if std::env::var_os("CARGO_FEATURE_CLIENT").is_some() {
+        cc::Build::new()
+            .include("kcp")
+            .file("kcp/ikcp.c")
+            .compile("kcp");
+    }
+
+    // protox parses the proto in-process, no protoc needed on the build machine
+    let descriptors = protox::compile(["message.proto"], ["./src"]).unwrap();
+    prost_build::Config::new()
+        .default_package_filename("message")
+        .compile_fds(descriptors)
+        .unwrap();
+    println!("cargo:rerun-if-changed=src/message.proto");
+}
+
+#[cfg(feature = "regenerate-bindings")]
+fn generate_bindings() {
+    let out_dir = std::path::PathBuf::from(std::env::var("OUT_DIR").unwrap());
     bindgen::builder()
         .header("kcp/ikcp.h")
         .allowlist_type("IQUEUEHEAD")
@@ -22,20 +45,11 @@ fn main() {
         .allowlist_function("ikcp_nodelay")
         .allowlist_function("ikcp_allocator")
         .allowlist_function("ikcp_getconv")
+        .size_t_is_usize(true)
+        .layout_tests(false)
         .generate()
         .unwrap()
-        .write_to_file("src/ikcp.rs")
-        .unwrap();
-
-    cc::Build::new()
-        .include("kcp")
-        .file("kcp/ikcp.c")
-        .compile("kcp");
-
-    protoc_rust::Codegen::new()
-        .out_dir("./src")
-        .inputs(&["./src/message.proto"])
-        .include("./src")
-        .run()
+        .write_to_file(out_dir.join("ikcp_bindings.rs"))
         .unwrap();
+    println!("cargo:rerun-if-changed=kcp/ikcp.h");
 }
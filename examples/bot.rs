@@ -0,0 +1,117 @@
+use kcp_rust::codec::Command;
+use kcp_rust::mock::MockServer;
+use kcp_rust::{Client, FrameHasher, NetEvent, PollStatus};
+use std::env;
+use std::net::SocketAddr;
+use std::thread;
+use std::time::Duration;
+
+const FRAME_INTERVAL: u64 = 50;
+
+// usage: bot [server_addr conv room_id player_id password]
+// without arguments a local mock server is started
+fn main() -> anyhow::Result<()> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let mut server = None;
+    let (addr, conv) = match args.get(0) {
+        Some(addr) => (
+            addr.parse::<SocketAddr>()?,
+            args.get(1)
+                .map(|conv| conv.parse())
+                .transpose()?
+                .unwrap_or(1),
+        ),
+        None => {
+            let mock = MockServer::start(1)?;
+            let addr = mock.addr();
+            server = Some(mock);
+            (addr, 1)
+        }
+    };
+    let room_id = args.get(2).map(String::as_str).unwrap_or("room");
+    let player_id = args.get(3).map(String::as_str).unwrap_or("bot");
+    let password = args.get(4).map(String::as_str).unwrap_or("");
+
+    let client = Client::connect(addr, conv, room_id, player_id, password)?;
+    let interrupt = client.handle().clone();
+    ctrlc::set_handler(move || {
+        println!("interrupted, disconnecting");
+        let _ = interrupt.game_over();
+    })?;
+
+    let mut handle = client.handle().clone();
+    let mut rng = XorShift(0x9e37_79b9 ^ conv);
+    let mut running = false;
+    let mut frame = 0u32;
+    let mut hasher = FrameHasher::new();
+    let mut hash = Vec::<u8>::new();
+    let mut commands = Vec::<Command>::new();
+    let mut events = Vec::<NetEvent>::new();
+    let mut received = 0usize;
+
+    loop {
+        events.clear();
+        match handle.poll(&mut events) {
+            PollStatus::NotStarted => {}
+            PollStatus::Active => running = true,
+            PollStatus::Finished(info) => {
+                println!("finished: {:?} {}", info.cause, info.message);
+                break;
+            }
+        };
+        for event in events.iter() {
+            match event {
+                NetEvent::State { conv, state, .. } => println!("state: conv {} -> {:?}", conv, state),
+                NetEvent::Commands { commands, .. } => received += commands.len(),
+                NetEvent::Stats(stats) => println!(
+                    "stats: frame {} commands received {} hash {:016x} jitter delay {} slowest {:?}",
+                    frame,
+                    received,
+                    hasher.finish(),
+                    stats.jitter_delay,
+                    stats.slowest_conv()
+                ),
+                NetEvent::Warning(warning) => println!("warning: {:?}", warning),
+                event => println!("{}", event),
+            };
+        }
+
+        if running {
+            frame += 1;
+            commands.clear();
+            for _ in 0..(rng.next() % 3) {
+                commands.push(match rng.next() % 2 {
+                    0 => Command::Aaa(rng.next() as i32, rng.next() as i32),
+                    #[cfg(not(feature = "no-float-commands"))]
+                    _ => Command::Bbb(rng.next() as f32, 0.5, -0.5, 0),
+                    #[cfg(feature = "no-float-commands")]
+                    _ => Command::Aaa(rng.next() as i32, 0),
+                });
+            }
+            for command in commands.iter() {
+                hasher.update_command(command);
+            }
+            hash.clear();
+            hasher.finish_into(&mut hash);
+            let _ = handle.send_input(frame, &commands, &hash);
+        }
+        thread::sleep(Duration::from_millis(FRAME_INTERVAL));
+    }
+
+    if let Some(summary) = client.join().and_then(|info| info.summary) {
+        println!("{:?}", summary);
+    }
+    drop(server);
+    return Ok(());
+}
+
+struct XorShift(u32);
+
+impl XorShift {
+    fn next(&mut self) -> u32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        return self.0;
+    }
+}
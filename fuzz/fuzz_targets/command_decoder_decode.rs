@@ -0,0 +1,19 @@
+#![no_main]
+use kcp_rust::codec::{CommandBatch, CommandDecoder, CommandEx};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let mut cd = CommandDecoder::new(0);
+    let _ = cd.decode(data);
+
+    let mut commands = Vec::<CommandEx>::new();
+    if cd.decode_into(data, &mut commands).is_err() {
+        assert!(commands.is_empty());
+    }
+
+    let mut batches = Vec::<CommandBatch>::new();
+    match cd.decode_batch_into(data, &mut batches) {
+        Ok(()) => assert!(CommandBatch::flatten(&batches).eq(commands.into_iter())),
+        Err(_) => assert!(batches.is_empty()),
+    };
+});
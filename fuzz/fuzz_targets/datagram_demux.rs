@@ -0,0 +1,11 @@
+#![no_main]
+use kcp_rust::codec::Datagram;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(Datagram::Unreliable(conv, payload)) = Datagram::demux(data) {
+        let mut bytes = Vec::new();
+        Datagram::encode_unreliable(conv, payload, &mut bytes).unwrap();
+        assert_eq!(bytes, data);
+    }
+});
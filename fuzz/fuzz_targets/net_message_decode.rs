@@ -0,0 +1,11 @@
+#![no_main]
+use kcp_rust::codec::NetMessage;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok((msg, offset)) = NetMessage::decode(data) {
+        assert!(offset <= data.len());
+        let mut bytes = Vec::new();
+        let _ = msg.encode(&mut bytes);
+    }
+});
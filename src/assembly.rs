@@ -0,0 +1,303 @@
+use crate::chan::NetEvent;
+use crate::codec::Command;
+use crate::message::NetPlayerState;
+use std::collections::BTreeMap;
+
+#[derive(Debug)]
+struct PendingFrame {
+    commands: BTreeMap<u32, Vec<Command>>,
+    since: u64,
+}
+
+// groups remote commands per frame and releases frame N once every active
+// conv has reported N (possibly without commands) or stopped, at most
+// `max_wait` ms after N was first reported, times are in ms
+#[derive(Debug)]
+pub struct FrameAssembler {
+    max_wait: u64,
+    // active conv -> first frame it has to report
+    active: BTreeMap<u32, u32>,
+    frames: BTreeMap<u32, PendingFrame>,
+    next: u32,
+    newest: u32,
+    late: u64,
+}
+
+impl FrameAssembler {
+    pub fn new(max_wait: u64) -> FrameAssembler {
+        return FrameAssembler {
+            max_wait,
+            active: BTreeMap::new(),
+            frames: BTreeMap::new(),
+            next: 1,
+            newest: 0,
+            late: 0,
+        };
+    }
+
+    // the match starts at `frame`, or a resumed session continues there,
+    // nothing before it is released
+    pub fn start_at(&mut self, frame: u32) {
+        self.next = frame.max(1);
+        self.newest = self.newest.max(self.next - 1);
+        self.frames = self.frames.split_off(&self.next);
+    }
+
+    // commands that arrived for frames already released incomplete
+    pub fn late(&self) -> u64 {
+        return self.late;
+    }
+
+    // pushing commands for it would only count them late
+    pub fn is_released(&self, frame: u32) -> bool {
+        return frame < self.next;
+    }
+
+    pub fn len(&self) -> usize {
+        return self.frames.len();
+    }
+
+    // a conv joining late only has to report the frames nobody reported yet
+    pub fn set_state(&mut self, conv: u32, state: NetPlayerState) {
+        match state {
+            NetPlayerState::Running | NetPlayerState::Background | NetPlayerState::Paused => {
+                let from = self.newest.max(self.next - 1) + 1;
+                self.active.entry(conv).or_insert(from);
+            }
+            NetPlayerState::Stopped => {
+                self.active.remove(&conv);
+            }
+            NetPlayerState::Initing | NetPlayerState::Waiting => {}
+        };
+    }
+
+    // one packet of `conv` for `frame`, `commands` may be empty
+    pub fn push(&mut self, conv: u32, frame: u32, commands: &[Command], now: u64) {
+        if frame < self.next {
+            self.late += commands.len() as u64;
+            return;
+        }
+        self.newest = self.newest.max(frame);
+        let pending = self.frames.entry(frame).or_insert_with(|| PendingFrame {
+            commands: BTreeMap::new(),
+            since: now,
+        });
+        pending
+            .commands
+            .entry(conv)
+            .or_insert_with(Vec::new)
+            .extend_from_slice(commands);
+    }
+
+    // releases frames in order, a frame nobody reported is released empty
+    // once a later one has waited `max_wait`
+    pub fn pop_ready(&mut self, now: u64, out: &mut Vec<NetEvent>) {
+        loop {
+            let (&first, pending) = match self.frames.iter().next() {
+                Some(first) => first,
+                None => return,
+            };
+            let expired = now >= pending.since + self.max_wait;
+            let complete = first == self.next && self.is_complete(first, pending);
+            if !complete && !expired {
+                return;
+            }
+
+            let commands = match first == self.next {
+                true => self.frames.remove(&first).unwrap().commands,
+                false => BTreeMap::new(),
+            };
+            out.push(NetEvent::FrameReady {
+                frame: self.next,
+                commands: commands.into_iter().collect(),
+                complete,
+            });
+            self.next += 1;
+        }
+    }
+
+    fn is_complete(&self, frame: u32, pending: &PendingFrame) -> bool {
+        return self
+            .active
+            .iter()
+            .all(|(conv, from)| frame < *from || pending.commands.contains_key(conv));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn command(conv: u32, frame: u32) -> Command {
+        return Command::Aaa(conv as i32, frame as i32);
+    }
+
+    fn ready(frame: u32, convs: &[(u32, usize)], complete: bool) -> NetEvent {
+        return NetEvent::FrameReady {
+            frame,
+            commands: convs
+                .iter()
+                .map(|&(conv, len)| {
+                    let commands = vec![Command::Aaa(conv as i32, frame as i32); len];
+                    (conv, commands)
+                })
+                .collect(),
+            complete,
+        };
+    }
+
+    fn running(convs: &[u32]) -> FrameAssembler {
+        let mut assembler = FrameAssembler::new(100);
+        for &conv in convs {
+            assembler.set_state(conv, NetPlayerState::Running);
+        }
+        return assembler;
+    }
+
+    #[test]
+    fn test_frame_assembler_waits_for_everyone() {
+        let mut assembler = running(&[1, 2, 3]);
+        let mut out = Vec::new();
+        assembler.push(1, 1, &[command(1, 1)], 0);
+        assembler.push(3, 1, &[command(3, 1), command(3, 1)], 0);
+        assembler.push(1, 2, &[command(1, 2)], 10);
+        assembler.pop_ready(10, &mut out);
+        assert!(out.is_empty());
+
+        // empty reports count, frames come out in order
+        assembler.push(2, 2, &[], 20);
+        assembler.push(3, 2, &[], 20);
+        assembler.pop_ready(20, &mut out);
+        assert!(out.is_empty());
+        assembler.push(2, 1, &[], 30);
+        assembler.pop_ready(30, &mut out);
+        assert_eq!(
+            out,
+            vec![
+                ready(1, &[(1, 1), (2, 0), (3, 2)], true),
+                ready(2, &[(1, 1), (2, 0), (3, 0)], true),
+            ]
+        );
+        assert_eq!(assembler.len(), 0);
+    }
+
+    #[test]
+    fn test_frame_assembler_start_at() {
+        let mut assembler = running(&[1, 2]);
+        let mut out = Vec::new();
+        assembler.push(1, 5, &[command(1, 5)], 0);
+        assembler.start_at(100);
+        assembler.pop_ready(1000, &mut out);
+        assert!(out.is_empty());
+        assert!(assembler.is_released(99));
+
+        // complete right away, no empty frames before it
+        assembler.push(1, 100, &[command(1, 100)], 1000);
+        assembler.push(2, 100, &[], 1000);
+        assembler.pop_ready(1000, &mut out);
+        assert_eq!(out, vec![ready(100, &[(1, 1), (2, 0)], true)]);
+    }
+
+    #[test]
+    fn test_frame_assembler_player_stops() {
+        let mut assembler = running(&[1, 2]);
+        let mut out = Vec::new();
+        assembler.push(1, 1, &[command(1, 1)], 0);
+        assembler.push(2, 1, &[command(2, 1)], 0);
+        assembler.push(1, 2, &[command(1, 2)], 0);
+        assembler.push(1, 3, &[command(1, 3)], 0);
+
+        // what 2 reported before stopping is kept, it isn't waited for after
+        assembler.set_state(2, NetPlayerState::Stopped);
+        assembler.pop_ready(0, &mut out);
+        assert_eq!(
+            out,
+            vec![
+                ready(1, &[(1, 1), (2, 1)], true),
+                ready(2, &[(1, 1)], true),
+                ready(3, &[(1, 1)], true),
+            ]
+        );
+
+        // everyone gone
+        out.clear();
+        assembler.set_state(1, NetPlayerState::Stopped);
+        assembler.push(1, 4, &[], 0);
+        assembler.pop_ready(0, &mut out);
+        assert_eq!(out, vec![ready(4, &[(1, 0)], true)]);
+    }
+
+    #[test]
+    fn test_frame_assembler_late_join() {
+        let mut assembler = running(&[1]);
+        let mut out = Vec::new();
+        for frame in 1..=3 {
+            assembler.push(1, frame, &[], 0);
+        }
+        assembler.set_state(2, NetPlayerState::Running);
+        assembler.pop_ready(0, &mut out);
+        assert_eq!(out.len(), 3);
+        assert!(out
+            .iter()
+            .all(|event| matches!(event, NetEvent::FrameReady { complete: true, .. })));
+
+        // required from the first frame nobody reported yet
+        out.clear();
+        assembler.push(1, 4, &[], 10);
+        assembler.pop_ready(10, &mut out);
+        assert!(out.is_empty());
+        assembler.push(2, 4, &[command(2, 4)], 20);
+        assembler.pop_ready(20, &mut out);
+        assert_eq!(out, vec![ready(4, &[(1, 0), (2, 1)], true)]);
+
+        // running again doesn't move an active conv's first frame
+        assembler.set_state(2, NetPlayerState::Running);
+        assembler.push(1, 5, &[], 30);
+        assembler.pop_ready(30, &mut out);
+        assert_eq!(out.len(), 1);
+    }
+
+    #[test]
+    fn test_frame_assembler_max_wait() {
+        let mut assembler = running(&[1, 2]);
+        let mut out = Vec::new();
+        assembler.push(1, 1, &[command(1, 1)], 0);
+        assembler.push(1, 2, &[command(1, 2)], 50);
+        assembler.pop_ready(99, &mut out);
+        assert!(out.is_empty());
+
+        // the straggler holds each frame at most max_wait after it was first
+        // reported
+        assembler.pop_ready(100, &mut out);
+        assert_eq!(out, vec![ready(1, &[(1, 1)], false)]);
+        assembler.pop_ready(149, &mut out);
+        assert_eq!(out.len(), 1);
+        assembler.pop_ready(150, &mut out);
+        assert_eq!(out[1], ready(2, &[(1, 1)], false));
+
+        // and its commands are dropped once it catches up
+        assembler.push(2, 1, &[command(2, 1)], 160);
+        assembler.push(2, 2, &[command(2, 2), command(2, 2)], 160);
+        assert_eq!(assembler.late(), 3);
+        assembler.push(2, 3, &[], 160);
+        assembler.push(1, 3, &[], 170);
+        assembler.pop_ready(170, &mut out);
+        assert_eq!(out[2], ready(3, &[(1, 0), (2, 0)], true));
+    }
+
+    #[test]
+    fn test_frame_assembler_gap() {
+        let mut assembler = running(&[1]);
+        let mut out = Vec::new();
+        assembler.push(1, 1, &[], 0);
+        assembler.push(1, 3, &[command(1, 3)], 10);
+        assembler.pop_ready(10, &mut out);
+        assert_eq!(out, vec![ready(1, &[(1, 0)], true)]);
+
+        // frame 2 never arrives, it is released empty behind frame 3
+        assembler.pop_ready(110, &mut out);
+        assert_eq!(out[1..], [ready(2, &[], false), ready(3, &[(1, 1)], true)]);
+        assembler.push(1, 2, &[command(1, 2)], 120);
+        assert_eq!(assembler.late(), 1);
+    }
+}
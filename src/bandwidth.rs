@@ -0,0 +1,200 @@
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Traffic {
+    pub bytes: u64,
+    pub datagrams: u64,
+}
+
+impl Traffic {
+    fn add(&mut self, bytes: usize) {
+        self.bytes = self.bytes.saturating_add(bytes as u64);
+        self.datagrams = self.datagrams.saturating_add(1);
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Bandwidth {
+    // during the last full second
+    pub sent_per_sec: Traffic,
+    pub recv_per_sec: Traffic,
+    // since the socket was created
+    pub sent_total: Traffic,
+    pub recv_total: Traffic,
+}
+
+// Counts what goes through the UDP socket per second, times are in ms.
+#[derive(Debug)]
+pub struct BandwidthMeter {
+    second: u64,
+    sent: Traffic,
+    recv: Traffic,
+    bandwidth: Bandwidth,
+}
+
+impl BandwidthMeter {
+    pub fn new(now: u64) -> BandwidthMeter {
+        return BandwidthMeter {
+            second: now / 1000,
+            sent: Traffic::default(),
+            recv: Traffic::default(),
+            bandwidth: Bandwidth::default(),
+        };
+    }
+
+    pub fn on_sent(&mut self, bytes: usize, now: u64) {
+        self.roll(now);
+        self.sent.add(bytes);
+        self.bandwidth.sent_total.add(bytes);
+    }
+
+    pub fn on_recv(&mut self, bytes: usize, now: u64) {
+        self.roll(now);
+        self.recv.add(bytes);
+        self.bandwidth.recv_total.add(bytes);
+    }
+
+    pub fn bandwidth(&mut self, now: u64) -> Bandwidth {
+        self.roll(now);
+        return self.bandwidth;
+    }
+
+    fn roll(&mut self, now: u64) {
+        let second = now / 1000;
+        if second <= self.second {
+            return;
+        }
+        // nothing was counted during an idle second in between
+        let (sent, recv) = match second - self.second {
+            1 => (self.sent, self.recv),
+            _ => (Traffic::default(), Traffic::default()),
+        };
+        self.bandwidth.sent_per_sec = sent;
+        self.bandwidth.recv_per_sec = recv;
+        self.sent = Traffic::default();
+        self.recv = Traffic::default();
+        self.second = second;
+    }
+}
+
+// When datagrams last went out and came in, fed from the meter's totals so
+// only what got through the socket counts. Times are in ms.
+#[derive(Debug, Default)]
+pub struct LinkActivity {
+    sent: u64,
+    recv: u64,
+    sent_at: Option<u64>,
+    recv_at: Option<u64>,
+    // the first datagram sent after the last one received
+    silent_since: Option<u64>,
+}
+
+impl LinkActivity {
+    pub fn update(&mut self, bandwidth: &Bandwidth, now: u64) {
+        if bandwidth.recv_total.datagrams > self.recv {
+            self.recv = bandwidth.recv_total.datagrams;
+            self.recv_at = Some(now);
+            self.silent_since = None;
+        }
+        if bandwidth.sent_total.datagrams > self.sent {
+            self.sent = bandwidth.sent_total.datagrams;
+            self.sent_at = Some(now);
+            if self.silent_since.is_none() {
+                self.silent_since = Some(now);
+            }
+        }
+    }
+
+    // how long we kept sending while nothing came back, 0 before anything
+    // ever did: a server never reached is Unreachable, not one-way
+    pub fn one_way(&self) -> u64 {
+        if self.recv_at.is_none() {
+            return 0;
+        }
+        return match (self.silent_since, self.sent_at) {
+            (Some(since), Some(sent_at)) => sent_at - since,
+            _ => 0,
+        };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_bandwidth_meter() {
+        let mut meter = BandwidthMeter::new(500);
+        meter.on_sent(100, 500);
+        meter.on_sent(50, 999);
+        meter.on_recv(30, 999);
+        // the second isn't over yet
+        let bandwidth = meter.bandwidth(999);
+        assert_eq!(bandwidth.sent_per_sec, Traffic::default());
+        assert_eq!(
+            bandwidth.sent_total,
+            Traffic {
+                bytes: 150,
+                datagrams: 2
+            }
+        );
+
+        meter.on_sent(10, 1000);
+        let bandwidth = meter.bandwidth(1500);
+        assert_eq!(
+            bandwidth.sent_per_sec,
+            Traffic {
+                bytes: 150,
+                datagrams: 2
+            }
+        );
+        assert_eq!(
+            bandwidth.recv_per_sec,
+            Traffic {
+                bytes: 30,
+                datagrams: 1
+            }
+        );
+        assert_eq!(bandwidth.sent_total.bytes, 160);
+
+        assert_eq!(meter.bandwidth(2000).sent_per_sec.bytes, 10);
+        assert_eq!(meter.bandwidth(2000).recv_per_sec, Traffic::default());
+        // idle seconds
+        meter.on_sent(10, 2500);
+        assert_eq!(meter.bandwidth(4100).sent_per_sec, Traffic::default());
+        assert_eq!(meter.bandwidth(4100).sent_total.bytes, 170);
+        // a clock going backwards is counted in the current second
+        meter.on_recv(5, 100);
+        assert_eq!(meter.bandwidth(5000).recv_per_sec.bytes, 5);
+
+        meter.bandwidth.sent_total.bytes = u64::MAX - 1;
+        meter.on_sent(10, 5000);
+        assert_eq!(meter.bandwidth(5000).sent_total.bytes, u64::MAX);
+    }
+
+    #[test]
+    fn test_link_activity() {
+        let mut meter = BandwidthMeter::new(0);
+        let mut link = LinkActivity::default();
+        // never reached
+        meter.on_sent(10, 0);
+        link.update(&meter.bandwidth(0), 0);
+        meter.on_sent(10, 3000);
+        link.update(&meter.bandwidth(3000), 3000);
+        assert_eq!(link.one_way(), 0);
+
+        meter.on_recv(10, 3100);
+        link.update(&meter.bandwidth(3100), 3100);
+        assert_eq!(link.one_way(), 0);
+        for now in (3200..=5200).step_by(100) {
+            meter.on_sent(10, now);
+            link.update(&meter.bandwidth(now), now);
+        }
+        assert_eq!(link.one_way(), 2000);
+        // only while we keep sending
+        link.update(&meter.bandwidth(9000), 9000);
+        assert_eq!(link.one_way(), 2000);
+
+        meter.on_recv(10, 9100);
+        link.update(&meter.bandwidth(9100), 9100);
+        assert_eq!(link.one_way(), 0);
+    }
+}
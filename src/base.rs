@@ -1,31 +1,174 @@
 use thiserror::Error;
 
-use crate::message::NetFinishCause;
+#[cfg(feature = "client")]
+use crate::decision::DecisionLog;
+use crate::message::{NetFinishCause, NetPlayerState, NetType};
+#[cfg(feature = "client")]
+use crate::summary::SessionSummary;
+use std::convert::TryFrom;
+use std::fmt;
+#[cfg(feature = "client")]
+use std::net::SocketAddr;
+use std::num::NonZeroU32;
+use std::time::SystemTime;
 
 pub const KCP_INTERVAL: u64 = 10;
 pub const KCP_MTU: usize = 470;
 pub const KCP_MIN_PACKET: usize = 1 + 2;
+// kcp segment header size
+pub const KCP_OVERHEAD: usize = 24;
 pub const KCP_MAX_PACKET: usize = 470 * 4;
+// of KCP_MAX_PACKET, a frame this large is warned about once
+pub const PACKET_WARN_PERCENT: usize = 80;
 pub const KCP_WINDOW_SIZE: usize = 256;
+// a hash and a command packet
+pub const KCP_FRAME_SEGMENTS: u32 = 2;
+pub const SEND_BUDGET_MARGIN: u32 = 2;
+pub const SEND_BATCH: usize = 16;
+pub const RECV_BATCH: usize = 32;
 
 pub const PLAYERS_CAP: usize = 16;
+// one per NetType
+pub const IGNORED_TYPES: usize = 11;
+// one per NetWarning variant
+pub const WARNING_KINDS: usize = 10;
+pub const WARNING_INTERVAL: u64 = 1000;
+pub const WARNING_BURST: u32 = 4;
+// queued for the game, later ones are dropped until it polls
+pub const WARNINGS_CAP: usize = 64;
 pub const COMMANDS_CAP: usize = 256;
+pub const COMMANDS_INLINE: usize = 4;
+pub const INPUT_MAX_BYTES: usize = KCP_MAX_PACKET;
+pub const INPUT_PENDING_BYTES: usize = 64 * 1024;
+pub const OUTPUT_MAX_COMMANDS: usize = 4096;
 pub const HASH_CAP: usize = 128;
+pub const HASH_SIZE: usize = 8;
+pub const HASH_FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+pub const HASH_FNV_PRIME: u64 = 0x0100_0000_01b3;
+pub const HASH_HISTORY: usize = 64;
+// bytes of command tail below which compressing isn't worth a try
+pub const DICTIONARY_THRESHOLD: usize = 32;
+// packet sizes for WorkerConfig::padding
+pub const PADDING_BUCKETS: [usize; 3] = [64, 128, 256];
+pub const CREDENTIAL_MAX_BYTES: usize = 64;
+// received protobuf fields, see ProtocolLimits, ids and passwords are held
+// to CREDENTIAL_MAX_BYTES and hashes to HASH_CAP
+pub const PROTOCOL_MAX_TOKEN: usize = 256;
+pub const PROTOCOL_MAX_NONCE: usize = 64;
+// an HMAC-SHA256
+pub const PROTOCOL_MAX_RESPONSE: usize = 32;
 
-pub const CONNECT_TIMEOUT: u64 = 10;
+pub const PROTOCOL_VERSION: u32 = 1;
+pub const SESSION_STATE_VERSION: u32 = 1;
+
+pub const UNRELIABLE_CONV: u32 = 0xffff_ffff;
+pub const UNRELIABLE_HEADER: usize = 4 + 4;
+pub const UNRELIABLE_MAX_PAYLOAD: usize = KCP_MTU - UNRELIABLE_HEADER;
+pub const UNRELIABLE_QUEUE: usize = 64;
+
+// type bytes from here on are the application's, see
+// NetMessage::Application
+pub const APPLICATION_TYPES: u8 = 128;
+
+pub const FRAME_INTERVAL: u64 = 50;
+pub const STATS_INTERVAL: u64 = 1000;
+pub const LOG_INTERVAL: u64 = 5000;
+pub const ESTIMATE_MAX_FRAMES: u32 = 10;
+pub const JITTER_MAX_DELAY: u32 = 4;
+pub const ASSEMBLY_MAX_WAIT: u64 = 500;
+
+pub const REBIND_ATTEMPTS: u32 = 3;
+pub const REBIND_COOLDOWN: u64 = 2000;
+pub const REBIND_SILENCE: u64 = 1500;
+
+// s until anything comes back from the server, then until it accepts
+pub const REACH_TIMEOUT: u64 = 5;
+pub const ACCEPT_TIMEOUT: u64 = 10;
 pub const START_TIMEOUT: u64 = 20;
 pub const UPDATE_TIMEOUT: u64 = 7;
+// ms we kept sending with nothing coming back before NetWarning::NoInboundTraffic,
+// past it a failure of the link is reported as KCPError::NoInboundTraffic
+pub const ONE_WAY_WARNING: u64 = 2000;
 pub const FINISH_TIMEOUT: u64 = 5;
+pub const DROP_TIMEOUT: u64 = 1000;
+// ms the server's traffic is still acked after game_over(), then the Finish
+// is sent
+pub const STOP_GRACE: u64 = 200;
+// ms a tick decodes packets for before leaving the rest to the next one
+pub const TICK_BUDGET: u64 = 4;
+// acked segments the latency percentiles are taken over
+pub const ACK_LATENCY_SAMPLES: usize = 256;
+// power of two µs buckets of the tick timings, the last from 2^22 µs up
+pub const TICK_TIMING_BUCKETS: usize = 24;
+// state changes and reconnects kept, older ones are dropped
+pub const TIMELINE_CAP: usize = 16;
+// ticks WorkerConfig::decision_log keeps at most
+pub const DECISION_LOG_CAP: usize = 300;
+// bytes of incomplete transfers held at once, of one transfer, and ms an
+// incomplete transfer may go without a chunk
+pub const REASSEMBLY_MAX_BYTES: usize = 1024 * 1024;
+pub const REASSEMBLY_MAX_TRANSFER: usize = 256 * 1024;
+pub const REASSEMBLY_MAX_AGE: u64 = 5000;
+// ms commands of a conv no State introduced yet wait for it, and batches
+// held of one conv and of all of them
+pub const ROSTER_HINT_WINDOW: u64 = 200;
+pub const ROSTER_HINT_PER_CONV: usize = 8;
+pub const ROSTER_HINT_TOTAL: usize = 32;
+// frames of the selftest session, commands per frame so compression kicks
+// in, and ms each of its stages may take
+pub const SELFTEST_FRAMES: u32 = 8;
+pub const SELFTEST_COMMANDS: usize = 16;
+pub const SELFTEST_TIMEOUT: u64 = 5000;
+// bytes per second sent and kcp segments not acked over which the worker
+// degrades, % of both it recovers under and ms at least between two steps
+pub const DEGRADE_MAX_UPSTREAM: u64 = 16 * 1024;
+pub const DEGRADE_MAX_WAITSND: u32 = KCP_WINDOW_SIZE as u32 / 4;
+pub const DEGRADE_RECOVER_PERCENT: u64 = 50;
+pub const DEGRADE_HOLD: u64 = 2000;
+// frames per hash sent once hashes are reduced
+pub const HASH_CADENCE: u32 = 4;
+
+pub const PRESENCE_INTERVAL: u64 = 1000;
+// ms between NetEvent::FrameAcked at most
+pub const FRAME_ACK_INTERVAL: u64 = 100;
+pub const BACKGROUND_INTERVAL: u64 = 50;
+// % of an interval a timer is shifted by at most
+pub const TIMER_JITTER_MAX: u64 = 50;
+// ms the wall clock may move forward between two readings, ticks are at most
+// BACKGROUND_INTERVAL apart, see WallClock
+pub const CLOCK_JUMP_MAX: u64 = 10 * 1000;
+
+// the single player of NetWorker::offline()
+pub const OFFLINE_CONV: u32 = 1;
+pub const OFFLINE_ID: &str = "offline";
+
+// diagnostics::run(), in ms
+pub const DIAGNOSTICS_TIMEOUT: u64 = 3000;
+pub const DIAGNOSTICS_DURATION: u64 = 3000;
+pub const DIAGNOSTICS_INTERVAL: u64 = 100;
+// for echoes of the last pings and the Finish to be acked
+pub const DIAGNOSTICS_GRACE: u64 = 500;
+pub const DIAGNOSTICS_MTU_PROBES: u32 = 3;
+
+pub const BOUNDED_RETRIES: usize = 2;
+pub const CONNECT_RETRIES: usize = 5;
 
 #[derive(Error, Debug)]
 pub enum KCPError {
     // network broken
+    #[cfg(feature = "client")]
     #[error("io error")]
     IO(#[from] std::io::Error),
     #[error("timeout")]
     Timeout,
     #[error("window exhausted")]
     WindowExhausted,
+    // nothing came back from the server's address
+    #[error("unreachable")]
+    Unreachable,
+    // the server answered but never accepted the connect
+    #[error("accept timeout")]
+    AcceptTimeout,
 
     // InvalidPacket
     #[error("packet broken")]
@@ -34,8 +177,23 @@ pub enum KCPError {
     PacketTooShort,
     #[error("packet too long")]
     PacketTooLong,
+    // a string or bytes field over its ProtocolLimits
+    #[error("field too long: {0}")]
+    FieldTooLong(&'static str),
+    // a queued kcp message larger than the receive buffer, left in the queue
+    #[error("oversized message: {0} bytes")]
+    Oversized(usize),
     #[error("unexpected packet")]
     UnexpectedPacket,
+    #[error("invalid command")]
+    InvalidCommand,
+    // commands for a frame already delivered, see LateCommandPolicy::Fatal
+    #[error("late commands for frame {0}")]
+    LateCommands(u32),
+
+    // our datagrams went out, nothing came back, see LinkActivity
+    #[error("no inbound traffic")]
+    NoInboundTraffic,
 
     #[error("game over")]
     GameOver,
@@ -45,37 +203,1063 @@ pub enum KCPError {
 
     // client error
     #[error("protobuf error")]
-    Protobuf(#[from] protobuf::ProtobufError),
+    Protobuf(#[from] ProtobufError),
     #[error("bincode error")]
     Bincode(#[from] bincode::Error),
-    #[error("kcp error {0}")]
-    KCP(i32),
+    #[error("kcp error: {0}")]
+    KCP(KCPFailure),
     #[error("unexpected error")]
     Unexpected,
     #[error("invalid frame")]
     InvalidFrame,
+    // the server started the match at a later frame
+    #[error("input frame {frame} is before the first frame {first_frame}")]
+    BeforeFirstFrame { frame: u32, first_frame: u32 },
     #[error("message too long")]
     MessageTooLong,
+    // an outgoing buffer that isn't exactly the messages it declares
+    #[error("misframed message")]
+    Misframed,
 }
 
 impl KCPError {
     pub fn cause(&self) -> NetFinishCause {
         return match self {
+            #[cfg(feature = "client")]
             Self::IO(_) => NetFinishCause::NetworkBroken,
             Self::Timeout => NetFinishCause::NetworkBroken,
             Self::WindowExhausted => NetFinishCause::NetworkBroken,
+            Self::Unreachable => NetFinishCause::NetworkBroken,
+            Self::AcceptTimeout => NetFinishCause::NetworkBroken,
+            Self::NoInboundTraffic => NetFinishCause::NetworkBroken,
             Self::PacketBroken => NetFinishCause::InvalidPacket,
             Self::PacketTooShort => NetFinishCause::InvalidPacket,
             Self::PacketTooLong => NetFinishCause::InvalidPacket,
+            Self::FieldTooLong(_) => NetFinishCause::InvalidPacket,
+            Self::Oversized(_) => NetFinishCause::InvalidPacket,
             Self::UnexpectedPacket => NetFinishCause::InvalidPacket,
+            Self::InvalidCommand => NetFinishCause::InvalidPacket,
+            Self::LateCommands(_) => NetFinishCause::InvalidPacket,
             Self::GameOver => NetFinishCause::GameOver,
             Self::RemoteFinished(cause) => *cause,
             Self::Protobuf(_) => NetFinishCause::ClientError,
             Self::Bincode(_) => NetFinishCause::ClientError,
-            Self::KCP(_) => NetFinishCause::ClientError,
+            Self::KCP(failure) => failure.cause(),
             Self::Unexpected => NetFinishCause::ClientError,
             Self::InvalidFrame => NetFinishCause::ClientError,
+            Self::BeforeFirstFrame { .. } => NetFinishCause::ClientError,
             Self::MessageTooLong => NetFinishCause::ClientError,
+            Self::Misframed => NetFinishCause::ClientError,
+        };
+    }
+
+    // no wildcard arms: a new variant must be classified here
+    pub fn is_retryable(&self) -> Retryability {
+        return match self {
+            #[cfg(feature = "client")]
+            Self::IO(_) => Retryability::Always,
+            Self::Timeout => Retryability::Always,
+            Self::WindowExhausted => Retryability::Always,
+            Self::Unreachable => Retryability::Always,
+            // an overloaded server, don't pile on
+            Self::AcceptTimeout => Retryability::Bounded,
+            // usually a firewall, the same path likely fails again
+            Self::NoInboundTraffic => Retryability::Bounded,
+            Self::PacketBroken => Retryability::Bounded,
+            Self::PacketTooShort => Retryability::Bounded,
+            Self::PacketTooLong => Retryability::Bounded,
+            Self::FieldTooLong(_) => Retryability::Bounded,
+            Self::Oversized(_) => Retryability::Bounded,
+            Self::UnexpectedPacket => Retryability::Bounded,
+            Self::InvalidCommand => Retryability::Never,
+            Self::LateCommands(_) => Retryability::Never,
+            Self::GameOver => Retryability::Never,
+            Self::RemoteFinished(cause) => Retryability::from_cause(*cause),
+            Self::Protobuf(_) => Retryability::Never,
+            Self::Bincode(_) => Retryability::Never,
+            Self::KCP(failure) => failure.is_retryable(),
+            Self::Unexpected => Retryability::Never,
+            Self::InvalidFrame => Retryability::Never,
+            Self::BeforeFirstFrame { .. } => Retryability::Never,
+            Self::MessageTooLong => Retryability::Never,
+            Self::Misframed => Retryability::Never,
+        };
+    }
+
+    // a single packet that couldn't be decoded, as opposed to the link or
+    // the protocol state failing
+    pub fn is_malformed(&self) -> bool {
+        return match self {
+            #[cfg(feature = "client")]
+            Self::IO(_) => false,
+            Self::Timeout => false,
+            Self::WindowExhausted => false,
+            Self::Unreachable => false,
+            Self::AcceptTimeout => false,
+            Self::NoInboundTraffic => false,
+            Self::PacketBroken => true,
+            Self::PacketTooShort => true,
+            Self::PacketTooLong => true,
+            Self::FieldTooLong(_) => true,
+            // it can't be skipped, the next recv peeks it again
+            Self::Oversized(_) => false,
+            Self::UnexpectedPacket => false,
+            Self::InvalidCommand => false,
+            Self::LateCommands(_) => false,
+            Self::GameOver => false,
+            Self::RemoteFinished(_) => false,
+            Self::Protobuf(_) => true,
+            Self::Bincode(_) => true,
+            Self::KCP(failure) => *failure == KCPFailure::InputMalformed,
+            Self::Unexpected => false,
+            Self::InvalidFrame => false,
+            Self::BeforeFirstFrame { .. } => false,
+            Self::MessageTooLong => false,
+            Self::Misframed => false,
+        };
+    }
+
+    pub fn kcp_failure(&self) -> Option<KCPFailure> {
+        return match self {
+            Self::KCP(failure) => Some(*failure),
+            _ => None,
+        };
+    }
+}
+
+// public error type of the facade, groups KCPError by what callers can do
+// about it:
+//   IO        socket setup and other io failures
+//   Network   timeouts and a congested link (Timeout, WindowExhausted,
+//             Unreachable, AcceptTimeout, NoInboundTraffic,
+//             KCPFailure::SendQueueFull)
+//   Protocol  broken or unexpected packets from the peer
+//   Internal  client side bugs (encoding, frames, other ikcp failures)
+//   Config    rejected configuration
+//   Validation  an outgoing command refused by the CommandValidator
+//   Input     an input over the chan's byte limits
+//   Finished  the connection is over, locally or by the server
+//   Other     anything not raised by this crate
+#[cfg(feature = "client")]
+#[derive(Error, Debug)]
+pub enum ClientError {
+    #[error("io error")]
+    IO(#[source] std::io::Error),
+    #[error("network error: {0}")]
+    Network(#[source] KCPError),
+    #[error("protocol error: {0}")]
+    Protocol(#[source] KCPError),
+    #[error("internal error: {0}")]
+    Internal(#[source] KCPError),
+    #[error("config error: {0}")]
+    Config(#[from] ConfigError),
+    #[error("validation error: {0}")]
+    Validation(#[from] ValidationError),
+    #[error("input error: {0}")]
+    Input(InputError),
+    #[error("finished: {0:?}")]
+    Finished(NetFinishCause),
+    #[error("other error: {0}")]
+    Other(String),
+}
+
+#[cfg(feature = "client")]
+impl ClientError {
+    pub fn is_retryable(&self) -> Retryability {
+        return match self {
+            Self::IO(_) => Retryability::Always,
+            Self::Network(err) => err.is_retryable(),
+            Self::Protocol(err) => err.is_retryable(),
+            Self::Internal(err) => err.is_retryable(),
+            Self::Config(_) => Retryability::Never,
+            Self::Validation(_) => Retryability::Never,
+            Self::Input(err) => err.is_retryable(),
+            Self::Finished(cause) => Retryability::from_cause(*cause),
+            Self::Other(_) => Retryability::Never,
+        };
+    }
+
+    pub fn cause(&self) -> NetFinishCause {
+        return match self {
+            Self::IO(_) => NetFinishCause::NetworkBroken,
+            Self::Network(err) => err.cause(),
+            Self::Protocol(err) => err.cause(),
+            Self::Internal(err) => err.cause(),
+            Self::Config(_) => NetFinishCause::ClientError,
+            Self::Validation(_) => NetFinishCause::ClientError,
+            Self::Input(_) => NetFinishCause::ClientError,
+            Self::Finished(cause) => *cause,
+            Self::Other(_) => NetFinishCause::ClientError,
+        };
+    }
+}
+
+#[cfg(feature = "client")]
+impl From<KCPError> for ClientError {
+    fn from(err: KCPError) -> ClientError {
+        return match err {
+            KCPError::IO(err) => ClientError::IO(err),
+            KCPError::Timeout
+            | KCPError::WindowExhausted
+            | KCPError::Unreachable
+            | KCPError::AcceptTimeout
+            | KCPError::NoInboundTraffic => ClientError::Network(err),
+            KCPError::KCP(KCPFailure::SendQueueFull) => ClientError::Network(err),
+            KCPError::PacketBroken
+            | KCPError::PacketTooShort
+            | KCPError::PacketTooLong
+            | KCPError::FieldTooLong(_)
+            | KCPError::Oversized(_)
+            | KCPError::UnexpectedPacket
+            | KCPError::InvalidCommand
+            | KCPError::LateCommands(_) => ClientError::Protocol(err),
+            KCPError::KCP(KCPFailure::InputRejected)
+            | KCPError::KCP(KCPFailure::InputMalformed)
+            | KCPError::KCP(KCPFailure::InputUnknownCommand) => ClientError::Protocol(err),
+            KCPError::GameOver => ClientError::Finished(NetFinishCause::GameOver),
+            KCPError::RemoteFinished(cause) => ClientError::Finished(cause),
+            KCPError::Protobuf(_)
+            | KCPError::Bincode(_)
+            | KCPError::KCP(_)
+            | KCPError::Unexpected
+            | KCPError::InvalidFrame
+            | KCPError::BeforeFirstFrame { .. }
+            | KCPError::MessageTooLong
+            | KCPError::Misframed => ClientError::Internal(err),
+        };
+    }
+}
+
+#[cfg(feature = "client")]
+impl From<std::io::Error> for ClientError {
+    fn from(err: std::io::Error) -> ClientError {
+        return ClientError::IO(err);
+    }
+}
+
+#[cfg(feature = "client")]
+impl From<InputError> for ClientError {
+    fn from(err: InputError) -> ClientError {
+        return match err {
+            InputError::Finished(cause) => ClientError::Finished(cause),
+            err => ClientError::Input(err),
+        };
+    }
+}
+
+#[cfg(feature = "client")]
+impl From<NetFinishCause> for ClientError {
+    fn from(cause: NetFinishCause) -> ClientError {
+        return ClientError::Finished(cause);
+    }
+}
+
+// the worker keeps using anyhow internally
+#[cfg(feature = "client")]
+impl From<anyhow::Error> for ClientError {
+    fn from(err: anyhow::Error) -> ClientError {
+        let message = format!("{:#}", err);
+        let err = match err.downcast::<KCPError>() {
+            Ok(err) => return err.into(),
+            Err(err) => err,
+        };
+        let err = match err.downcast::<ConfigError>() {
+            Ok(err) => return err.into(),
+            Err(err) => err,
+        };
+        return match err.downcast::<std::io::Error>() {
+            Ok(err) => err.into(),
+            Err(_) => ClientError::Other(message),
+        };
+    }
+}
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    #[error("invalid {field}: {reason}")]
+    InvalidField {
+        field: &'static str,
+        reason: &'static str,
+    },
+    #[error("{field} too long: {len} > {limit} bytes")]
+    TooLong {
+        field: &'static str,
+        len: usize,
+        limit: usize,
+    },
+    // another worker holds it, see NetChan::attach()
+    #[error("chan already attached to a worker")]
+    ChanAlreadyAttached,
+}
+
+// rejected NetChan::send_input(), nothing was queued
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum InputError {
+    #[error("finished: {0:?}")]
+    Finished(NetFinishCause),
+    #[error("input too large: {size} > {limit} bytes")]
+    TooLarge { size: usize, limit: usize },
+    #[error("pending inputs over budget: {size} > {limit} bytes")]
+    OverBudget { size: usize, limit: usize },
+    #[error("hash too long: {len} > {limit} bytes")]
+    HashTooLong { len: usize, limit: usize },
+    // frames start at 1, a resumed session continues after its last frame
+    #[error("invalid frame: {frame}")]
+    InvalidFrame { frame: u32 },
+}
+
+impl InputError {
+    // the budget frees up once the worker catches up
+    pub fn is_retryable(&self) -> Retryability {
+        return match self {
+            Self::Finished(cause) => Retryability::from_cause(*cause),
+            Self::TooLarge { .. } => Retryability::Never,
+            Self::OverBudget { .. } => Retryability::Always,
+            Self::HashTooLong { .. } => Retryability::Never,
+            Self::InvalidFrame { .. } => Retryability::Never,
+        };
+    }
+}
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[error("invalid command: {reason}")]
+pub struct ValidationError {
+    pub reason: String,
+}
+
+impl ValidationError {
+    pub fn new(reason: &str) -> ValidationError {
+        return ValidationError {
+            reason: reason.to_string(),
+        };
+    }
+}
+
+// prost reports decoding and encoding failures with separate types
+#[derive(Error, Debug)]
+pub enum ProtobufError {
+    #[error("decode error: {0}")]
+    Decode(#[from] prost::DecodeError),
+    #[error("encode error: {0}")]
+    Encode(#[from] prost::EncodeError),
+}
+
+// negative ikcp return codes, their meaning depends on the called function
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KCPFailure {
+    // raised by our wrappers when ikcp_waitsnd reaches the window
+    #[error("send queue full")]
+    SendQueueFull,
+    #[error("invalid send size")]
+    InvalidSendSize,
+    #[error("message too large for window")]
+    MessageTooLargeForWindow,
+    #[error("receive queue empty")]
+    RecvQueueEmpty,
+    #[error("invalid peek size")]
+    InvalidPeekSize,
+    #[error("buffer too small")]
+    BufferTooSmall,
+    #[error("input too short or conv mismatch")]
+    InputRejected,
+    #[error("input segment malformed")]
+    InputMalformed,
+    #[error("input command unknown")]
+    InputUnknownCommand,
+    #[error("invalid mtu")]
+    InvalidMTU,
+    #[error("unknown code {0}")]
+    Unknown(i32),
+}
+
+impl KCPFailure {
+    pub fn from_send(code: i32) -> KCPFailure {
+        return match code {
+            -1 => Self::InvalidSendSize,
+            -2 => Self::MessageTooLargeForWindow,
+            _ => Self::Unknown(code),
+        };
+    }
+
+    pub fn from_recv(code: i32) -> KCPFailure {
+        return match code {
+            -1 => Self::RecvQueueEmpty,
+            -2 => Self::InvalidPeekSize,
+            -3 => Self::BufferTooSmall,
+            _ => Self::Unknown(code),
+        };
+    }
+
+    pub fn from_input(code: i32) -> KCPFailure {
+        return match code {
+            -1 => Self::InputRejected,
+            -2 => Self::InputMalformed,
+            -3 => Self::InputUnknownCommand,
+            _ => Self::Unknown(code),
+        };
+    }
+
+    pub fn from_setmtu(code: i32) -> KCPFailure {
+        return match code {
+            -1 => Self::InvalidMTU,
+            _ => Self::Unknown(code),
+        };
+    }
+
+    pub fn is_retryable(&self) -> Retryability {
+        return match self {
+            Self::SendQueueFull => Retryability::Always,
+            Self::InputRejected => Retryability::Bounded,
+            Self::InputMalformed => Retryability::Bounded,
+            Self::InputUnknownCommand => Retryability::Bounded,
+            Self::InvalidSendSize => Retryability::Never,
+            Self::MessageTooLargeForWindow => Retryability::Never,
+            Self::RecvQueueEmpty => Retryability::Never,
+            Self::InvalidPeekSize => Retryability::Never,
+            Self::BufferTooSmall => Retryability::Never,
+            Self::InvalidMTU => Retryability::Never,
+            Self::Unknown(_) => Retryability::Never,
+        };
+    }
+
+    pub fn cause(&self) -> NetFinishCause {
+        return match self {
+            Self::SendQueueFull => NetFinishCause::NetworkBroken,
+            Self::InputRejected => NetFinishCause::InvalidPacket,
+            Self::InputMalformed => NetFinishCause::InvalidPacket,
+            Self::InputUnknownCommand => NetFinishCause::InvalidPacket,
+            Self::InvalidSendSize => NetFinishCause::ClientError,
+            Self::MessageTooLargeForWindow => NetFinishCause::ClientError,
+            Self::RecvQueueEmpty => NetFinishCause::ClientError,
+            Self::InvalidPeekSize => NetFinishCause::ClientError,
+            Self::BufferTooSmall => NetFinishCause::ClientError,
+            Self::InvalidMTU => NetFinishCause::ClientError,
+            Self::Unknown(_) => NetFinishCause::ClientError,
+        };
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Retryability {
+    Never,
+    // worth a couple of attempts, e.g. corrupted packets
+    Bounded,
+    Always,
+}
+
+impl Retryability {
+    pub fn from_cause(cause: NetFinishCause) -> Retryability {
+        return match cause {
+            NetFinishCause::GameOver => Retryability::Never,
+            NetFinishCause::NetworkBroken => Retryability::Always,
+            NetFinishCause::InvalidPacket => Retryability::Bounded,
+            NetFinishCause::AuthFailed => Retryability::Never,
+            NetFinishCause::TimeOutOfSync => Retryability::Bounded,
+            NetFinishCause::DataOutOfSync => Retryability::Never,
+            NetFinishCause::OtherPlayer => Retryability::Never,
+            NetFinishCause::ServerError => Retryability::Bounded,
+            NetFinishCause::ClientError => Retryability::Never,
+            // reconnecting would take the match back from the other device
+            NetFinishCause::Superseded => Retryability::Never,
+        };
+    }
+
+    // whether another attempt is allowed after `attempts` retries
+    pub fn allows(&self, attempts: usize) -> bool {
+        return match self {
+            Self::Never => false,
+            Self::Bounded => attempts < BOUNDED_RETRIES,
+            Self::Always => attempts < CONNECT_RETRIES,
+        };
+    }
+}
+
+// what the worker knew when an error was raised
+#[cfg(feature = "client")]
+#[derive(Debug, Clone, PartialEq)]
+pub struct WorkerContext {
+    pub addr: SocketAddr,
+    pub conv: u32,
+    pub state: NetPlayerState,
+    pub frame: u32,
+    pub input_frame: Option<u32>,
+}
+
+#[cfg(feature = "client")]
+impl fmt::Display for WorkerContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "addr {} conv {} state {:?} frame {}",
+            self.addr, self.conv, self.state, self.frame
+        )?;
+        if let Some(input_frame) = self.input_frame {
+            write!(f, " input frame {}", input_frame)?;
+        }
+        return Ok(());
+    }
+}
+
+#[cfg(feature = "client")]
+#[derive(Debug, Clone, PartialEq)]
+pub struct FinishInfo {
+    pub cause: NetFinishCause,
+    pub context: Option<WorkerContext>,
+    pub message: String,
+    // what the server kept sending after we stopped
+    pub ignored_packets: IgnoredPackets,
+    // how far the last connect got
+    pub connect: ConnectTimes,
+    // of everything delivered so far, with WorkerConfig::delivery_hash
+    pub delivery_hash: Option<u64>,
+    // set by the worker, updated while it drains
+    pub summary: Option<SessionSummary>,
+    // with WorkerConfig::decision_log, the ticks before a finish other than
+    // GameOver
+    pub decisions: Option<DecisionLog>,
+}
+
+#[cfg(feature = "client")]
+impl FinishInfo {
+    pub fn new(cause: NetFinishCause) -> FinishInfo {
+        return FinishInfo {
+            cause,
+            context: None,
+            message: String::new(),
+            ignored_packets: IgnoredPackets::default(),
+            connect: ConnectTimes::default(),
+            delivery_hash: None,
+            summary: None,
+            decisions: None,
+        };
+    }
+}
+
+// ms the stages of a connect took, None until done: reaching the server (the
+// first datagram back, e.g. the ack of the Connect) and the Accept after that
+#[cfg(feature = "client")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConnectTimes {
+    pub reach: Option<u64>,
+    pub accept: Option<u64>,
+}
+
+// packets received while Stopped per NetType, fixed size so NetStats stays a
+// cheap copy, types beyond it are counted as Unknown
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IgnoredPackets {
+    counts: [u64; IGNORED_TYPES],
+    bytes: u64,
+}
+
+impl IgnoredPackets {
+    pub fn record(&mut self, packet: &[u8]) {
+        let idx = packet_type(packet);
+        self.counts[idx] = self.counts[idx].saturating_add(1);
+        self.bytes = self.bytes.saturating_add(packet.len() as u64);
+    }
+
+    pub fn count(&self, kind: NetType) -> u64 {
+        return self.counts.get(kind as usize).copied().unwrap_or(0);
+    }
+
+    pub fn total(&self) -> u64 {
+        return self.counts.iter().sum();
+    }
+
+    pub fn bytes(&self) -> u64 {
+        return self.bytes;
+    }
+}
+
+// below IGNORED_TYPES, only the type byte is looked at
+pub fn packet_type(packet: &[u8]) -> usize {
+    let kind = match packet.first() {
+        Some(kind) => NetType::try_from(*kind as i32).unwrap_or(NetType::Unknown),
+        None => NetType::Unknown,
+    };
+    return match kind as usize {
+        idx if idx < IGNORED_TYPES => idx,
+        _ => NetType::Unknown as usize,
+    };
+}
+
+// bytes a string or bytes field of a decoded message may carry, checked by
+// NetMessage::decode() so a broken or hostile packet can't hand over more
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProtocolLimits {
+    pub max_room_id: usize,
+    pub max_player_id: usize,
+    pub max_password: usize,
+    pub max_response: usize,
+    // resume tokens of NetConnect and NetAccept
+    pub max_token: usize,
+    pub max_nonce: usize,
+    pub max_hash: usize,
+}
+
+impl Default for ProtocolLimits {
+    fn default() -> ProtocolLimits {
+        return ProtocolLimits {
+            max_room_id: CREDENTIAL_MAX_BYTES,
+            max_player_id: CREDENTIAL_MAX_BYTES,
+            max_password: CREDENTIAL_MAX_BYTES,
+            max_response: PROTOCOL_MAX_RESPONSE,
+            max_token: PROTOCOL_MAX_TOKEN,
+            max_nonce: PROTOCOL_MAX_NONCE,
+            max_hash: HASH_CAP,
+        };
+    }
+}
+
+// optional wire behaviors, advertised in NetConnect and enabled by the
+// server in NetAccept, a legacy server enabling none, bits this client
+// doesn't know are dropped
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Capabilities {
+    bits: u64,
+}
+
+impl Capabilities {
+    // zstd command tails, with the dictionary_id agreed on as well
+    pub const COMPRESSION: Capabilities = Capabilities { bits: 1 << 0 };
+    // NetCommand.padding
+    pub const PADDING: Capabilities = Capabilities { bits: 1 << 1 };
+    // the CommandVersion::V2 command tail
+    pub const COMMANDS_V2: Capabilities = Capabilities { bits: 1 << 2 };
+    const KNOWN: u64 = Self::COMPRESSION.bits | Self::PADDING.bits | Self::COMMANDS_V2.bits;
+
+    pub fn from_bits(bits: u64) -> Capabilities {
+        return Capabilities {
+            bits: bits & Self::KNOWN,
+        };
+    }
+
+    pub fn bits(&self) -> u64 {
+        return self.bits;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.bits == 0;
+    }
+
+    pub fn contains(&self, other: Capabilities) -> bool {
+        return self.bits & other.bits == other.bits;
+    }
+
+    pub fn insert(&mut self, other: Capabilities) {
+        self.bits |= other.bits;
+    }
+
+    pub fn intersect(&self, other: Capabilities) -> Capabilities {
+        return Capabilities {
+            bits: self.bits & other.bits,
         };
     }
 }
+
+// what NetWorker::connect_blocking() got in with, the roster is of the
+// States that came with the Accept, sorted by conv
+#[cfg(feature = "client")]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Acceptance {
+    pub conv: u32,
+    pub roster: Vec<(u32, NetPlayerState)>,
+    // empty when the server doesn't support resuming
+    pub resume_token: Vec<u8>,
+    pub capabilities: Capabilities,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StartInfo {
+    pub conv: u32,
+    pub started_at: SystemTime,
+    // the first frame inputs are taken for, after the server's first frame
+    // and a resumed session's last
+    pub first_frame: u32,
+}
+
+// an assigned player conv, 0 on the wire means "not assigned yet" (e.g.
+// commands the server hasn't stamped) and UNRELIABLE_CONV tags side-channel
+// datagrams, neither may key a kcp session or a player's state
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Conv(NonZeroU32);
+
+impl Conv {
+    // None for the unassigned 0
+    pub fn new(conv: u32) -> Option<Conv> {
+        return NonZeroU32::new(conv).map(Conv);
+    }
+
+    pub fn get(self) -> u32 {
+        return self.0.get();
+    }
+}
+
+impl TryFrom<u32> for Conv {
+    type Error = ConfigError;
+
+    fn try_from(conv: u32) -> Result<Conv, ConfigError> {
+        if conv == UNRELIABLE_CONV {
+            return Err(ConfigError::InvalidField {
+                field: "conv",
+                reason: "reserved",
+            });
+        }
+        return Conv::new(conv).ok_or(ConfigError::InvalidField {
+            field: "conv",
+            reason: "not assigned",
+        });
+    }
+}
+
+impl From<Conv> for u32 {
+    fn from(conv: Conv) -> u32 {
+        return conv.get();
+    }
+}
+
+impl fmt::Display for Conv {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(f, "{}", self.0);
+    }
+}
+
+// counts dropped packets per category and logs at most one summary line per
+// category every `interval` ms instead of one line per packet, times are in ms
+pub struct RateLimitedLogger {
+    interval: u64,
+    sink: Box<dyn FnMut(&str) + Send>,
+    categories: Vec<LogCategory>,
+}
+
+struct LogCategory {
+    name: &'static str,
+    total: u64,
+    pending: u64,
+    since: u64,
+}
+
+impl RateLimitedLogger {
+    pub fn new(interval: u64) -> RateLimitedLogger {
+        return RateLimitedLogger::with_sink(interval, Box::new(|line| println!("{}", line)));
+    }
+
+    pub fn with_sink(interval: u64, sink: Box<dyn FnMut(&str) + Send>) -> RateLimitedLogger {
+        return RateLimitedLogger {
+            interval,
+            sink,
+            categories: Vec::new(),
+        };
+    }
+
+    // `name` reads as a plural noun, "malformed datagrams"
+    pub fn count(&mut self, name: &'static str, now: u64) {
+        let idx = match self.categories.iter().position(|c| c.name == name) {
+            Some(idx) => idx,
+            None => {
+                self.categories.push(LogCategory {
+                    name,
+                    total: 0,
+                    pending: 0,
+                    since: now,
+                });
+                self.categories.len() - 1
+            }
+        };
+        self.flush(now);
+        let category = &mut self.categories[idx];
+        if category.pending == 0 {
+            category.since = now;
+        }
+        category.total += 1;
+        category.pending += 1;
+    }
+
+    // logs the categories whose interval is over, call it regularly so the
+    // tail of a flood is reported too
+    pub fn flush(&mut self, now: u64) {
+        for category in self.categories.iter_mut() {
+            if category.pending == 0 || now < category.since + self.interval {
+                continue;
+            }
+            let line = format!(
+                "dropped {} {} in last {}s",
+                category.pending,
+                category.name,
+                (now - category.since + 999) / 1000
+            );
+            (self.sink)(&line);
+            category.pending = 0;
+        }
+    }
+
+    pub fn total(&self, name: &str) -> u64 {
+        return match self.categories.iter().find(|c| c.name == name) {
+            Some(category) => category.total,
+            None => 0,
+        };
+    }
+}
+
+impl fmt::Debug for RateLimitedLogger {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let totals: Vec<_> = self.categories.iter().map(|c| (c.name, c.total)).collect();
+        return f
+            .debug_struct("RateLimitedLogger")
+            .field("interval", &self.interval)
+            .field("totals", &totals)
+            .finish();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::message::NetState;
+    use prost::Message;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    #[cfg(feature = "client")]
+    fn test_client_error_classes() {
+        let io = || std::io::Error::new(std::io::ErrorKind::Other, "io");
+        assert!(matches!(
+            ClientError::from(KCPError::IO(io())),
+            ClientError::IO(_)
+        ));
+        assert!(matches!(ClientError::from(io()), ClientError::IO(_)));
+
+        for err in [
+            KCPError::Timeout,
+            KCPError::WindowExhausted,
+            KCPError::Unreachable,
+            KCPError::AcceptTimeout,
+            KCPError::NoInboundTraffic,
+            KCPError::KCP(KCPFailure::SendQueueFull),
+        ] {
+            assert!(matches!(ClientError::from(err), ClientError::Network(_)));
+        }
+
+        for err in [
+            KCPError::PacketBroken,
+            KCPError::PacketTooShort,
+            KCPError::PacketTooLong,
+            KCPError::FieldTooLong("hash"),
+            KCPError::Oversized(KCP_MAX_PACKET + 1),
+            KCPError::UnexpectedPacket,
+            KCPError::InvalidCommand,
+            KCPError::LateCommands(3),
+            KCPError::KCP(KCPFailure::InputMalformed),
+        ] {
+            let err = ClientError::from(err);
+            assert!(matches!(err, ClientError::Protocol(_)));
+            assert_eq!(err.cause(), NetFinishCause::InvalidPacket);
+        }
+
+        for err in [
+            KCPError::Unexpected,
+            KCPError::InvalidFrame,
+            KCPError::BeforeFirstFrame {
+                frame: 1,
+                first_frame: 100,
+            },
+            KCPError::MessageTooLong,
+            KCPError::Misframed,
+            KCPError::KCP(KCPFailure::BufferTooSmall),
+        ] {
+            let err = ClientError::from(err);
+            assert!(matches!(err, ClientError::Internal(_)));
+            assert_eq!(err.cause(), NetFinishCause::ClientError);
+        }
+
+        let err = ClientError::from(KCPError::RemoteFinished(NetFinishCause::AuthFailed));
+        assert!(matches!(
+            err,
+            ClientError::Finished(NetFinishCause::AuthFailed)
+        ));
+        let err = ClientError::from(KCPError::GameOver);
+        assert!(matches!(
+            err,
+            ClientError::Finished(NetFinishCause::GameOver)
+        ));
+
+        let err = anyhow::Error::new(KCPError::Timeout).context("NetWorker::update()");
+        assert!(matches!(ClientError::from(err), ClientError::Network(_)));
+        let err = anyhow::anyhow!("boom");
+        assert!(matches!(ClientError::from(err), ClientError::Other(_)));
+
+        let err = ClientError::from(ConfigError::InvalidField {
+            field: "conv",
+            reason: "reserved",
+        });
+        assert_eq!(err.to_string(), "config error: invalid conv: reserved");
+        let err: anyhow::Error = err.into();
+        assert!(matches!(ClientError::from(err), ClientError::Config(_)));
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let mut both = Capabilities::default();
+        assert!(both.is_empty());
+        both.insert(Capabilities::COMPRESSION);
+        both.insert(Capabilities::PADDING);
+        assert_eq!(Capabilities::from_bits(both.bits()), both);
+        assert!(both.contains(Capabilities::PADDING));
+        assert!(!Capabilities::PADDING.contains(both));
+
+        // unknown bits from the server are dropped
+        let unknown = Capabilities::from_bits(1 << 63 | Capabilities::PADDING.bits());
+        assert_eq!(unknown, Capabilities::PADDING);
+        let mut all = both;
+        all.insert(Capabilities::COMMANDS_V2);
+        assert_eq!(Capabilities::from_bits(u64::MAX), all);
+        assert_eq!(both.intersect(unknown), Capabilities::PADDING);
+        assert!(Capabilities::COMPRESSION
+            .intersect(Capabilities::PADDING)
+            .is_empty());
+    }
+
+    #[test]
+    fn test_conv() {
+        assert_eq!(Conv::new(0), None);
+        let conv = Conv::new(6666).unwrap();
+        assert_eq!(conv.get(), 6666);
+        assert_eq!(u32::from(conv), 6666);
+        assert_eq!(conv.to_string(), "6666");
+        assert_eq!(Conv::try_from(6666), Ok(conv));
+        assert_eq!(
+            Conv::try_from(0),
+            Err(ConfigError::InvalidField {
+                field: "conv",
+                reason: "not assigned"
+            })
+        );
+        assert_eq!(
+            Conv::try_from(UNRELIABLE_CONV),
+            Err(ConfigError::InvalidField {
+                field: "conv",
+                reason: "reserved"
+            })
+        );
+        assert!(Conv::new(1) < Conv::new(2));
+    }
+
+    #[test]
+    fn test_retryability() {
+        let protobuf = NetState::decode(&[0xff][..]).unwrap_err();
+        let bincode = Box::new(bincode::ErrorKind::SizeLimit);
+        #[allow(unused_mut)]
+        let mut cases = vec![
+            (KCPError::Timeout, Retryability::Always),
+            (KCPError::WindowExhausted, Retryability::Always),
+            (KCPError::Unreachable, Retryability::Always),
+            (KCPError::AcceptTimeout, Retryability::Bounded),
+            (KCPError::NoInboundTraffic, Retryability::Bounded),
+            (KCPError::PacketBroken, Retryability::Bounded),
+            (KCPError::PacketTooShort, Retryability::Bounded),
+            (KCPError::PacketTooLong, Retryability::Bounded),
+            (KCPError::FieldTooLong("hash"), Retryability::Bounded),
+            (
+                KCPError::Oversized(KCP_MAX_PACKET + 1),
+                Retryability::Bounded,
+            ),
+            (KCPError::UnexpectedPacket, Retryability::Bounded),
+            (KCPError::InvalidCommand, Retryability::Never),
+            (KCPError::LateCommands(3), Retryability::Never),
+            (KCPError::GameOver, Retryability::Never),
+            (
+                KCPError::RemoteFinished(NetFinishCause::AuthFailed),
+                Retryability::Never,
+            ),
+            (
+                KCPError::RemoteFinished(NetFinishCause::ServerError),
+                Retryability::Bounded,
+            ),
+            (
+                KCPError::RemoteFinished(NetFinishCause::Superseded),
+                Retryability::Never,
+            ),
+            (
+                KCPError::RemoteFinished(NetFinishCause::NetworkBroken),
+                Retryability::Always,
+            ),
+            (KCPError::Protobuf(protobuf.into()), Retryability::Never),
+            (KCPError::Bincode(bincode), Retryability::Never),
+            (
+                KCPError::KCP(KCPFailure::SendQueueFull),
+                Retryability::Always,
+            ),
+            (
+                KCPError::KCP(KCPFailure::InputMalformed),
+                Retryability::Bounded,
+            ),
+            (
+                KCPError::KCP(KCPFailure::BufferTooSmall),
+                Retryability::Never,
+            ),
+            (KCPError::Unexpected, Retryability::Never),
+            (KCPError::InvalidFrame, Retryability::Never),
+            (
+                KCPError::BeforeFirstFrame {
+                    frame: 1,
+                    first_frame: 100,
+                },
+                Retryability::Never,
+            ),
+            (KCPError::MessageTooLong, Retryability::Never),
+            (KCPError::Misframed, Retryability::Never),
+        ];
+        #[cfg(feature = "client")]
+        cases.push((
+            KCPError::IO(std::io::Error::new(std::io::ErrorKind::Other, "io")),
+            Retryability::Always,
+        ));
+        for (err, retryability) in cases {
+            assert_eq!(err.is_retryable(), retryability, "{:?}", err);
+            #[cfg(feature = "client")]
+            assert_eq!(
+                ClientError::from(err).is_retryable(),
+                retryability,
+                "client error"
+            );
+        }
+
+        assert!(!Retryability::Never.allows(0));
+        assert!(Retryability::Bounded.allows(BOUNDED_RETRIES - 1));
+        assert!(!Retryability::Bounded.allows(BOUNDED_RETRIES));
+        assert!(Retryability::Always.allows(CONNECT_RETRIES - 1));
+        assert!(!Retryability::Always.allows(CONNECT_RETRIES));
+    }
+
+    #[test]
+    fn test_rate_limited_logger() {
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let sink = lines.clone();
+        let mut logger = RateLimitedLogger::with_sink(
+            LOG_INTERVAL,
+            Box::new(move |line| sink.lock().unwrap().push(line.to_string())),
+        );
+
+        // a flood over 20s, one summary per category per interval
+        for now in 0..20_000 {
+            logger.count("malformed datagrams", now);
+            if now % 10 == 0 {
+                logger.count("undecodable packets", now);
+            }
+        }
+        logger.flush(20_000);
+        assert_eq!(logger.total("malformed datagrams"), 20_000);
+        assert_eq!(logger.total("undecodable packets"), 2_000);
+        assert_eq!(logger.total("foreign conv datagrams"), 0);
+        let lines = lines.lock().unwrap().clone();
+        assert_eq!(lines.len(), 8);
+        assert_eq!(lines[0], "dropped 5000 malformed datagrams in last 5s");
+        assert_eq!(lines[1], "dropped 500 undecodable packets in last 5s");
+
+        // quiet again, nothing to report until the next drop
+        let mut silent = RateLimitedLogger::with_sink(LOG_INTERVAL, Box::new(|_| panic!()));
+        silent.count("malformed datagrams", 0);
+        silent.flush(LOG_INTERVAL - 1);
+    }
+}
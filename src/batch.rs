@@ -0,0 +1,444 @@
+use crate::base::{KCP_MTU, RECV_BATCH, SEND_BATCH};
+use std::io;
+use std::net::SocketAddr;
+#[cfg(target_os = "linux")]
+use std::os::unix::io::{AsRawFd, RawFd};
+
+// the sockets a batch can be sent on
+pub trait BatchSocket {
+    fn send_one(&self, datagram: &[u8], peer: SocketAddr) -> io::Result<usize>;
+    fn recv_one(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)>;
+
+    #[cfg(target_os = "linux")]
+    fn raw_fd(&self) -> RawFd;
+}
+
+impl BatchSocket for std::net::UdpSocket {
+    fn send_one(&self, datagram: &[u8], peer: SocketAddr) -> io::Result<usize> {
+        return self.send_to(datagram, peer);
+    }
+
+    fn recv_one(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        return self.recv_from(buf);
+    }
+
+    #[cfg(target_os = "linux")]
+    fn raw_fd(&self) -> RawFd {
+        return self.as_raw_fd();
+    }
+}
+
+impl BatchSocket for mio::net::UdpSocket {
+    fn send_one(&self, datagram: &[u8], peer: SocketAddr) -> io::Result<usize> {
+        return self.send_to(datagram, peer);
+    }
+
+    fn recv_one(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        return self.recv_from(buf);
+    }
+
+    #[cfg(target_os = "linux")]
+    fn raw_fd(&self) -> RawFd {
+        return self.as_raw_fd();
+    }
+}
+
+// Collects the datagrams kcp outputs during one flush, so they go out with
+// one sendmmsg on Linux instead of a send_to each. Order is kept.
+#[derive(Debug)]
+pub struct SendBatch {
+    data: Vec<u8>,
+    ends: Vec<usize>,
+    syscalls: u64,
+    syscalls_saved: u64,
+}
+
+impl SendBatch {
+    pub fn new() -> SendBatch {
+        return SendBatch {
+            data: Vec::with_capacity(SEND_BATCH * KCP_MTU),
+            ends: Vec::with_capacity(SEND_BATCH),
+            syscalls: 0,
+            syscalls_saved: 0,
+        };
+    }
+
+    // for the kcp output callback
+    pub fn push(&mut self, datagram: &[u8]) {
+        self.data.extend_from_slice(datagram);
+        self.ends.push(self.data.len());
+    }
+
+    pub fn len(&self) -> usize {
+        return self.ends.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.ends.is_empty();
+    }
+
+    pub fn syscalls(&self) -> u64 {
+        return self.syscalls;
+    }
+
+    // compared to a send_to per datagram
+    pub fn syscalls_saved(&self) -> u64 {
+        return self.syscalls_saved;
+    }
+
+    fn datagram(&self, index: usize) -> &[u8] {
+        let start = if index == 0 { 0 } else { self.ends[index - 1] };
+        return &self.data[start..self.ends[index]];
+    }
+
+    // sends and clears the batch, returns how many datagrams went out, the
+    // rest are dropped on an error and left to kcp to retransmit
+    pub fn send<S: BatchSocket>(&mut self, socket: &S, peer: SocketAddr) -> io::Result<usize> {
+        return self.send_with(socket, peer, |_| {});
+    }
+
+    // the same, `on_sent` sees each datagram that went out, for the meters
+    pub fn send_with<S, F>(
+        &mut self,
+        socket: &S,
+        peer: SocketAddr,
+        mut on_sent: F,
+    ) -> io::Result<usize>
+    where
+        S: BatchSocket,
+        F: FnMut(&[u8]),
+    {
+        let result = self.send_impl(socket, peer);
+        if let Ok(sent) = result {
+            for i in 0..sent {
+                on_sent(self.datagram(i));
+            }
+        }
+        self.data.clear();
+        self.ends.clear();
+        return result;
+    }
+
+    #[cfg(target_os = "linux")]
+    fn send_impl<S: BatchSocket>(&mut self, socket: &S, peer: SocketAddr) -> io::Result<usize> {
+        let (addr, addr_len) = sockaddr(peer);
+        let mut sent = 0;
+        while sent < self.len() {
+            let count = (self.len() - sent).min(SEND_BATCH);
+            // no allocation, the headers point into `data` and `iovecs`
+            let mut iovecs: [libc::iovec; SEND_BATCH] = unsafe { std::mem::zeroed() };
+            let mut headers: [libc::mmsghdr; SEND_BATCH] = unsafe { std::mem::zeroed() };
+            for i in 0..count {
+                let datagram = self.datagram(sent + i);
+                iovecs[i].iov_base = datagram.as_ptr() as *mut libc::c_void;
+                iovecs[i].iov_len = datagram.len();
+                let hdr = &mut headers[i].msg_hdr;
+                hdr.msg_name = &addr as *const libc::sockaddr_storage as *mut libc::c_void;
+                hdr.msg_namelen = addr_len;
+                hdr.msg_iov = &mut iovecs[i];
+                hdr.msg_iovlen = 1;
+            }
+            let n = unsafe {
+                libc::sendmmsg(
+                    socket.raw_fd(),
+                    headers.as_mut_ptr(),
+                    count as libc::c_uint,
+                    0,
+                )
+            };
+            if n < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            self.syscalls += 1;
+            if n == 0 {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            // after a partial send the rest gets another call
+            self.syscalls_saved += n as u64 - 1;
+            sent += n as usize;
+        }
+        return Ok(sent);
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn send_impl<S: BatchSocket>(&mut self, socket: &S, peer: SocketAddr) -> io::Result<usize> {
+        return self.send_each(socket, peer);
+    }
+
+    #[allow(dead_code)]
+    fn send_each<S: BatchSocket>(&mut self, socket: &S, peer: SocketAddr) -> io::Result<usize> {
+        for i in 0..self.len() {
+            socket.send_one(self.datagram(i), peer)?;
+            self.syscalls += 1;
+        }
+        return Ok(self.len());
+    }
+}
+
+// Drains at most `max` queued datagrams per tick, with recvmmsg on Linux and a
+// recv_from loop elsewhere. The rest stay in the kernel for the next tick, so
+// a backlog can't starve the tick. The socket must be non-blocking.
+#[derive(Debug)]
+pub struct RecvBatch {
+    // a KCP_MTU slot per datagram, longer ones are truncated
+    data: Vec<u8>,
+    lens: Vec<usize>,
+    peers: Vec<SocketAddr>,
+    max: usize,
+    syscalls: u64,
+}
+
+impl RecvBatch {
+    pub fn new(max: usize) -> RecvBatch {
+        let max = max.max(1);
+        return RecvBatch {
+            data: vec![0; max * KCP_MTU],
+            lens: Vec::with_capacity(max),
+            peers: Vec::with_capacity(max),
+            max,
+            syscalls: 0,
+        };
+    }
+
+    pub fn len(&self) -> usize {
+        return self.lens.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.lens.is_empty();
+    }
+
+    pub fn syscalls(&self) -> u64 {
+        return self.syscalls;
+    }
+
+    // the datagrams of the last recv(), for ikcp_input
+    pub fn get(&self, index: usize) -> (&[u8], SocketAddr) {
+        let start = index * KCP_MTU;
+        return (
+            &self.data[start..start + self.lens[index]],
+            self.peers[index],
+        );
+    }
+
+    // replaces the batch with what is queued, up to `max`, an empty socket
+    // is not an error
+    pub fn recv<S: BatchSocket>(&mut self, socket: &S) -> io::Result<usize> {
+        return self.recv_at_most(socket, self.max);
+    }
+
+    // the same with a lower bound, for what is left of a tick's
+    pub fn recv_at_most<S: BatchSocket>(&mut self, socket: &S, max: usize) -> io::Result<usize> {
+        self.lens.clear();
+        self.peers.clear();
+        match self.recv_impl(socket, max.min(self.max)) {
+            Err(err) if err.kind() != io::ErrorKind::WouldBlock => return Err(err),
+            _ => return Ok(self.len()),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn recv_impl<S: BatchSocket>(&mut self, socket: &S, max: usize) -> io::Result<()> {
+        while self.len() < max {
+            let first = self.len();
+            let count = (max - first).min(RECV_BATCH);
+            let mut addrs: [libc::sockaddr_storage; RECV_BATCH] = unsafe { std::mem::zeroed() };
+            let mut iovecs: [libc::iovec; RECV_BATCH] = unsafe { std::mem::zeroed() };
+            let mut headers: [libc::mmsghdr; RECV_BATCH] = unsafe { std::mem::zeroed() };
+            for i in 0..count {
+                let slot = &mut self.data[(first + i) * KCP_MTU..(first + i + 1) * KCP_MTU];
+                iovecs[i].iov_base = slot.as_mut_ptr() as *mut libc::c_void;
+                iovecs[i].iov_len = KCP_MTU;
+                let hdr = &mut headers[i].msg_hdr;
+                hdr.msg_name = &mut addrs[i] as *mut libc::sockaddr_storage as *mut libc::c_void;
+                hdr.msg_namelen = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+                hdr.msg_iov = &mut iovecs[i];
+                hdr.msg_iovlen = 1;
+            }
+            let n = unsafe {
+                libc::recvmmsg(
+                    socket.raw_fd(),
+                    headers.as_mut_ptr(),
+                    count as libc::c_uint,
+                    libc::MSG_DONTWAIT,
+                    std::ptr::null_mut(),
+                )
+            };
+            self.syscalls += 1;
+            if n < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            for i in 0..n as usize {
+                let peer = match from_sockaddr(&addrs[i]) {
+                    Some(peer) => peer,
+                    None => continue,
+                };
+                // packed, a skipped datagram leaves no gap
+                let slot = (first + i) * KCP_MTU;
+                let at = self.len() * KCP_MTU;
+                let len = headers[i].msg_len as usize;
+                self.data.copy_within(slot..slot + len, at);
+                self.lens.push(len);
+                self.peers.push(peer);
+            }
+            // the queue is empty
+            if (n as usize) < count {
+                break;
+            }
+        }
+        return Ok(());
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn recv_impl<S: BatchSocket>(&mut self, socket: &S, max: usize) -> io::Result<()> {
+        return self.recv_each(socket, max);
+    }
+
+    #[allow(dead_code)]
+    fn recv_each<S: BatchSocket>(&mut self, socket: &S, max: usize) -> io::Result<()> {
+        while self.len() < max {
+            let start = self.len() * KCP_MTU;
+            self.syscalls += 1;
+            let (len, peer) = socket.recv_one(&mut self.data[start..start + KCP_MTU])?;
+            self.lens.push(len);
+            self.peers.push(peer);
+        }
+        return Ok(());
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn from_sockaddr(storage: &libc::sockaddr_storage) -> Option<SocketAddr> {
+    match storage.ss_family as libc::c_int {
+        libc::AF_INET => {
+            let sin = unsafe { &*(storage as *const _ as *const libc::sockaddr_in) };
+            let ip = std::net::Ipv4Addr::from(sin.sin_addr.s_addr.to_ne_bytes());
+            return Some(SocketAddr::from((ip, u16::from_be(sin.sin_port))));
+        }
+        libc::AF_INET6 => {
+            let sin6 = unsafe { &*(storage as *const _ as *const libc::sockaddr_in6) };
+            let ip = std::net::Ipv6Addr::from(sin6.sin6_addr.s6_addr);
+            return Some(SocketAddr::V6(std::net::SocketAddrV6::new(
+                ip,
+                u16::from_be(sin6.sin6_port),
+                sin6.sin6_flowinfo,
+                sin6.sin6_scope_id,
+            )));
+        }
+        _ => return None,
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn sockaddr(addr: SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let len = match addr {
+        SocketAddr::V4(addr) => {
+            let sin = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in) };
+            sin.sin_family = libc::AF_INET as libc::sa_family_t;
+            sin.sin_port = addr.port().to_be();
+            sin.sin_addr.s_addr = u32::from_ne_bytes(addr.ip().octets());
+            std::mem::size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(addr) => {
+            let sin6 = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6) };
+            sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sin6.sin6_port = addr.port().to_be();
+            sin6.sin6_flowinfo = addr.flowinfo();
+            sin6.sin6_addr.s6_addr = addr.ip().octets();
+            sin6.sin6_scope_id = addr.scope_id();
+            std::mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+    return (storage, len as libc::socklen_t);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::UdpSocket;
+
+    fn recv_all(socket: &UdpSocket, count: usize) -> Vec<Vec<u8>> {
+        let mut buf = [0; KCP_MTU];
+        return (0..count)
+            .map(|_| {
+                let (n, _) = socket.recv_from(&mut buf).unwrap();
+                return buf[..n].to_vec();
+            })
+            .collect();
+    }
+
+    fn datagrams() -> Vec<Vec<u8>> {
+        return (0..SEND_BATCH as u8 + 3)
+            .map(|i| vec![i; 1 + i as usize * 20])
+            .collect();
+    }
+
+    #[test]
+    fn test_send_batch_each() {
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut batch = SendBatch::new();
+        for datagram in datagrams() {
+            batch.push(&datagram);
+        }
+        let peer = receiver.local_addr().unwrap();
+        assert_eq!(batch.send_each(&sender, peer).unwrap(), datagrams().len());
+        assert_eq!(batch.syscalls(), datagrams().len() as u64);
+        assert_eq!(batch.syscalls_saved(), 0);
+        assert_eq!(recv_all(&receiver, datagrams().len()), datagrams());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_send_batch_sendmmsg() {
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let peer = receiver.local_addr().unwrap();
+        let mut batch = SendBatch::new();
+        for datagram in datagrams() {
+            batch.push(&datagram);
+        }
+        assert_eq!(batch.len(), SEND_BATCH + 3);
+        assert_eq!(batch.send(&sender, peer).unwrap(), datagrams().len());
+        assert!(batch.is_empty());
+        // two calls, SEND_BATCH and the 3 left
+        assert_eq!(batch.syscalls(), 2);
+        assert_eq!(batch.syscalls_saved(), datagrams().len() as u64 - 2);
+        assert_eq!(recv_all(&receiver, datagrams().len()), datagrams());
+
+        batch.push(&[1, 2, 3]);
+        assert_eq!(batch.send(&sender, peer).unwrap(), 1);
+        assert_eq!(recv_all(&receiver, 1), vec![vec![1, 2, 3]]);
+        assert_eq!(batch.syscalls_saved(), datagrams().len() as u64 - 2);
+    }
+
+    #[test]
+    fn test_recv_batch_bounded_drain() {
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver.set_nonblocking(true).unwrap();
+        let peer = receiver.local_addr().unwrap();
+        for i in 0..50u8 {
+            sender.send_to(&[i; 3], peer).unwrap();
+        }
+        std::thread::sleep(std::time::Duration::from_millis(50));
+
+        // a tick each, ceil(50 / 16)
+        let mut batch = RecvBatch::new(16);
+        let mut received = Vec::new();
+        let mut ticks = 0;
+        while batch.recv(&receiver).unwrap() > 0 {
+            ticks += 1;
+            assert!(batch.len() <= 16);
+            for i in 0..batch.len() {
+                let (datagram, from) = batch.get(i);
+                assert_eq!(from, sender.local_addr().unwrap());
+                received.push(datagram[0]);
+            }
+        }
+        assert_eq!(ticks, 4);
+        assert_eq!(received, (0..50).collect::<Vec<u8>>());
+        assert!(batch.is_empty());
+    }
+}
@@ -1,25 +1,42 @@
-use crate::base::{KCPError, COMMANDS_CAP, HASH_CAP, PLAYERS_CAP};
-use crate::codec::{Command, CommandEx};
+use crate::bandwidth::Bandwidth;
+use crate::base::{
+    Capabilities, ConfigError, ConnectTimes, FinishInfo, IgnoredPackets, InputError, KCPError,
+    StartInfo, HASH_CAP, INPUT_MAX_BYTES, INPUT_PENDING_BYTES, KCP_FRAME_SEGMENTS, KCP_WINDOW_SIZE,
+    OUTPUT_MAX_COMMANDS, PLAYERS_CAP, UNRELIABLE_MAX_PAYLOAD, UNRELIABLE_QUEUE, WARNINGS_CAP,
+};
+use crate::codec::{Command, CommandBatch, CommandEx, Commands};
+use crate::delivery::DeliveryHasher;
+use crate::estimate::FrameEstimate;
+use crate::latency::AckLatency;
 use crate::message::{NetFinishCause, NetPlayerState};
+use crate::pool::{self, BufferPool, SharedPool};
+use crate::resume::SessionState;
+use crate::summary::{SessionSummary, Timeline};
+use crate::timing::TickTimings;
 use anyhow::Result;
+use arc_swap::ArcSwap;
 use fn_error_context::context;
-use std::collections::{HashMap, VecDeque};
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::mem;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
 use std::sync::Arc;
-use std::sync::Mutex;
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime};
 
 #[derive(Debug)]
 pub struct NetInput {
     pub frame: u32,
-    pub commands: Vec<Command>,
+    pub commands: Commands,
     pub hash: Vec<u8>,
 }
 
 impl NetInput {
-    fn new() -> NetInput {
+    fn new(pool: &SharedPool) -> NetInput {
         return NetInput {
             frame: 0,
-            commands: Vec::with_capacity(COMMANDS_CAP),
-            hash: Vec::with_capacity(HASH_CAP),
+            commands: Commands::new(),
+            hash: pool::acquire_bytes(pool, HASH_CAP),
         };
     }
 
@@ -28,12 +45,19 @@ impl NetInput {
         self.commands.clear();
         self.hash.clear();
     }
-}
 
-#[derive(Debug)]
-enum NetInputWrap {
-    Input(NetInput),
-    Finish,
+    // approximate, commands are counted at their in-memory size
+    fn bytes(commands: &[Command], hash: &[u8]) -> usize {
+        return commands.len() * mem::size_of::<Command>() + hash.len();
+    }
+
+    // release burst allocations before the input goes back to the pool
+    fn shrink(&mut self, pool: &SharedPool) {
+        pool::release_commands(pool, &mut self.commands);
+        if self.hash.capacity() > HASH_CAP {
+            self.hash.shrink_to(HASH_CAP);
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -43,45 +67,678 @@ pub enum NetInputState {
     Finish,
 }
 
+// byte limits on queued inputs, checked on the game thread
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InputLimits {
+    // a single send_input()
+    pub max_bytes: usize,
+    // everything the worker hasn't taken yet
+    pub pending_bytes: usize,
+}
+
+impl Default for InputLimits {
+    fn default() -> InputLimits {
+        return InputLimits {
+            max_bytes: INPUT_MAX_BYTES,
+            pending_bytes: INPUT_PENDING_BYTES,
+        };
+    }
+}
+
+// what to drop when the game stops draining output commands, the worker
+// never blocks and states are never dropped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    DropOldest,
+    // drops whole frames, oldest first, so no frame is delivered partially
+    CoalesceFrames,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutputLimits {
+    pub max_commands: usize,
+    pub overflow: OverflowPolicy,
+}
+
+impl Default for OutputLimits {
+    fn default() -> OutputLimits {
+        return OutputLimits {
+            max_commands: OUTPUT_MAX_COMMANDS,
+            overflow: OverflowPolicy::DropOldest,
+        };
+    }
+}
+
+// what the app tells the other players about itself, e.g. when the OS
+// moves it to background
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Presence {
+    Active,
+    Background,
+    Paused,
+}
+
+impl Presence {
+    pub fn state(self) -> NetPlayerState {
+        return match self {
+            Presence::Active => NetPlayerState::Running,
+            Presence::Background => NetPlayerState::Background,
+            Presence::Paused => NetPlayerState::Paused,
+        };
+    }
+
+    fn from_u8(value: u8) -> Presence {
+        return match value {
+            1 => Presence::Background,
+            2 => Presence::Paused,
+            _ => Presence::Active,
+        };
+    }
+}
+
+// sent by the game and handled at the top of the worker's next tick, ahead
+// of every input still queued
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlMsg {
+    // ends the session right away, queued inputs are never sent
+    Abort,
+    // see NetChan::cancel_pending_inputs()
+    CancelInputs(u32),
+    Presence(Presence),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetWarning {
+    // the jitter buffer had to grow its delay
+    Degraded {
+        jitter_delay: u32,
+    },
+    DroppedPackets(u64),
+    // submitted before Start or after the game stopped
+    InputDropped {
+        frame: u32,
+    },
+    // past the frame the server paused the lockstep at, not sent
+    InputPaused {
+        frame: u32,
+        paused: u32,
+    },
+    // the server reported our own conv in another state, see SelfStatePolicy
+    StateDiverged {
+        local: NetPlayerState,
+        server: NetPlayerState,
+    },
+    // the first frame over PACKET_WARN_PERCENT of `limit`, not repeated
+    LargePacket {
+        frame: u32,
+        size: usize,
+        limit: usize,
+    },
+    // commands of a conv no State introduced within
+    // RosterLimits::window, or over its bounds, were discarded
+    UnknownConv {
+        conv: u32,
+        batches: usize,
+    },
+    // the level WorkerConfig::degradation stepped to, 0 is nothing degraded
+    QualityChanged(usize),
+    // ms we kept sending while nothing came back, once per silence
+    NoInboundTraffic(u64),
+    // a packet with our conv for a frame we never sent, dropped
+    SpoofedConv {
+        frame: u32,
+    },
+}
+
+impl NetWarning {
+    // below WARNING_KINDS, for per-kind limits
+    pub fn kind(&self) -> usize {
+        return match self {
+            NetWarning::Degraded { .. } => 0,
+            NetWarning::DroppedPackets(_) => 1,
+            NetWarning::InputDropped { .. } => 2,
+            NetWarning::InputPaused { .. } => 3,
+            NetWarning::StateDiverged { .. } => 4,
+            NetWarning::LargePacket { .. } => 5,
+            NetWarning::UnknownConv { .. } => 6,
+            NetWarning::QualityChanged(_) => 7,
+            NetWarning::NoInboundTraffic(_) => 8,
+            NetWarning::SpoofedConv { .. } => 9,
+        };
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum NetEvent {
+    // `frame` is the newest delivered before the change, which applies after
+    // that frame's commands
+    State {
+        conv: u32,
+        state: NetPlayerState,
+        frame: u32,
+    },
+    Started(StartInfo),
+    Commands {
+        frame: u32,
+        commands: Vec<CommandEx>,
+    },
+    // frame assembly mode: every conv that reported `frame`, in conv order,
+    // not `complete` when a straggler was given up on
+    FrameReady {
+        frame: u32,
+        commands: Vec<(u32, Vec<Command>)>,
+        complete: bool,
+    },
+    HashMismatch {
+        frame: u32,
+        conv: u32,
+    },
+    Stats(NetStats),
+    Warning(NetWarning),
+    // commands dropped while the game wasn't draining, delivered once it
+    // drains again
+    OutputOverflow {
+        dropped: u64,
+    },
+    // the server paused the lockstep after `frame`
+    Paused {
+        frame: u32,
+    },
+    // inputs continue from `frame`
+    Resumed {
+        frame: u32,
+    },
+    // commands of `conv` for a frame it already delivered, only with
+    // LateCommandPolicy::DeliverFlagged, never part of the simulation
+    LateCommands {
+        conv: u32,
+        frame: u32,
+        commands: Vec<Command>,
+    },
+    // the server has our frames up to `frame`, see
+    // WorkerConfig::frame_acked_events
+    FrameAcked {
+        frame: u32,
+    },
+    // a message of one of WorkerConfig::application_types
+    Application {
+        kind: u8,
+        payload: Vec<u8>,
+    },
+}
+
+impl fmt::Display for NetEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return match self {
+            NetEvent::State { conv, state, frame } => {
+                write!(f, "State conv={} state={:?} frame={}", conv, state, frame)
+            }
+            NetEvent::Started(info) => write!(f, "Started conv={}", info.conv),
+            NetEvent::Commands { frame, commands } => {
+                write!(f, "Commands frame={} commands={}", frame, commands.len())
+            }
+            NetEvent::FrameReady {
+                frame,
+                commands,
+                complete,
+            } => write!(
+                f,
+                "FrameReady frame={} convs={}{}",
+                frame,
+                commands.len(),
+                if *complete { "" } else { " incomplete" }
+            ),
+            NetEvent::HashMismatch { frame, conv } => {
+                write!(f, "HashMismatch frame={} conv={}", frame, conv)
+            }
+            NetEvent::Stats(stats) => write!(f, "Stats {}", stats),
+            NetEvent::Warning(warning) => write!(f, "Warning {:?}", warning),
+            NetEvent::OutputOverflow { dropped } => {
+                write!(f, "OutputOverflow dropped={}", dropped)
+            }
+            NetEvent::Paused { frame } => write!(f, "Paused frame={}", frame),
+            NetEvent::Resumed { frame } => write!(f, "Resumed frame={}", frame),
+            NetEvent::LateCommands {
+                conv,
+                frame,
+                commands,
+            } => write!(
+                f,
+                "LateCommands conv={} frame={} commands={}",
+                conv,
+                frame,
+                commands.len()
+            ),
+            NetEvent::FrameAcked { frame } => write!(f, "FrameAcked frame={}", frame),
+            NetEvent::Application { kind, payload } => {
+                write!(f, "Application({}) payload={} bytes", kind, payload.len())
+            }
+        };
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NetStats {
+    // frames the jitter buffer currently holds remote commands back
+    pub jitter_delay: u32,
+    // where the server likely is now, None until commands arrive
+    pub server_frame: Option<FrameEstimate>,
+    // incoming commands dropped by the CommandValidator
+    pub dropped_commands: u64,
+    // commands for frames already delivered, see LateCommandPolicy::Drop
+    pub late_commands: u64,
+    // packets dropped mid-match because they couldn't be decoded
+    pub undecodable_packets: u64,
+    // warnings over WARNING_BURST of their kind per WARNING_INTERVAL
+    pub suppressed_warnings: u64,
+    // warnings over WARNINGS_CAP while the game didn't poll
+    pub dropped_warnings: u64,
+    // ticks that used up WorkerConfig::tick_budget decoding, the packets
+    // left were decoded by the next one
+    pub budget_overruns: u64,
+    // wall clock steps back or jumps over CLOCK_JUMP_MAX ahead, see WallClock
+    pub clock_anomalies: u64,
+    // states of other convs not forwarded as nothing changed, see
+    // WorkerConfig::forward_redundant_states
+    pub redundant_states: u64,
+    // command batches of convs no State introduced yet, see
+    // WorkerConfig::roster
+    pub held_hints: usize,
+    pub released_hints: u64,
+    pub discarded_hints: u64,
+    // self-checks that failed, see Invariants
+    #[cfg(feature = "paranoid")]
+    pub invariant_violations: u64,
+    // of the current connect attempt
+    pub connect: ConnectTimes,
+    // newest frame sent to the server
+    pub sent_frame: u32,
+    // newest of our frames the server echoed back or acknowledged, None
+    // while it did neither
+    pub last_acked_frame: Option<u32>,
+    // rungs of WorkerConfig::degradation engaged
+    pub quality_level: usize,
+    // zeros sent to pad command packets to WorkerConfig::padding sizes
+    pub padding_bytes: u64,
+    // the optional wire behaviors negotiated for this session
+    pub capabilities: Capabilities,
+    // of everything the game drained so far, with WorkerConfig::delivery_hash
+    pub delivery_hash: Option<u64>,
+    // the frame the server paused the lockstep at
+    pub paused: Option<u32>,
+    // inputs dropped before Start or after the game stopped
+    pub dropped_inputs: u64,
+    // received after we stopped
+    pub ignored_packets: IgnoredPackets,
+    // kcp segments not yet acked, the send fails at KCP_WINDOW_SIZE
+    pub kcp_waitsnd: u32,
+    pub bandwidth: Bandwidth,
+    // send_to calls the batched sends of the current connection saved,
+    // sendmmsg is Linux only
+    pub syscalls_saved: u64,
+    // datagrams the last tick took in, at most WorkerConfig::recv_batch
+    pub recv_drained: usize,
+    // game sockets replaced after the network changed, see
+    // WorkerConfig::rebind
+    pub rebinds: u64,
+    // first transmission to ack of kcp segments, for a netgraph
+    pub ack_latency: AckLatency,
+    // per stage, with WorkerConfig::tick_timings
+    pub tick_timings: Option<TickTimings>,
+    // largest message sent, longer than kcp_mtu minus the segment header
+    // and kcp had to split it
+    pub largest_packet: usize,
+    pub kcp_mtu: usize,
+    pub segmented: bool,
+    // newest frame received per conv
+    pub lag: LagTable,
+    // state changes and reconnects of the worker, see elapsed_in_state()
+    pub timeline: Timeline,
+    // ms in the current state as of the last tick
+    pub state_ms: u64,
+    // when the Finish goes out after game_over(), see
+    // WorkerConfig::stop_grace
+    pub stopping_deadline: Option<SystemTime>,
+}
+
+impl NetStats {
+    // the running conv furthest behind, the one making the game stutter
+    pub fn slowest_conv(&self) -> Option<u32> {
+        return self
+            .lag
+            .iter()
+            .filter(|(_, info)| !info.stopped)
+            .min_by_key(|(conv, info)| (info.last_frame, info.last_seen, *conv))
+            .map(|(conv, _)| conv);
+    }
+
+    // how long the worker has been in its current state, e.g. stuck Waiting
+    pub fn elapsed_in_state(&self) -> Duration {
+        return Duration::from_millis(self.state_ms);
+    }
+}
+
+// one line for logs where the derived Debug is dozens, counters only when
+// not 0
+impl fmt::Display for NetStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "sent_frame={} waitsnd={} jitter_delay={} rtt={}/{}ms up={}B/s down={}B/s",
+            self.sent_frame,
+            self.kcp_waitsnd,
+            self.jitter_delay,
+            self.ack_latency.p50,
+            self.ack_latency.p95,
+            self.bandwidth.sent_per_sec.bytes,
+            self.bandwidth.recv_per_sec.bytes,
+        )?;
+        if let Some(estimate) = self.server_frame {
+            write!(f, " server_frame={}", estimate.frame)?;
+        }
+        let counters = [
+            ("dropped_commands", self.dropped_commands),
+            ("late_commands", self.late_commands),
+            ("undecodable_packets", self.undecodable_packets),
+            ("suppressed_warnings", self.suppressed_warnings),
+            ("dropped_warnings", self.dropped_warnings),
+            ("budget_overruns", self.budget_overruns),
+            ("clock_anomalies", self.clock_anomalies),
+            ("dropped_inputs", self.dropped_inputs),
+            ("discarded_hints", self.discarded_hints),
+            ("rebinds", self.rebinds),
+        ];
+        for (name, value) in counters.iter().filter(|(_, value)| *value > 0) {
+            write!(f, " {}={}", name, value)?;
+        }
+        return Ok(());
+    }
+}
+
+// of NetChan::cancel_pending_inputs(), frames being numbered one after the
+// other as in lockstep
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CancelResult {
+    // never reach the wire, the game may send them again
+    pub cancelled: usize,
+    // from `from_frame` on, already handed to kcp by the worker, the next
+    // frame to send is `from_frame + already_sent`
+    pub already_sent: usize,
+}
+
+// how many more frames fit before the kcp window is exhausted, from the last
+// stats snapshot
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendBudget {
+    pub kcp_waitsnd: u32,
+    pub window_size: u32,
+    // inputs the worker hasn't taken yet
+    pub queued_inputs: usize,
+    pub est_frames_remaining: u32,
+}
+
+impl SendBudget {
+    fn new(kcp_waitsnd: u32, queued_inputs: usize) -> SendBudget {
+        let window_size = KCP_WINDOW_SIZE as u32;
+        let queued = (queued_inputs as u32).saturating_mul(KCP_FRAME_SEGMENTS);
+        let free = window_size.saturating_sub(kcp_waitsnd.saturating_add(queued));
+        return SendBudget {
+            kcp_waitsnd,
+            window_size,
+            queued_inputs,
+            est_frames_remaining: free / KCP_FRAME_SEGMENTS,
+        };
+    }
+
+    // `margin` frames are kept back for frames bigger than estimated
+    pub fn can_send_frame(&self, margin: u32) -> bool {
+        return self.est_frames_remaining > margin;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LagInfo {
+    pub last_frame: u32,
+    pub last_seen: Instant,
+    pub stopped: bool,
+}
+
+// fixed size so NetStats stays a cheap copy, convs beyond PLAYERS_CAP are
+// not tracked
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LagTable {
+    len: usize,
+    entries: [Option<(u32, LagInfo)>; PLAYERS_CAP],
+}
+
+impl LagTable {
+    pub fn len(&self) -> usize {
+        return self.len;
+    }
+
+    pub fn get(&self, conv: u32) -> Option<&LagInfo> {
+        return self
+            .entries
+            .iter()
+            .flatten()
+            .find(|(other, _)| *other == conv)
+            .map(|(_, info)| info);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (u32, &LagInfo)> {
+        return self
+            .entries
+            .iter()
+            .flatten()
+            .map(|(conv, info)| (*conv, info));
+    }
+
+    pub fn insert(&mut self, conv: u32, info: LagInfo) {
+        for entry in self.entries.iter_mut().flatten() {
+            if entry.0 == conv {
+                entry.1 = info;
+                return;
+            }
+        }
+        if self.len < PLAYERS_CAP {
+            self.entries[self.len] = Some((conv, info));
+            self.len += 1;
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct NetOutput {
-    pub commands: Vec<CommandEx>,
-    pub states: HashMap<u32, NetPlayerState>,
+    pub commands: Vec<CommandBatch>,
+    // ordered, so iterating it is the same on every client
+    pub states: BTreeMap<u32, NetPlayerState>,
+    pub events: Vec<NetEvent>,
+    pub stats: NetStats,
+    pub start: Option<StartInfo>,
+    // published when it changes, not every tick
+    pub session: Option<SessionState>,
+    // ms since the worker's start() as of this exchange, with
+    // WorkerConfig::timestamps only
+    pub session_ms: Option<u64>,
 }
 
 impl NetOutput {
-    fn new() -> NetOutput {
+    pub fn new() -> NetOutput {
         return NetOutput {
             commands: Vec::with_capacity(PLAYERS_CAP * 2),
-            states: HashMap::with_capacity(COMMANDS_CAP),
+            states: BTreeMap::new(),
+            events: Vec::with_capacity(PLAYERS_CAP),
+            stats: NetStats::default(),
+            start: None,
+            session: None,
+            session_ms: None,
         };
     }
 
-    fn clear(&mut self) {
+    pub fn clear(&mut self) {
         self.commands.clear();
         self.states.clear();
+        self.events.clear();
     }
 }
 
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ChanMetrics {
+    pub locks: u64,
+    pub output_appends: u64,
+    pub unreliable_dropped: u64,
+    pub peak_input_bytes: u64,
+    pub peak_output_commands: u64,
+    pub output_dropped: u64,
+}
+
 #[derive(Debug)]
 pub struct NetChanImpl {
     cache_stack: Vec<NetInput>,
-    input_queue: VecDeque<NetInputWrap>,
+    input_queue: VecDeque<NetInput>,
+    // set by game_over(), the worker takes no input after it
+    finish_requested: bool,
+    // newest frame the worker took from `input_queue`
+    taken_frame: u32,
+    // taken but held by the worker until Start, see EarlyInputPolicy::Buffer
+    parked: Vec<u32>,
+    // the worker still has to drop its parked frames from here on
+    cancel_from: Option<u32>,
+    input_limits: InputLimits,
+    input_bytes: usize,
+    // a single frame hash, ProtocolLimits::max_hash of the worker's config
+    max_hash: usize,
     output: NetOutput,
+    output_limits: OutputLimits,
+    // not yet reported by an OutputOverflow event
+    output_overflow: u64,
+    queued_warnings: usize,
+    dropped_warnings: u64,
     finish_cause: Option<NetFinishCause>,
+    finish_info: Option<FinishInfo>,
+    // the worker drained everything it sent before exiting
+    flushed: bool,
+    start_info: Option<StartInfo>,
+    session: Option<SessionState>,
+    metrics: ChanMetrics,
+    unreliable_out: VecDeque<Vec<u8>>,
+    unreliable_in: VecDeque<(u32, Vec<u8>)>,
+    // fed as the game drains, not as the worker queues
+    delivery: Option<DeliveryHasher>,
+    // exchanges published, and the snapshot swapped out by the last one
+    published: u64,
+    spare: Option<Arc<ChanSnapshot>>,
+    // the worker's last session_ms and when it arrived, delivered_at of
+    // CommandStamps goes on from it
+    session_clock: Option<(u64, Instant)>,
+    // of inputs and received batches, shared with the worker
+    pool: SharedPool,
+    // not bounded by input_limits, no overflow policy drops them
+    controls: VecDeque<ControlMsg>,
+}
+
+#[derive(Debug)]
+struct NetChanShared {
+    chan: Mutex<NetChanImpl>,
+    // signaled on start and finish
+    cond: Condvar,
+    // read by the worker every tick, outside the lock
+    presence: AtomicU8,
+    // a cancel_from is set, so the worker only locks when there is one
+    cancel: AtomicBool,
+    // controls is not empty, same as `cancel`
+    control: AtomicBool,
+    // what stats() reads without the lock
+    snapshot: ArcSwap<ChanSnapshot>,
+    // delivery is Some, stats() has to lock for the hash
+    delivery: AtomicBool,
+    // a worker holds the chan, see attach()
+    attached: AtomicBool,
+}
+
+// the stats as of the worker's last exchange, swapped in whole so readers
+// every frame neither lock nor see half an update
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChanSnapshot {
+    // exchanges published so far
+    pub seq: u64,
+    pub stats: NetStats,
 }
 
 #[derive(Debug, Clone)]
-pub struct NetChan(Arc<Mutex<NetChanImpl>>);
+pub struct NetChan(Arc<NetChanShared>);
 
 impl NetChan {
     pub fn new() -> NetChan {
-        return NetChan(Arc::new(Mutex::new(NetChanImpl {
+        return NetChan::with_limits(InputLimits::default(), OutputLimits::default());
+    }
+
+    pub fn with_limits(input_limits: InputLimits, output_limits: OutputLimits) -> NetChan {
+        return NetChan::create(input_limits, output_limits, None);
+    }
+
+    // buffers of inputs and of the worker's encoder and decoder are taken
+    // from `pool` instead of the global allocator
+    pub fn with_pool(
+        input_limits: InputLimits,
+        output_limits: OutputLimits,
+        pool: Arc<dyn BufferPool>,
+    ) -> NetChan {
+        return NetChan::create(input_limits, output_limits, Some(pool));
+    }
+
+    fn create(input_limits: InputLimits, output_limits: OutputLimits, pool: SharedPool) -> NetChan {
+        let chan = Mutex::new(NetChanImpl {
             cache_stack: Vec::with_capacity(3),
             input_queue: VecDeque::with_capacity(3),
+            finish_requested: false,
+            taken_frame: 0,
+            parked: Vec::new(),
+            cancel_from: None,
+            input_limits,
+            input_bytes: 0,
+            max_hash: HASH_CAP,
             output: NetOutput::new(),
+            output_limits,
+            output_overflow: 0,
+            queued_warnings: 0,
+            dropped_warnings: 0,
             finish_cause: None,
-        })));
+            finish_info: None,
+            flushed: false,
+            start_info: None,
+            session: None,
+            metrics: ChanMetrics::default(),
+            unreliable_out: VecDeque::with_capacity(UNRELIABLE_QUEUE),
+            unreliable_in: VecDeque::with_capacity(UNRELIABLE_QUEUE),
+            delivery: None,
+            published: 0,
+            spare: None,
+            session_clock: None,
+            controls: VecDeque::new(),
+            pool,
+        });
+        return NetChan(Arc::new(NetChanShared {
+            chan,
+            cond: Condvar::new(),
+            presence: AtomicU8::new(Presence::Active as u8),
+            cancel: AtomicBool::new(false),
+            control: AtomicBool::new(false),
+            snapshot: ArcSwap::from_pointee(ChanSnapshot::default()),
+            delivery: AtomicBool::new(false),
+            attached: AtomicBool::new(false),
+        }));
+    }
+
+    fn lock(&self) -> MutexGuard<NetChanImpl> {
+        let mut chan = self.0.chan.lock().unwrap();
+        chan.metrics.locks += 1;
+        return chan;
+    }
+
+    // not counted in `locks`
+    pub fn metrics(&self) -> ChanMetrics {
+        return self.0.chan.lock().unwrap().metrics;
     }
 
     pub fn send_input(
@@ -89,86 +746,1472 @@ impl NetChan {
         frame: u32,
         commands: &[Command],
         hash: &[u8],
-    ) -> Result<(), NetFinishCause> {
-        let chan = &mut self.0.lock().unwrap();
+    ) -> Result<(), InputError> {
+        let chan = &mut self.lock();
         if let Some(cause) = chan.finish_cause {
-            return Err(cause);
+            return Err(InputError::Finished(cause));
+        }
+        if chan.finish_requested {
+            return Err(InputError::Finished(NetFinishCause::GameOver));
         }
+        if frame == 0 {
+            return Err(InputError::InvalidFrame { frame });
+        }
+        if hash.len() > chan.max_hash {
+            return Err(InputError::HashTooLong {
+                len: hash.len(),
+                limit: chan.max_hash,
+            });
+        }
+        let bytes = NetInput::bytes(commands, hash);
+        if bytes > chan.input_limits.max_bytes {
+            return Err(InputError::TooLarge {
+                size: bytes,
+                limit: chan.input_limits.max_bytes,
+            });
+        }
+        if chan.input_bytes + bytes > chan.input_limits.pending_bytes {
+            return Err(InputError::OverBudget {
+                size: chan.input_bytes + bytes,
+                limit: chan.input_limits.pending_bytes,
+            });
+        }
+        chan.input_bytes += bytes;
+        chan.metrics.peak_input_bytes = chan.metrics.peak_input_bytes.max(chan.input_bytes as u64);
 
-        let mut input = chan.cache_stack.pop().unwrap_or(NetInput::new());
+        let mut input = match chan.cache_stack.pop() {
+            Some(input) => input,
+            None => NetInput::new(&chan.pool),
+        };
+        if commands.len() > input.commands.capacity() {
+            input.commands = pool::acquire_commands(&chan.pool, commands.len());
+        }
         input.frame = frame;
-        input.commands.extend_from_slice(commands);
+        input.commands.extend(commands.iter().cloned());
         input.hash.extend_from_slice(hash);
-        chan.input_queue.push_back(NetInputWrap::Input(input));
+        chan.input_queue.push_back(input);
         return Ok(());
     }
 
     pub fn recv_input(
         &self,
         frame: &mut u32,
-        commands: &mut Vec<Command>,
+        commands: &mut Commands,
         hash: &mut Vec<u8>,
     ) -> NetInputState {
-        let chan = &mut self.0.lock().unwrap();
-        let mut input = match chan.input_queue.pop_front() {
-            Some(NetInputWrap::Input(input)) => input,
-            Some(NetInputWrap::Finish) => return NetInputState::Finish,
+        let chan = &mut self.lock();
+        if chan.finish_requested {
+            return NetInputState::Finish;
+        }
+        let input = match chan.input_queue.pop_front() {
+            Some(input) => input,
             None => return NetInputState::Empty,
         };
+        chan.input_bytes -= NetInput::bytes(&input.commands, &input.hash);
+        chan.taken_frame = input.frame;
 
         *frame = input.frame;
-        commands.extend_from_slice(&input.commands);
+        commands.extend(input.commands.iter().cloned());
         hash.extend_from_slice(&input.hash);
-        input.clear();
-        if chan.cache_stack.capacity() > chan.cache_stack.len() {
-            chan.cache_stack.push(input);
-        }
+        chan.recycle(input);
         return NetInputState::NonEmpty;
     }
 
-    pub fn send_output_commands(&self, commands: &[CommandEx]) {
-        let chan = &mut self.0.lock().unwrap();
+    pub fn send_output_commands(&self, commands: &[CommandBatch]) {
+        let chan = &mut self.lock();
+        chan.metrics.output_appends += 1;
         chan.output.commands.extend_from_slice(commands);
+        chan.trim_output();
     }
 
     pub fn send_output_states(&self, conv: u32, state: NetPlayerState) {
-        let chan = &mut self.0.lock().unwrap();
+        let chan = &mut self.lock();
         chan.output.states.insert(conv, state);
     }
 
     pub fn recv_output(
         &self,
         commands: &mut Vec<CommandEx>,
-        states: &mut HashMap<u32, NetPlayerState>,
+        states: &mut BTreeMap<u32, NetPlayerState>,
     ) -> Result<(), NetFinishCause> {
-        let chan = &mut self.0.lock().unwrap();
+        let chan = &mut self.lock();
         if let Some(cause) = chan.finish_cause {
             return Err(cause);
         }
 
-        commands.extend_from_slice(&chan.output.commands);
+        chan.deliver_commands();
+        commands.extend(CommandBatch::flatten(&chan.output.commands));
         states.clone_from(&chan.output.states);
-        chan.output.clear();
+        chan.clear_commands();
+        chan.output.states.clear();
+        if let Some(event) = chan.take_overflow() {
+            chan.output.events.push(event);
+        }
         return Ok(());
     }
 
-    pub fn game_over(&self) -> Result<(), NetFinishCause> {
-        let chan = &mut self.0.lock().unwrap();
+    // without the lock unless the delivery hash is enabled
+    pub fn stats(&self) -> NetStats {
+        let mut stats = self.0.snapshot.load().stats;
+        if self.0.delivery.load(Ordering::Acquire) {
+            stats.delivery_hash = self.lock().delivery_hash();
+        }
+        return stats;
+    }
+
+    pub fn snapshot(&self) -> Arc<ChanSnapshot> {
+        return self.0.snapshot.load_full();
+    }
+
+    // after merge_output(), under the lock. The snapshot swapped out last
+    // time is reused once no reader holds it, a steady tick doesn't allocate
+    fn publish(&self, chan: &mut NetChanImpl) {
+        chan.published += 1;
+        let snapshot = ChanSnapshot {
+            seq: chan.published,
+            stats: chan.output.stats,
+        };
+        let next = match chan.spare.take() {
+            Some(mut spare) => match Arc::get_mut(&mut spare) {
+                Some(slot) => {
+                    *slot = snapshot;
+                    spare
+                }
+                None => Arc::new(snapshot),
+            },
+            None => Arc::new(snapshot),
+        };
+        chan.spare = Some(self.0.snapshot.swap(next));
+    }
+
+    pub fn send_budget(&self) -> SendBudget {
+        let chan = &mut self.lock();
+        return SendBudget::new(chan.output.stats.kcp_waitsnd, chan.input_queue.len());
+    }
+
+    // commands and events of the same ticks in one lock, states are only
+    // delivered as events here
+    pub fn drain_output(&self, commands: &mut Vec<CommandEx>, events: &mut Vec<NetEvent>) {
+        let chan = &mut self.lock();
+        chan.deliver_commands();
+        commands.extend(CommandBatch::flatten(&chan.output.commands));
+        chan.clear_commands();
+        chan.output.states.clear();
+        chan.take_events(events);
+    }
+
+    // drain_output() without flattening, one batch per received packet
+    pub fn drain_batches(&self, batches: &mut Vec<CommandBatch>, events: &mut Vec<NetEvent>) {
+        let chan = &mut self.lock();
+        chan.deliver_commands();
+        batches.append(&mut chan.output.commands);
+        chan.output.states.clear();
+        chan.take_events(events);
+    }
+
+    // still delivered after finish, a mismatch usually precedes it
+    pub fn recv_events(&self, events: &mut Vec<NetEvent>) {
+        let chan = &mut self.lock();
+        chan.deliver_events();
+        events.append(&mut chan.output.events);
+        chan.queued_warnings = 0;
+    }
+
+    // best effort, oversized payloads and the oldest queued ones are dropped
+    pub fn send_unreliable(&self, payload: &[u8]) -> Result<(), NetFinishCause> {
+        let chan = &mut self.lock();
         if let Some(cause) = chan.finish_cause {
             return Err(cause);
         }
+        if payload.len() > UNRELIABLE_MAX_PAYLOAD {
+            chan.metrics.unreliable_dropped += 1;
+            return Ok(());
+        }
 
-        chan.input_queue.push_back(NetInputWrap::Finish);
+        let chan = &mut **chan;
+        push_bounded(
+            &mut chan.unreliable_out,
+            payload.to_vec(),
+            &mut chan.metrics,
+        );
+        return Ok(());
+    }
+
+    pub fn recv_unreliable(&self, payloads: &mut Vec<(u32, Vec<u8>)>) {
+        let chan = &mut self.lock();
+        payloads.extend(chan.unreliable_in.drain(..));
+    }
+
+    pub fn send_control(&self, control: ControlMsg) -> Result<(), NetFinishCause> {
+        let chan = &mut self.lock();
+        if let Some(cause) = chan.finish_cause {
+            return Err(cause);
+        }
+        chan.controls.push_back(control);
+        self.0.control.store(true, Ordering::Release);
         return Ok(());
     }
 
+    // idempotent, also a no-op once finished, inputs still queued are
+    // discarded: no frame is sent after the request
+    pub fn game_over(&self) -> Result<(), NetFinishCause> {
+        let chan = &mut self.lock();
+        chan.finish_requested = true;
+        chan.input_queue.clear();
+        chan.input_bytes = 0;
+        return Ok(());
+    }
+
+    // retracts the frames from `from_frame` on that didn't reach the wire:
+    // those still queued right away, those the worker parked before Start
+    // at its next tick, or before it sends them once started, whichever
+    // comes first. What the worker already sent stays sent, and as frames
+    // are only ever dropped from `from_frame` on no gap opens before them.
+    pub fn cancel_pending_inputs(&self, from_frame: u32) -> CancelResult {
+        let chan = &mut self.lock();
+        let chan = &mut **chan;
+        let mut cancelled = 0;
+        let mut idx = 0;
+        while idx < chan.input_queue.len() {
+            if chan.input_queue[idx].frame < from_frame {
+                idx += 1;
+                continue;
+            }
+            let input = chan.input_queue.remove(idx).unwrap();
+            chan.input_bytes -= NetInput::bytes(&input.commands, &input.hash);
+            cancelled += 1;
+            chan.recycle(input);
+        }
+
+        let parked = chan.parked.len();
+        chan.parked.retain(|frame| *frame < from_frame);
+        if chan.parked.len() < parked {
+            cancelled += parked - chan.parked.len();
+            let from = chan
+                .cancel_from
+                .map_or(from_frame, |from| from.min(from_frame));
+            chan.cancel_from = Some(from);
+            self.0.cancel.store(true, Ordering::Release);
+        }
+
+        // what was taken and isn't parked went to kcp
+        let sent = match chan.parked.first() {
+            Some(parked) => parked - 1,
+            None => chan.taken_frame,
+        };
+        let already_sent = sent.saturating_add(1).saturating_sub(from_frame) as usize;
+        return CancelResult {
+            cancelled,
+            already_sent,
+        };
+    }
+
+    pub fn set_presence(&self, presence: Presence) {
+        self.0.presence.store(presence as u8, Ordering::Relaxed);
+    }
+
+    pub fn presence(&self) -> Presence {
+        return Presence::from_u8(self.0.presence.load(Ordering::Relaxed));
+    }
+
     pub fn finish(&self, cause: NetFinishCause) {
-        let chan = &mut self.0.lock().unwrap();
-        chan.finish_cause = Some(cause);
+        self.finish_with(FinishInfo::new(cause));
+    }
+
+    pub fn finish_with(&self, info: FinishInfo) {
+        let chan = &mut self.lock();
+        chan.finish_cause = Some(info.cause);
+        chan.finish_info = Some(info);
+        self.notify();
+    }
+
+    fn notify(&self) {
+        self.0.cond.notify_all();
+    }
+
+    pub fn start_info(&self) -> Option<StartInfo> {
+        let chan = &mut self.lock();
+        return chan.start_info.clone();
+    }
+
+    // Ok(None) on timeout, queued outputs are left for recv_output()
+    pub fn wait_for_start(&self, timeout: Duration) -> Result<Option<StartInfo>, FinishInfo> {
+        let deadline = Instant::now() + timeout;
+        let mut chan = self.lock();
+        loop {
+            if let Some(info) = &chan.finish_info {
+                let mut info = info.clone();
+                info.delivery_hash = chan.delivery_hash();
+                return Err(info);
+            }
+            if let Some(start) = &chan.start_info {
+                return Ok(Some(start.clone()));
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            chan = self.0.cond.wait_timeout(chan, deadline - now).unwrap().0;
+        }
+    }
+
+    // whether the graceful finish reached the server
+    pub fn flushed(&self) -> bool {
+        let chan = &mut self.lock();
+        return chan.flushed;
+    }
+
+    pub fn finish_info(&self) -> Option<FinishInfo> {
+        let chan = &mut self.lock();
+        let delivery_hash = chan.delivery_hash();
+        return chan.finish_info.clone().map(|mut info| {
+            info.delivery_hash = delivery_hash;
+            return info;
+        });
+    }
+
+    // the newest frame comes from the stats, updated every tick
+    pub fn session_state(&self) -> Option<SessionState> {
+        let chan = &mut self.lock();
+        let sent_frame = chan.output.stats.sent_frame;
+        return chan.session.clone().map(|mut session| {
+            session.last_frame = session.last_frame.max(sent_frame);
+            return session;
+        });
+    }
+
+    // set by the worker, a hash the server would reject fails send_input()
+    // on the game thread already
+    pub fn set_max_hash(&self, max_hash: usize) {
+        let chan = &mut self.lock();
+        chan.max_hash = max_hash;
+    }
+
+    // from now on, before the game drains anything
+    pub fn enable_delivery_hash(&self) {
+        let chan = &mut self.lock();
+        if chan.delivery.is_none() {
+            chan.delivery = Some(DeliveryHasher::new());
+            self.0.delivery.store(true, Ordering::Release);
+        }
     }
+
+    pub fn delivery_hash(&self) -> Option<u64> {
+        let chan = &mut self.lock();
+        return chan.delivery_hash();
+    }
+
+    // unclaimed, for tests driving the worker side by hand
+    pub fn worker_handle(&self) -> WorkerHandle {
+        return WorkerHandle(self.clone(), false);
+    }
+
+    // the worker side for one worker at a time, two would pop the same
+    // inputs and interleave their outputs, released when the handle drops
+    pub fn attach(&self) -> Result<WorkerHandle, ConfigError> {
+        let claimed =
+            self.0
+                .attached
+                .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire);
+        if claimed.is_err() {
+            return Err(ConfigError::ChanAlreadyAttached);
+        }
+        return Ok(WorkerHandle(self.clone(), true));
+    }
+
+    pub fn is_attached(&self) -> bool {
+        return self.0.attached.load(Ordering::Acquire);
+    }
+}
+
+// worker side of the chan, batches a whole tick into one lock acquisition,
+// the flag is set for the one from attach()
+#[derive(Debug)]
+pub struct WorkerHandle(NetChan, bool);
+
+impl Drop for WorkerHandle {
+    fn drop(&mut self) {
+        if self.1 {
+            self.0 .0.attached.store(false, Ordering::Release);
+        }
+    }
+}
+
+impl WorkerHandle {
+    // `inputs_out` holds the inputs processed during the last tick on entry,
+    // they go back to the pool, and the pending inputs on return
+    pub fn tick_exchange(
+        &self,
+        inputs_out: &mut Vec<NetInput>,
+        outputs_in: &mut NetOutput,
+    ) -> NetInputState {
+        let chan = &mut self.0.lock();
+        for input in inputs_out.drain(..) {
+            chan.recycle(input);
+        }
+        if Self::merge_output(chan, outputs_in) {
+            self.0.notify();
+        }
+        self.0.publish(chan);
+
+        // requested, or every other handle is gone and nobody will read the
+        // output or call game_over(): leave gracefully
+        if chan.finish_requested || Arc::strong_count(&self.0 .0) == 1 {
+            return NetInputState::Finish;
+        }
+        let mut state = NetInputState::Empty;
+        while let Some(input) = chan.input_queue.pop_front() {
+            chan.input_bytes -= NetInput::bytes(&input.commands, &input.hash);
+            chan.taken_frame = input.frame;
+            inputs_out.push(input);
+            state = NetInputState::NonEmpty;
+        }
+        return state;
+    }
+
+    pub fn send_output(&self, outputs_in: &mut NetOutput) {
+        let chan = &mut self.0.lock();
+        if Self::merge_output(chan, outputs_in) {
+            self.0.notify();
+        }
+        self.0.publish(chan);
+    }
+
+    // the input byte budget matches the queued inputs, not counted in
+    // `locks`
+    #[cfg(feature = "paranoid")]
+    pub fn pool_balanced(&self) -> bool {
+        let chan = self.0 .0.chan.lock().unwrap();
+        let queued: usize = chan
+            .input_queue
+            .iter()
+            .map(|input| NetInput::bytes(&input.commands, &input.hash))
+            .sum();
+        return queued == chan.input_bytes;
+    }
+
+    pub fn finish(&self, info: FinishInfo) {
+        self.0.finish_with(info);
+    }
+
+    pub fn presence(&self) -> Presence {
+        return self.0.presence();
+    }
+
+    pub fn set_flushed(&self) {
+        let chan = &mut self.0.lock();
+        chan.flushed = true;
+    }
+
+    // of the finish published, none before it
+    pub fn set_summary(&self, summary: SessionSummary) {
+        let chan = &mut self.0.lock();
+        if let Some(info) = &mut chan.finish_info {
+            info.summary = Some(summary);
+        }
+    }
+
+    // only before Start, a lock per buffered input
+    pub fn park_input(&self, frame: u32) {
+        let chan = &mut self.0.lock();
+        chan.parked.push(frame);
+    }
+
+    // parked frames from here on are to be dropped, checked every tick
+    // outside the lock
+    pub fn take_cancel(&self) -> Option<u32> {
+        if !self.0 .0.cancel.load(Ordering::Acquire) {
+            return None;
+        }
+        let chan = &mut self.0.lock();
+        self.0 .0.cancel.store(false, Ordering::Release);
+        return chan.cancel_from.take();
+    }
+
+    pub fn pool(&self) -> SharedPool {
+        return self.0.lock().pool.clone();
+    }
+
+    // in the order sent, checked every tick outside the lock
+    pub fn drain_controls(&self, controls: &mut Vec<ControlMsg>) {
+        if !self.0 .0.control.load(Ordering::Acquire) {
+            return;
+        }
+        let chan = &mut self.0.lock();
+        self.0 .0.control.store(false, Ordering::Release);
+        controls.extend(chan.controls.drain(..));
+    }
+
+    pub fn cancel_pending_inputs(&self, from_frame: u32) {
+        self.0.cancel_pending_inputs(from_frame);
+    }
+
+    pub fn set_presence(&self, presence: Presence) {
+        self.0.set_presence(presence);
+    }
+
+    // under the same lock as cancel_pending_inputs(), so a cancel either
+    // comes first and is returned or finds nothing parked
+    pub fn release_parked(&self) -> Option<u32> {
+        let chan = &mut self.0.lock();
+        self.0 .0.cancel.store(false, Ordering::Release);
+        chan.parked.clear();
+        return chan.cancel_from.take();
+    }
+
+    // keeps the first finish, for paths that may run after it
+    pub fn finish_if_running(&self, info: FinishInfo) {
+        if self.0.finish_info().is_none() {
+            self.0.finish_with(info);
+        }
+    }
+
+    // separate from tick_exchange(), only taken when the side channel is used
+    pub fn exchange_unreliable(
+        &self,
+        outgoing: &mut Vec<Vec<u8>>,
+        incoming: &mut Vec<(u32, Vec<u8>)>,
+    ) {
+        let chan = &mut self.0.lock();
+        let chan = &mut **chan;
+        outgoing.extend(chan.unreliable_out.drain(..));
+        for payload in incoming.drain(..) {
+            push_bounded(&mut chan.unreliable_in, payload, &mut chan.metrics);
+        }
+    }
+
+    // true when the output started the game
+    fn merge_output(chan: &mut NetChanImpl, outputs_in: &mut NetOutput) -> bool {
+        if !outputs_in.commands.is_empty() {
+            chan.metrics.output_appends += 1;
+            if chan.output.commands.is_empty() {
+                mem::swap(&mut chan.output.commands, &mut outputs_in.commands);
+            } else {
+                chan.output.commands.append(&mut outputs_in.commands);
+            }
+        }
+        chan.trim_output();
+        chan.output.states.append(&mut outputs_in.states);
+        chan.queue_events(&mut outputs_in.events);
+        chan.output.stats = outputs_in.stats;
+        chan.output.stats.dropped_warnings = chan.dropped_warnings;
+        if let Some(session) = outputs_in.session.take() {
+            chan.session = Some(session);
+        }
+        if let Some(ms) = outputs_in.session_ms.take() {
+            chan.session_clock = Some((ms, Instant::now()));
+        }
+        if let Some(start) = outputs_in.start.take() {
+            chan.start_info = Some(start);
+            return true;
+        }
+        return false;
+    }
+}
+
+impl NetChanImpl {
+    // applies output_limits after commands were appended, counted in
+    // commands not batches
+    fn trim_output(&mut self) {
+        let batches = &mut self.output.commands;
+        let max = self.output_limits.max_commands;
+        let len: usize = batches.iter().map(CommandBatch::len).sum();
+        let mut left = len;
+        if len > max {
+            match self.output_limits.overflow {
+                OverflowPolicy::DropOldest => {
+                    let mut whole = 0;
+                    // all of them for a max of 0
+                    while whole < batches.len() && left - batches[whole].len() >= max {
+                        left -= batches[whole].len();
+                        whole += 1;
+                    }
+                    batches.drain(..whole);
+                    if left > max {
+                        batches[0].commands.drain(..(left - max));
+                        left = max;
+                    }
+                }
+                OverflowPolicy::CoalesceFrames => {
+                    while left > max {
+                        let oldest = batches.iter().map(|batch| batch.frame).min().unwrap();
+                        batches.retain(|batch| {
+                            if batch.frame == oldest {
+                                left -= batch.len();
+                                return false;
+                            }
+                            return true;
+                        });
+                    }
+                }
+            };
+            let dropped = (len - left) as u64;
+            self.metrics.output_dropped += dropped;
+            self.output_overflow += dropped;
+        }
+        let peak = &mut self.metrics.peak_output_commands;
+        *peak = (*peak).max(left as u64);
+    }
+
+    // warnings beyond WARNINGS_CAP are dropped, other events never
+    fn queue_events(&mut self, events: &mut Vec<NetEvent>) {
+        let queued = &mut self.queued_warnings;
+        let dropped = &mut self.dropped_warnings;
+        events.retain(|event| {
+            if !matches!(event, NetEvent::Warning(_)) {
+                return true;
+            }
+            if *queued >= WARNINGS_CAP {
+                *dropped += 1;
+                return false;
+            }
+            *queued += 1;
+            return true;
+        });
+        self.output.events.append(events);
+    }
+
+    fn take_events(&mut self, events: &mut Vec<NetEvent>) {
+        self.deliver_events();
+        events.append(&mut self.output.events);
+        events.extend(self.take_overflow());
+        self.queued_warnings = 0;
+    }
+
+    fn deliver_commands(&mut self) {
+        if let Some(delivery) = &mut self.delivery {
+            delivery.update_batches(&self.output.commands);
+        }
+        if let Some((ms, at)) = self.session_clock {
+            let now = ms + at.elapsed().as_millis() as u64;
+            let stamps = self
+                .output
+                .commands
+                .iter_mut()
+                .filter_map(|b| b.stamps.as_mut());
+            stamps.for_each(|stamps| stamps.delivered_at = now);
+        }
+    }
+
+    // flattened for the game, spilled batches go back to the pool
+    fn clear_commands(&mut self) {
+        if self.pool.is_some() {
+            for batch in self.output.commands.iter_mut() {
+                pool::release_commands(&self.pool, &mut batch.commands);
+            }
+        }
+        self.output.commands.clear();
+    }
+
+    // back to cache_stack, its buffers to the pool when that is full
+    fn recycle(&mut self, mut input: NetInput) {
+        input.clear();
+        input.shrink(&self.pool);
+        if self.cache_stack.capacity() > self.cache_stack.len() {
+            self.cache_stack.push(input);
+            return;
+        }
+        pool::release_bytes(&self.pool, &mut input.hash);
+    }
+
+    fn deliver_events(&mut self) {
+        if let Some(delivery) = &mut self.delivery {
+            delivery.update_events(&self.output.events);
+        }
+    }
+
+    fn delivery_hash(&self) -> Option<u64> {
+        return self.delivery.as_ref().map(DeliveryHasher::digest);
+    }
+
+    fn take_overflow(&mut self) -> Option<NetEvent> {
+        if self.output_overflow == 0 {
+            return None;
+        }
+        let dropped = mem::replace(&mut self.output_overflow, 0);
+        return Some(NetEvent::OutputOverflow { dropped });
+    }
+}
+
+fn push_bounded<T>(queue: &mut VecDeque<T>, item: T, metrics: &mut ChanMetrics) {
+    if queue.len() >= UNRELIABLE_QUEUE {
+        queue.pop_front();
+        metrics.unreliable_dropped += 1;
+    }
+    queue.push_back(item);
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::base::KCP_MAX_PACKET;
+    use crate::codec::{CommandDecoder, CommandEncoder};
+    use crate::testing::allocations;
+
+    #[test]
+    fn test_net_chan_pool_shrink() {
+        let chan = NetChan::new();
+        let burst = vec![Command::Aaa(1, 2); 64];
+        chan.send_input(1, &burst, &vec![7; HASH_CAP * 4]).unwrap();
+
+        let mut frame = 0;
+        let mut commands = Commands::new();
+        let mut hash = Vec::new();
+        assert_eq!(
+            chan.recv_input(&mut frame, &mut commands, &mut hash),
+            NetInputState::NonEmpty
+        );
+        assert_eq!(commands.len(), 64);
+        assert_eq!(hash.len(), HASH_CAP * 4);
+
+        let chan_impl = chan.0.chan.lock().unwrap();
+        let input = &chan_impl.cache_stack[0];
+        assert!(!input.commands.spilled());
+        assert!(input.hash.capacity() <= HASH_CAP);
+    }
+
+    #[test]
+    fn test_net_chan_input_max_bytes() {
+        let chan = NetChan::with_limits(
+            InputLimits {
+                max_bytes: 100,
+                pending_bytes: 1000,
+            },
+            OutputLimits::default(),
+        );
+        let err = chan.send_input(1, &[], &[0; 101]).unwrap_err();
+        assert_eq!(
+            err,
+            InputError::TooLarge {
+                size: 101,
+                limit: 100
+            }
+        );
+        let commands = vec![Command::Aaa(1, 2); 100 / mem::size_of::<Command>() + 1];
+        assert!(matches!(
+            chan.send_input(1, &commands, &[]),
+            Err(InputError::TooLarge { .. })
+        ));
+        chan.send_input(1, &[], &[0; 100]).unwrap();
+        assert_eq!(chan.metrics().peak_input_bytes, 100);
+    }
+
+    #[test]
+    fn test_net_chan_input_max_hash() {
+        let chan = NetChan::new();
+        chan.send_input(1, &[], &[0; HASH_CAP]).unwrap();
+        let err = chan.send_input(2, &[], &[0; HASH_CAP + 1]).unwrap_err();
+        assert_eq!(
+            err,
+            InputError::HashTooLong {
+                len: HASH_CAP + 1,
+                limit: HASH_CAP
+            }
+        );
+        assert!(!err.is_retryable().allows(0));
+
+        let chan = NetChan::new();
+        chan.set_max_hash(8);
+        chan.send_input(1, &[], &[0; 8]).unwrap();
+        assert!(matches!(
+            chan.send_input(2, &[], &[0; 9]),
+            Err(InputError::HashTooLong { len: 9, limit: 8 })
+        ));
+        assert_eq!(chan.metrics().peak_input_bytes, 8);
+    }
+
+    #[test]
+    fn test_net_chan_input_frame_zero() {
+        let chan = NetChan::new();
+        let err = chan.send_input(0, &[], &[]).unwrap_err();
+        assert_eq!(err, InputError::InvalidFrame { frame: 0 });
+        assert!(!err.is_retryable().allows(0));
+        assert_eq!(chan.metrics().peak_input_bytes, 0);
+        chan.send_input(1, &[], &[]).unwrap();
+    }
+
+    #[test]
+    fn test_net_chan_input_pending_bytes() {
+        let chan = NetChan::with_limits(
+            InputLimits {
+                max_bytes: 100,
+                pending_bytes: 250,
+                ..InputLimits::default()
+            },
+            OutputLimits::default(),
+        );
+        chan.send_input(1, &[], &[0; 100]).unwrap();
+        chan.send_input(2, &[], &[0; 100]).unwrap();
+        let err = chan.send_input(3, &[], &[0; 100]).unwrap_err();
+        assert_eq!(
+            err,
+            InputError::OverBudget {
+                size: 300,
+                limit: 250
+            }
+        );
+        chan.send_input(3, &[], &[0; 50]).unwrap();
+
+        // the worker taking inputs frees the budget
+        let mut inputs = Vec::new();
+        let mut output = NetOutput::new();
+        chan.worker_handle().tick_exchange(&mut inputs, &mut output);
+        assert_eq!(inputs.len(), 3);
+        chan.send_input(4, &[], &[0; 100]).unwrap();
+        chan.send_input(5, &[], &[0; 100]).unwrap();
+        assert_eq!(chan.metrics().peak_input_bytes, 250);
+
+        chan.finish(NetFinishCause::GameOver);
+        assert_eq!(
+            chan.send_input(6, &[], &[]),
+            Err(InputError::Finished(NetFinishCause::GameOver))
+        );
+    }
+
+    // 20 ticks of output, drained every `every` ticks
+    fn delivered(every: u32, altered: bool) -> NetChan {
+        let chan = NetChan::new();
+        chan.enable_delivery_hash();
+        let handle = chan.worker_handle();
+        let mut output = NetOutput::new();
+        let mut inputs = Vec::new();
+        let mut commands = Vec::new();
+        let mut batches = Vec::new();
+        let mut events = Vec::new();
+        for frame in 1..=20 {
+            for conv in [6666, 8888] {
+                let mut batch = CommandBatch::new(conv, frame);
+                let value = match altered && frame == 7 && conv == 8888 {
+                    true => -1,
+                    false => frame as i32,
+                };
+                batch.commands.push(Command::Aaa(value, conv as i32));
+                output.commands.push(batch);
+            }
+            if frame == 10 {
+                output.events.push(NetEvent::State {
+                    conv: 8888,
+                    state: NetPlayerState::Background,
+                    frame,
+                });
+            }
+            output.events.push(NetEvent::Stats(NetStats::default()));
+            handle.tick_exchange(&mut inputs, &mut output);
+            if frame % every != 0 {
+                continue;
+            }
+            match frame % 3 {
+                0 => chan.drain_output(&mut commands, &mut events),
+                1 => chan.drain_batches(&mut batches, &mut events),
+                _ => chan.recv_events(&mut events),
+            };
+        }
+        chan.drain_output(&mut commands, &mut events);
+        return chan;
+    }
+
+    #[test]
+    fn test_net_chan_delivery_hash() {
+        assert_eq!(NetChan::new().delivery_hash(), None);
+        let expected = delivered(1, false).delivery_hash().unwrap();
+        for every in [2, 3, 7, 20] {
+            assert_eq!(delivered(every, false).delivery_hash(), Some(expected));
+        }
+        assert_ne!(delivered(1, true).delivery_hash(), Some(expected));
+
+        // queued but not drained yet isn't delivered
+        let chan = delivered(1, false);
+        let mut output = NetOutput::new();
+        output.commands.push(CommandBatch::new(6666, 21));
+        output.commands[0].commands.push(Command::Aaa(21, 0));
+        chan.worker_handle().send_output(&mut output);
+        assert_eq!(chan.stats().delivery_hash, Some(expected));
+        chan.finish(NetFinishCause::GameOver);
+        let mut commands = Vec::new();
+        let mut events = Vec::new();
+        chan.drain_output(&mut commands, &mut events);
+        let info = chan.finish_info().unwrap();
+        assert!(info.delivery_hash.is_some());
+        assert_ne!(info.delivery_hash, Some(expected));
+        assert_eq!(info.delivery_hash, chan.stats().delivery_hash);
+    }
+
+    fn overflow(overflow: OverflowPolicy) -> (NetChan, Vec<NetEvent>) {
+        let chan = NetChan::with_limits(
+            InputLimits::default(),
+            OutputLimits {
+                max_commands: 1000,
+                overflow,
+            },
+        );
+        let handle = chan.worker_handle();
+        let mut output = NetOutput::new();
+        let mut inputs = Vec::new();
+
+        // 10k commands from 2 players, the game doesn't drain
+        for frame in 0..5000 {
+            for conv in [1, 2] {
+                let mut batch = CommandBatch::new(conv, frame);
+                batch.commands.push(Command::Aaa(frame as i32, 0));
+                output.commands.push(batch);
+            }
+            output.states.insert(1, NetPlayerState::Running);
+            handle.tick_exchange(&mut inputs, &mut output);
+            assert!(chan.lock().output.commands.capacity() <= 2048);
+        }
+
+        let mut commands = Vec::new();
+        let mut events = Vec::new();
+        chan.drain_output(&mut commands, &mut events);
+        assert_eq!(commands.len(), 1000);
+        assert_eq!(commands[0].frame, 4500);
+        assert_eq!(commands[999].frame, 4999);
+        let metrics = chan.metrics();
+        assert_eq!(metrics.peak_output_commands, 1000);
+        assert_eq!(metrics.output_dropped, 9000);
+
+        // reported once
+        let mut again = Vec::new();
+        chan.drain_output(&mut commands, &mut again);
+        assert!(again.is_empty());
+        return (chan, events);
+    }
+
+    #[test]
+    fn test_net_chan_output_drop_oldest() {
+        let (chan, events) = overflow(OverflowPolicy::DropOldest);
+        assert_eq!(events, vec![NetEvent::OutputOverflow { dropped: 9000 }]);
+
+        // a batch is cut at the front when only part of it is over the limit
+        let handle = chan.worker_handle();
+        let mut output = NetOutput::new();
+        let mut batch = CommandBatch::new(1, 9);
+        batch.commands.extend((0..1001).map(|x| Command::Aaa(x, 0)));
+        output.commands.push(batch);
+        handle.send_output(&mut output);
+        let mut batches = Vec::new();
+        let mut events = Vec::new();
+        chan.drain_batches(&mut batches, &mut events);
+        assert_eq!(batches.len(), 1);
+        assert_eq!(batches[0].len(), 1000);
+        assert_eq!(batches[0].commands[0], Command::Aaa(1, 0));
+        assert_eq!(events, vec![NetEvent::OutputOverflow { dropped: 1 }]);
+    }
+
+    #[test]
+    fn test_net_chan_output_limit_zero() {
+        let chan = NetChan::with_limits(
+            InputLimits::default(),
+            OutputLimits {
+                max_commands: 0,
+                overflow: OverflowPolicy::DropOldest,
+            },
+        );
+        let handle = chan.worker_handle();
+        let mut output = NetOutput::new();
+        for conv in [1, 2] {
+            let mut batch = CommandBatch::new(conv, 1);
+            batch.commands.push(Command::Aaa(1, 0));
+            output.commands.push(batch);
+        }
+        handle.send_output(&mut output);
+        let mut batches = Vec::new();
+        let mut events = Vec::new();
+        chan.drain_batches(&mut batches, &mut events);
+        assert!(batches.is_empty());
+        assert_eq!(events, vec![NetEvent::OutputOverflow { dropped: 2 }]);
+    }
+
+    #[test]
+    fn test_net_chan_output_coalesce_frames() {
+        let (chan, events) = overflow(OverflowPolicy::CoalesceFrames);
+        assert_eq!(events, vec![NetEvent::OutputOverflow { dropped: 9000 }]);
+
+        // a frame bigger than the limit on its own is dropped whole
+        let handle = chan.worker_handle();
+        let mut output = NetOutput::new();
+        let mut batch = CommandBatch::new(0, 9);
+        batch.commands.extend((0..1001).map(|_| Command::Aaa(0, 0)));
+        output.commands.push(batch);
+        handle.send_output(&mut output);
+        let mut commands = Vec::new();
+        let mut states = BTreeMap::new();
+        chan.recv_output(&mut commands, &mut states).unwrap();
+        assert!(commands.is_empty());
+        let mut events = Vec::new();
+        chan.recv_events(&mut events);
+        assert_eq!(events, vec![NetEvent::OutputOverflow { dropped: 1001 }]);
+    }
+
+    #[test]
+    fn test_net_chan_warnings_cap() {
+        let chan = NetChan::new();
+        let handle = chan.worker_handle();
+        let mut output = NetOutput::new();
+        for frame in 0..100 {
+            let warning = NetWarning::InputDropped { frame };
+            output.events.push(NetEvent::Warning(warning));
+            if frame == 80 {
+                output.events.push(NetEvent::Paused { frame });
+            }
+        }
+        handle.send_output(&mut output);
+        assert_eq!(chan.stats().dropped_warnings, 36);
+
+        // other events are never dropped
+        let mut events = Vec::new();
+        chan.recv_events(&mut events);
+        assert_eq!(events.len(), WARNINGS_CAP + 1);
+        assert_eq!(events[WARNINGS_CAP], NetEvent::Paused { frame: 80 });
+
+        // room again once polled
+        let warning = NetWarning::InputDropped { frame: 100 };
+        output.events.push(NetEvent::Warning(warning));
+        handle.send_output(&mut output);
+        events.clear();
+        chan.recv_events(&mut events);
+        assert_eq!(events, vec![NetEvent::Warning(warning)]);
+        assert_eq!(chan.stats().dropped_warnings, 36);
+    }
+
+    #[test]
+    fn test_net_chan_game_over() {
+        let chan = NetChan::new();
+        let handle = chan.worker_handle();
+        chan.send_input(1, &[Command::Aaa(1, 2)], &[1]).unwrap();
+        chan.send_input(2, &[Command::Aaa(3, 4)], &[2]).unwrap();
+        assert_eq!(chan.game_over(), Ok(()));
+        assert_eq!(chan.game_over(), Ok(()));
+        assert_eq!(
+            chan.send_input(3, &[], &[]),
+            Err(InputError::Finished(NetFinishCause::GameOver))
+        );
+
+        // queued inputs are never handed to the worker
+        let mut inputs = Vec::new();
+        let mut output = NetOutput::new();
+        assert_eq!(
+            handle.tick_exchange(&mut inputs, &mut output),
+            NetInputState::Finish
+        );
+        assert!(inputs.is_empty());
+        let (mut frame, mut commands, mut hash) = (0, Commands::new(), Vec::new());
+        assert_eq!(
+            chan.recv_input(&mut frame, &mut commands, &mut hash),
+            NetInputState::Finish
+        );
+
+        handle.finish(FinishInfo::new(NetFinishCause::GameOver));
+        assert_eq!(chan.game_over(), Ok(()));
+        assert_eq!(
+            chan.finish_info().map(|info| info.cause),
+            Some(NetFinishCause::GameOver)
+        );
+    }
+
+    #[test]
+    fn test_net_chan_game_over_race() {
+        for _ in 0..50 {
+            let chan = NetChan::new();
+            let handle = chan.worker_handle();
+            let sender = chan.clone();
+            let sending = std::thread::spawn(move || {
+                let mut frame = 0;
+                while sender.send_input(frame + 1, &[], &[]).is_ok() {
+                    frame += 1;
+                    if frame % 64 == 0 {
+                        std::thread::yield_now();
+                    }
+                }
+                return frame;
+            });
+            let stopper = chan.clone();
+            let stopping = std::thread::spawn(move || {
+                std::thread::yield_now();
+                stopper.game_over().unwrap();
+                stopper.game_over().unwrap();
+            });
+
+            // every input taken was sent before the request, nothing after
+            let mut taken = 0;
+            let mut inputs = Vec::new();
+            let mut output = NetOutput::new();
+            loop {
+                let state = handle.tick_exchange(&mut inputs, &mut output);
+                for input in inputs.iter() {
+                    taken += 1;
+                    assert_eq!(input.frame, taken);
+                }
+                if state == NetInputState::Finish {
+                    break;
+                }
+            }
+            stopping.join().unwrap();
+            let sent = sending.join().unwrap();
+            assert!(taken <= sent);
+            assert_eq!(
+                handle.tick_exchange(&mut inputs, &mut output),
+                NetInputState::Finish
+            );
+            assert!(inputs.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_net_chan_controls() {
+        let limits = InputLimits {
+            pending_bytes: 8,
+            ..InputLimits::default()
+        };
+        let chan = NetChan::with_limits(limits, OutputLimits::default());
+        let handle = chan.worker_handle();
+        let mut controls = Vec::new();
+        handle.drain_controls(&mut controls);
+        assert!(controls.is_empty());
+        assert_eq!(chan.metrics().locks, 0);
+
+        chan.send_input(1, &[], &[0; 8]).unwrap();
+        assert!(chan.send_input(2, &[], &[0; 8]).is_err());
+        chan.send_control(ControlMsg::Presence(Presence::Paused))
+            .unwrap();
+        chan.send_control(ControlMsg::Abort).unwrap();
+        handle.drain_controls(&mut controls);
+        assert_eq!(
+            controls,
+            vec![ControlMsg::Presence(Presence::Paused), ControlMsg::Abort]
+        );
+        controls.clear();
+        handle.drain_controls(&mut controls);
+        assert!(controls.is_empty());
+
+        chan.game_over().unwrap();
+        chan.send_control(ControlMsg::Abort).unwrap();
+        chan.finish(NetFinishCause::GameOver);
+        assert_eq!(
+            chan.send_control(ControlMsg::Abort),
+            Err(NetFinishCause::GameOver)
+        );
+    }
+
+    #[test]
+    fn test_net_chan_cancel_pending_inputs() {
+        let chan = NetChan::new();
+        let handle = chan.worker_handle();
+        for frame in 1..=5 {
+            chan.send_input(frame, &[Command::Aaa(1, 2)], &[1]).unwrap();
+        }
+        assert_eq!(
+            chan.cancel_pending_inputs(4),
+            CancelResult {
+                cancelled: 2,
+                already_sent: 0,
+            }
+        );
+        let queued = 3 * NetInput::bytes(&[Command::Aaa(1, 2)], &[1]);
+        assert_eq!(chan.lock().input_bytes, queued);
+        assert_eq!(handle.take_cancel(), None);
+
+        let mut inputs = Vec::new();
+        let mut output = NetOutput::new();
+        handle.tick_exchange(&mut inputs, &mut output);
+        assert_eq!(inputs.len(), 3);
+        assert_eq!(
+            chan.cancel_pending_inputs(2),
+            CancelResult {
+                cancelled: 0,
+                already_sent: 2,
+            }
+        );
+        assert_eq!(chan.cancel_pending_inputs(9), CancelResult::default());
+    }
+
+    #[test]
+    fn test_net_chan_cancel_race() {
+        for _ in 0..50 {
+            let chan = NetChan::new();
+            let handle = chan.worker_handle();
+            let game = chan.clone();
+            let playing = std::thread::spawn(move || {
+                let mut next = 1;
+                for round in 1..=64 {
+                    for _ in 0..8 {
+                        game.send_input(next, &[], &[]).unwrap();
+                        next += 1;
+                    }
+                    // roll back a few frames, resend from the first unsent
+                    let from = next - 1 - round % 4;
+                    let cancel = game.cancel_pending_inputs(from);
+                    assert_eq!(
+                        cancel.cancelled + cancel.already_sent,
+                        (next - from) as usize
+                    );
+                    next = from + cancel.already_sent as u32;
+                    std::thread::yield_now();
+                }
+                game.game_over().unwrap();
+                return next - 1;
+            });
+
+            // frames reach the worker one after the other, none skipped
+            let mut taken = 0;
+            let mut inputs = Vec::new();
+            let mut output = NetOutput::new();
+            loop {
+                let state = handle.tick_exchange(&mut inputs, &mut output);
+                for input in inputs.iter() {
+                    taken += 1;
+                    assert_eq!(input.frame, taken);
+                }
+                if state == NetInputState::Finish {
+                    break;
+                }
+            }
+            assert!(taken <= playing.join().unwrap());
+        }
+    }
+
+    #[test]
+    fn test_net_chan_orphaned_worker() {
+        let chan = NetChan::new();
+        let handle = chan.worker_handle();
+        let mut inputs = Vec::new();
+        let mut output = NetOutput::new();
+        assert_eq!(
+            handle.tick_exchange(&mut inputs, &mut output),
+            NetInputState::Empty
+        );
+        drop(chan);
+        assert_eq!(
+            handle.tick_exchange(&mut inputs, &mut output),
+            NetInputState::Finish
+        );
+    }
+
+    #[test]
+    fn test_net_chan_unreliable_drop_oldest() {
+        let chan = NetChan::new();
+        let handle = chan.worker_handle();
+        for idx in 0..(UNRELIABLE_QUEUE + 10) {
+            chan.send_unreliable(&[idx as u8]).unwrap();
+        }
+        chan.send_unreliable(&vec![0; UNRELIABLE_MAX_PAYLOAD + 1])
+            .unwrap();
+        assert_eq!(chan.metrics().unreliable_dropped, 11);
+
+        let mut outgoing = Vec::new();
+        let mut incoming: Vec<_> = (0..(UNRELIABLE_QUEUE + 5))
+            .map(|idx| (7777, vec![idx as u8]))
+            .collect();
+        handle.exchange_unreliable(&mut outgoing, &mut incoming);
+        assert_eq!(outgoing.len(), UNRELIABLE_QUEUE);
+        assert_eq!(outgoing[0], vec![10]);
+        assert!(incoming.is_empty());
+        assert_eq!(chan.metrics().unreliable_dropped, 16);
+
+        let mut payloads = Vec::new();
+        chan.recv_unreliable(&mut payloads);
+        assert_eq!(payloads.len(), UNRELIABLE_QUEUE);
+        assert_eq!(payloads[0], (7777, vec![5]));
+        assert_eq!(payloads.last().unwrap().1, vec![UNRELIABLE_QUEUE as u8 + 4]);
+
+        chan.finish(NetFinishCause::GameOver);
+        assert_eq!(chan.send_unreliable(&[1]), Err(NetFinishCause::GameOver));
+    }
+
+    #[test]
+    fn test_net_chan_wait_for_start() {
+        let start = StartInfo {
+            conv: 6666,
+            started_at: std::time::SystemTime::now(),
+            first_frame: 1,
+        };
+        let started = |chan: &NetChan| {
+            let mut output = NetOutput::new();
+            let mut batch = CommandBatch::new(7777, 1);
+            batch.commands.push(Command::Aaa(1, 2));
+            output.commands.push(batch);
+            output.states.insert(6666, NetPlayerState::Running);
+            output.start = Some(start.clone());
+            chan.worker_handle().send_output(&mut output);
+        };
+
+        // started before the call, the outputs stay queued
+        let chan = NetChan::new();
+        started(&chan);
+        assert_eq!(chan.wait_for_start(Duration::ZERO), Ok(Some(start.clone())));
+        let mut commands = Vec::new();
+        let mut states = BTreeMap::new();
+        chan.recv_output(&mut commands, &mut states).unwrap();
+        assert_eq!(commands.len(), 1);
+        assert_eq!(states[&6666], NetPlayerState::Running);
+
+        // started while waiting
+        let chan = NetChan::new();
+        let waiter = chan.clone();
+        let thread = std::thread::spawn(move || waiter.wait_for_start(Duration::from_secs(5)));
+        std::thread::sleep(Duration::from_millis(20));
+        started(&chan);
+        assert_eq!(thread.join().unwrap(), Ok(Some(start.clone())));
+
+        // finished while waiting
+        let chan = NetChan::new();
+        let waiter = chan.clone();
+        let thread = std::thread::spawn(move || waiter.wait_for_start(Duration::from_secs(5)));
+        std::thread::sleep(Duration::from_millis(20));
+        chan.finish(NetFinishCause::AuthFailed);
+        let info = thread.join().unwrap().unwrap_err();
+        assert_eq!(info.cause, NetFinishCause::AuthFailed);
+
+        // timed out
+        let chan = NetChan::new();
+        let before = Instant::now();
+        assert_eq!(chan.wait_for_start(Duration::from_millis(30)), Ok(None));
+        assert!(before.elapsed() >= Duration::from_millis(30));
+    }
+
+    #[test]
+    fn test_net_event_display() {
+        let command = CommandEx {
+            conv: 7777,
+            frame: 3,
+            command: Command::Aaa(1, 2),
+            stamps: None,
+        };
+        let events = [
+            NetEvent::State {
+                conv: 7777,
+                state: NetPlayerState::Running,
+                frame: 2,
+            },
+            NetEvent::Commands {
+                frame: 3,
+                commands: vec![command; 100],
+            },
+            NetEvent::FrameReady {
+                frame: 3,
+                commands: vec![(7777, Vec::new()), (8888, vec![Command::Aaa(1, 2)])],
+                complete: false,
+            },
+            NetEvent::Warning(NetWarning::DroppedPackets(3)),
+            NetEvent::Application {
+                kind: 200,
+                payload: vec![0; 1000],
+            },
+        ];
+        let lines: Vec<_> = events.iter().map(|event| event.to_string()).collect();
+        assert_eq!(
+            lines,
+            [
+                "State conv=7777 state=Running frame=2",
+                "Commands frame=3 commands=100",
+                "FrameReady frame=3 convs=2 incomplete",
+                "Warning DroppedPackets(3)",
+                "Application(200) payload=1000 bytes",
+            ]
+        );
+
+        let mut stats = NetStats::default();
+        assert_eq!(
+            NetEvent::Stats(stats).to_string(),
+            "Stats sent_frame=0 waitsnd=0 jitter_delay=0 rtt=0/0ms up=0B/s down=0B/s"
+        );
+        stats.sent_frame = 300;
+        stats.kcp_waitsnd = 4;
+        stats.ack_latency.p50 = 30;
+        stats.ack_latency.p95 = 80;
+        stats.bandwidth.sent_per_sec.bytes = 1200;
+        stats.late_commands = 2;
+        stats.server_frame = Some(FrameEstimate {
+            frame: 302,
+            staleness: 10,
+            capped: false,
+        });
+        assert_eq!(
+            stats.to_string(),
+            "sent_frame=300 waitsnd=4 jitter_delay=0 rtt=30/80ms up=1200B/s down=0B/s \
+             server_frame=302 late_commands=2"
+        );
+    }
+
+    #[test]
+    fn test_net_chan_snapshot() {
+        let chan = NetChan::new();
+        let worker = chan.worker_handle();
+        let before = chan.metrics().locks;
+        assert_eq!(*chan.snapshot(), ChanSnapshot::default());
+        assert_eq!(chan.stats(), NetStats::default());
+        assert_eq!(chan.metrics().locks, before);
+
+        // every field written from the seq, a torn read breaks the relation
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let chan = chan.clone();
+                return std::thread::spawn(move || {
+                    let mut last = 0;
+                    while last < 2000 {
+                        let snapshot = chan.snapshot();
+                        assert!(snapshot.seq >= last);
+                        last = snapshot.seq;
+                        if last == 0 {
+                            continue;
+                        }
+                        let stats = snapshot.stats;
+                        assert_eq!(stats.sent_frame as u64, last);
+                        assert_eq!(stats.dropped_commands, last * 3);
+                        assert_eq!(stats.late_commands, !last);
+                        assert!(chan.stats().sent_frame as u64 >= last);
+                    }
+                });
+            })
+            .collect();
+        let mut output = NetOutput::new();
+        for seq in 1..=2000u64 {
+            output.stats.sent_frame = seq as u32;
+            output.stats.dropped_commands = seq * 3;
+            output.stats.late_commands = !seq;
+            worker.send_output(&mut output);
+        }
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        assert_eq!(chan.snapshot().seq, 2000);
+    }
+
+    #[test]
+    fn test_net_chan_steady_state_allocations() {
+        let chan = NetChan::new();
+        let mut frame = 0;
+        let mut commands = Commands::new();
+        let mut hash = Vec::with_capacity(HASH_CAP);
+        let mut ce = CommandEncoder::new(0);
+        let mut cd = CommandDecoder::new(0);
+        let mut bytes = Vec::with_capacity(KCP_MAX_PACKET);
+
+        let mut run = |idx: u32| {
+            chan.send_input(idx, &[Command::Aaa(idx as i32, 1)], &[1, 2, 3, 4])
+                .unwrap();
+            commands.clear();
+            hash.clear();
+            chan.recv_input(&mut frame, &mut commands, &mut hash);
+
+            let (ce_commands, ce_hash) = ce.buffers();
+            ce_commands.extend(commands.drain(..));
+            ce_hash.extend_from_slice(&hash);
+            ce.encode(frame).unwrap();
+
+            bytes.clear();
+            bytes.extend_from_slice(ce.command_bytes());
+            cd.decode(&bytes).unwrap();
+            assert_eq!(cd.len(), 1);
+        };
+
+        for idx in 1..10 {
+            run(idx);
+        }
+        let before = allocations();
+        for idx in 10..1000 {
+            run(idx);
+        }
+        assert_eq!(allocations(), before);
+    }
 }
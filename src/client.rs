@@ -0,0 +1,808 @@
+use crate::base::{
+    ClientError, FinishInfo, StartInfo, DROP_TIMEOUT, KCP_INTERVAL, OFFLINE_CONV,
+    SEND_BUDGET_MARGIN, STATS_INTERVAL,
+};
+use crate::chan::{
+    CancelResult, ControlMsg, NetChan, NetEvent, NetStats, NetWarning, Presence, SendBudget,
+};
+use crate::codec::{Command, CommandBatch, CommandEx};
+use crate::message::NetPlayerState;
+use crate::resume::SessionState;
+use crate::validate::CommandValidator;
+use crate::worker::{NetWorker, WorkerConfig};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PollStatus {
+    NotStarted,
+    Active,
+    Finished(FinishInfo),
+}
+
+#[derive(Debug, Clone)]
+pub struct GameHandle {
+    conv: u32,
+    chan: NetChan,
+    validator: Option<Arc<dyn CommandValidator>>,
+    // frames can_send_frame() keeps in reserve
+    budget_margin: u32,
+
+    // poll() state
+    batches: Vec<CommandBatch>,
+    events: Vec<NetEvent>,
+    stats_at: Option<Instant>,
+    jitter_delay: u32,
+    dropped: u64,
+}
+
+impl GameHandle {
+    pub fn new(conv: u32, chan: NetChan) -> GameHandle {
+        return GameHandle {
+            conv,
+            chan,
+            validator: None,
+            budget_margin: SEND_BUDGET_MARGIN,
+            batches: Vec::new(),
+            events: Vec::new(),
+            stats_at: None,
+            jitter_delay: 0,
+            dropped: 0,
+        };
+    }
+
+    pub fn with_validator(mut self, validator: Option<Arc<dyn CommandValidator>>) -> GameHandle {
+        self.validator = validator;
+        return self;
+    }
+
+    pub fn with_budget_margin(mut self, margin: u32) -> GameHandle {
+        self.budget_margin = margin;
+        return self;
+    }
+
+    pub fn conv(&self) -> u32 {
+        return self.conv;
+    }
+
+    pub fn send_input(
+        &self,
+        frame: u32,
+        commands: &[Command],
+        hash: &[u8],
+    ) -> Result<(), ClientError> {
+        if let Some(validator) = &self.validator {
+            for command in commands {
+                validator.validate_outgoing(command)?;
+            }
+        }
+        return Ok(self.chan.send_input(frame, commands, hash)?);
+    }
+
+    pub fn recv_output(
+        &self,
+        commands: &mut Vec<CommandEx>,
+        states: &mut BTreeMap<u32, NetPlayerState>,
+    ) -> Result<(), ClientError> {
+        return Ok(self.chan.recv_output(commands, states)?);
+    }
+
+    pub fn recv_events(&self, events: &mut Vec<NetEvent>) {
+        self.chan.recv_events(events);
+    }
+
+    // see NetChan::cancel_pending_inputs()
+    pub fn cancel_pending_inputs(&self, from_frame: u32) -> CancelResult {
+        return self.chan.cancel_pending_inputs(from_frame);
+    }
+
+    // handled by the worker before any input still queued
+    pub fn send_control(&self, control: ControlMsg) -> Result<(), ClientError> {
+        return Ok(self.chan.send_control(control)?);
+    }
+
+    pub fn send_unreliable(&self, payload: &[u8]) -> Result<(), ClientError> {
+        return Ok(self.chan.send_unreliable(payload)?);
+    }
+
+    pub fn recv_unreliable(&self, payloads: &mut Vec<(u32, Vec<u8>)>) {
+        self.chan.recv_unreliable(payloads);
+    }
+
+    // cheap enough to call every frame, never waits on the worker
+    pub fn stats(&self) -> NetStats {
+        return self.chan.stats();
+    }
+
+    pub fn send_budget(&self) -> SendBudget {
+        return self.chan.send_budget();
+    }
+
+    // whether another frame can be sent now without risking WindowExhausted
+    pub fn can_send_frame(&self) -> bool {
+        return self.send_budget().can_send_frame(self.budget_margin);
+    }
+
+    pub fn finish_info(&self) -> Option<FinishInfo> {
+        return self.chan.finish_info();
+    }
+
+    pub fn session_state(&self) -> Option<SessionState> {
+        return self.chan.session_state();
+    }
+
+    // blocks until started or finished, Ok(None) when `timeout` expires first
+    pub fn wait_for_start(&self, timeout: Duration) -> Result<Option<StartInfo>, FinishInfo> {
+        return self.chan.wait_for_start(timeout);
+    }
+
+    // Everything since the last call, in order: events as they arrived with
+    // commands grouped per frame, each state change after the commands of
+    // its frame, then warnings and a stats snapshot at most every
+    // STATS_INTERVAL ms. The order is the same on every client given the
+    // same packets.
+    pub fn poll(&mut self, out: &mut Vec<NetEvent>) -> PollStatus {
+        self.batches.clear();
+        self.chan.drain_batches(&mut self.batches, &mut self.events);
+        // stable, same frame and conv stay in arrival order
+        self.batches.sort_by_key(|batch| (batch.frame, batch.conv));
+        let mut first = 0;
+        for event in self.events.drain(..) {
+            if let NetEvent::State { frame, .. } = event {
+                first = Self::group_commands(&self.batches, first, Some(frame), out);
+            }
+            out.push(event);
+        }
+        Self::group_commands(&self.batches, first, None, out);
+
+        let dropped = self.chan.metrics().unreliable_dropped;
+        if dropped > self.dropped {
+            out.push(NetEvent::Warning(NetWarning::DroppedPackets(
+                dropped - self.dropped,
+            )));
+            self.dropped = dropped;
+        }
+        let stats = self.chan.stats();
+        if stats.jitter_delay > self.jitter_delay {
+            out.push(NetEvent::Warning(NetWarning::Degraded {
+                jitter_delay: stats.jitter_delay,
+            }));
+        }
+        self.jitter_delay = stats.jitter_delay;
+        let interval = Duration::from_millis(STATS_INTERVAL);
+        if self.stats_at.map_or(true, |at| at.elapsed() >= interval) {
+            self.stats_at = Some(Instant::now());
+            out.push(NetEvent::Stats(stats));
+        }
+
+        if let Some(info) = self.chan.finish_info() {
+            return PollStatus::Finished(info);
+        }
+        return match self.chan.start_info() {
+            Some(_) => PollStatus::Active,
+            None => PollStatus::NotStarted,
+        };
+    }
+
+    // one Commands event per frame from `first` on, up to frame `until`,
+    // returns where it stopped
+    fn group_commands(
+        batches: &[CommandBatch],
+        mut first: usize,
+        until: Option<u32>,
+        out: &mut Vec<NetEvent>,
+    ) -> usize {
+        while first < batches.len() {
+            let frame = batches[first].frame;
+            if until.map_or(false, |until| frame > until) {
+                break;
+            }
+            let len = batches[first..]
+                .iter()
+                .take_while(|batch| batch.frame == frame)
+                .count();
+            let batches = &batches[first..first + len];
+            out.push(NetEvent::Commands {
+                frame,
+                commands: CommandBatch::flatten(batches).collect(),
+            });
+            first += len;
+        }
+        return first;
+    }
+
+    // reported to the server and other players once running, changes are
+    // sent at most every PRESENCE_INTERVAL ms
+    pub fn set_presence(&self, presence: Presence) {
+        self.chan.set_presence(presence);
+    }
+
+    // graceful disconnect, safe to call from any thread (e.g. a signal handler)
+    pub fn game_over(&self) -> Result<(), ClientError> {
+        return Ok(self.chan.game_over()?);
+    }
+}
+
+pub struct Client {
+    handle: GameHandle,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Client {
+    pub fn connect(
+        addr: SocketAddr,
+        conv: u32,
+        room_id: &str,
+        player_id: &str,
+        password: &str,
+    ) -> Result<Client, ClientError> {
+        return Client::connect_with_config(
+            addr,
+            conv,
+            room_id,
+            player_id,
+            password,
+            WorkerConfig::default(),
+        );
+    }
+
+    pub fn connect_with_config(
+        addr: SocketAddr,
+        conv: u32,
+        room_id: &str,
+        player_id: &str,
+        password: &str,
+        config: WorkerConfig,
+    ) -> Result<Client, ClientError> {
+        return Client::spawn(addr, conv, room_id, player_id, password, config, None);
+    }
+
+    // rejoins the match of a client that crashed, from its session_state(),
+    // the game continues sending inputs after `state.last_frame`
+    pub fn resume(state: &SessionState, config: WorkerConfig) -> Result<Client, ClientError> {
+        return Client::spawn(
+            state.addr,
+            state.conv,
+            &state.room_id,
+            &state.player_id,
+            "",
+            config,
+            Some(state),
+        );
+    }
+
+    fn spawn(
+        addr: SocketAddr,
+        conv: u32,
+        room_id: &str,
+        player_id: &str,
+        password: &str,
+        config: WorkerConfig,
+        resume: Option<&SessionState>,
+    ) -> Result<Client, ClientError> {
+        let chan = NetChan::with_limits(config.input_limits, config.output_limits);
+        let validator = config.validator.clone();
+        // only local setup, the worker retries the handshake itself
+        let mut worker = NetWorker::with_config(
+            addr,
+            conv,
+            room_id,
+            player_id,
+            password,
+            chan.clone(),
+            config,
+        )?;
+        if let Some(state) = resume {
+            worker.resume(state)?;
+        }
+        return Client::launch(worker, conv, chan, validator);
+    }
+
+    // practice offline: the same worker and messages as a single player
+    // match, answered in-process without any network
+    pub fn offline(config: WorkerConfig) -> Result<Client, ClientError> {
+        let chan = NetChan::with_limits(config.input_limits, config.output_limits);
+        let validator = config.validator.clone();
+        let worker = NetWorker::offline(chan.clone(), config)?;
+        return Client::launch(worker, OFFLINE_CONV, chan, validator);
+    }
+
+    fn launch(
+        mut worker: NetWorker,
+        conv: u32,
+        chan: NetChan,
+        validator: Option<Arc<dyn CommandValidator>>,
+    ) -> Result<Client, ClientError> {
+        let thread = thread::Builder::new()
+            .name(format!("net-worker-{}", conv))
+            .spawn(move || worker.run())?;
+
+        return Ok(Client {
+            handle: GameHandle::new(conv, chan).with_validator(validator),
+            thread: Some(thread),
+        });
+    }
+
+    pub fn handle(&self) -> &GameHandle {
+        return &self.handle;
+    }
+
+    // to persist for resume(), None before the worker published it
+    pub fn session_state(&self) -> Option<SessionState> {
+        return self.handle.session_state();
+    }
+
+    // requests the graceful finish and waits at most DROP_TIMEOUT ms for the
+    // worker to flush it
+    pub fn disconnect(self) {
+        let _ = self.disconnect_timeout(Duration::from_millis(DROP_TIMEOUT));
+    }
+
+    // true when the finish was flushed within `timeout`, otherwise the worker
+    // keeps draining detached until its own finish_timeout
+    pub fn disconnect_timeout(mut self, timeout: Duration) -> bool {
+        let _ = self.handle.game_over();
+        return self.wait_worker(timeout) && self.handle.chan.flushed();
+    }
+
+    // the finish with its SessionSummary, once the worker exited
+    pub fn join(mut self) -> Option<FinishInfo> {
+        self.join_worker();
+        return self.handle.finish_info();
+    }
+
+    fn join_worker(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+
+    // whether the worker exited in time, it is detached otherwise
+    fn wait_worker(&mut self, timeout: Duration) -> bool {
+        let thread = match self.thread.take() {
+            Some(thread) => thread,
+            None => return true,
+        };
+        let deadline = Instant::now() + timeout;
+        while !thread.is_finished() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(KCP_INTERVAL));
+        }
+        if !thread.is_finished() {
+            return false;
+        }
+        let _ = thread.join();
+        return true;
+    }
+}
+
+// dropped without disconnect() or join(), e.g. while unwinding: request the
+// graceful finish and give the worker DROP_TIMEOUT ms to send it, it keeps
+// draining detached after that
+impl Drop for Client {
+    fn drop(&mut self) {
+        if self.thread.is_none() {
+            return;
+        }
+        let _ = self.handle.game_over();
+        self.wait_worker(Duration::from_millis(DROP_TIMEOUT));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::base::{ConfigError, PRESENCE_INTERVAL};
+    use crate::codec::Command;
+    use crate::message::{NetConnect, NetFinishCause};
+    use crate::mock::{MockAuth, MockServer};
+    use crate::offline::OfflineConfig;
+    use std::net::UdpSocket;
+    use std::time::SystemTime;
+
+    fn poll_until<F: Fn(&PollStatus, &[NetEvent]) -> bool>(
+        handle: &mut GameHandle,
+        events: &mut Vec<NetEvent>,
+        done: F,
+    ) -> PollStatus {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            let mut batch = Vec::new();
+            let status = handle.poll(&mut batch);
+            events.extend(
+                batch
+                    .into_iter()
+                    .filter(|event| !matches!(event, NetEvent::Stats(_))),
+            );
+            if done(&status, events) || Instant::now() > deadline {
+                return status;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    // 3 frames echoed back then a disconnect, the events but stats
+    fn scripted_match(client: Client) -> (Vec<NetEvent>, PollStatus) {
+        let mut handle = client.handle().clone();
+        let mut events = Vec::new();
+
+        let status = poll_until(&mut handle, &mut events, |status, _| {
+            *status == PollStatus::Active
+        });
+        assert_eq!(status, PollStatus::Active);
+
+        for frame in 1..=3 {
+            handle
+                .send_input(frame, &[Command::Aaa(frame as i32, 0)], &[1])
+                .unwrap();
+        }
+        poll_until(&mut handle, &mut events, |_, events| {
+            events
+                .iter()
+                .any(|event| matches!(event, NetEvent::Commands { frame: 3, .. }))
+        });
+        client.disconnect();
+        let status = poll_until(&mut handle, &mut events, |status, _| {
+            matches!(status, PollStatus::Finished(_))
+        });
+        return (events, status);
+    }
+
+    #[test]
+    fn test_client_poll_scripted_match() {
+        let server = MockServer::start(1).unwrap();
+        let client = Client::connect(server.addr(), 1, "room", "player", "").unwrap();
+        let chan = client.handle().chan.clone();
+        let (events, status) = scripted_match(client);
+
+        let start = chan.start_info().unwrap();
+        let commands = |frame: u32| NetEvent::Commands {
+            frame,
+            commands: vec![CommandEx {
+                conv: 1,
+                frame,
+                command: Command::Aaa(frame as i32, 0),
+                stamps: None,
+            }],
+        };
+        assert_eq!(
+            events,
+            vec![
+                NetEvent::State {
+                    conv: 1,
+                    state: NetPlayerState::Waiting,
+                    frame: 0
+                },
+                NetEvent::State {
+                    conv: 1,
+                    state: NetPlayerState::Running,
+                    frame: 0
+                },
+                NetEvent::Started(start),
+                commands(1),
+                commands(2),
+                commands(3),
+                NetEvent::State {
+                    conv: 1,
+                    state: NetPlayerState::Stopped,
+                    frame: 3
+                },
+            ]
+        );
+        match status {
+            PollStatus::Finished(info) => assert_eq!(info.cause, NetFinishCause::GameOver),
+            status => panic!("unexpected {:?}", status),
+        };
+    }
+
+    #[test]
+    fn test_client_offline() {
+        // only when it started differs
+        let scrub = |events: Vec<NetEvent>| -> Vec<NetEvent> {
+            return events
+                .into_iter()
+                .map(|event| match event {
+                    NetEvent::Started(mut start) => {
+                        start.started_at = SystemTime::UNIX_EPOCH;
+                        NetEvent::Started(start)
+                    }
+                    event => event,
+                })
+                .collect();
+        };
+        let server = MockServer::start(1).unwrap();
+        let client = Client::connect(server.addr(), OFFLINE_CONV, "room", "player", "").unwrap();
+        let (online, online_status) = scripted_match(client);
+        let online = scrub(online);
+        assert_eq!(server.records().connects.len(), 1);
+
+        for offline in [
+            OfflineConfig::default(),
+            OfflineConfig {
+                start_delay: 200,
+                latency: 50,
+            },
+        ] {
+            let config = WorkerConfig {
+                offline,
+                ..WorkerConfig::default()
+            };
+            let client = Client::offline(config).unwrap();
+            assert_eq!(client.handle().conv(), OFFLINE_CONV);
+            let (events, status) = scripted_match(client);
+            assert_eq!(scrub(events), online);
+            match (status, &online_status) {
+                (PollStatus::Finished(info), PollStatus::Finished(online)) => {
+                    assert_eq!(info.cause, online.cause);
+                }
+                status => panic!("unexpected {:?}", status),
+            };
+        }
+    }
+
+    #[test]
+    fn test_client_presence() {
+        let server = MockServer::start(2).unwrap();
+        let client = Client::connect(server.addr(), 1, "room", "player-1", "").unwrap();
+        let other = Client::connect(server.addr(), 2, "room", "player-2", "").unwrap();
+        let mut handle = other.handle().clone();
+        let mut events = Vec::new();
+        poll_until(&mut handle, &mut events, |status, _| {
+            *status == PollStatus::Active
+        });
+        assert!(client
+            .handle()
+            .wait_for_start(Duration::from_secs(10))
+            .unwrap()
+            .is_some());
+
+        let wait_for = |len: usize| {
+            let deadline = Instant::now() + Duration::from_secs(10);
+            while server.records().presences.len() < len && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(5));
+            }
+            return server.records().presences;
+        };
+        client.handle().set_presence(Presence::Background);
+        assert_eq!(wait_for(1), vec![(1, NetPlayerState::Background)]);
+        poll_until(&mut handle, &mut events, |_, events| {
+            events.iter().any(|event| {
+                matches!(
+                    event,
+                    NetEvent::State {
+                        conv: 1,
+                        state: NetPlayerState::Background,
+                        ..
+                    }
+                )
+            })
+        });
+
+        // flapping is coalesced into the last presence once the interval
+        // is over, repeating it sends nothing
+        for idx in 0..20 {
+            let presence = match idx % 2 {
+                0 => Presence::Active,
+                _ => Presence::Background,
+            };
+            client.handle().set_presence(presence);
+            thread::sleep(Duration::from_millis(1));
+        }
+        client.handle().set_presence(Presence::Paused);
+        assert_eq!(wait_for(2)[1], (1, NetPlayerState::Paused));
+        client.handle().set_presence(Presence::Paused);
+        thread::sleep(Duration::from_millis(PRESENCE_INTERVAL + 100));
+        assert_eq!(server.records().presences.len(), 2);
+
+        client.handle().set_presence(Presence::Active);
+        assert_eq!(wait_for(3)[2], (1, NetPlayerState::Running));
+        client.disconnect();
+        other.disconnect();
+    }
+
+    #[test]
+    fn test_client_invalid_credentials() {
+        let server = MockServer::start(1).unwrap();
+        let err = Client::connect(server.addr(), 1, "room", "bad\nname", "")
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err,
+            ClientError::Config(ConfigError::InvalidField {
+                field: "player_id",
+                ..
+            })
+        ));
+        assert!(!err.is_retryable().allows(0));
+
+        // no conv assigned yet, nothing to open a kcp session with
+        let err = Client::connect(server.addr(), 0, "room", "player", "")
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err,
+            ClientError::Config(ConfigError::InvalidField { field: "conv", .. })
+        ));
+        thread::sleep(Duration::from_millis(50));
+        assert!(server.records().connects.is_empty());
+    }
+
+    // polls until started or finished, the server's records at that point
+    fn authenticate(
+        auth: MockAuth,
+        password: &str,
+        challenge: bool,
+        plaintext_fallback: bool,
+    ) -> (PollStatus, Vec<NetConnect>) {
+        let server = MockServer::start_with_auth(1, auth).unwrap();
+        let config = WorkerConfig {
+            challenge,
+            plaintext_fallback,
+            ..WorkerConfig::default()
+        };
+        let client =
+            Client::connect_with_config(server.addr(), 1, "room", "player", password, config)
+                .unwrap();
+        let mut handle = client.handle().clone();
+        let status = poll_until(&mut handle, &mut Vec::new(), |status, _| {
+            *status != PollStatus::NotStarted
+        });
+        // not disconnect(), a refused worker drains for FINISH_TIMEOUT
+        drop(client);
+        let connects = server.records().connects;
+        return (status, connects.into_iter().map(|(_, c)| c).collect());
+    }
+
+    fn auth_failed(status: &PollStatus) -> bool {
+        return match status {
+            PollStatus::Finished(info) => info.cause == NetFinishCause::AuthFailed,
+            _ => false,
+        };
+    }
+
+    #[test]
+    fn test_client_challenge_response() {
+        let auth = MockAuth {
+            password: Some("secret".to_string()),
+            challenge: true,
+            ..MockAuth::default()
+        };
+        let (status, connects) = authenticate(auth.clone(), "secret", true, false);
+        assert_eq!(status, PollStatus::Active);
+        assert_eq!(connects.len(), 2);
+        assert!(connects[0].wants_challenge);
+        assert!(connects[0].response.is_empty());
+        assert_eq!(connects[1].response.len(), 32);
+        assert!(connects.iter().all(|connect| connect.password.is_empty()));
+
+        let (status, connects) = authenticate(auth.clone(), "wrong", true, false);
+        assert!(auth_failed(&status));
+        assert!(connects.iter().all(|connect| connect.password.is_empty()));
+
+        // the plaintext path still works against a challenge server
+        let (status, connects) = authenticate(auth, "secret", false, false);
+        assert_eq!(status, PollStatus::Active);
+        assert_eq!(connects.len(), 1);
+        assert_eq!(connects[0].password, "secret");
+    }
+
+    #[test]
+    fn test_client_challenge_legacy_server() {
+        let auth = MockAuth {
+            password: Some("secret".to_string()),
+            challenge: false,
+            ..MockAuth::default()
+        };
+        // the password never leaves unless the fallback is allowed
+        let (status, connects) = authenticate(auth.clone(), "secret", true, false);
+        assert!(auth_failed(&status));
+        assert_eq!(connects.len(), 1);
+        assert!(connects[0].password.is_empty());
+
+        let (status, connects) = authenticate(auth, "secret", true, true);
+        assert_eq!(status, PollStatus::Active);
+        assert_eq!(connects.len(), 2);
+        assert!(connects[0].wants_challenge);
+        assert_eq!(connects[1].password, "secret");
+
+        // a legacy server without a password accepts the first Connect
+        let (status, connects) = authenticate(MockAuth::default(), "", true, false);
+        assert_eq!(status, PollStatus::Active);
+        assert_eq!(connects.len(), 1);
+    }
+
+    #[test]
+    fn test_client_connect_retry() {
+        // kcp acks the Connect, the Accept never comes
+        let auth = MockAuth {
+            silent: true,
+            ..MockAuth::default()
+        };
+        let server = MockServer::start_with_auth(1, auth).unwrap();
+        let config = WorkerConfig {
+            reach_timeout: 200,
+            accept_timeout: 300,
+            ..WorkerConfig::default()
+        };
+        let client =
+            Client::connect_with_config(server.addr(), 1, "room", "player", "", config).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while server.records().connects.len() < 2 {
+            assert!(Instant::now() < deadline, "not retried");
+            thread::sleep(Duration::from_millis(10));
+        }
+        // over a fresh kcp session of the same conv
+        let connects = server.records().connects;
+        assert!(connects.iter().all(|(conv, _)| *conv == 1));
+        assert!(client.handle().finish_info().is_none());
+        drop(client);
+    }
+
+    #[test]
+    fn test_client_disconnect_timeout() {
+        let server = MockServer::start(1).unwrap();
+        let client = Client::connect(server.addr(), 1, "room", "player", "").unwrap();
+        assert!(client
+            .handle()
+            .wait_for_start(Duration::from_secs(10))
+            .unwrap()
+            .is_some());
+        assert!(client.disconnect_timeout(Duration::from_secs(5)));
+
+        // nothing is ever acked
+        let blackhole = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = blackhole.local_addr().unwrap();
+        let config = WorkerConfig {
+            finish_timeout: 300,
+            ..WorkerConfig::default()
+        };
+        let client =
+            Client::connect_with_config(addr, 1, "room", "player", "", config.clone()).unwrap();
+        let disconnected_at = Instant::now();
+        assert!(!client.disconnect_timeout(Duration::from_millis(50)));
+        assert!(disconnected_at.elapsed() < Duration::from_millis(50 + 100));
+
+        // the detached worker still exits by its own deadline
+        let mut client =
+            Client::connect_with_config(addr, 2, "room", "player", "", config).unwrap();
+        let handle = client.handle().clone();
+        let thread = client.thread.take().unwrap();
+        let dropped_at = Instant::now();
+        drop(client);
+        while !thread.is_finished() {
+            assert!(dropped_at.elapsed() < Duration::from_millis(300 + 500));
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert!(!handle.chan.flushed());
+        assert_eq!(
+            handle.finish_info().unwrap().cause,
+            NetFinishCause::GameOver
+        );
+    }
+
+    #[test]
+    fn test_client_drop_sends_finish() {
+        let server = MockServer::start(1).unwrap();
+        let client = Client::connect(server.addr(), 1, "room", "player", "").unwrap();
+        let handle = client.handle().clone();
+        assert!(handle
+            .wait_for_start(Duration::from_secs(10))
+            .unwrap()
+            .is_some());
+
+        let dropped_at = Instant::now();
+        drop(client);
+        assert!(dropped_at.elapsed() <= Duration::from_millis(DROP_TIMEOUT + 500));
+
+        let deadline = Instant::now() + Duration::from_millis(DROP_TIMEOUT);
+        while server.records().finishes.is_empty() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(5));
+        }
+        let finishes = server.records().finishes;
+        assert_eq!(finishes.len(), 1);
+        assert_eq!(finishes[0].0, 1);
+        assert_eq!(finishes[0].1.cause(), NetFinishCause::GameOver);
+    }
+}
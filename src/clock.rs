@@ -0,0 +1,124 @@
+use crate::base::CLOCK_JUMP_MAX;
+use std::time::{Duration, SystemTime};
+
+// Milliseconds since reset() read off the wall clock, which NTP or the user
+// can step either way at any time. Each reading adds the wall time since the
+// previous one: a step back adds nothing instead of going back or holding
+// every timeout off until the clock caught up again, a jump forward adds at
+// most CLOCK_JUMP_MAX instead of firing every timeout at once. Both are
+// counted as anomalies.
+#[derive(Debug, Clone, Copy)]
+pub struct WallClock {
+    // replaced in tests
+    now: fn() -> SystemTime,
+    // the previous reading, None until reset()
+    read_at: Option<SystemTime>,
+    elapsed: Duration,
+    anomalies: u64,
+}
+
+impl WallClock {
+    pub fn new(now: fn() -> SystemTime) -> WallClock {
+        return WallClock {
+            now,
+            read_at: None,
+            elapsed: Duration::ZERO,
+            anomalies: 0,
+        };
+    }
+
+    // counts from 0 again, the anomalies are kept
+    pub fn reset(&mut self) {
+        self.read_at = Some((self.now)());
+        self.elapsed = Duration::ZERO;
+    }
+
+    // 0 before reset(), never decreases
+    pub fn elapsed(&mut self) -> u64 {
+        let read_at = match self.read_at {
+            Some(read_at) => read_at,
+            None => return 0,
+        };
+        let now = (self.now)();
+        let max = Duration::from_millis(CLOCK_JUMP_MAX);
+        let step = match now.duration_since(read_at) {
+            Ok(step) if step > max => {
+                self.anomalies += 1;
+                max
+            }
+            Ok(step) => step,
+            Err(_) => {
+                self.anomalies += 1;
+                Duration::ZERO
+            }
+        };
+        self.elapsed += step;
+        self.read_at = Some(now);
+        return self.elapsed.as_millis() as u64;
+    }
+
+    pub fn anomalies(&self) -> u64 {
+        return self.anomalies;
+    }
+
+    // kcp waits on the socket against the real clock, `wait` ms from now
+    pub fn until(wait: u64) -> SystemTime {
+        return SystemTime::now() + Duration::from_millis(wait);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::Cell;
+
+    thread_local! {
+        static NOW: Cell<SystemTime> = Cell::new(SystemTime::UNIX_EPOCH);
+    }
+
+    fn fake_now() -> SystemTime {
+        return NOW.with(Cell::get);
+    }
+
+    fn set(ms: u64) {
+        NOW.with(|now| {
+            now.set(SystemTime::UNIX_EPOCH + Duration::from_secs(3600) + Duration::from_millis(ms))
+        });
+    }
+
+    #[test]
+    fn test_wall_clock() {
+        set(0);
+        let mut clock = WallClock::new(fake_now);
+        set(500);
+        assert_eq!(clock.elapsed(), 0);
+        clock.reset();
+        set(510);
+        assert_eq!(clock.elapsed(), 10);
+
+        // under a ms per reading still adds up
+        for tick in 0..10 {
+            NOW.with(|now| now.set(now.get() + Duration::from_micros(500)));
+            assert_eq!(clock.elapsed(), 10 + (tick + 1) / 2);
+        }
+        assert_eq!(clock.anomalies(), 0);
+
+        // stepped back an hour: holds, then goes on from there
+        set(515);
+        let before = clock.elapsed();
+        NOW.with(|now| now.set(now.get() - Duration::from_secs(3600)));
+        assert_eq!(clock.elapsed(), before);
+        NOW.with(|now| now.set(now.get() + Duration::from_millis(20)));
+        assert_eq!(clock.elapsed(), before + 20);
+        assert_eq!(clock.anomalies(), 1);
+
+        // jumped a day ahead: at most CLOCK_JUMP_MAX of it
+        NOW.with(|now| now.set(now.get() + Duration::from_secs(24 * 3600)));
+        assert_eq!(clock.elapsed(), before + 20 + CLOCK_JUMP_MAX);
+        assert_eq!(clock.anomalies(), 2);
+
+        clock.reset();
+        assert_eq!(clock.elapsed(), 0);
+        assert_eq!(clock.anomalies(), 2);
+    }
+}
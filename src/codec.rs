@@ -1,17 +1,28 @@
-use crate::base::{KCPError, HASH_CAP, KCP_MAX_PACKET, KCP_MIN_PACKET};
+use crate::base::{
+    Capabilities, KCPError, ProtocolLimits, APPLICATION_TYPES, COMMANDS_CAP, COMMANDS_INLINE,
+    HASH_CAP, KCP_MAX_PACKET, KCP_MIN_PACKET, UNRELIABLE_CONV, UNRELIABLE_HEADER,
+    UNRELIABLE_MAX_PAYLOAD,
+};
+use crate::hash::FrameHasher;
 use crate::message::{
-    NetAccept, NetCommand, NetConnect, NetFinish, NetHash, NetStart, NetState, NetType,
+    NetAccept, NetChallenge, NetCommand, NetConnect, NetFinish, NetFinishCause, NetHash, NetPause,
+    NetPlayerState, NetResume, NetStart, NetState, NetType,
 };
+use crate::pool::{self, SharedPool};
 use anyhow::Result;
 use bincode::config::{DefaultOptions, Options};
-use byteorder::{BigEndian, ByteOrder};
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
 use fn_error_context::context;
-use protobuf::{Message, ProtobufEnum};
+use prost::Message;
 use serde::de::{DeserializeSeed, Deserializer, SeqAccess, Visitor};
+use serde::ser::{SerializeSeq, Serializer};
 use serde::{Deserialize, Serialize};
+use smallvec::SmallVec;
 use std::fmt;
+use std::ops::Range;
 
-#[derive(Debug, Clone, PartialEq)]
+// Debug is Display, the derived one prints every protobuf field
+#[derive(Clone, PartialEq)]
 pub enum NetMessage {
     Connect(NetConnect),
     Accept(NetAccept),
@@ -20,11 +31,400 @@ pub enum NetMessage {
     Finish(NetFinish),
     Command(NetCommand),
     Hash(NetHash),
+    Challenge(NetChallenge),
+    Pause(NetPause),
+    Resume(NetResume),
+    // a type byte from APPLICATION_TYPES up and the payload as is, see
+    // WorkerConfig::application_types
+    Application(u8, Vec<u8>),
+}
+
+// what part of the session a message belongs to, the worker dispatches on
+// it before looking at the message itself
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageCategory {
+    // Connect, Accept and Challenge
+    Handshake,
+    // State, Start, Finish, Pause and Resume
+    State,
+    // Command and Hash
+    Gameplay,
+    Application,
+}
+
+// Post-parse checks of a decoded message against ProtocolLimits, only
+// messages with string or bytes fields override it. Errs with
+// KCPError::FieldTooLong naming the first field over its limit.
+pub trait ValidateLimits {
+    fn validate_limits(&self, _limits: &ProtocolLimits) -> Result<()> {
+        return Ok(());
+    }
+}
+
+fn check_len(field: &'static str, len: usize, max: usize) -> Result<()> {
+    if len > max {
+        return Err(KCPError::FieldTooLong(field).into());
+    }
+    return Ok(());
+}
+
+impl ValidateLimits for NetConnect {
+    fn validate_limits(&self, limits: &ProtocolLimits) -> Result<()> {
+        check_len("NetConnect.room_id", self.room_id.len(), limits.max_room_id)?;
+        check_len(
+            "NetConnect.player_id",
+            self.player_id.len(),
+            limits.max_player_id,
+        )?;
+        check_len(
+            "NetConnect.password",
+            self.password.len(),
+            limits.max_password,
+        )?;
+        check_len(
+            "NetConnect.response",
+            self.response.len(),
+            limits.max_response,
+        )?;
+        check_len(
+            "NetConnect.resume_token",
+            self.resume_token.len(),
+            limits.max_token,
+        )?;
+        return Ok(());
+    }
+}
+
+impl ValidateLimits for NetAccept {
+    fn validate_limits(&self, limits: &ProtocolLimits) -> Result<()> {
+        return check_len(
+            "NetAccept.resume_token",
+            self.resume_token.len(),
+            limits.max_token,
+        );
+    }
+}
+
+impl ValidateLimits for NetChallenge {
+    fn validate_limits(&self, limits: &ProtocolLimits) -> Result<()> {
+        return check_len("NetChallenge.nonce", self.nonce.len(), limits.max_nonce);
+    }
+}
+
+impl ValidateLimits for NetHash {
+    fn validate_limits(&self, limits: &ProtocolLimits) -> Result<()> {
+        return check_len("NetHash.hash", self.hash.len(), limits.max_hash);
+    }
 }
 
+impl ValidateLimits for NetState {}
+impl ValidateLimits for NetStart {}
+impl ValidateLimits for NetFinish {}
+impl ValidateLimits for NetCommand {}
+impl ValidateLimits for NetPause {}
+impl ValidateLimits for NetResume {}
+
+// application payloads aren't protobuf, the packet size bounds them
+impl ValidateLimits for NetMessage {
+    fn validate_limits(&self, limits: &ProtocolLimits) -> Result<()> {
+        return match self {
+            NetMessage::Connect(msg) => msg.validate_limits(limits),
+            NetMessage::Accept(msg) => msg.validate_limits(limits),
+            NetMessage::State(msg) => msg.validate_limits(limits),
+            NetMessage::Start(msg) => msg.validate_limits(limits),
+            NetMessage::Finish(msg) => msg.validate_limits(limits),
+            NetMessage::Command(msg) => msg.validate_limits(limits),
+            NetMessage::Hash(msg) => msg.validate_limits(limits),
+            NetMessage::Challenge(msg) => msg.validate_limits(limits),
+            NetMessage::Pause(msg) => msg.validate_limits(limits),
+            NetMessage::Resume(msg) => msg.validate_limits(limits),
+            NetMessage::Application(..) => Ok(()),
+        };
+    }
+}
+
+impl fmt::Display for NetMessage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match MessageKind::of(self.type_byte()) {
+            Some(kind) => write!(f, "{:?}", kind.typ)?,
+            None => write!(f, "Application({})", self.type_byte())?,
+        };
+        return self.fmt_fields(f);
+    }
+}
+
+impl fmt::Debug for NetMessage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return fmt::Display::fmt(self, f);
+    }
+}
+
+// a row of MESSAGE_KINDS
+struct MessageKind {
+    typ: NetType,
+    category: MessageCategory,
+    decode: fn(&[u8]) -> Result<NetMessage, prost::DecodeError>,
+}
+
+impl MessageKind {
+    fn of(typ: u8) -> Option<&'static MessageKind> {
+        return MESSAGE_KINDS.iter().find(|kind| kind.typ as u8 == typ);
+    }
+}
+
+// every NetType but Unknown, a new message type is a new row here and a
+// NetMessage variant
+static MESSAGE_KINDS: [MessageKind; 10] = [
+    MessageKind {
+        typ: NetType::Connect,
+        category: MessageCategory::Handshake,
+        decode: |pb| Ok(NetMessage::Connect(NetConnect::decode(pb)?)),
+    },
+    MessageKind {
+        typ: NetType::Accept,
+        category: MessageCategory::Handshake,
+        decode: |pb| Ok(NetMessage::Accept(NetAccept::decode(pb)?)),
+    },
+    MessageKind {
+        typ: NetType::State,
+        category: MessageCategory::State,
+        decode: |pb| Ok(NetMessage::State(NetState::decode(pb)?)),
+    },
+    MessageKind {
+        typ: NetType::Start,
+        category: MessageCategory::State,
+        decode: |pb| Ok(NetMessage::Start(NetStart::decode(pb)?)),
+    },
+    MessageKind {
+        typ: NetType::Finish,
+        category: MessageCategory::State,
+        decode: |pb| Ok(NetMessage::Finish(NetFinish::decode(pb)?)),
+    },
+    MessageKind {
+        typ: NetType::Command,
+        category: MessageCategory::Gameplay,
+        decode: |pb| Ok(NetMessage::Command(NetCommand::decode(pb)?)),
+    },
+    MessageKind {
+        typ: NetType::Hash,
+        category: MessageCategory::Gameplay,
+        decode: |pb| Ok(NetMessage::Hash(NetHash::decode(pb)?)),
+    },
+    MessageKind {
+        typ: NetType::Challenge,
+        category: MessageCategory::Handshake,
+        decode: |pb| Ok(NetMessage::Challenge(NetChallenge::decode(pb)?)),
+    },
+    MessageKind {
+        typ: NetType::Pause,
+        category: MessageCategory::State,
+        decode: |pb| Ok(NetMessage::Pause(NetPause::decode(pb)?)),
+    },
+    MessageKind {
+        typ: NetType::Resume,
+        category: MessageCategory::State,
+        decode: |pb| Ok(NetMessage::Resume(NetResume::decode(pb)?)),
+    },
+];
+
 impl NetMessage {
-    #[context("NetMessage::decode()")]
+    pub fn connect(room_id: &str, player_id: &str, password: &str) -> NetMessage {
+        let mut connect = NetConnect::default();
+        connect.room_id = room_id.to_string();
+        connect.player_id = player_id.to_string();
+        connect.password = password.to_string();
+        return NetMessage::Connect(connect);
+    }
+
+    // the first Connect of the challenge-response handshake, no password
+    pub fn connect_challenge(room_id: &str, player_id: &str) -> NetMessage {
+        let mut connect = NetConnect::default();
+        connect.room_id = room_id.to_string();
+        connect.player_id = player_id.to_string();
+        connect.wants_challenge = true;
+        return NetMessage::Connect(connect);
+    }
+
+    pub fn connect_response(room_id: &str, player_id: &str, response: &[u8]) -> NetMessage {
+        let mut connect = NetConnect::default();
+        connect.room_id = room_id.to_string();
+        connect.player_id = player_id.to_string();
+        connect.response = response.to_vec();
+        return NetMessage::Connect(connect);
+    }
+
+    // rejoins a running match with the token of an earlier Accept
+    pub fn connect_resume(room_id: &str, player_id: &str, resume_token: &[u8]) -> NetMessage {
+        let mut connect = NetConnect::default();
+        connect.room_id = room_id.to_string();
+        connect.player_id = player_id.to_string();
+        connect.resume_token = resume_token.to_vec();
+        return NetMessage::Connect(connect);
+    }
+
+    // see diagnostics::run()
+    pub fn connect_diagnostic() -> NetMessage {
+        let mut connect = NetConnect::default();
+        connect.diagnostic = true;
+        return NetMessage::Connect(connect);
+    }
+
+    pub fn challenge(nonce: &[u8]) -> NetMessage {
+        let mut challenge = NetChallenge::default();
+        challenge.nonce = nonce.to_vec();
+        return NetMessage::Challenge(challenge);
+    }
+
+    pub fn accept() -> NetMessage {
+        return NetMessage::Accept(NetAccept::default());
+    }
+
+    pub fn accept_resumable(resume_token: &[u8]) -> NetMessage {
+        let mut accept = NetAccept::default();
+        accept.resume_token = resume_token.to_vec();
+        return NetMessage::Accept(accept);
+    }
+
+    pub fn state(conv: u32, state: NetPlayerState) -> NetMessage {
+        let mut net_state = NetState::default();
+        net_state.conv = conv;
+        net_state.set_state(state);
+        return NetMessage::State(net_state);
+    }
+
+    pub fn start() -> NetMessage {
+        return NetMessage::Start(NetStart::default());
+    }
+
+    pub fn start_at(first_frame: u32) -> NetMessage {
+        let mut start = NetStart::default();
+        start.first_frame = first_frame;
+        return NetMessage::Start(start);
+    }
+
+    pub fn finish(frame: u32, cause: NetFinishCause) -> NetMessage {
+        let mut finish = NetFinish::default();
+        finish.frame = frame;
+        finish.set_cause(cause);
+        return NetMessage::Finish(finish);
+    }
+
+    pub fn command(frame: u32, conv: u32) -> NetMessage {
+        let mut command = NetCommand::default();
+        command.frame = frame;
+        command.conv = conv;
+        return NetMessage::Command(command);
+    }
+
+    pub fn hash(frame: u32, conv: u32, hash: &[u8]) -> NetMessage {
+        let mut net_hash = NetHash::default();
+        net_hash.frame = frame;
+        net_hash.conv = conv;
+        net_hash.hash = hash.to_vec();
+        return NetMessage::Hash(net_hash);
+    }
+
+    pub fn pause(frame: u32) -> NetMessage {
+        let mut pause = NetPause::default();
+        pause.frame = frame;
+        return NetMessage::Pause(pause);
+    }
+
+    pub fn resume(frame: u32) -> NetMessage {
+        let mut resume = NetResume::default();
+        resume.frame = frame;
+        return NetMessage::Resume(resume);
+    }
+
+    pub fn type_byte(&self) -> u8 {
+        let typ = match self {
+            NetMessage::Connect(_) => NetType::Connect,
+            NetMessage::Accept(_) => NetType::Accept,
+            NetMessage::State(_) => NetType::State,
+            NetMessage::Start(_) => NetType::Start,
+            NetMessage::Finish(_) => NetType::Finish,
+            NetMessage::Command(_) => NetType::Command,
+            NetMessage::Hash(_) => NetType::Hash,
+            NetMessage::Challenge(_) => NetType::Challenge,
+            NetMessage::Pause(_) => NetType::Pause,
+            NetMessage::Resume(_) => NetType::Resume,
+            NetMessage::Application(typ, _) => return *typ,
+        };
+        return typ as u8;
+    }
+
+    // " frame=.. conv=.." after the type name, payloads only by size and never
+    // the password
+    pub fn fmt_fields(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NetMessage::Connect(msg) => {
+                write!(
+                    f,
+                    " room_id={:?} player_id={:?} password={}",
+                    msg.room_id,
+                    msg.player_id,
+                    match (
+                        msg.password.is_empty(),
+                        msg.wants_challenge,
+                        msg.response.is_empty()
+                    ) {
+                        _ if !msg.resume_token.is_empty() => "resume",
+                        (_, true, _) => "challenge",
+                        (_, _, false) => "response",
+                        (true, _, _) => "none",
+                        (false, _, _) => "set",
+                    }
+                )?;
+                if msg.capabilities != 0 {
+                    write!(f, " capabilities={:#x}", msg.capabilities)?;
+                }
+                if msg.diagnostic {
+                    write!(f, " diagnostic")?;
+                }
+            }
+            NetMessage::Accept(msg) if msg.capabilities != 0 => {
+                write!(f, " capabilities={:#x}", msg.capabilities)?
+            }
+            NetMessage::State(msg) => write!(f, " conv={} state={:?}", msg.conv, msg.state())?,
+            NetMessage::Finish(msg) => write!(f, " frame={} cause={:?}", msg.frame, msg.cause())?,
+            NetMessage::Command(msg) => {
+                write!(f, " frame={} conv={}", msg.frame, msg.conv)?;
+                if msg.compressed {
+                    write!(f, " compressed")?;
+                }
+                if msg.padding > 0 {
+                    write!(f, " padding={}", msg.padding)?;
+                }
+            }
+            NetMessage::Hash(msg) => {
+                write!(f, " frame={} conv={} hash=", msg.frame, msg.conv)?;
+                for byte in msg.hash.iter() {
+                    write!(f, "{:02x}", byte)?;
+                }
+            }
+            NetMessage::Challenge(msg) => write!(f, " nonce={} bytes", msg.nonce.len())?,
+            NetMessage::Pause(msg) => write!(f, " frame={}", msg.frame)?,
+            NetMessage::Resume(msg) => write!(f, " frame={}", msg.frame)?,
+            NetMessage::Application(_, payload) => write!(f, " payload={} bytes", payload.len())?,
+            NetMessage::Accept(_) | NetMessage::Start(_) => {}
+        };
+        return Ok(());
+    }
+
+    pub fn category(&self) -> MessageCategory {
+        return match MessageKind::of(self.type_byte()) {
+            Some(kind) => kind.category,
+            None => MessageCategory::Application,
+        };
+    }
+
+    // with the default ProtocolLimits
     pub fn decode(bytes: &[u8]) -> Result<(NetMessage, usize)> {
+        return NetMessage::decode_with(bytes, &ProtocolLimits::default());
+    }
+
+    #[context("NetMessage::decode()")]
+    pub fn decode_with(bytes: &[u8], limits: &ProtocolLimits) -> Result<(NetMessage, usize)> {
         if bytes.len() < KCP_MIN_PACKET {
             return Err(KCPError::PacketTooShort.into());
         }
@@ -38,40 +438,19 @@ impl NetMessage {
             return Err(KCPError::PacketBroken.into());
         }
 
-        let typ = NetType::from_i32(bytes[0] as i32).unwrap_or(NetType::Unknown);
+        let typ = bytes[0];
         let pb_bytes = &bytes[KCP_MIN_PACKET..(offset)];
-        let msg = match typ {
-            NetType::Connect => {
-                let connect = NetConnect::parse_from_bytes(pb_bytes).map_err(KCPError::Protobuf)?;
-                NetMessage::Connect(connect)
-            }
-            NetType::Accept => {
-                let accept = NetAccept::parse_from_bytes(pb_bytes).map_err(KCPError::Protobuf)?;
-                NetMessage::Accept(accept)
-            }
-            NetType::State => {
-                let state = NetState::parse_from_bytes(pb_bytes).map_err(KCPError::Protobuf)?;
-                NetMessage::State(state)
-            }
-            NetType::Start => {
-                let start = NetStart::parse_from_bytes(pb_bytes).map_err(KCPError::Protobuf)?;
-                NetMessage::Start(start)
-            }
-            NetType::Finish => {
-                let finish = NetFinish::parse_from_bytes(pb_bytes).map_err(KCPError::Protobuf)?;
-                NetMessage::Finish(finish)
-            }
-            NetType::Command => {
-                let command = NetCommand::parse_from_bytes(pb_bytes).map_err(KCPError::Protobuf)?;
-                NetMessage::Command(command)
-            }
-            NetType::Hash => {
-                let hash = NetHash::parse_from_bytes(pb_bytes).map_err(KCPError::Protobuf)?;
-                NetMessage::Hash(hash)
-            }
-            _ => return Err(KCPError::PacketBroken.into()),
+        let msg = match MessageKind::of(typ) {
+            _ if typ >= APPLICATION_TYPES => NetMessage::Application(typ, pb_bytes.to_vec()),
+            Some(kind) => (kind.decode)(pb_bytes).map_err(|err| KCPError::Protobuf(err.into()))?,
+            None => return Err(KCPError::PacketBroken.into()),
         };
+        msg.validate_limits(limits)?;
 
+        // only command packets carry a tail, the bincode encoded commands
+        if typ != NetType::Command as u8 && offset != bytes.len() {
+            return Err(KCPError::PacketBroken.into());
+        }
         return Ok((msg, offset));
     }
 
@@ -80,36 +459,27 @@ impl NetMessage {
         let base = bytes.len();
         bytes.extend_from_slice(&[0, 0, 0]);
 
+        bytes[base] = self.type_byte();
         match self {
-            NetMessage::Connect(msg) => {
-                bytes[base] = NetType::Connect.value() as u8;
-                msg.write_to_vec(bytes).map_err(KCPError::Protobuf)?;
-            }
-            NetMessage::Accept(msg) => {
-                bytes[base] = NetType::Accept.value() as u8;
-                msg.write_to_vec(bytes).map_err(KCPError::Protobuf)?;
-            }
-            NetMessage::State(msg) => {
-                bytes[base] = NetType::State.value() as u8;
-                msg.write_to_vec(bytes).map_err(KCPError::Protobuf)?;
-            }
-            NetMessage::Start(msg) => {
-                bytes[base] = NetType::Start.value() as u8;
-                msg.write_to_vec(bytes).map_err(KCPError::Protobuf)?;
+            NetMessage::Connect(msg) => msg.encode(bytes),
+            NetMessage::Accept(msg) => msg.encode(bytes),
+            NetMessage::State(msg) => msg.encode(bytes),
+            NetMessage::Start(msg) => msg.encode(bytes),
+            NetMessage::Finish(msg) => msg.encode(bytes),
+            NetMessage::Command(msg) => msg.encode(bytes),
+            NetMessage::Hash(msg) => msg.encode(bytes),
+            NetMessage::Challenge(msg) => msg.encode(bytes),
+            NetMessage::Pause(msg) => msg.encode(bytes),
+            NetMessage::Resume(msg) => msg.encode(bytes),
+            NetMessage::Application(typ, _) if *typ < APPLICATION_TYPES => {
+                return Err(KCPError::UnexpectedPacket.into());
             }
-            NetMessage::Finish(msg) => {
-                bytes[base] = NetType::Finish.value() as u8;
-                msg.write_to_vec(bytes).map_err(KCPError::Protobuf)?;
+            NetMessage::Application(_, payload) => {
+                bytes.extend_from_slice(payload);
+                Ok(())
             }
-            NetMessage::Command(msg) => {
-                bytes[base] = NetType::Command.value() as u8;
-                msg.write_to_vec(bytes).map_err(KCPError::Protobuf)?;
-            }
-            NetMessage::Hash(msg) => {
-                bytes[base] = NetType::Hash.value() as u8;
-                msg.write_to_vec(bytes).map_err(KCPError::Protobuf)?;
-            }
-        };
+        }
+        .map_err(|err| KCPError::Protobuf(err.into()))?;
 
         let offset = bytes.len() - base;
         if offset > KCP_MAX_PACKET {
@@ -120,45 +490,401 @@ impl NetMessage {
 
         return Ok(offset);
     }
+
+    // encode() for buffers that may already hold messages, in debug builds
+    // those must be whole and this one must follow them rather than end up
+    // in a command packet's tail, returns where it went
+    #[context("NetMessage::encode_framed()")]
+    pub fn encode_framed(&self, bytes: &mut Vec<u8>) -> Result<Range<usize>> {
+        #[cfg(debug_assertions)]
+        let prior = NetMessage::validate_stream(bytes);
+        debug_assert!(prior.is_ok(), "misframed buffer {:?}", bytes);
+
+        let base = bytes.len();
+        let len = self.encode(bytes)?;
+        #[cfg(debug_assertions)]
+        debug_assert_eq!(
+            NetMessage::validate_stream(bytes).ok(),
+            prior.ok().map(|count| count + 1),
+            "appended after a command packet"
+        );
+        return Ok(base..(base + len));
+    }
+
+    // the number of whole messages in `bytes` back to back, a command
+    // packet's tail runs to the end so it can only be the last
+    #[context("NetMessage::validate_stream()")]
+    pub fn validate_stream(bytes: &[u8]) -> Result<usize> {
+        let (mut offset, mut count) = (0, 0);
+        while offset < bytes.len() {
+            let rest = &bytes[offset..];
+            if rest.len() < KCP_MIN_PACKET {
+                return Err(KCPError::Misframed.into());
+            }
+            let typ = rest[0];
+            if typ < APPLICATION_TYPES && MessageKind::of(typ).is_none() {
+                return Err(KCPError::Misframed.into());
+            }
+            let end = KCP_MIN_PACKET + BigEndian::read_u16(&rest[1..]) as usize;
+            if end > rest.len() || end > KCP_MAX_PACKET {
+                return Err(KCPError::Misframed.into());
+            }
+            count += 1;
+            if typ == NetType::Command as u8 {
+                if rest.len() > KCP_MAX_PACKET {
+                    return Err(KCPError::Misframed.into());
+                }
+                return Ok(count);
+            }
+            offset += end;
+        }
+        return Ok(count);
+    }
+}
+
+// Datagrams on the game socket: kcp segments, or side-channel payloads that
+// start with the reserved UNRELIABLE_CONV followed by the sender's conv.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Datagram<'t> {
+    KCP(&'t [u8]),
+    Unreliable(u32, &'t [u8]),
+}
+
+impl<'t> Datagram<'t> {
+    // a malformed side-channel datagram is an error, never a kcp segment
+    #[context("Datagram::demux()")]
+    pub fn demux(bytes: &'t [u8]) -> Result<Datagram<'t>> {
+        if bytes.len() < 4 || LittleEndian::read_u32(bytes) != UNRELIABLE_CONV {
+            return Ok(Datagram::KCP(bytes));
+        }
+        if bytes.len() < UNRELIABLE_HEADER {
+            return Err(KCPError::PacketTooShort.into());
+        }
+        if bytes.len() - UNRELIABLE_HEADER > UNRELIABLE_MAX_PAYLOAD {
+            return Err(KCPError::PacketTooLong.into());
+        }
+        let conv = LittleEndian::read_u32(&bytes[4..]);
+        if conv == UNRELIABLE_CONV {
+            return Err(KCPError::PacketBroken.into());
+        }
+        return Ok(Datagram::Unreliable(conv, &bytes[UNRELIABLE_HEADER..]));
+    }
+
+    #[context("Datagram::encode_unreliable()")]
+    pub fn encode_unreliable(conv: u32, payload: &[u8], bytes: &mut Vec<u8>) -> Result<usize> {
+        if payload.len() > UNRELIABLE_MAX_PAYLOAD {
+            return Err(KCPError::MessageTooLong.into());
+        }
+        let base = bytes.len();
+        bytes.resize(base + UNRELIABLE_HEADER, 0);
+        LittleEndian::write_u32(&mut bytes[base..], UNRELIABLE_CONV);
+        LittleEndian::write_u32(&mut bytes[(base + 4)..], conv);
+        bytes.extend_from_slice(payload);
+        return Ok(bytes.len() - base);
+    }
 }
 
+// the newest layout, older ones are shims converting from and to it
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Command {
     Aaa(i32, i32),
+    // the u8 came with CommandVersion::V2, 0 from older peers, compiled out
+    // with no-float-commands, whose peers fail to decode it
+    #[cfg(not(feature = "no-float-commands"))]
+    Bbb(f32, f32, f32, u8),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum CommandV1 {
+    Aaa(i32, i32),
+    #[cfg(not(feature = "no-float-commands"))]
     Bbb(f32, f32, f32),
 }
 
+impl From<CommandV1> for Command {
+    fn from(command: CommandV1) -> Command {
+        return match command {
+            CommandV1::Aaa(a, b) => Command::Aaa(a, b),
+            #[cfg(not(feature = "no-float-commands"))]
+            CommandV1::Bbb(x, y, z) => Command::Bbb(x, y, z, 0),
+        };
+    }
+}
+
+// what V1 peers can take, the newer fields are dropped
+impl From<&Command> for CommandV1 {
+    fn from(command: &Command) -> CommandV1 {
+        return match command {
+            Command::Aaa(a, b) => CommandV1::Aaa(*a, *b),
+            #[cfg(not(feature = "no-float-commands"))]
+            Command::Bbb(x, y, z, _) => CommandV1::Bbb(*x, *y, *z),
+        };
+    }
+}
+
+// Layouts of the command tail. Each newer one has a capability, the newest
+// the client advertises and the server enabled is used for both directions,
+// V1 with a server enabling none of them. A new layout becomes Command, the
+// one it replaces moves to a CommandVN shim with its From conversions, and
+// gets its arms in write() and read().
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum CommandVersion {
+    V1,
+    V2,
+}
+
+impl CommandVersion {
+    pub const LATEST: CommandVersion = CommandVersion::V2;
+    pub const ALL: [CommandVersion; 2] = [CommandVersion::V1, CommandVersion::V2];
+
+    // V1 needs none
+    pub fn capability(self) -> Capabilities {
+        return match self {
+            CommandVersion::V1 => Capabilities::default(),
+            CommandVersion::V2 => Capabilities::COMMANDS_V2,
+        };
+    }
+
+    // to advertise every layout up to `max`
+    pub fn supported(max: CommandVersion) -> Capabilities {
+        let mut supported = Capabilities::default();
+        for version in CommandVersion::ALL
+            .iter()
+            .filter(|version| **version <= max)
+        {
+            supported.insert(version.capability());
+        }
+        return supported;
+    }
+
+    // the newest layout `enabled`
+    pub fn negotiate(enabled: Capabilities) -> CommandVersion {
+        return CommandVersion::ALL
+            .iter()
+            .rev()
+            .copied()
+            .find(|version| enabled.contains(version.capability()))
+            .unwrap_or(CommandVersion::V1);
+    }
+
+    // the commands of a tail in this layout, `tail` is advanced past them
+    pub fn read_commands(self, tail: &mut &[u8]) -> Result<Vec<Command>, bincode::Error> {
+        let mut commands = Vec::new();
+        let visiter = CommandsVisitor {
+            version: self,
+            frame: 0,
+            conv: 0,
+            commands: &mut commands,
+        };
+        DefaultOptions::default()
+            .with_fixint_encoding()
+            .allow_trailing_bytes()
+            .deserialize_from_seed(visiter, tail)?;
+        return Ok(commands
+            .into_iter()
+            .map(|command| command.command)
+            .collect());
+    }
+
+    fn write<S: SerializeSeq>(self, seq: &mut S, command: &Command) -> Result<(), S::Error> {
+        return match self {
+            CommandVersion::V1 => seq.serialize_element(&CommandV1::from(command)),
+            CommandVersion::V2 => seq.serialize_element(command),
+        };
+    }
+
+    fn read<'de, S: SeqAccess<'de>>(self, seq: &mut S) -> Result<Option<Command>, S::Error> {
+        return match self {
+            CommandVersion::V1 => Ok(seq.next_element::<CommandV1>()?.map(Command::from)),
+            CommandVersion::V2 => seq.next_element::<Command>(),
+        };
+    }
+}
+
+// serializes like the Vec of the version's own type
+struct VersionedCommands<'t> {
+    version: CommandVersion,
+    commands: &'t [Command],
+}
+
+impl<'t> Serialize for VersionedCommands<'t> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(Some(self.commands.len()))?;
+        for command in self.commands {
+            self.version.write(&mut seq, command)?;
+        }
+        return seq.end();
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct CommandEx {
     pub conv: u32,
     pub frame: u32,
     pub command: Command,
+    // of its batch
+    pub stamps: Option<CommandStamps>,
+}
+
+// ms since the worker's start(), with WorkerConfig::timestamps only
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CommandStamps {
+    // the packet was decoded
+    pub received_at: u64,
+    // the game drained it, 0 until then
+    pub delivered_at: u64,
+}
+
+impl fmt::Display for CommandEx {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(
+            f,
+            "conv={} frame={} {:?}",
+            self.conv, self.frame, self.command
+        );
+    }
+}
+
+// per-frame containers, a frame rarely carries more than a few commands
+pub type Commands = SmallVec<[Command; COMMANDS_INLINE]>;
+pub type CommandExs = SmallVec<[CommandEx; COMMANDS_INLINE]>;
+
+// the commands of one packet, conv and frame are stored once like on the wire
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommandBatch {
+    pub conv: u32,
+    pub frame: u32,
+    pub commands: Commands,
+    pub stamps: Option<CommandStamps>,
+}
+
+impl fmt::Display for CommandBatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(
+            f,
+            "conv={} frame={} commands={}",
+            self.conv,
+            self.frame,
+            self.commands.len()
+        );
+    }
+}
+
+impl CommandBatch {
+    pub fn new(conv: u32, frame: u32) -> CommandBatch {
+        return CommandBatch {
+            conv,
+            frame,
+            commands: Commands::new(),
+            stamps: None,
+        };
+    }
+
+    pub fn len(&self) -> usize {
+        return self.commands.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.commands.is_empty();
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = CommandEx> + '_ {
+        return self.commands.iter().map(move |command| CommandEx {
+            conv: self.conv,
+            frame: self.frame,
+            command: command.clone(),
+            stamps: self.stamps,
+        });
+    }
+
+    // for callers still working on CommandEx
+    pub fn flatten(batches: &[CommandBatch]) -> impl Iterator<Item = CommandEx> + '_ {
+        return batches.iter().flat_map(CommandBatch::iter);
+    }
+}
+
+impl Extend<CommandEx> for CommandBatch {
+    fn extend<I: IntoIterator<Item = CommandEx>>(&mut self, iter: I) {
+        self.commands
+            .extend(iter.into_iter().map(|command| command.command));
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct CommandEncoder {
     net_command: NetMessage,
     net_hash: NetMessage,
-    commands: Vec<Command>,
+    max_hash: usize,
+    commands: Commands,
     hash_bytes: Vec<u8>,
     command_bytes: Vec<u8>,
+    version: CommandVersion,
+    pool: SharedPool,
 }
 
 impl CommandEncoder {
     pub fn new(cap: usize) -> CommandEncoder {
+        return CommandEncoder::with_pool(cap, None);
+    }
+
+    // the scratch buffers are held until the encoder is dropped, spilled
+    // commands go back to the pool after every encode()
+    pub fn with_pool(cap: usize, pool: SharedPool) -> CommandEncoder {
+        // every buffer is sized for the largest packet up front, so encoding
+        // never reallocates once the encoder exists
+        let mut net_hash = NetHash::default();
+        net_hash.hash = pool::acquire_bytes(&pool, HASH_CAP);
         return CommandEncoder {
             net_command: NetMessage::Command(NetCommand::default()),
-            net_hash: NetMessage::Hash(NetHash::default()),
-            commands: Vec::with_capacity(cap),
-            hash_bytes: Vec::with_capacity(HASH_CAP * 2),
-            command_bytes: Vec::with_capacity(KCP_MAX_PACKET),
+            net_hash: NetMessage::Hash(net_hash),
+            max_hash: HASH_CAP,
+            commands: Commands::with_capacity(cap),
+            hash_bytes: pool::acquire_bytes(&pool, KCP_MAX_PACKET),
+            command_bytes: pool::acquire_bytes(&pool, KCP_MAX_PACKET),
+            version: CommandVersion::V1,
+            pool,
+        };
+    }
+
+    // the layout of the next encode()
+    pub fn set_version(&mut self, version: CommandVersion) {
+        self.version = version;
+    }
+
+    // stamped into both messages, the server overwrites it on relay
+    pub fn set_conv(&mut self, conv: u32) {
+        match (&mut self.net_command, &mut self.net_hash) {
+            (NetMessage::Command(cmd), NetMessage::Hash(hash)) => {
+                cmd.conv = conv;
+                hash.conv = conv;
+            }
+            _ => unreachable!(),
         };
     }
 
-    pub fn commands(&mut self) -> &mut Vec<Command> {
+    pub fn version(&self) -> CommandVersion {
+        return self.version;
+    }
+
+    pub fn with_max_hash(mut self, max_hash: usize) -> CommandEncoder {
+        let hash = self.hash();
+        hash.reserve(max_hash.saturating_sub(hash.capacity()));
+        self.max_hash = max_hash;
+        return self;
+    }
+
+    pub fn commands(&mut self) -> &mut Commands {
         return &mut self.commands;
     }
 
+    // room for `len` commands before they are added, from the pool if they
+    // don't fit inline
+    pub fn reserve_commands(&mut self, len: usize) {
+        if self.commands.is_empty() && len > self.commands.capacity() {
+            self.commands = pool::acquire_commands(&self.pool, len);
+        }
+    }
+
     pub fn hash(&mut self) -> &mut Vec<u8> {
         return match &mut self.net_hash {
             NetMessage::Hash(hash) => &mut hash.hash,
@@ -166,7 +892,13 @@ impl CommandEncoder {
         };
     }
 
-    pub fn buffers(&mut self) -> (&mut Vec<Command>, &mut Vec<u8>) {
+    pub fn hash_from(&mut self, hasher: &FrameHasher) {
+        let hash = self.hash();
+        hash.clear();
+        hasher.finish_into(hash);
+    }
+
+    pub fn buffers(&mut self) -> (&mut Commands, &mut Vec<u8>) {
         return match &mut self.net_hash {
             NetMessage::Hash(hash) => (&mut self.commands, &mut hash.hash),
             _ => unreachable!(),
@@ -176,6 +908,9 @@ impl CommandEncoder {
     #[context("CommandEncoder::encode()")]
     pub fn encode(&mut self, frame: u32) -> Result<()> {
         match &mut self.net_hash {
+            NetMessage::Hash(hash) if hash.hash.len() > self.max_hash => {
+                return Err(KCPError::MessageTooLong.into());
+            }
             NetMessage::Hash(hash) => hash.frame = frame,
             _ => unreachable!(),
         };
@@ -193,11 +928,17 @@ impl CommandEncoder {
 
         DefaultOptions::default()
             .with_fixint_encoding()
-            .serialize_into(&mut self.command_bytes, &self.commands)
+            .serialize_into(
+                &mut self.command_bytes,
+                &VersionedCommands {
+                    version: self.version,
+                    commands: &self.commands,
+                },
+            )
             .map_err(KCPError::Bincode)?;
 
         self.hash().clear();
-        self.commands().clear();
+        pool::release_commands(&self.pool, &mut self.commands);
         return Ok(());
     }
 
@@ -210,39 +951,216 @@ impl CommandEncoder {
     }
 }
 
+// Pads command packets up to the next of `buckets`, KCP_MAX_PACKET past the
+// last one, so their size doesn't tell which commands they carry. Packets
+// that can't hit a bucket exactly are left as they are.
+#[derive(Debug, Clone)]
+pub struct CommandPadder {
+    buckets: Vec<usize>,
+    header: Vec<u8>,
+}
+
+impl CommandPadder {
+    pub fn new(buckets: &[usize]) -> CommandPadder {
+        let mut buckets: Vec<usize> = buckets
+            .iter()
+            .copied()
+            .filter(|bucket| *bucket < KCP_MAX_PACKET)
+            .collect();
+        buckets.push(KCP_MAX_PACKET);
+        buckets.sort_unstable();
+        buckets.dedup();
+        return CommandPadder {
+            buckets,
+            header: Vec::with_capacity(KCP_MAX_PACKET),
+        };
+    }
+
+    // returns the bytes added
+    #[context("CommandPadder::pad()")]
+    pub fn pad(&mut self, packet: &mut Vec<u8>) -> Result<usize> {
+        let (mut command, tail) = CommandDecoder::split(packet)?;
+        let (len, offset) = (packet.len(), packet.len() - tail.len());
+        if command.padding != 0 {
+            return Ok(0);
+        }
+        // the tag and the fixed32
+        let field = 1 + 4;
+        let bucket = match self
+            .buckets
+            .iter()
+            .find(|bucket| **bucket == len || **bucket > len + field)
+        {
+            Some(bucket) if *bucket > len => *bucket,
+            _ => return Ok(0),
+        };
+
+        command.padding = (bucket - len - field) as u32;
+        self.header.clear();
+        NetMessage::Command(command).encode(&mut self.header)?;
+        packet.resize(bucket, 0);
+        packet.copy_within(offset..len, self.header.len());
+        packet[..self.header.len()].copy_from_slice(&self.header);
+        return Ok(bucket - len);
+    }
+}
+
 pub struct CommandDecoder {
-    commands: Vec<CommandEx>,
+    commands: CommandExs,
+    frame: u32,
+    conv: u32,
+    version: CommandVersion,
+    // batches too large to be inline are taken from it
+    pool: SharedPool,
 }
 
 impl CommandDecoder {
     pub fn new(cap: usize) -> CommandDecoder {
         return CommandDecoder {
-            commands: Vec::with_capacity(cap),
+            commands: CommandExs::with_capacity(cap),
+            frame: 0,
+            conv: 0,
+            version: CommandVersion::V1,
+            pool: None,
         };
     }
 
+    pub fn with_pool(mut self, pool: SharedPool) -> CommandDecoder {
+        self.pool = pool;
+        return self;
+    }
+
+    // the layout tails are read in from now on
+    pub fn set_version(&mut self, version: CommandVersion) {
+        self.version = version;
+    }
+
+    pub fn version(&self) -> CommandVersion {
+        return self.version;
+    }
+
     #[context("CommandDecoder::decode()")]
     pub fn decode(&mut self, bytes: &[u8]) -> Result<()> {
+        self.commands.clear();
+        if self.commands.spilled() {
+            self.commands.shrink_to_fit();
+        }
+        let (frame, conv) = Self::decode_impl(self.version, bytes, &mut self.commands)?;
+        self.frame = frame;
+        self.conv = conv;
+        return Ok(());
+    }
+
+    // appends to `commands`, which is left untouched on error
+    #[context("CommandDecoder::decode_into()")]
+    pub fn decode_into(&mut self, bytes: &[u8], commands: &mut Vec<CommandEx>) -> Result<()> {
+        let len = commands.len();
+        match Self::decode_impl(self.version, bytes, commands) {
+            Ok((frame, conv)) => {
+                self.frame = frame;
+                self.conv = conv;
+            }
+            Err(err) => {
+                commands.truncate(len);
+                return Err(err);
+            }
+        };
+        return Ok(());
+    }
+
+    // appends the packet as one batch, packets without commands append
+    // nothing, `batches` is left untouched on error
+    #[context("CommandDecoder::decode_batch_into()")]
+    pub fn decode_batch_into(
+        &mut self,
+        bytes: &[u8],
+        batches: &mut Vec<CommandBatch>,
+    ) -> Result<()> {
+        let mut batch = CommandBatch::new(0, 0);
+        if self.pool.is_some() {
+            batch.commands = pool::acquire_commands(&self.pool, Self::count(bytes));
+        }
+        let (frame, conv) = match Self::decode_impl(self.version, bytes, &mut batch) {
+            Ok(decoded) => decoded,
+            Err(err) => {
+                pool::release_commands(&self.pool, &mut batch.commands);
+                return Err(err);
+            }
+        };
+        self.frame = frame;
+        self.conv = conv;
+        if !batch.is_empty() {
+            batch.frame = frame;
+            batch.conv = conv;
+            batches.push(batch);
+        }
+        return Ok(());
+    }
+
+    // the length the tail declares, 0 when it can't be read, capped at
+    // COMMANDS_CAP as it isn't checked yet
+    fn count(bytes: &[u8]) -> usize {
+        return match Self::split(bytes) {
+            Ok((command, tail)) if !command.compressed && tail.len() >= 8 => {
+                LittleEndian::read_u64(tail).min(COMMANDS_CAP as u64) as usize
+            }
+            _ => 0,
+        };
+    }
+
+    // the header and tail of a command packet, without the padding
+    #[context("CommandDecoder::split()")]
+    pub fn split(bytes: &[u8]) -> Result<(NetCommand, &[u8])> {
         let (command, offset) = match NetMessage::decode(bytes)? {
             (NetMessage::Command(command), offset) => (command, offset),
             _ => return Err(KCPError::PacketBroken.into()),
         };
+        let end = match bytes.len().checked_sub(command.padding as usize) {
+            Some(end) if end >= offset => end,
+            _ => return Err(KCPError::PacketBroken.into()),
+        };
+        if bytes[end..].iter().any(|byte| *byte != 0) {
+            return Err(KCPError::PacketBroken.into());
+        }
+        return Ok((command, &bytes[offset..end]));
+    }
 
-        // size was checked in NetMessage::decode()
-        let size = BigEndian::read_u16(&bytes[1..]) as usize;
+    // returns the packet's frame and conv, also set for packets without
+    // commands
+    fn decode_impl<C: Extend<CommandEx>>(
+        version: CommandVersion,
+        bytes: &[u8],
+        commands: &mut C,
+    ) -> Result<(u32, u32)> {
+        let (command, tail) = Self::split(bytes)?;
+        // unpacked by a CommandPacker first
+        if command.compressed {
+            return Err(KCPError::PacketBroken.into());
+        }
 
-        self.commands.clear();
         let visiter = CommandsVisitor {
+            version,
             frame: command.frame,
             conv: command.conv,
-            commands: &mut self.commands,
+            commands,
         };
         DefaultOptions::default()
             .with_fixint_encoding()
-            .deserialize_seed(visiter, &bytes[offset..])
+            .reject_trailing_bytes()
+            .deserialize_seed(visiter, tail)
             .map_err(KCPError::Bincode)?;
 
-        return Ok(());
+        return Ok((command.frame, command.conv));
+    }
+
+    // frame of the last successfully decoded packet
+    pub fn frame(&self) -> u32 {
+        return self.frame;
+    }
+
+    // conv of the last successfully decoded packet
+    pub fn conv(&self) -> u32 {
+        return self.conv;
     }
 
     pub fn len(&self) -> usize {
@@ -258,13 +1176,14 @@ impl CommandDecoder {
     }
 }
 
-struct CommandsVisitor<'t> {
+struct CommandsVisitor<'t, C> {
+    version: CommandVersion,
     frame: u32,
     conv: u32,
-    commands: &'t mut Vec<CommandEx>,
+    commands: &'t mut C,
 }
 
-impl<'de, 't> DeserializeSeed<'de> for CommandsVisitor<'t> {
+impl<'de, 't, C: Extend<CommandEx>> DeserializeSeed<'de> for CommandsVisitor<'t, C> {
     type Value = ();
 
     fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
@@ -272,7 +1191,7 @@ impl<'de, 't> DeserializeSeed<'de> for CommandsVisitor<'t> {
     }
 }
 
-impl<'de, 't> Visitor<'de> for CommandsVisitor<'t> {
+impl<'de, 't, C: Extend<CommandEx>> Visitor<'de> for CommandsVisitor<'t, C> {
     type Value = ();
 
     fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
@@ -280,12 +1199,13 @@ impl<'de, 't> Visitor<'de> for CommandsVisitor<'t> {
     }
 
     fn visit_seq<S: SeqAccess<'de>>(self, mut seq: S) -> Result<Self::Value, S::Error> {
-        while let Some(command) = seq.next_element::<Command>()? {
-            self.commands.push(CommandEx {
+        while let Some(command) = self.version.read(&mut seq)? {
+            self.commands.extend(Some(CommandEx {
                 conv: self.conv,
                 frame: self.frame,
                 command,
-            });
+                stamps: None,
+            }));
         }
         return Ok(());
     }
@@ -294,6 +1214,7 @@ impl<'de, 't> Visitor<'de> for CommandsVisitor<'t> {
 #[cfg(test)]
 mod test {
     use super::*;
+    use std::convert::TryFrom;
 
     #[test]
     fn test_message_encode() {
@@ -340,9 +1261,12 @@ mod test {
         hash.hash.extend_from_slice(&[9, 9, 9, 9, 9]);
         bytes.clear();
         NetMessage::Hash(hash.clone()).encode(&mut bytes).unwrap();
-        assert_eq!(hash.compute_size() as usize + 3, bytes.len());
+        assert_eq!(hash.encoded_len() + 3, bytes.len());
         assert_eq!(bytes[0], NetType::Hash as u8);
-        assert_eq!(BigEndian::read_u16(&bytes[1..]) as u32, hash.compute_size());
+        assert_eq!(
+            BigEndian::read_u16(&bytes[1..]) as usize,
+            hash.encoded_len()
+        );
 
         let mut hash = NetHash::default();
         hash.hash = vec![0; KCP_MAX_PACKET + 1];
@@ -401,31 +1325,439 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_message_framing() {
+        let mut bytes = Vec::new();
+        let state = NetMessage::state(7777, NetPlayerState::Running);
+        let range = state.encode_framed(&mut bytes).unwrap();
+        assert_eq!(range, 0..bytes.len());
+        let hash = NetMessage::hash(345, 7777, &[1; 8]);
+        let range = hash.encode_framed(&mut bytes).unwrap();
+        assert_eq!(range.end, bytes.len());
+        assert_eq!(
+            NetMessage::decode(&bytes[range]).unwrap().0,
+            NetMessage::hash(345, 7777, &[1; 8])
+        );
+        assert_eq!(NetMessage::validate_stream(&bytes).unwrap(), 2);
+        NetMessage::command(345, 7777)
+            .encode_framed(&mut bytes)
+            .unwrap();
+        bytes.extend_from_slice(&[1, 2, 3]);
+        assert_eq!(NetMessage::validate_stream(&bytes).unwrap(), 3);
+        assert_eq!(NetMessage::validate_stream(&[]).unwrap(), 0);
+
+        let misframed = |bytes: &[u8]| {
+            let err = NetMessage::validate_stream(bytes).unwrap_err();
+            return matches!(err.downcast_ref::<KCPError>(), Some(KCPError::Misframed));
+        };
+        // a prior message cut short, then one claiming more than it has
+        let mut corrupt = Vec::new();
+        state.encode(&mut corrupt).unwrap();
+        corrupt.pop();
+        hash.encode(&mut corrupt).unwrap();
+        assert!(misframed(&corrupt));
+        let mut corrupt = Vec::new();
+        state.encode(&mut corrupt).unwrap();
+        corrupt[2] += 1;
+        assert!(misframed(&corrupt));
+        assert!(misframed(&[NetType::Hash as u8, 0]));
+        assert!(misframed(&[APPLICATION_TYPES - 1, 0, 0]));
+        assert_eq!(
+            NetMessage::validate_stream(&[APPLICATION_TYPES, 0, 0]).unwrap(),
+            1
+        );
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "misframed buffer")]
+    fn test_message_framing_corrupt_prior() {
+        let mut bytes = Vec::new();
+        NetMessage::hash(345, 7777, &[1; 8])
+            .encode(&mut bytes)
+            .unwrap();
+        // an error path that skipped the clear after a partial write
+        bytes.truncate(bytes.len() - 2);
+        let _ = NetMessage::start().encode_framed(&mut bytes);
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "appended after a command packet")]
+    fn test_message_framing_after_command() {
+        let mut bytes = Vec::new();
+        NetMessage::command(345, 7777).encode(&mut bytes).unwrap();
+        let _ = NetMessage::start().encode_framed(&mut bytes);
+    }
+
+    #[test]
+    fn test_message_limits() {
+        let limits = ProtocolLimits::default();
+        let string = |len: usize| "x".repeat(len);
+        let bytes = |len: usize| vec![7; len];
+        // a message with the field `len` long, its name and limit
+        let fields: Vec<(&str, usize, Box<dyn Fn(usize) -> NetMessage>)> = vec![
+            (
+                "NetConnect.room_id",
+                limits.max_room_id,
+                Box::new(move |len| NetMessage::connect(&string(len), "player", "")),
+            ),
+            (
+                "NetConnect.player_id",
+                limits.max_player_id,
+                Box::new(move |len| NetMessage::connect("room", &string(len), "")),
+            ),
+            (
+                "NetConnect.password",
+                limits.max_password,
+                Box::new(move |len| NetMessage::connect("room", "player", &string(len))),
+            ),
+            (
+                "NetConnect.response",
+                limits.max_response,
+                Box::new(move |len| NetMessage::connect_response("room", "player", &bytes(len))),
+            ),
+            (
+                "NetConnect.resume_token",
+                limits.max_token,
+                Box::new(move |len| NetMessage::connect_resume("room", "player", &bytes(len))),
+            ),
+            (
+                "NetAccept.resume_token",
+                limits.max_token,
+                Box::new(move |len| {
+                    let mut accept = NetAccept::default();
+                    accept.resume_token = bytes(len);
+                    NetMessage::Accept(accept)
+                }),
+            ),
+            (
+                "NetChallenge.nonce",
+                limits.max_nonce,
+                Box::new(move |len| NetMessage::challenge(&bytes(len))),
+            ),
+            (
+                "NetHash.hash",
+                limits.max_hash,
+                Box::new(move |len| NetMessage::hash(1, 6666, &bytes(len))),
+            ),
+        ];
+        for (name, max, build) in fields.iter() {
+            let mut encoded = Vec::new();
+            build(*max).encode(&mut encoded).unwrap();
+            let (msg, _) = NetMessage::decode(&encoded).unwrap();
+            assert_eq!(msg, build(*max), "{}", name);
+
+            encoded.clear();
+            build(*max + 1).encode(&mut encoded).unwrap();
+            let err = NetMessage::decode(&encoded).unwrap_err();
+            assert_eq!(
+                err.downcast::<KCPError>().unwrap().to_string(),
+                format!("field too long: {}", name)
+            );
+
+            // unless the limits are raised
+            let raised = ProtocolLimits {
+                max_room_id: max + 1,
+                max_player_id: max + 1,
+                max_password: max + 1,
+                max_response: max + 1,
+                max_token: max + 1,
+                max_nonce: max + 1,
+                max_hash: max + 1,
+            };
+            NetMessage::decode_with(&encoded, &raised).unwrap();
+        }
+
+        // nothing to hold back in the others
+        let zero = ProtocolLimits {
+            max_room_id: 0,
+            max_player_id: 0,
+            max_password: 0,
+            max_response: 0,
+            max_token: 0,
+            max_nonce: 0,
+            max_hash: 0,
+        };
+        for msg in [
+            NetMessage::state(6666, NetPlayerState::Running),
+            NetMessage::start(),
+            NetMessage::finish(1, NetFinishCause::GameOver),
+            NetMessage::command(1, 6666),
+            NetMessage::pause(1),
+            NetMessage::resume(2),
+            NetMessage::Application(200, vec![7; 512]),
+        ]
+        .iter()
+        {
+            let mut encoded = Vec::new();
+            msg.encode(&mut encoded).unwrap();
+            assert_eq!(&NetMessage::decode_with(&encoded, &zero).unwrap().0, msg);
+        }
+    }
+
+    #[test]
+    fn test_message_kinds() {
+        for typ in 1..APPLICATION_TYPES {
+            match NetType::try_from(typ as i32) {
+                Ok(_) => assert_eq!(MessageKind::of(typ).unwrap().typ as u8, typ),
+                Err(_) => assert!(MessageKind::of(typ).is_none()),
+            };
+        }
+        assert!(MessageKind::of(NetType::Unknown as u8).is_none());
+        assert_eq!(NetMessage::pause(1).category(), MessageCategory::State);
+        assert_eq!(
+            NetMessage::challenge(&[1]).category(),
+            MessageCategory::Handshake
+        );
+
+        let msg = NetMessage::Application(200, b"hello".to_vec());
+        assert_eq!(msg.category(), MessageCategory::Application);
+        let mut bytes = Vec::new();
+        msg.encode(&mut bytes).unwrap();
+        assert_eq!(bytes, [200, 0, 5, b'h', b'e', b'l', b'l', b'o']);
+        assert_eq!(NetMessage::decode(&bytes).unwrap(), (msg, bytes.len()));
+
+        // below the range is ours, known or not
+        assert!(NetMessage::Application(11, Vec::new())
+            .encode(&mut bytes)
+            .is_err());
+        assert!(NetMessage::decode(&[11, 0, 0]).is_err());
+        bytes.push(0);
+        assert!(NetMessage::decode(&bytes).is_err());
+    }
+
+    #[test]
+    fn test_message_display() {
+        let mut command = NetMessage::command(345, 7777);
+        if let NetMessage::Command(msg) = &mut command {
+            msg.padding = 12;
+        }
+        assert_eq!(
+            command.to_string(),
+            "Command frame=345 conv=7777 padding=12"
+        );
+        assert_eq!(format!("{:?}", command), command.to_string());
+        assert_eq!(
+            NetMessage::connect("room", "bot", "secret").to_string(),
+            r#"Connect room_id="room" player_id="bot" password=set"#
+        );
+        assert_eq!(
+            NetMessage::hash(2, 7777, &[0xab; 8]).to_string(),
+            "Hash frame=2 conv=7777 hash=abababababababab"
+        );
+        assert_eq!(NetMessage::start().to_string(), "Start");
+        let application = NetMessage::Application(200, vec![0; 1000]);
+        assert_eq!(
+            application.to_string(),
+            "Application(200) payload=1000 bytes"
+        );
+
+        let mut batch = CommandBatch::new(7777, 3);
+        batch.commands.push(Command::Aaa(1, 2));
+        batch.commands.push(Command::Aaa(3, 4));
+        assert_eq!(batch.to_string(), "conv=7777 frame=3 commands=2");
+        let commands: Vec<_> = batch.iter().map(|command| command.to_string()).collect();
+        assert_eq!(
+            commands,
+            ["conv=7777 frame=3 Aaa(1, 2)", "conv=7777 frame=3 Aaa(3, 4)"]
+        );
+        #[cfg(not(feature = "no-float-commands"))]
+        {
+            batch.commands[1] = Command::Bbb(1.0, 2.0, 3.0, 4);
+            let command = batch.iter().nth(1).unwrap();
+            assert_eq!(
+                command.to_string(),
+                "conv=7777 frame=3 Bbb(1.0, 2.0, 3.0, 4)"
+            );
+        }
+    }
+
     #[test]
     fn test_command_encoder() {
         let mut ce = CommandEncoder::new(0);
+        ce.set_conv(7777);
         ce.commands().push(Command::Aaa(47, 57));
-        ce.commands().push(Command::Bbb(3.0, 3.0, 8.0));
+        #[cfg(not(feature = "no-float-commands"))]
+        ce.commands().push(Command::Bbb(3.0, 3.0, 8.0, 0));
         ce.hash().extend_from_slice(&[8, 7, 8, 6]);
         ce.encode(345).unwrap();
 
         let (msg, offset) = NetMessage::decode(ce.hash_bytes()).unwrap();
         let mut hash = NetHash::default();
+        hash.conv = 7777;
         hash.frame = 345;
         hash.hash = vec![8, 7, 8, 6];
         assert_eq!(msg, NetMessage::Hash(hash));
 
         let (msg, offset) = NetMessage::decode(ce.command_bytes()).unwrap();
         let mut cmd = NetCommand::default();
+        cmd.conv = 7777;
         cmd.frame = 345;
         assert_eq!(msg, NetMessage::Command(cmd));
 
-        let cmds: Vec<Command> = DefaultOptions::default()
+        let cmds: Vec<CommandV1> = DefaultOptions::default()
             .with_fixint_encoding()
             .deserialize(&ce.command_bytes()[offset..])
             .unwrap();
-        assert_eq!(cmds[0], Command::Aaa(47, 57));
-        assert_eq!(cmds[1], Command::Bbb(3.0, 3.0, 8.0));
+        assert_eq!(cmds[0], CommandV1::Aaa(47, 57));
+        #[cfg(not(feature = "no-float-commands"))]
+        assert_eq!(cmds[1], CommandV1::Bbb(3.0, 3.0, 8.0));
+
+        let mut hasher = FrameHasher::new();
+        hasher.update(b"foobar");
+        ce.hash().push(1);
+        ce.hash_from(&hasher);
+        ce.encode(346).unwrap();
+        let (msg, _) = NetMessage::decode(ce.hash_bytes()).unwrap();
+        let mut hash = NetHash::default();
+        hash.conv = 7777;
+        hash.frame = 346;
+        hash.hash = vec![0x85, 0x94, 0x41, 0x71, 0xf7, 0x39, 0x67, 0xe8];
+        assert_eq!(msg, NetMessage::Hash(hash));
+    }
+
+    #[test]
+    fn test_command_padder() {
+        let mut padder = CommandPadder::new(&[256, 64, 128]);
+        let mut cd = CommandDecoder::new(0);
+        let mut ce = CommandEncoder::new(0);
+        let mut sizes = Vec::new();
+        for count in 0..48 {
+            let commands: Vec<Command> = (0..count)
+                .map(|idx| match idx % 2 {
+                    0 => Command::Aaa(idx, -idx),
+                    #[cfg(not(feature = "no-float-commands"))]
+                    _ => Command::Bbb(idx as f32, 0.5, -1.0, 0),
+                    #[cfg(feature = "no-float-commands")]
+                    _ => Command::Aaa(-idx, idx),
+                })
+                .collect();
+            ce.commands().extend(commands.iter().cloned());
+            ce.encode(count as u32 + 1).unwrap();
+            let mut packet = ce.command_bytes().to_vec();
+            let padding = padder.pad(&mut packet).unwrap();
+            assert_eq!(packet.len(), ce.command_bytes().len() + padding);
+            assert!(
+                [64, 128, 256, KCP_MAX_PACKET].contains(&packet.len()),
+                "{} commands in {} bytes",
+                count,
+                packet.len()
+            );
+            sizes.push(packet.len());
+
+            cd.decode(&packet).unwrap();
+            assert_eq!(cd.frame(), count as u32 + 1);
+            let decoded: Vec<Command> = cd.commands().iter().map(|c| c.command.clone()).collect();
+            assert_eq!(decoded, commands);
+            // already padded
+            assert_eq!(padder.pad(&mut packet).unwrap(), 0);
+        }
+        sizes.dedup();
+        assert_eq!(sizes, vec![64, 128, 256, KCP_MAX_PACKET]);
+
+        // the padding has to be zeros within the packet
+        ce.commands().push(Command::Aaa(1, 2));
+        ce.encode(1).unwrap();
+        let mut packet = ce.command_bytes().to_vec();
+        padder.pad(&mut packet).unwrap();
+        let last = packet.len() - 1;
+        packet[last] = 1;
+        assert!(cd.decode(&packet).is_err());
+        packet[last] = 0;
+        packet.truncate(ce.command_bytes().len());
+        assert!(cd.decode(&packet).is_err());
+    }
+
+    #[test]
+    fn test_command_encoder_max_hash() {
+        let mut ce = CommandEncoder::new(0);
+        ce.hash().resize(HASH_CAP, 1);
+        ce.encode(1).unwrap();
+        ce.hash().resize(HASH_CAP + 1, 1);
+        let err = ce.encode(2).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<KCPError>(),
+            Some(KCPError::MessageTooLong)
+        ));
+
+        let mut ce = CommandEncoder::new(0).with_max_hash(8);
+        ce.hash().resize(8, 1);
+        ce.encode(1).unwrap();
+        assert_eq!(
+            NetMessage::decode(ce.hash_bytes()).unwrap().1,
+            ce.hash_bytes().len()
+        );
+        ce.hash().resize(9, 1);
+        assert!(ce.encode(2).is_err());
+    }
+
+    #[test]
+    fn test_datagram_demux() {
+        let mut bytes = Vec::new();
+        Datagram::encode_unreliable(7777, b"ping", &mut bytes).unwrap();
+        assert_eq!(
+            Datagram::demux(&bytes).unwrap(),
+            Datagram::Unreliable(7777, b"ping")
+        );
+
+        let segment = [0x61, 0x1e, 0, 0, 81, 0, 1, 0];
+        assert_eq!(Datagram::demux(&segment).unwrap(), Datagram::KCP(&segment));
+
+        let err = Datagram::demux(&bytes[..6]).unwrap_err();
+        assert_eq!(
+            err.downcast::<KCPError>().unwrap().to_string(),
+            "packet too short"
+        );
+        let mut flood = Vec::new();
+        Datagram::encode_unreliable(7777, &[], &mut flood).unwrap();
+        flood.resize(UNRELIABLE_HEADER + UNRELIABLE_MAX_PAYLOAD + 1, 0);
+        assert!(Datagram::demux(&flood).is_err());
+        assert!(Datagram::encode_unreliable(7777, &flood, &mut bytes).is_err());
+        let forged = [0xff; UNRELIABLE_HEADER + 2];
+        assert!(Datagram::demux(&forged).is_err());
+    }
+
+    #[test]
+    fn test_decode_hardening() {
+        // declared size 0 followed by a protobuf tail
+        let mut bytes = vec![NetType::Hash as u8, 0, 0];
+        let mut hash = NetHash::default();
+        hash.frame = 7;
+        hash.write_to_vec(&mut bytes).unwrap();
+        let err = NetMessage::decode(&bytes).unwrap_err();
+        assert_eq!(
+            err.downcast::<KCPError>().unwrap().to_string(),
+            "packet broken"
+        );
+
+        let mut header = Vec::new();
+        NetMessage::command(1, 2).encode(&mut header).unwrap();
+        let mut cd = CommandDecoder::new(0);
+        let mut commands = vec![CommandEx {
+            conv: 1,
+            frame: 1,
+            command: Command::Aaa(1, 1),
+            stamps: None,
+        }];
+        let prefixes: [&[u8]; 5] = [
+            // huge sequence length with no elements
+            &[0xff; 8],
+            // one element with an unknown variant
+            &[1, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 0xff, 0xff],
+            // one element cut short
+            &[1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1],
+            // a valid empty list followed by garbage
+            &[0, 0, 0, 0, 0, 0, 0, 0, 1],
+            &[],
+        ];
+        for prefix in prefixes {
+            let mut bytes = header.clone();
+            bytes.extend_from_slice(prefix);
+            assert!(cd.decode(&bytes).is_err());
+            assert!(cd.decode_into(&bytes, &mut commands).is_err());
+            assert_eq!(commands.len(), 1);
+        }
     }
 
     #[test]
@@ -438,11 +1770,17 @@ mod test {
 
         let mut cmds = Vec::<Command>::new();
         cmds.push(Command::Aaa(22, 33));
-        cmds.push(Command::Bbb(5.0, 6.0, 7.0));
-        cmds.push(Command::Bbb(9.0, 8.0, 7.0));
+        #[cfg(not(feature = "no-float-commands"))]
+        cmds.extend([
+            Command::Bbb(5.0, 6.0, 7.0, 0),
+            Command::Bbb(9.0, 8.0, 7.0, 0),
+        ]);
+        #[cfg(feature = "no-float-commands")]
+        cmds.extend([Command::Aaa(5, 6), Command::Aaa(9, 8)]);
+        let v1: Vec<CommandV1> = cmds.iter().map(CommandV1::from).collect();
         DefaultOptions::default()
             .with_fixint_encoding()
-            .serialize_into(&mut bytes, &cmds)
+            .serialize_into(&mut bytes, &v1)
             .unwrap();
 
         let mut cd = CommandDecoder::new(0);
@@ -455,6 +1793,7 @@ mod test {
                 conv: 6666,
                 frame: 123,
                 command: Command::Aaa(22, 33),
+                stamps: None,
             }
         );
         assert_eq!(
@@ -462,8 +1801,22 @@ mod test {
             CommandEx {
                 conv: 6666,
                 frame: 123,
-                command: Command::Bbb(9.0, 8.0, 7.0),
+                command: cmds[2].clone(),
+                stamps: None,
             }
         );
+
+        let mut batches = Vec::new();
+        cd.decode_batch_into(&bytes, &mut batches).unwrap();
+        assert_eq!(batches.len(), 1);
+        assert_eq!((batches[0].conv, batches[0].frame), (6666, 123));
+        assert_eq!(batches[0].commands.to_vec(), cmds);
+        let flat: Vec<_> = CommandBatch::flatten(&batches).collect();
+        assert_eq!(flat, cd.commands().to_vec());
+
+        assert!(cd
+            .decode_batch_into(&bytes[..bytes.len() - 1], &mut batches)
+            .is_err());
+        assert_eq!(batches.len(), 1);
     }
 }
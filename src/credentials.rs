@@ -0,0 +1,277 @@
+use crate::base::{ConfigError, CREDENTIAL_MAX_BYTES};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use unicode_normalization::UnicodeNormalization;
+
+// the server's rules, in bytes of the encoded utf-8
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CredentialLimits {
+    pub max_room_id: usize,
+    pub max_player_id: usize,
+    pub max_password: usize,
+    // NFC the ids so the same visible name always hashes the same server
+    // side, the password is sent as given
+    pub normalize: bool,
+}
+
+impl Default for CredentialLimits {
+    fn default() -> CredentialLimits {
+        return CredentialLimits {
+            max_room_id: CREDENTIAL_MAX_BYTES,
+            max_player_id: CREDENTIAL_MAX_BYTES,
+            max_password: CREDENTIAL_MAX_BYTES,
+            normalize: true,
+        };
+    }
+}
+
+// what goes into NetConnect, only built through new() so the connect path
+// never sees values the server would reject
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Credentials {
+    room_id: String,
+    player_id: String,
+    password: String,
+}
+
+impl Credentials {
+    pub fn new(
+        room_id: &str,
+        player_id: &str,
+        password: &str,
+        limits: &CredentialLimits,
+    ) -> Result<Credentials, ConfigError> {
+        let normalize = limits.normalize;
+        return Ok(Credentials {
+            room_id: Self::check("room_id", room_id, limits.max_room_id, normalize)?,
+            player_id: Self::check("player_id", player_id, limits.max_player_id, normalize)?,
+            password: Self::check("password", password, limits.max_password, false)?,
+        });
+    }
+
+    pub fn room_id(&self) -> &str {
+        return &self.room_id;
+    }
+
+    pub fn player_id(&self) -> &str {
+        return &self.player_id;
+    }
+
+    pub fn password(&self) -> &str {
+        return &self.password;
+    }
+
+    pub fn response(&self, nonce: &[u8]) -> Vec<u8> {
+        return challenge_response(&self.password, nonce, &self.room_id, &self.player_id);
+    }
+
+    fn check(
+        field: &'static str,
+        value: &str,
+        limit: usize,
+        normalize: bool,
+    ) -> Result<String, ConfigError> {
+        let value: String = match normalize {
+            true => value.nfc().collect(),
+            false => value.to_string(),
+        };
+        if value.contains('\0') {
+            return Err(ConfigError::InvalidField {
+                field,
+                reason: "contains NUL",
+            });
+        }
+        if value.chars().any(char::is_control) {
+            return Err(ConfigError::InvalidField {
+                field,
+                reason: "contains control characters",
+            });
+        }
+        if value.len() > limit {
+            return Err(ConfigError::TooLong {
+                field,
+                len: value.len(),
+                limit,
+            });
+        }
+        return Ok(value);
+    }
+}
+
+// HMAC-SHA256(password, nonce || room_id || player_id), proves the password
+// to the server without sending it
+pub fn challenge_response(password: &str, nonce: &[u8], room_id: &str, player_id: &str) -> Vec<u8> {
+    let mut mac = match Hmac::<Sha256>::new_from_slice(password.as_bytes()) {
+        Ok(mac) => mac,
+        // hmac takes keys of any length
+        Err(_) => unreachable!(),
+    };
+    mac.update(nonce);
+    mac.update(room_id.as_bytes());
+    mac.update(player_id.as_bytes());
+    return mac.finalize().into_bytes().to_vec();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::codec::NetMessage;
+
+    fn new(room_id: &str, player_id: &str, password: &str) -> Result<Credentials, ConfigError> {
+        return Credentials::new(room_id, player_id, password, &CredentialLimits::default());
+    }
+
+    #[test]
+    fn test_credentials_rules() {
+        let credentials = new("room", "player", "").unwrap();
+        assert_eq!(credentials.room_id(), "room");
+        assert_eq!(credentials.player_id(), "player");
+        assert_eq!(credentials.password(), "");
+
+        let long = "x".repeat(CREDENTIAL_MAX_BYTES + 1);
+        assert_eq!(
+            new(&long, "player", ""),
+            Err(ConfigError::TooLong {
+                field: "room_id",
+                len: CREDENTIAL_MAX_BYTES + 1,
+                limit: CREDENTIAL_MAX_BYTES
+            })
+        );
+        assert!(new("room", &long[1..], &long[1..]).is_ok());
+        assert!(matches!(
+            new("room", "player", &long),
+            Err(ConfigError::TooLong {
+                field: "password",
+                ..
+            })
+        ));
+
+        // the limit is in bytes, not chars
+        let wide = "é".repeat(CREDENTIAL_MAX_BYTES / 2 + 1);
+        assert!(matches!(
+            new("room", &wide, ""),
+            Err(ConfigError::TooLong {
+                field: "player_id",
+                ..
+            })
+        ));
+
+        assert_eq!(
+            new("room", "pla\0yer", ""),
+            Err(ConfigError::InvalidField {
+                field: "player_id",
+                reason: "contains NUL"
+            })
+        );
+        for value in ["a\nb", "a\tb", "\u{1b}[31m", "a\u{7f}", "a\u{85}"] {
+            assert_eq!(
+                new(value, "player", ""),
+                Err(ConfigError::InvalidField {
+                    field: "room_id",
+                    reason: "contains control characters"
+                })
+            );
+        }
+        assert!(matches!(
+            new("room", "player", "pass\r"),
+            Err(ConfigError::InvalidField {
+                field: "password",
+                ..
+            })
+        ));
+
+        let limits = CredentialLimits {
+            max_room_id: 4,
+            ..CredentialLimits::default()
+        };
+        assert!(Credentials::new("room", "player", "", &limits).is_ok());
+        assert!(Credentials::new("rooms", "player", "", &limits).is_err());
+    }
+
+    #[test]
+    fn test_credentials_normalize() {
+        // e + combining acute becomes the precomposed é
+        let credentials = new("cafe\u{301}", "jose\u{301}", "pa\u{301}ss").unwrap();
+        assert_eq!(credentials.room_id(), "caf\u{e9}");
+        assert_eq!(credentials.player_id(), "jos\u{e9}");
+        assert_eq!(credentials.password(), "pa\u{301}ss");
+
+        let limits = CredentialLimits {
+            normalize: false,
+            ..CredentialLimits::default()
+        };
+        let credentials = Credentials::new("cafe\u{301}", "player", "", &limits).unwrap();
+        assert_eq!(credentials.room_id(), "cafe\u{301}");
+
+        // counted after normalizing
+        let decomposed = "e\u{301}".repeat(CREDENTIAL_MAX_BYTES / 2);
+        assert!(new("room", &decomposed, "").is_ok());
+    }
+
+    #[test]
+    fn test_challenge_response() {
+        // rfc 4231 test case 2, the message split across the three parts
+        let response = challenge_response("Jefe", b"what do ya", " want for ", "nothing?");
+        let expected = "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843";
+        let hex: String = response
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        assert_eq!(hex, expected);
+
+        let credentials = new("room", "player", "secret").unwrap();
+        let response = credentials.response(&[1, 2, 3]);
+        assert_eq!(
+            response,
+            challenge_response("secret", &[1, 2, 3], "room", "player")
+        );
+        assert_ne!(response, credentials.response(&[1, 2, 4]));
+        assert_eq!(response.len(), 32);
+    }
+
+    // random valid (and already NFC) inputs reach the server unchanged
+    #[test]
+    fn test_credentials_round_trip() {
+        let pool: Vec<char> = (' '..='~').chain("éüßñ中文字🙂".chars()).collect();
+        let mut seed = 0x2545_f491u32;
+        let mut next = || {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            return seed as usize;
+        };
+        let mut value = |max: usize| {
+            let mut value = String::new();
+            for _ in 0..next() % 40 {
+                let c = pool[next() % pool.len()];
+                if value.len() + c.len_utf8() > max {
+                    break;
+                }
+                value.push(c);
+            }
+            return value;
+        };
+
+        for _ in 0..1000 {
+            let room_id = value(CREDENTIAL_MAX_BYTES);
+            let player_id = value(CREDENTIAL_MAX_BYTES);
+            let password = value(CREDENTIAL_MAX_BYTES);
+            let credentials = new(&room_id, &player_id, &password).unwrap();
+
+            let msg = NetMessage::connect(
+                credentials.room_id(),
+                credentials.player_id(),
+                credentials.password(),
+            );
+            let mut bytes = Vec::new();
+            msg.encode(&mut bytes).unwrap();
+            let connect = match NetMessage::decode(&bytes).unwrap().0 {
+                NetMessage::Connect(connect) => connect,
+                msg => panic!("unexpected {:?}", msg),
+            };
+            assert_eq!(connect.room_id, room_id);
+            assert_eq!(connect.player_id, player_id);
+            assert_eq!(connect.password, password);
+        }
+    }
+}
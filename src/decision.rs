@@ -0,0 +1,236 @@
+use crate::base::{packet_type, IGNORED_TYPES};
+use crate::message::{NetPlayerState, NetType};
+use byteorder::{ByteOrder, LittleEndian};
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+
+// what the worker did in a tick, fixed size so recording allocates nothing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Decision {
+    // counted from the worker's first tick
+    pub tick: u64,
+    // when the tick began
+    pub state: NetPlayerState,
+    pub inputs: u16,
+    // per NetType, before decoding, types beyond it are counted as Unknown
+    pub packets: [u16; IGNORED_TYPES],
+    pub bytes_sent: u32,
+    // the tick ended with DecisionLog::error
+    pub failed: bool,
+}
+
+impl Decision {
+    pub fn packets(&self, kind: NetType) -> u16 {
+        return self.packets.get(kind as usize).copied().unwrap_or(0);
+    }
+}
+
+// the last ticks' Decisions, oldest first, for bug reports of sessions that
+// ended badly, see encode() for what goes into telemetry
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecisionLog {
+    records: VecDeque<Decision>,
+    cap: usize,
+    ticks: u64,
+    sent_total: u64,
+    pub error: Option<String>,
+}
+
+impl DecisionLog {
+    pub fn new(cap: usize) -> DecisionLog {
+        return DecisionLog {
+            records: VecDeque::with_capacity(cap),
+            cap,
+            ticks: 0,
+            sent_total: 0,
+            error: None,
+        };
+    }
+
+    // the rest of the tick fills in the newest record
+    pub fn begin(&mut self, state: NetPlayerState) {
+        if self.records.len() == self.cap {
+            self.records.pop_front();
+        }
+        self.records.push_back(Decision {
+            tick: self.ticks,
+            state,
+            inputs: 0,
+            packets: [0; IGNORED_TYPES],
+            bytes_sent: 0,
+            failed: false,
+        });
+        self.ticks += 1;
+    }
+
+    pub fn packet(&mut self, packet: &[u8]) {
+        if let Some(record) = self.records.back_mut() {
+            let count = &mut record.packets[packet_type(packet)];
+            *count = count.saturating_add(1);
+        }
+    }
+
+    pub fn inputs(&mut self, inputs: usize) {
+        if let Some(record) = self.records.back_mut() {
+            record.inputs = inputs.min(u16::MAX as usize) as u16;
+        }
+    }
+
+    // from the bandwidth meter's running total
+    pub fn sent(&mut self, sent_total: u64) {
+        let bytes = sent_total.saturating_sub(self.sent_total);
+        self.sent_total = sent_total;
+        if let Some(record) = self.records.back_mut() {
+            record.bytes_sent = bytes.min(u32::MAX as u64) as u32;
+        }
+    }
+
+    pub fn fail(&mut self, error: &str) {
+        if let Some(record) = self.records.back_mut() {
+            record.failed = true;
+        }
+        self.error = Some(error.to_string());
+    }
+
+    pub fn records(&self) -> impl Iterator<Item = &Decision> + '_ {
+        return self.records.iter();
+    }
+
+    pub fn len(&self) -> usize {
+        return self.records.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.records.is_empty();
+    }
+
+    // little endian: the first tick (u64), the record count (u16), the error
+    // (u16 length and utf-8), then per record its state (u8), failed (u8),
+    // inputs (u16), bytes_sent (u32), a u16 mask of the NetTypes received
+    // and a u16 count for each, ticks are consecutive
+    pub fn encode(&self, bytes: &mut Vec<u8>) {
+        let mut buf = [0; 8];
+        let first = self
+            .records
+            .front()
+            .map_or(self.ticks, |record| record.tick);
+        LittleEndian::write_u64(&mut buf, first);
+        bytes.extend_from_slice(&buf);
+        LittleEndian::write_u16(&mut buf, self.records.len() as u16);
+        bytes.extend_from_slice(&buf[..2]);
+        let error = self.error.as_deref().unwrap_or("");
+        let mut end = error.len().min(u16::MAX as usize);
+        while !error.is_char_boundary(end) {
+            end -= 1;
+        }
+        let error = &error.as_bytes()[..end];
+        LittleEndian::write_u16(&mut buf, error.len() as u16);
+        bytes.extend_from_slice(&buf[..2]);
+        bytes.extend_from_slice(error);
+        for record in self.records.iter() {
+            bytes.push(record.state as u8);
+            bytes.push(record.failed as u8);
+            LittleEndian::write_u16(&mut buf, record.inputs);
+            LittleEndian::write_u32(&mut buf[2..], record.bytes_sent);
+            bytes.extend_from_slice(&buf[..6]);
+            let mut mask = 0u16;
+            for (idx, count) in record.packets.iter().enumerate() {
+                if *count > 0 {
+                    mask |= 1 << idx;
+                }
+            }
+            LittleEndian::write_u16(&mut buf, mask);
+            bytes.extend_from_slice(&buf[..2]);
+            for count in record.packets.iter().filter(|count| **count > 0) {
+                LittleEndian::write_u16(&mut buf, *count);
+                bytes.extend_from_slice(&buf[..2]);
+            }
+        }
+    }
+
+    // None for anything encode() couldn't have written
+    pub fn decode(bytes: &[u8]) -> Option<DecisionLog> {
+        let mut at = 0;
+        let first = LittleEndian::read_u64(take(bytes, &mut at, 8)?);
+        let len = LittleEndian::read_u16(take(bytes, &mut at, 2)?) as usize;
+        let error_len = LittleEndian::read_u16(take(bytes, &mut at, 2)?) as usize;
+        let error = String::from_utf8(take(bytes, &mut at, error_len)?.to_vec()).ok()?;
+        let mut log = DecisionLog::new(len);
+        log.ticks = first;
+        for _ in 0..len {
+            let head = take(bytes, &mut at, 10)?;
+            let state = NetPlayerState::try_from(head[0] as i32).ok()?;
+            let failed = match head[1] {
+                0 => false,
+                1 => true,
+                _ => return None,
+            };
+            log.begin(state);
+            let record = log.records.back_mut()?;
+            record.failed = failed;
+            record.inputs = LittleEndian::read_u16(&head[2..]);
+            record.bytes_sent = LittleEndian::read_u32(&head[4..]);
+            let mask = LittleEndian::read_u16(&head[8..]);
+            if mask >> IGNORED_TYPES != 0 {
+                return None;
+            }
+            for idx in 0..IGNORED_TYPES {
+                if mask & (1 << idx) != 0 {
+                    record.packets[idx] = LittleEndian::read_u16(take(bytes, &mut at, 2)?);
+                }
+            }
+        }
+        if !error.is_empty() {
+            log.error = Some(error);
+        }
+        if at != bytes.len() {
+            return None;
+        }
+        return Some(log);
+    }
+}
+
+fn take<'a>(bytes: &'a [u8], at: &mut usize, len: usize) -> Option<&'a [u8]> {
+    let head = bytes.get(*at..at.checked_add(len)?)?;
+    *at += len;
+    return Some(head);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_decision_log() {
+        let mut log = DecisionLog::new(3);
+        for tick in 0..5 {
+            log.begin(NetPlayerState::Running);
+            log.inputs(tick);
+            log.packet(&[NetType::Command as u8, 0, 0]);
+            log.packet(&[NetType::Command as u8, 0, 0]);
+            log.packet(&[0xff]);
+            log.sent(100 * (tick as u64 + 1));
+        }
+        log.fail("invalid frame");
+        let ticks: Vec<_> = log.records().map(|record| record.tick).collect();
+        assert_eq!(ticks, vec![2, 3, 4]);
+        let last = log.records().last().unwrap();
+        assert_eq!(last.inputs, 4);
+        assert_eq!(last.bytes_sent, 100);
+        assert_eq!(last.packets(NetType::Command), 2);
+        assert_eq!(last.packets(NetType::Unknown), 1);
+        assert!(last.failed);
+
+        let mut bytes = Vec::new();
+        log.encode(&mut bytes);
+        let decoded = DecisionLog::decode(&bytes).unwrap();
+        assert_eq!(
+            decoded.records().collect::<Vec<_>>(),
+            log.records().collect::<Vec<_>>()
+        );
+        assert_eq!(decoded.error.as_deref(), Some("invalid frame"));
+        assert!(DecisionLog::decode(&bytes[..bytes.len() - 1]).is_none());
+        bytes.push(0);
+        assert!(DecisionLog::decode(&bytes).is_none());
+    }
+}
@@ -0,0 +1,146 @@
+use crate::base::{
+    DEGRADE_HOLD, DEGRADE_MAX_UPSTREAM, DEGRADE_MAX_WAITSND, DEGRADE_RECOVER_PERCENT,
+};
+
+// what a step down the ladder engages, only as far as the server allows
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rung {
+    // frames without commands send only their NetHash, as
+    // WorkerConfig::hash_only
+    SkipEmptyFrames,
+    // only every HASH_CADENCE-th frame carries its hash
+    ReduceHashes,
+    // command tails are packed with WorkerConfig::dictionary, which the
+    // server has to have accepted, not before this rung otherwise
+    Compression,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DegradeConfig {
+    // level n has the first n rungs engaged
+    pub ladder: Vec<Rung>,
+    // bytes per second sent or kcp segments not acked over these step down
+    pub max_upstream: u64,
+    pub max_waitsnd: u32,
+    // % of both the worker has to be under to step back up
+    pub recover_percent: u64,
+    // ms at least between two steps
+    pub hold: u64,
+    // stays at this level, at most the ladder's length
+    pub pinned: Option<usize>,
+}
+
+impl Default for DegradeConfig {
+    fn default() -> DegradeConfig {
+        return DegradeConfig {
+            ladder: vec![Rung::SkipEmptyFrames, Rung::ReduceHashes, Rung::Compression],
+            max_upstream: DEGRADE_MAX_UPSTREAM,
+            max_waitsnd: DEGRADE_MAX_WAITSND,
+            recover_percent: DEGRADE_RECOVER_PERCENT,
+            hold: DEGRADE_HOLD,
+            pinned: None,
+        };
+    }
+}
+
+// One rung per step while congested, back one per step once well under the
+// thresholds, the gap between them and `hold` keep it from oscillating,
+// times are in ms.
+#[derive(Debug)]
+pub struct DegradeController {
+    config: DegradeConfig,
+    level: usize,
+    changed_at: Option<u64>,
+}
+
+impl DegradeController {
+    pub fn new(config: DegradeConfig) -> DegradeController {
+        let level = config.pinned.unwrap_or(0).min(config.ladder.len());
+        return DegradeController {
+            config,
+            level,
+            changed_at: None,
+        };
+    }
+
+    pub fn level(&self) -> usize {
+        return self.level;
+    }
+
+    pub fn has(&self, rung: Rung) -> bool {
+        return self.config.ladder.contains(&rung);
+    }
+
+    pub fn engaged(&self, rung: Rung) -> bool {
+        return self.config.ladder[..self.level].contains(&rung);
+    }
+
+    // the new level when it changed
+    pub fn update(&mut self, upstream: u64, waitsnd: u32, now: u64) -> Option<usize> {
+        if self.config.pinned.is_some() {
+            return None;
+        }
+        if matches!(self.changed_at, Some(at) if now < at + self.config.hold) {
+            return None;
+        }
+        let config = &self.config;
+        let congested = upstream > config.max_upstream || waitsnd > config.max_waitsnd;
+        let recovered = upstream.saturating_mul(100)
+            <= config.max_upstream * config.recover_percent
+            && waitsnd as u64 * 100 <= config.max_waitsnd as u64 * config.recover_percent;
+        if congested && self.level < config.ladder.len() {
+            self.level += 1;
+        } else if recovered && !congested && self.level > 0 {
+            self.level -= 1;
+        } else {
+            return None;
+        }
+        self.changed_at = Some(now);
+        return Some(self.level);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_degrade_controller() {
+        let config = DegradeConfig {
+            max_upstream: 1000,
+            max_waitsnd: 10,
+            hold: 100,
+            ..DegradeConfig::default()
+        };
+        let mut controller = DegradeController::new(config.clone());
+        assert_eq!(controller.update(1000, 10, 0), None);
+        assert_eq!(controller.update(1001, 0, 0), Some(1));
+        assert!(controller.engaged(Rung::SkipEmptyFrames));
+        assert!(!controller.engaged(Rung::ReduceHashes));
+
+        // one step per hold
+        assert_eq!(controller.update(1001, 0, 99), None);
+        assert_eq!(controller.update(0, 11, 100), Some(2));
+        assert_eq!(controller.update(0, 11, 200), Some(3));
+        assert_eq!(controller.update(0, 11, 300), None);
+        assert!(controller.engaged(Rung::Compression));
+
+        // between the thresholds it stays
+        assert_eq!(controller.update(501, 0, 400), None);
+        assert_eq!(controller.update(0, 6, 500), None);
+        assert_eq!(controller.update(500, 5, 600), Some(2));
+        assert_eq!(controller.update(500, 5, 650), None);
+        assert_eq!(controller.update(0, 0, 700), Some(1));
+        assert_eq!(controller.update(0, 0, 800), Some(0));
+        assert_eq!(controller.update(0, 0, 900), None);
+
+        let pinned = DegradeConfig {
+            pinned: Some(5),
+            ..config
+        };
+        let mut controller = DegradeController::new(pinned);
+        assert_eq!(controller.level(), 3);
+        assert_eq!(controller.update(0, 0, 0), None);
+        assert_eq!(controller.update(u64::MAX / 100, 0, 0), None);
+    }
+}
@@ -0,0 +1,156 @@
+use crate::chan::NetEvent;
+use crate::codec::{Command, CommandBatch};
+use bincode::config::{DefaultOptions, Options};
+use std::fmt;
+use xxhash_rust::xxh3::{xxh3_64, Xxh3};
+
+// Hashes everything the chan delivers to the game in a canonical form, two
+// clients of the same match end with the same value. Commands and events
+// are hashed separately, each in the order they were queued, so how often
+// and through which call the game drains doesn't change the result: a
+// state change is placed among the commands by its frame. Started, stats,
+// warnings, mismatches and overflows describe this client only and are left
+// out, as are commands dropped before they were delivered.
+#[derive(Clone)]
+pub struct DeliveryHasher {
+    commands: Xxh3,
+    events: Xxh3,
+    scratch: Vec<u8>,
+}
+
+impl DeliveryHasher {
+    pub fn new() -> DeliveryHasher {
+        return DeliveryHasher {
+            commands: Xxh3::new(),
+            events: Xxh3::new(),
+            scratch: Vec::new(),
+        };
+    }
+
+    pub fn update_batches(&mut self, batches: &[CommandBatch]) {
+        for batch in batches {
+            for command in batch.commands.iter() {
+                self.scratch.clear();
+                self.scratch.extend_from_slice(&batch.frame.to_be_bytes());
+                self.scratch.extend_from_slice(&batch.conv.to_be_bytes());
+                DeliveryHasher::encode(&mut self.scratch, command);
+                self.commands.update(&self.scratch);
+            }
+        }
+    }
+
+    pub fn update_events(&mut self, events: &[NetEvent]) {
+        for event in events {
+            self.scratch.clear();
+            let scratch = &mut self.scratch;
+            match event {
+                NetEvent::State { conv, state, frame } => {
+                    scratch.push(1);
+                    scratch.extend_from_slice(&conv.to_be_bytes());
+                    scratch.extend_from_slice(&(*state as i32).to_be_bytes());
+                    scratch.extend_from_slice(&frame.to_be_bytes());
+                }
+                NetEvent::Paused { frame } => {
+                    scratch.push(2);
+                    scratch.extend_from_slice(&frame.to_be_bytes());
+                }
+                NetEvent::Resumed { frame } => {
+                    scratch.push(3);
+                    scratch.extend_from_slice(&frame.to_be_bytes());
+                }
+                NetEvent::FrameReady {
+                    frame,
+                    commands,
+                    complete,
+                } => {
+                    scratch.push(4);
+                    scratch.extend_from_slice(&frame.to_be_bytes());
+                    scratch.push(*complete as u8);
+                    for (conv, commands) in commands {
+                        scratch.extend_from_slice(&conv.to_be_bytes());
+                        scratch.extend_from_slice(&(commands.len() as u32).to_be_bytes());
+                        for command in commands {
+                            DeliveryHasher::encode(scratch, command);
+                        }
+                    }
+                }
+                _ => continue,
+            };
+            self.events.update(&self.scratch);
+        }
+    }
+
+    pub fn digest(&self) -> u64 {
+        let mut both = [0; 16];
+        both[..8].copy_from_slice(&self.commands.digest().to_be_bytes());
+        both[8..].copy_from_slice(&self.events.digest().to_be_bytes());
+        return xxh3_64(&both);
+    }
+
+    // the wire encoding, fixed size so nothing needs a length prefix
+    fn encode(scratch: &mut Vec<u8>, command: &Command) {
+        DefaultOptions::default()
+            .with_fixint_encoding()
+            .serialize_into(scratch, command)
+            .expect("commands always encode");
+    }
+}
+
+impl fmt::Debug for DeliveryHasher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return write!(f, "DeliveryHasher({:#x})", self.digest());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::chan::NetWarning;
+    use crate::codec::Commands;
+    use crate::message::NetPlayerState;
+
+    fn batch(conv: u32, frame: u32, commands: &[Command]) -> CommandBatch {
+        return CommandBatch {
+            conv,
+            frame,
+            commands: commands.iter().cloned().collect::<Commands>(),
+            stamps: None,
+        };
+    }
+
+    #[test]
+    fn test_delivery_hasher() {
+        let batches = vec![
+            batch(6666, 1, &[Command::Aaa(97, -101)]),
+            batch(8888, 1, &[Command::Aaa(-5, 8), Command::Aaa(1, 2)]),
+        ];
+        let state = NetEvent::State {
+            conv: 8888,
+            state: NetPlayerState::Stopped,
+            frame: 1,
+        };
+
+        let mut whole = DeliveryHasher::new();
+        whole.update_batches(&batches);
+        whole.update_events(&[
+            state.clone(),
+            NetEvent::Warning(NetWarning::DroppedPackets(3)),
+        ]);
+
+        // split differently, interleaved differently, same hash
+        let mut split = DeliveryHasher::new();
+        split.update_events(&[state.clone()]);
+        split.update_batches(&batches[..1]);
+        split.update_batches(&batches[1..]);
+        assert_eq!(split.digest(), whole.digest());
+
+        let mut altered = DeliveryHasher::new();
+        altered.update_batches(&[
+            batches[0].clone(),
+            batch(8888, 1, &[Command::Aaa(-5, 8), Command::Aaa(1, 3)]),
+        ]);
+        altered.update_events(&[state]);
+        assert_ne!(altered.digest(), whole.digest());
+        assert_ne!(DeliveryHasher::new().digest(), whole.digest());
+    }
+}
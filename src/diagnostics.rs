@@ -0,0 +1,514 @@
+use crate::bandwidth::LinkActivity;
+use crate::base::{
+    ConfigError, KCPError, DIAGNOSTICS_DURATION, DIAGNOSTICS_GRACE, DIAGNOSTICS_INTERVAL,
+    DIAGNOSTICS_MTU_PROBES, DIAGNOSTICS_TIMEOUT, KCP_INTERVAL, KCP_MAX_PACKET, KCP_MTU,
+    ONE_WAY_WARNING, UNRELIABLE_HEADER,
+};
+use crate::clock::WallClock;
+use crate::codec::{Datagram, NetMessage};
+use crate::kcp::NetKCP;
+use crate::message::NetFinishCause;
+use anyhow::Result;
+use byteorder::{BigEndian, ByteOrder};
+use fn_error_context::context;
+use std::io::ErrorKind;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// side-channel payloads: kind, seq and for probes padding up to min_mtu
+const PING: u8 = 1;
+const MTU_PROBE: u8 = 2;
+const PROBE_HEADER: usize = 1 + 4;
+
+// times are in ms
+#[derive(Debug, Clone)]
+pub struct DiagnosticsConfig {
+    // for the diagnostic Connect to be accepted
+    pub timeout: u64,
+    // of pinging, late echoes get DIAGNOSTICS_GRACE more
+    pub duration: u64,
+    pub interval: u64,
+    // the datagram size the path has to carry, headers of the side-channel
+    // included but not those of UDP
+    pub min_mtu: usize,
+    // set from another thread to stop the run at the next step
+    pub cancel: Option<Arc<AtomicBool>>,
+}
+
+impl Default for DiagnosticsConfig {
+    fn default() -> DiagnosticsConfig {
+        return DiagnosticsConfig {
+            timeout: DIAGNOSTICS_TIMEOUT,
+            duration: DIAGNOSTICS_DURATION,
+            interval: DIAGNOSTICS_INTERVAL,
+            min_mtu: KCP_MTU,
+            cancel: None,
+        };
+    }
+}
+
+impl DiagnosticsConfig {
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.interval == 0 {
+            return Err(ConfigError::InvalidField {
+                field: "interval",
+                reason: "zero",
+            });
+        }
+        if self.min_mtu < UNRELIABLE_HEADER + PROBE_HEADER || self.min_mtu > KCP_MTU {
+            return Err(ConfigError::InvalidField {
+                field: "min_mtu",
+                reason: "not a side-channel datagram size",
+            });
+        }
+        return Ok(());
+    }
+}
+
+// in the order run() takes them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiagnosticsStep {
+    Config,
+    Resolve,
+    Bind,
+    Handshake,
+    Ping,
+}
+
+// Whatever was measured until the run failed or was cancelled, the steps
+// after that are left None or 0. Times are in ms.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DiagnosticsReport {
+    pub resolved: Option<SocketAddr>,
+    pub local: Option<SocketAddr>,
+    // from the Connect to the Accept
+    pub handshake: Option<u64>,
+    pub pings_sent: u32,
+    pub pings_echoed: u32,
+    // of the echoed pings
+    pub rtt_min: Option<u64>,
+    pub rtt_avg: Option<u64>,
+    pub rtt_max: Option<u64>,
+    pub min_mtu: usize,
+    // a min_mtu sized datagram made it there and back, None when none was
+    // sent
+    pub mtu_ok: Option<bool>,
+    pub cancelled: bool,
+    pub failure: Option<(DiagnosticsStep, String)>,
+}
+
+impl DiagnosticsReport {
+    // of the pings sent, None without any
+    pub fn loss(&self) -> Option<f64> {
+        if self.pings_sent == 0 {
+            return None;
+        }
+        let lost = self.pings_sent - self.pings_echoed;
+        return Some(lost as f64 / self.pings_sent as f64);
+    }
+
+    // every step completed, the loss and the rtt are for the caller to judge
+    pub fn passed(&self) -> bool {
+        return self.failure.is_none()
+            && !self.cancelled
+            && self.handshake.is_some()
+            && self.pings_echoed > 0
+            && self.mtu_ok == Some(true);
+    }
+
+    // one line per step, for players to paste into a support ticket
+    pub fn summary(&self) -> String {
+        let mut lines = Vec::new();
+        if let Some(addr) = self.resolved {
+            lines.push(format!("server: {}", addr));
+        }
+        if let Some(addr) = self.local {
+            lines.push(format!("socket: bound to {}", addr));
+        }
+        if let Some(handshake) = self.handshake {
+            lines.push(format!("handshake: {} ms", handshake));
+        }
+        match (self.rtt_min, self.rtt_avg, self.rtt_max, self.loss()) {
+            (Some(min), Some(avg), Some(max), Some(loss)) => lines.push(format!(
+                "ping: {}/{}/{} ms min/avg/max, {:.1}% of {} lost",
+                min,
+                avg,
+                max,
+                loss * 100.0,
+                self.pings_sent
+            )),
+            (_, _, _, Some(_)) => lines.push(format!("ping: all {} lost", self.pings_sent)),
+            _ => {}
+        };
+        match self.mtu_ok {
+            Some(true) => lines.push(format!("mtu: {} bytes ok", self.min_mtu)),
+            Some(false) => lines.push(format!("mtu: {} bytes lost", self.min_mtu)),
+            None => {}
+        };
+        if self.cancelled {
+            lines.push("cancelled".to_string());
+        }
+        if let Some((step, reason)) = &self.failure {
+            lines.push(format!("failed: {:?}, {}", step, reason));
+        }
+        return lines.join("\n");
+    }
+}
+
+// Checks the path to the server without a game session: resolves `addr`,
+// binds a socket, completes a diagnostic Connect over kcp and pings the
+// server over the side-channel for `duration` ms, with a few min_mtu sized
+// probes among the pings. Side-channel datagrams bypass kcp, so the loss is
+// that of the path and not hidden by retransmits. Blocks for at most
+// timeout + duration + 2 * DIAGNOSTICS_GRACE ms.
+pub fn run(addr: &str, config: DiagnosticsConfig) -> DiagnosticsReport {
+    let mut diagnostics = Diagnostics {
+        report: DiagnosticsReport {
+            min_mtu: config.min_mtu,
+            ..DiagnosticsReport::default()
+        },
+        config,
+        step: DiagnosticsStep::Config,
+        started_at: Instant::now(),
+    };
+    let result = diagnostics.run(addr);
+    let mut report = diagnostics.report;
+    if let Err(err) = result {
+        report.failure = Some((diagnostics.step, err.root_cause().to_string()));
+    }
+    return report;
+}
+
+struct Diagnostics {
+    config: DiagnosticsConfig,
+    report: DiagnosticsReport,
+    step: DiagnosticsStep,
+    // of the kcp clock
+    started_at: Instant,
+}
+
+impl Diagnostics {
+    #[context("Diagnostics::run()")]
+    fn run(&mut self, addr: &str) -> Result<()> {
+        self.config.validate()?;
+
+        self.step = DiagnosticsStep::Resolve;
+        let peer = addr.to_socket_addrs().map_err(KCPError::IO)?.next().ok_or(
+            ConfigError::InvalidField {
+                field: "addr",
+                reason: "no address",
+            },
+        )?;
+        self.report.resolved = Some(peer);
+
+        self.step = DiagnosticsStep::Bind;
+        let any = match peer {
+            SocketAddr::V4(_) => SocketAddr::from(([0; 4], 0)),
+            SocketAddr::V6(_) => SocketAddr::from(([0u16; 8], 0)),
+        };
+        let socket = UdpSocket::bind(any).map_err(KCPError::IO)?;
+        socket.set_nonblocking(true).map_err(KCPError::IO)?;
+        self.report.local = socket.local_addr().ok();
+        if self.cancelled() {
+            return Ok(());
+        }
+
+        self.step = DiagnosticsStep::Handshake;
+        let conv = Diagnostics::conv();
+        let kcp_socket = socket.try_clone().map_err(KCPError::IO)?;
+        let mut kcp = NetKCP::with_socket(kcp_socket, peer, conv)?;
+        if !self.handshake(&mut kcp)? {
+            return Ok(());
+        }
+
+        self.step = DiagnosticsStep::Ping;
+        self.ping(&socket, peer, conv)?;
+        // best effort, the server times the session out otherwise
+        let _ = self.finish(&mut kcp);
+        return Ok(());
+    }
+
+    // false when cancelled
+    #[context("Diagnostics::handshake()")]
+    fn handshake(&mut self, kcp: &mut NetKCP) -> Result<bool> {
+        let mut bytes = Vec::with_capacity(KCP_MAX_PACKET);
+        NetMessage::connect_diagnostic().encode(&mut bytes)?;
+        kcp.send_kcp(&bytes)?;
+        let sent_at = Diagnostics::ms(self.started_at);
+
+        let mut buffer = vec![0; KCP_MAX_PACKET];
+        // the same detection as the worker's
+        let mut link = LinkActivity::default();
+        loop {
+            if self.cancelled() {
+                return Ok(false);
+            }
+            let current = Diagnostics::ms(self.started_at);
+            let bandwidth = kcp.bandwidth(current);
+            link.update(&bandwidth, current);
+            if current - sent_at > self.config.timeout {
+                return match bandwidth.recv_total.datagrams {
+                    0 => Err(KCPError::Unreachable.into()),
+                    _ if link.one_way() >= ONE_WAY_WARNING => {
+                        Err(KCPError::NoInboundTraffic.into())
+                    }
+                    _ => Err(KCPError::AcceptTimeout.into()),
+                };
+            }
+            kcp.update_kcp(current);
+            kcp.update_udp(WallClock::until(KCP_INTERVAL))?;
+            if let Some(len) = kcp.recv_into(&mut buffer)? {
+                match NetMessage::decode(&buffer[..len])?.0 {
+                    NetMessage::Accept(_) => {
+                        self.report.handshake = Some(Diagnostics::ms(self.started_at) - sent_at);
+                        return Ok(true);
+                    }
+                    NetMessage::Finish(finish) => {
+                        return Err(KCPError::RemoteFinished(finish.cause()).into());
+                    }
+                    _ => return Err(KCPError::UnexpectedPacket.into()),
+                };
+            }
+        }
+    }
+
+    // kcp is left alone meanwhile, the socket is read directly
+    #[context("Diagnostics::ping()")]
+    fn ping(&mut self, socket: &UdpSocket, peer: SocketAddr, conv: u32) -> Result<()> {
+        let started_at = Instant::now();
+        // None once echoed
+        let mut pings: Vec<Option<Instant>> = Vec::new();
+        let mut rtts: Vec<u64> = Vec::new();
+        let mut probes = 0;
+        let mut mtu_ok = false;
+        let mut bytes = Vec::with_capacity(KCP_MTU);
+        let mut payload = Vec::with_capacity(KCP_MTU);
+        let mut datagram = vec![0; KCP_MAX_PACKET];
+
+        loop {
+            if self.cancelled() {
+                break;
+            }
+            let elapsed = Diagnostics::ms(started_at);
+            let pinging = elapsed < self.config.duration;
+            let pending = pings.iter().any(Option::is_some);
+            if elapsed >= self.config.duration + DIAGNOSTICS_GRACE || (!pinging && !pending) {
+                break;
+            }
+
+            if pinging && elapsed >= pings.len() as u64 * self.config.interval {
+                if probes < DIAGNOSTICS_MTU_PROBES && !mtu_ok {
+                    payload.clear();
+                    payload.push(MTU_PROBE);
+                    payload.extend_from_slice(&probes.to_be_bytes());
+                    payload.resize(self.config.min_mtu - UNRELIABLE_HEADER, 0);
+                    Diagnostics::send(socket, peer, conv, &payload, &mut bytes)?;
+                    probes += 1;
+                }
+                payload.clear();
+                payload.push(PING);
+                payload.extend_from_slice(&(pings.len() as u32).to_be_bytes());
+                Diagnostics::send(socket, peer, conv, &payload, &mut bytes)?;
+                pings.push(Some(Instant::now()));
+            }
+
+            let len = match socket.recv_from(&mut datagram) {
+                Ok((len, _)) => len,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                    thread::sleep(Duration::from_millis(1));
+                    continue;
+                }
+                Err(err) => return Err(KCPError::IO(err).into()),
+            };
+            let echo = match Datagram::demux(&datagram[..len]) {
+                Ok(Datagram::Unreliable(echoed, echo)) if echoed == conv => echo,
+                // kcp retransmits of the handshake, and strays
+                _ => continue,
+            };
+            if echo.len() < PROBE_HEADER {
+                continue;
+            }
+            let seq = BigEndian::read_u32(&echo[1..]) as usize;
+            match echo[0] {
+                PING => {
+                    if let Some(sent_at) = pings.get_mut(seq).and_then(Option::take) {
+                        rtts.push(sent_at.elapsed().as_millis() as u64);
+                    }
+                }
+                MTU_PROBE => mtu_ok |= echo.len() + UNRELIABLE_HEADER == self.config.min_mtu,
+                _ => {}
+            };
+        }
+
+        self.report.pings_sent = pings.len() as u32;
+        self.report.pings_echoed = rtts.len() as u32;
+        self.report.rtt_min = rtts.iter().copied().min();
+        self.report.rtt_max = rtts.iter().copied().max();
+        if !rtts.is_empty() {
+            self.report.rtt_avg = Some(rtts.iter().sum::<u64>() / rtts.len() as u64);
+        }
+        if probes > 0 {
+            self.report.mtu_ok = Some(mtu_ok);
+        }
+        return Ok(());
+    }
+
+    // waits for the Finish to be acked, at most DIAGNOSTICS_GRACE
+    #[context("Diagnostics::finish()")]
+    fn finish(&mut self, kcp: &mut NetKCP) -> Result<()> {
+        let mut bytes = Vec::with_capacity(KCP_MAX_PACKET);
+        NetMessage::finish(0, NetFinishCause::GameOver).encode(&mut bytes)?;
+        kcp.send_kcp(&bytes)?;
+        let sent_at = Diagnostics::ms(self.started_at);
+        loop {
+            let current = Diagnostics::ms(self.started_at);
+            kcp.update_kcp(current);
+            kcp.flush();
+            if kcp.waitsnd() == 0 || current - sent_at > DIAGNOSTICS_GRACE {
+                return Ok(());
+            }
+            kcp.update_udp(WallClock::until(KCP_INTERVAL))?;
+        }
+    }
+
+    #[context("Diagnostics::send()")]
+    fn send(
+        socket: &UdpSocket,
+        peer: SocketAddr,
+        conv: u32,
+        payload: &[u8],
+        bytes: &mut Vec<u8>,
+    ) -> Result<()> {
+        bytes.clear();
+        Datagram::encode_unreliable(conv, payload, bytes)?;
+        return match socket.send_to(bytes, peer) {
+            Ok(_) => Ok(()),
+            // a full send buffer loses the ping like the path would
+            Err(err) if err.kind() == ErrorKind::WouldBlock => Ok(()),
+            Err(err) => Err(KCPError::IO(err).into()),
+        };
+    }
+
+    fn cancelled(&mut self) -> bool {
+        if let Some(cancel) = &self.config.cancel {
+            self.report.cancelled |= cancel.load(Ordering::Relaxed);
+        }
+        return self.report.cancelled;
+    }
+
+    // the upper half, out of the way of matchmaking and never reserved
+    fn conv() -> u32 {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or(Duration::ZERO)
+            .subsec_nanos();
+        return 0x8000_0000 | (nanos & 0x7fff_fffe);
+    }
+
+    fn ms(since: Instant) -> u64 {
+        return since.elapsed().as_millis() as u64;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mock::{MockAuth, MockServer};
+
+    fn config(duration: u64) -> DiagnosticsConfig {
+        return DiagnosticsConfig {
+            duration,
+            interval: 20,
+            ..DiagnosticsConfig::default()
+        };
+    }
+
+    #[test]
+    fn test_diagnostics_clean() {
+        let server = MockServer::start(1).unwrap();
+        let report = run(&server.addr().to_string(), config(500));
+        assert_eq!(report.failure, None);
+        assert!(report.passed());
+        assert_eq!(report.resolved, Some(server.addr()));
+        assert!(report.local.is_some());
+        assert!(report.handshake.unwrap() < DIAGNOSTICS_TIMEOUT);
+        assert!(report.pings_sent >= 20);
+        assert_eq!(report.pings_echoed, report.pings_sent);
+        assert_eq!(report.loss(), Some(0.0));
+        assert!(report.rtt_min <= report.rtt_avg && report.rtt_avg <= report.rtt_max);
+        assert_eq!(report.mtu_ok, Some(true));
+        assert!(report.summary().contains("0.0% of"));
+
+        let records = server.records();
+        assert_eq!(records.connects.len(), 1);
+        assert!(records.connects[0].1.diagnostic);
+        assert_eq!(records.finishes.len(), 1);
+        assert!(records.unreliable.len() as u32 >= report.pings_sent);
+    }
+
+    #[test]
+    fn test_diagnostics_lossy() {
+        let auth = MockAuth {
+            drop_every: 3,
+            ..MockAuth::default()
+        };
+        let server = MockServer::start_with_auth(1, auth).unwrap();
+        let report = run(&server.addr().to_string(), config(1000));
+        assert_eq!(report.failure, None);
+        // retransmitted by kcp, unlike the pings
+        assert!(report.handshake.is_some());
+        let loss = report.loss().unwrap();
+        assert!(loss > 0.1 && loss < 0.6, "{}", loss);
+        assert!(report.pings_echoed > 0 && report.rtt_max.is_some());
+        assert!(report.mtu_ok.is_some());
+    }
+
+    #[test]
+    fn test_diagnostics_failures() {
+        let report = run("nowhere", config(100));
+        assert_eq!(report.failure.unwrap().0, DiagnosticsStep::Resolve);
+        assert_eq!(report.resolved, None);
+
+        let invalid = DiagnosticsConfig {
+            min_mtu: KCP_MTU + 1,
+            ..config(100)
+        };
+        let report = run("127.0.0.1:1", invalid);
+        assert_eq!(report.failure.unwrap().0, DiagnosticsStep::Config);
+
+        // acks the Connect in kcp, never accepts it
+        let auth = MockAuth {
+            silent: true,
+            ..MockAuth::default()
+        };
+        let server = MockServer::start_with_auth(1, auth).unwrap();
+        let timeout = DiagnosticsConfig {
+            timeout: 300,
+            ..config(100)
+        };
+        let report = run(&server.addr().to_string(), timeout);
+        assert_eq!(
+            report.failure,
+            Some((DiagnosticsStep::Handshake, "accept timeout".to_string()))
+        );
+        assert_eq!(report.handshake, None);
+        assert_eq!(report.loss(), None);
+        assert!(!report.passed());
+        assert!(report.summary().contains("failed: Handshake"));
+
+        let cancel = Arc::new(AtomicBool::new(true));
+        let cancelled = DiagnosticsConfig {
+            cancel: Some(cancel),
+            ..config(100)
+        };
+        let started_at = Instant::now();
+        let report = run(&server.addr().to_string(), cancelled);
+        assert!(report.cancelled && !report.passed());
+        assert_eq!(report.failure, None);
+        assert_eq!(report.handshake, None);
+        assert!(started_at.elapsed() < Duration::from_millis(DIAGNOSTICS_TIMEOUT));
+    }
+}
@@ -0,0 +1,238 @@
+use crate::base::{ConfigError, KCPError, DICTIONARY_THRESHOLD, KCP_MAX_PACKET};
+use crate::codec::{CommandDecoder, NetMessage};
+use crate::hash::FrameHasher;
+use anyhow::Result;
+use fn_error_context::context;
+use std::fmt;
+use std::sync::Arc;
+use zstd::bulk::{Compressor, Decompressor};
+
+// A zstd dictionary trained offline on recorded command streams. Both ends
+// compress with it only after agreeing on its id at connect.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Dictionary {
+    id: u64,
+    bytes: Arc<Vec<u8>>,
+}
+
+impl Dictionary {
+    pub fn new(bytes: Vec<u8>) -> Dictionary {
+        let mut hasher = FrameHasher::new();
+        hasher.update(&bytes);
+        return Dictionary {
+            id: hasher.finish(),
+            bytes: Arc::new(bytes),
+        };
+    }
+
+    pub fn id(&self) -> u64 {
+        return self.id;
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        return &self.bytes;
+    }
+}
+
+impl fmt::Debug for Dictionary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(
+            f,
+            "Dictionary({:016x}, {} bytes)",
+            self.id,
+            self.bytes.len()
+        );
+    }
+}
+
+// Compresses the bincode tail of command packets with a Dictionary and
+// flags it in the NetCommand, `threshold` bytes of tail at least.
+pub struct CommandPacker {
+    compressor: Compressor<'static>,
+    decompressor: Decompressor<'static>,
+    threshold: usize,
+    // KCP_MAX_PACKET and never grown, also caps what a tail inflates to
+    buffer: Vec<u8>,
+}
+
+impl CommandPacker {
+    pub fn new(dictionary: &Dictionary) -> Result<CommandPacker> {
+        return CommandPacker::with_threshold(dictionary, DICTIONARY_THRESHOLD);
+    }
+
+    #[context("CommandPacker::with_threshold()")]
+    pub fn with_threshold(dictionary: &Dictionary, threshold: usize) -> Result<CommandPacker> {
+        let invalid = |_| ConfigError::InvalidField {
+            field: "dictionary",
+            reason: "not a zstd dictionary",
+        };
+        let level = zstd::DEFAULT_COMPRESSION_LEVEL;
+        let mut compressor =
+            Compressor::with_dictionary(level, dictionary.bytes()).map_err(invalid)?;
+        // both ends know the dictionary, no need to repeat its id
+        compressor.include_dictid(false).map_err(invalid)?;
+        compressor.include_checksum(false).map_err(invalid)?;
+        let decompressor = Decompressor::with_dictionary(dictionary.bytes()).map_err(invalid)?;
+        return Ok(CommandPacker {
+            compressor,
+            decompressor,
+            threshold,
+            buffer: Vec::with_capacity(KCP_MAX_PACKET),
+        });
+    }
+
+    // compresses the tail in place when that makes the packet shorter,
+    // returns whether it did
+    #[context("CommandPacker::pack()")]
+    pub fn pack(&mut self, packet: &mut Vec<u8>) -> Result<bool> {
+        let (mut command, tail) = CommandDecoder::split(packet)?;
+        // padding goes last
+        if command.compressed || command.padding != 0 || tail.len() < self.threshold {
+            return Ok(false);
+        }
+        // too small a buffer means it wouldn't have been shorter anyway, the
+        // flag adds 2 bytes to the header
+        match self.compressor.compress_to_buffer(tail, &mut self.buffer) {
+            Ok(len) if len + 2 < tail.len() => {}
+            _ => return Ok(false),
+        };
+
+        command.compressed = true;
+        packet.clear();
+        NetMessage::Command(command).encode(packet)?;
+        packet.extend_from_slice(&self.buffer);
+        return Ok(true);
+    }
+
+    // the inverse of pack(), also drops the padding, a tail inflating past
+    // KCP_MAX_PACKET is rejected before it is allocated, returns whether it
+    // was compressed
+    #[context("CommandPacker::unpack()")]
+    pub fn unpack(&mut self, packet: &mut Vec<u8>) -> Result<bool> {
+        let (mut command, tail) = CommandDecoder::split(packet)?;
+        if !command.compressed {
+            return Ok(false);
+        }
+        self.decompressor
+            .decompress_to_buffer(tail, &mut self.buffer)
+            .map_err(|_| KCPError::PacketBroken)?;
+
+        command.compressed = false;
+        command.padding = 0;
+        packet.clear();
+        NetMessage::Command(command).encode(packet)?;
+        if packet.len() + self.buffer.len() > KCP_MAX_PACKET {
+            return Err(KCPError::PacketTooLong.into());
+        }
+        packet.extend_from_slice(&self.buffer);
+        return Ok(true);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::codec::{Command, CommandDecoder, CommandEncoder};
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn fixture(name: &str) -> Vec<u8> {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("fixtures")
+            .join("dictionary")
+            .join(name);
+        return fs::read(path).unwrap();
+    }
+
+    // command packets recorded from a match, one hex line each
+    fn samples() -> Vec<Vec<u8>> {
+        let text = String::from_utf8(fixture("samples.hex")).unwrap();
+        return text
+            .lines()
+            .map(|line| {
+                let digits: Vec<u8> = line
+                    .chars()
+                    .map(|c| c.to_digit(16).unwrap() as u8)
+                    .collect();
+                return digits
+                    .chunks(2)
+                    .map(|pair| pair[0] << 4 | pair[1])
+                    .collect();
+            })
+            .collect();
+    }
+
+    #[test]
+    fn test_command_packer_samples() {
+        let dictionary = Dictionary::new(fixture("commands.dict"));
+        let mut packer = CommandPacker::new(&dictionary).unwrap();
+        let mut decoder = CommandDecoder::new(0);
+        let (mut plain, mut packed) = (0, 0);
+        for sample in samples() {
+            let mut packet = sample.clone();
+            let compressed = packer.pack(&mut packet).unwrap();
+            plain += sample.len();
+            packed += packet.len();
+            assert_eq!(compressed, packet.len() < sample.len());
+            if compressed {
+                assert!(decoder.decode(&packet).is_err());
+            }
+
+            assert_eq!(packer.unpack(&mut packet).unwrap(), compressed);
+            assert_eq!(packet, sample);
+            // recorded with float commands, builds without them can't decode
+            // the samples
+            #[cfg(not(feature = "no-float-commands"))]
+            decoder.decode(&packet).unwrap();
+        }
+        assert!(packed * 4 < plain * 3, "{} of {} bytes", packed, plain);
+    }
+
+    #[test]
+    fn test_command_packer_threshold() {
+        let dictionary = Dictionary::new(fixture("commands.dict"));
+        let mut packer = CommandPacker::new(&dictionary).unwrap();
+        let mut ce = CommandEncoder::new(0);
+        ce.commands().push(Command::Aaa(97, -101));
+        ce.encode(1).unwrap();
+        let mut packet = ce.command_bytes().to_vec();
+        assert!(!packer.pack(&mut packet).unwrap());
+        assert_eq!(packet, ce.command_bytes());
+        assert!(!packer.unpack(&mut packet).unwrap());
+
+        let mut packer = CommandPacker::with_threshold(&dictionary, 0).unwrap();
+        let mut sample = samples().remove(0);
+        assert!(packer.pack(&mut sample).unwrap());
+        // already compressed
+        assert!(!packer.pack(&mut sample).unwrap());
+
+        let mut state = Vec::new();
+        NetMessage::start().encode(&mut state).unwrap();
+        assert!(packer.pack(&mut state).is_err());
+        assert!(packer.unpack(&mut state).is_err());
+    }
+
+    #[test]
+    fn test_command_packer_bomb() {
+        let dictionary = Dictionary::new(fixture("commands.dict"));
+        let mut packer = CommandPacker::new(&dictionary).unwrap();
+
+        // a tiny tail inflating far past any packet
+        let bomb = vec![0; KCP_MAX_PACKET * 64];
+        let mut compressor = Compressor::with_dictionary(3, dictionary.bytes()).unwrap();
+        let tail = compressor.compress(&bomb).unwrap();
+        assert!(tail.len() < 64);
+        let mut msg = NetMessage::command(1, 7777);
+        if let NetMessage::Command(command) = &mut msg {
+            command.compressed = true;
+        }
+        let mut packet = Vec::new();
+        msg.encode(&mut packet).unwrap();
+        packet.extend_from_slice(&tail);
+        let err = packer.unpack(&mut packet).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<KCPError>(),
+            Some(KCPError::PacketBroken)
+        ));
+    }
+}
@@ -0,0 +1,91 @@
+use crate::base::ESTIMATE_MAX_FRAMES;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FrameEstimate {
+    pub frame: u32,
+    // ms since the newest command packet arrived, the higher the less reliable
+    pub staleness: u64,
+    // extrapolation hit ESTIMATE_MAX_FRAMES, the server is likely paused
+    pub capped: bool,
+}
+
+// Estimates the frame the server is at from the newest frame seen in command
+// packets, times are in ms.
+#[derive(Debug)]
+pub struct FrameEstimator {
+    interval: u64,
+    newest: Option<(u32, u64)>,
+}
+
+impl FrameEstimator {
+    pub fn new(interval: u64) -> FrameEstimator {
+        return FrameEstimator {
+            interval: interval.max(1),
+            newest: None,
+        };
+    }
+
+    pub fn observe(&mut self, frame: u32, now: u64) {
+        match self.newest {
+            Some((newest, _)) if newest >= frame => {}
+            _ => self.newest = Some((frame, now)),
+        };
+    }
+
+    pub fn estimate(&self, now: u64) -> Option<FrameEstimate> {
+        let (frame, arrived_at) = self.newest?;
+        let staleness = now.saturating_sub(arrived_at);
+        let ahead = staleness / self.interval;
+        let capped = ahead >= ESTIMATE_MAX_FRAMES as u64;
+        let ahead = ahead.min(ESTIMATE_MAX_FRAMES as u64) as u32;
+        return Some(FrameEstimate {
+            frame: frame.saturating_add(ahead),
+            staleness,
+            capped,
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_frame_estimator() {
+        let mut estimator = FrameEstimator::new(50);
+        assert_eq!(estimator.estimate(0), None);
+
+        // the server ticks every 50ms, packets arrive in pairs every 100ms
+        for now in (0..2000).step_by(10) {
+            if now % 100 == 0 {
+                let frame = now as u32 / 50;
+                estimator.observe(frame.saturating_sub(1), now);
+                estimator.observe(frame, now);
+            }
+            let truth = now as u32 / 50;
+            let estimate = estimator.estimate(now).unwrap();
+            assert!(estimate.frame <= truth + 1 && estimate.frame + 1 >= truth);
+            assert!(!estimate.capped);
+        }
+
+        // late and duplicate packets never move the estimate back
+        estimator.observe(3, 2000);
+        assert_eq!(estimator.estimate(2000).unwrap().frame, 40);
+
+        // the server pauses, extrapolation stops at the cap
+        let paused = estimator.estimate(2000 + 50 * 100).unwrap();
+        assert_eq!(paused.frame, 38 + ESTIMATE_MAX_FRAMES);
+        assert!(paused.capped);
+        assert_eq!(paused.staleness, 50 * 100 + 100);
+
+        estimator.observe(40, 7100);
+        assert_eq!(
+            estimator.estimate(7120).unwrap(),
+            FrameEstimate {
+                frame: 40,
+                staleness: 20,
+                capped: false,
+            }
+        );
+    }
+}
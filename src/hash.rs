@@ -0,0 +1,293 @@
+use crate::base::{HASH_CAP, HASH_FNV_OFFSET, HASH_FNV_PRIME, HASH_SIZE};
+use crate::codec::Command;
+use byteorder::{BigEndian, ByteOrder};
+use std::collections::VecDeque;
+
+// fnv-1a 64 over big endian values, the server compares the finished hash
+// as 8 big endian bytes. Hash values through the update_* encoders, never
+// struct memory, whose byte order and padding differ per platform; usize
+// has no encoder as its width differs too, hash it as u64
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameHasher {
+    state: u64,
+}
+
+impl FrameHasher {
+    pub fn new() -> FrameHasher {
+        return FrameHasher {
+            state: HASH_FNV_OFFSET,
+        };
+    }
+
+    pub fn reset(&mut self) {
+        self.state = HASH_FNV_OFFSET;
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.state ^= *byte as u64;
+            self.state = self.state.wrapping_mul(HASH_FNV_PRIME);
+        }
+    }
+
+    pub fn update_bool(&mut self, value: bool) {
+        self.update_u8(value as u8);
+    }
+
+    pub fn update_u8(&mut self, value: u8) {
+        self.update(&[value]);
+    }
+
+    pub fn update_u16(&mut self, value: u16) {
+        self.update(&value.to_be_bytes());
+    }
+
+    pub fn update_u32(&mut self, value: u32) {
+        self.update(&value.to_be_bytes());
+    }
+
+    pub fn update_u64(&mut self, value: u64) {
+        self.update(&value.to_be_bytes());
+    }
+
+    // two's complement, the same bytes as the unsigned type
+    pub fn update_i8(&mut self, value: i8) {
+        self.update(&value.to_be_bytes());
+    }
+
+    pub fn update_i16(&mut self, value: i16) {
+        self.update(&value.to_be_bytes());
+    }
+
+    pub fn update_i32(&mut self, value: i32) {
+        self.update(&value.to_be_bytes());
+    }
+
+    pub fn update_i64(&mut self, value: i64) {
+        self.update(&value.to_be_bytes());
+    }
+
+    // -0.0 hashes as 0.0 and every NaN as the canonical quiet NaN
+    pub fn update_f32(&mut self, value: f32) {
+        let bits = if value.is_nan() {
+            0x7fc0_0000
+        } else if value == 0.0 {
+            0
+        } else {
+            value.to_bits()
+        };
+        self.update_u32(bits);
+    }
+
+    // like update_f32(), the canonical quiet NaN is 0x7ff8_0000_0000_0000
+    pub fn update_f64(&mut self, value: f64) {
+        let bits = if value.is_nan() {
+            0x7ff8_0000_0000_0000
+        } else if value == 0.0 {
+            0
+        } else {
+            value.to_bits()
+        };
+        self.update_u64(bits);
+    }
+
+    // the variant's index as a u8, then its fields in order
+    pub fn update_command(&mut self, command: &Command) {
+        match command {
+            Command::Aaa(a, b) => {
+                self.update_u8(0);
+                self.update_i32(*a);
+                self.update_i32(*b);
+            }
+            #[cfg(not(feature = "no-float-commands"))]
+            Command::Bbb(x, y, z, flags) => {
+                self.update_u8(1);
+                self.update_f32(*x);
+                self.update_f32(*y);
+                self.update_f32(*z);
+                self.update_u8(*flags);
+            }
+        };
+    }
+
+    pub fn finish(&self) -> u64 {
+        return self.state;
+    }
+
+    pub fn finish_into(&self, hash: &mut Vec<u8>) {
+        let base = hash.len();
+        hash.resize(base + HASH_SIZE, 0);
+        BigEndian::write_u64(&mut hash[base..], self.state);
+    }
+}
+
+impl Default for FrameHasher {
+    fn default() -> FrameHasher {
+        return FrameHasher::new();
+    }
+}
+
+// the count as a u32, then every command, see FrameHasher::update_command()
+pub fn hash_commands(commands: &[Command]) -> u64 {
+    let mut hasher = FrameHasher::new();
+    hasher.update_u32(commands.len() as u32);
+    for command in commands {
+        hasher.update_command(command);
+    }
+    return hasher.finish();
+}
+
+// our own recent hashes, frames are recorded in increasing order and the
+// oldest is evicted once `cap` is reached
+#[derive(Debug)]
+pub struct HashHistory {
+    hashes: VecDeque<(u32, Vec<u8>)>,
+    cap: usize,
+}
+
+impl HashHistory {
+    pub fn new(cap: usize) -> HashHistory {
+        return HashHistory {
+            hashes: VecDeque::with_capacity(cap),
+            cap,
+        };
+    }
+
+    pub fn record(&mut self, frame: u32, hash: &[u8]) {
+        if self.cap == 0 {
+            return;
+        }
+        let mut entry = match self.hashes.len() >= self.cap {
+            true => self.hashes.pop_front().unwrap(),
+            false => (0, Vec::with_capacity(HASH_CAP)),
+        };
+        entry.0 = frame;
+        entry.1.clear();
+        entry.1.extend_from_slice(hash);
+        self.hashes.push_back(entry);
+    }
+
+    pub fn get(&self, frame: u32) -> Option<&[u8]> {
+        return match self
+            .hashes
+            .binary_search_by_key(&frame, |(frame, _)| *frame)
+        {
+            Ok(idx) => Some(&self.hashes[idx].1),
+            Err(_) => None,
+        };
+    }
+
+    // None when the frame was never recorded or is already evicted
+    pub fn matches(&self, frame: u32, hash: &[u8]) -> Option<bool> {
+        return self.get(frame).map(|ours| ours == hash);
+    }
+
+    pub fn len(&self) -> usize {
+        return self.hashes.len();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // shared with the server's hash tests, keep both sides in sync
+    #[test]
+    fn test_frame_hasher_vectors() {
+        let hash = |f: &dyn Fn(&mut FrameHasher)| {
+            let mut hasher = FrameHasher::new();
+            f(&mut hasher);
+            return hasher.finish();
+        };
+
+        assert_eq!(hash(&|_| {}), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(hash(&|h| h.update(b"a")), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(hash(&|h| h.update(b"foobar")), 0x8594_4171_f739_67e8);
+        assert_eq!(hash(&|h| h.update_u32(1)), 0x4d25_757f_9dce_1242);
+        assert_eq!(
+            hash(&|h| h.update_u64(0x0102_0304_0506_0708)),
+            0x7eb5_108b_368a_78ed
+        );
+        assert_eq!(hash(&|h| h.update_f32(1.5)), 0xec7c_5227_977d_f9fa);
+        assert_eq!(hash(&|h| h.update_f32(0.0)), 0x4d25_767f_9dce_13f5);
+        assert_eq!(hash(&|h| h.update_f32(-0.0)), 0x4d25_767f_9dce_13f5);
+        assert_eq!(hash(&|h| h.update_f32(f32::NAN)), 0xecee_d435_c8ef_1dba);
+        assert_eq!(hash(&|h| h.update_f32(-f32::NAN)), 0xecee_d435_c8ef_1dba);
+        assert_eq!(
+            hash(&|h| {
+                h.update_u32(7);
+                h.update_f32(-2.25);
+                h.update(b"state");
+            }),
+            0x2c9e_fd61_bc6b_2e95
+        );
+
+        let mut hasher = FrameHasher::new();
+        hasher.update(b"foobar");
+        let mut bytes = vec![0xff];
+        hasher.finish_into(&mut bytes);
+        assert_eq!(
+            bytes,
+            vec![0xff, 0x85, 0x94, 0x41, 0x71, 0xf7, 0x39, 0x67, 0xe8]
+        );
+
+        hasher.reset();
+        assert_eq!(hasher, FrameHasher::new());
+    }
+
+    // shared with the server like the above, a platform hashing other bytes
+    // fails here
+    #[test]
+    fn test_frame_hasher_primitives() {
+        let hash = |f: &dyn Fn(&mut FrameHasher)| {
+            let mut hasher = FrameHasher::new();
+            f(&mut hasher);
+            return hasher.finish();
+        };
+
+        assert_eq!(hash(&|h| h.update_bool(true)), 0xaf63_bc4c_8601_b62c);
+        assert_eq!(hash(&|h| h.update_u8(0xab)), 0xaf64_264c_8602_6a4a);
+        assert_eq!(hash(&|h| h.update_u16(0x0102)), 0x082f_2407_b4e8_902a);
+        assert_eq!(hash(&|h| h.update_i8(-1)), 0xaf64_724c_8602_eb6e);
+        assert_eq!(hash(&|h| h.update_i16(-2)), 0x0a99_c807_b6f6_45b0);
+        assert_eq!(hash(&|h| h.update_i32(-1)), 0x994f_7665_3e2a_3951);
+        assert_eq!(hash(&|h| h.update_i64(-2)), 0x8cf5_198b_fca3_868a);
+        assert_eq!(hash(&|h| h.update_f64(1.5)), 0xd222_7d94_62d4_6a12);
+        assert_eq!(hash(&|h| h.update_f64(0.0)), 0xa8c7_f832_281a_39c5);
+        assert_eq!(hash(&|h| h.update_f64(-0.0)), 0xa8c7_f832_281a_39c5);
+        assert_eq!(hash(&|h| h.update_f64(f64::NAN)), 0x951a_f05c_60ea_a9d2);
+        assert_eq!(hash(&|h| h.update_f64(-f64::NAN)), 0x951a_f05c_60ea_a9d2);
+        // a NaN with another payload
+        let nan = f32::from_bits(0x7f80_0001);
+        assert_eq!(hash(&|h| h.update_f32(nan)), 0xecee_d435_c8ef_1dba);
+
+        assert_eq!(hash_commands(&[]), 0x4d25_767f_9dce_13f5);
+        assert_eq!(hash_commands(&[Command::Aaa(7, -3)]), 0x9ed7_a211_c6de_02d1);
+        #[cfg(not(feature = "no-float-commands"))]
+        {
+            let commands = [Command::Aaa(7, -3), Command::Bbb(1.5, -0.0, f32::NAN, 4)];
+            assert_eq!(hash_commands(&commands), 0x44fd_91ae_785a_1b5f);
+            let canonical = [Command::Aaa(7, -3), Command::Bbb(1.5, 0.0, -f32::NAN, 4)];
+            assert_eq!(hash_commands(&canonical), hash_commands(&commands));
+        }
+    }
+
+    #[test]
+    fn test_hash_history() {
+        let mut history = HashHistory::new(3);
+        for frame in 1..=5 {
+            history.record(frame, &[frame as u8; 4]);
+        }
+        assert_eq!(history.len(), 3);
+        assert_eq!(history.get(2), None);
+        assert_eq!(history.get(3), Some(&[3u8; 4][..]));
+        assert_eq!(history.matches(5, &[5; 4]), Some(true));
+        assert_eq!(history.matches(4, &[5; 4]), Some(false));
+        assert_eq!(history.matches(1, &[1; 4]), None);
+        assert_eq!(history.matches(6, &[6; 4]), None);
+
+        let mut history = HashHistory::new(0);
+        history.record(1, &[1]);
+        assert_eq!(history.matches(1, &[1]), None);
+    }
+}
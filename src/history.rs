@@ -0,0 +1,213 @@
+use crate::codec::CommandEx;
+use crate::message::NetPlayerState;
+use std::collections::{BTreeMap, VecDeque};
+
+#[derive(Debug)]
+struct FrameCommands {
+    frame: u32,
+    commands: Vec<CommandEx>,
+}
+
+// confirmed remote commands of the last `depth` frames, fed from recv_output()
+#[derive(Debug)]
+pub struct FrameHistory {
+    frames: VecDeque<FrameCommands>,
+    reported: BTreeMap<u32, u32>,
+    depth: usize,
+}
+
+impl FrameHistory {
+    pub fn new(depth: usize) -> FrameHistory {
+        return FrameHistory {
+            frames: VecDeque::with_capacity(depth),
+            reported: BTreeMap::new(),
+            depth,
+        };
+    }
+
+    pub fn push(&mut self, command: &CommandEx) {
+        self.report(command.conv, command.frame);
+        let idx = match self.slot(command.frame) {
+            Some(idx) => idx,
+            None => return,
+        };
+
+        // ordered by conv then arrival, so replays never depend on packet order
+        let commands = &mut self.frames[idx].commands;
+        let pos = commands.partition_point(|cmd| cmd.conv <= command.conv);
+        commands.insert(pos, command.clone());
+    }
+
+    pub fn extend(&mut self, commands: &[CommandEx]) {
+        for command in commands {
+            self.push(command);
+        }
+    }
+
+    // a conv reported `frame`, commands arrive in order so all its earlier
+    // frames are complete as well
+    pub fn report(&mut self, conv: u32, frame: u32) {
+        let reported = self.reported.entry(conv).or_insert(frame);
+        if *reported < frame {
+            *reported = frame;
+        }
+    }
+
+    pub fn get(&self, frame: u32) -> Option<&[CommandEx]> {
+        let first = self.frames.front()?.frame;
+        if frame < first {
+            return None;
+        }
+        return self
+            .frames
+            .get((frame - first) as usize)
+            .map(|frame| frame.commands.as_slice());
+    }
+
+    // stopped players no longer hold back the complete frame
+    pub fn latest_complete_frame(&self, players: &BTreeMap<u32, NetPlayerState>) -> Option<u32> {
+        let mut latest = None;
+        for (conv, state) in players.iter() {
+            if *state == NetPlayerState::Stopped {
+                continue;
+            }
+            let frame = *self.reported.get(conv)?;
+            latest = Some(latest.map_or(frame, |latest: u32| latest.min(frame)));
+        }
+        return latest;
+    }
+
+    pub fn oldest_frame(&self) -> Option<u32> {
+        return self.frames.front().map(|frame| frame.frame);
+    }
+
+    pub fn newest_frame(&self) -> Option<u32> {
+        return self.frames.back().map(|frame| frame.frame);
+    }
+
+    fn slot(&mut self, frame: u32) -> Option<usize> {
+        if self.depth == 0 {
+            return None;
+        }
+        let first = match self.frames.front() {
+            Some(first) => first.frame,
+            None => {
+                self.frames.push_back(FrameCommands {
+                    frame,
+                    commands: Vec::new(),
+                });
+                return Some(0);
+            }
+        };
+        if frame < first {
+            return None;
+        }
+
+        let mut last = self.frames.back().unwrap().frame;
+        while last < frame {
+            last += 1;
+            // the evicted frame's buffer is reused for the new one
+            let mut commands = match self.frames.len() >= self.depth {
+                true => self.frames.pop_front().unwrap().commands,
+                false => Vec::new(),
+            };
+            commands.clear();
+            self.frames.push_back(FrameCommands {
+                frame: last,
+                commands,
+            });
+        }
+        let first = self.frames.front().unwrap().frame;
+        return Some((frame - first) as usize);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::codec::Command;
+
+    fn command(conv: u32, frame: u32, value: i32) -> CommandEx {
+        return CommandEx {
+            conv,
+            frame,
+            command: Command::Aaa(value, 0),
+            stamps: None,
+        };
+    }
+
+    #[test]
+    fn test_frame_history_partial_frames() {
+        let mut history = FrameHistory::new(8);
+        assert_eq!(history.get(1), None);
+
+        history.extend(&[
+            command(2, 1, 20),
+            command(1, 1, 10),
+            command(2, 1, 21),
+            command(1, 3, 30),
+        ]);
+        assert_eq!(
+            history.get(1).unwrap(),
+            &[command(1, 1, 10), command(2, 1, 20), command(2, 1, 21)][..]
+        );
+        assert!(history.get(2).unwrap().is_empty());
+        assert_eq!(history.get(3).unwrap(), &[command(1, 3, 30)][..]);
+        assert_eq!(history.get(4), None);
+
+        history.push(&command(2, 3, 31));
+        assert_eq!(
+            history.get(3).unwrap(),
+            &[command(1, 3, 30), command(2, 3, 31)][..]
+        );
+    }
+
+    #[test]
+    fn test_frame_history_eviction() {
+        let mut history = FrameHistory::new(3);
+        for frame in 1..=5 {
+            history.push(&command(1, frame, frame as i32));
+        }
+        assert_eq!(history.oldest_frame(), Some(3));
+        assert_eq!(history.newest_frame(), Some(5));
+        assert_eq!(history.get(2), None);
+        assert_eq!(history.get(3).unwrap(), &[command(1, 3, 3)][..]);
+
+        // too old to keep, still counts as reported
+        history.push(&command(2, 1, 0));
+        assert_eq!(history.get(1), None);
+        assert_eq!(history.oldest_frame(), Some(3));
+
+        history.push(&command(1, 10, 10));
+        assert_eq!(history.oldest_frame(), Some(8));
+        assert!(history.get(8).unwrap().is_empty());
+        assert_eq!(history.get(5), None);
+    }
+
+    #[test]
+    fn test_frame_history_latest_complete_frame() {
+        let mut history = FrameHistory::new(16);
+        let mut players = BTreeMap::new();
+        players.insert(1, NetPlayerState::Running);
+        players.insert(2, NetPlayerState::Running);
+        players.insert(3, NetPlayerState::Running);
+        assert_eq!(history.latest_complete_frame(&players), None);
+
+        history.extend(&[command(1, 1, 0), command(1, 2, 0), command(1, 3, 0)]);
+        history.extend(&[command(2, 1, 0), command(2, 2, 0)]);
+        history.report(3, 1);
+        assert_eq!(history.latest_complete_frame(&players), Some(1));
+
+        history.report(3, 5);
+        assert_eq!(history.latest_complete_frame(&players), Some(2));
+
+        players.insert(2, NetPlayerState::Stopped);
+        assert_eq!(history.latest_complete_frame(&players), Some(3));
+
+        players.insert(4, NetPlayerState::Running);
+        assert_eq!(history.latest_complete_frame(&players), None);
+
+        players.clear();
+        assert_eq!(history.latest_complete_frame(&players), None);
+    }
+}
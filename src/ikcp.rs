@@ -0,0 +1,51 @@
+// the committed bindings, or bindgen's output with `regenerate-bindings`
+#[cfg(not(feature = "regenerate-bindings"))]
+include!("ikcp_bindings.rs");
+#[cfg(feature = "regenerate-bindings")]
+include!(concat!(env!("OUT_DIR"), "/ikcp_bindings.rs"));
+
+#[cfg(all(test, feature = "regenerate-bindings"))]
+mod test {
+    // top-level items without comments, whitespace and trailing commas,
+    // sorted, so only real changes to the bindings show up
+    fn items(source: &str) -> Vec<String> {
+        let mut items = Vec::new();
+        let mut item = String::new();
+        let mut depth = 0;
+        for line in source.lines() {
+            let line = line.trim();
+            if line.starts_with("//") || (line.starts_with("/*") && line.ends_with("*/")) {
+                continue;
+            }
+            for c in line.chars().filter(|c| !c.is_whitespace()) {
+                item.push(c);
+                match c {
+                    '{' => depth += 1,
+                    '}' => depth -= 1,
+                    _ => {}
+                };
+                if depth == 0 && (c == ';' || c == '}') {
+                    let normalized = item
+                        .replace(",}", "}")
+                        .replace(",)", ")")
+                        .replace(",>", ">");
+                    items.push(normalized);
+                    item.clear();
+                }
+            }
+        }
+        items.sort();
+        return items;
+    }
+
+    #[test]
+    fn test_committed_bindings() {
+        let committed = include_str!("ikcp_bindings.rs");
+        let generated = include_str!(concat!(env!("OUT_DIR"), "/ikcp_bindings.rs"));
+        assert_eq!(
+            items(generated),
+            items(committed),
+            "src/ikcp_bindings.rs is stale, copy $OUT_DIR/ikcp_bindings.rs over it"
+        );
+    }
+}
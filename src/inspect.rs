@@ -0,0 +1,293 @@
+use crate::base::{APPLICATION_TYPES, KCP_MAX_PACKET, KCP_MIN_PACKET};
+use crate::codec::{Command, CommandVersion, NetMessage};
+use crate::message::NetType;
+use byteorder::{BigEndian, ByteOrder};
+use std::convert::TryFrom;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Anomaly {
+    TooShort(usize),
+    TooLong(usize),
+    SizeMismatch { declared: usize, available: usize },
+    UnknownType(u8),
+    Protobuf,
+    Commands,
+    TrailingBytes(usize),
+}
+
+impl fmt::Display for Anomaly {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return match self {
+            Anomaly::TooShort(len) => write!(f, "too short ({} bytes)", len),
+            Anomaly::TooLong(len) => write!(f, "too long ({} bytes)", len),
+            Anomaly::SizeMismatch {
+                declared,
+                available,
+            } => write!(
+                f,
+                "size mismatch (declared {}, available {})",
+                declared, available
+            ),
+            Anomaly::UnknownType(typ) => write!(f, "unknown type {}", typ),
+            Anomaly::Protobuf => write!(f, "protobuf broken"),
+            Anomaly::Commands => write!(f, "commands broken"),
+            Anomaly::TrailingBytes(len) => write!(f, "trailing garbage ({} bytes)", len),
+        };
+    }
+}
+
+// Everything that can be read from a captured packet, never fails: what
+// doesn't decode is recorded in `anomalies`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PacketSummary {
+    pub typ: Option<NetType>,
+    pub raw_type: u8,
+    pub len: usize,
+    pub declared: usize,
+    pub message: Option<NetMessage>,
+    pub commands: Option<Vec<Command>>,
+    pub anomalies: Vec<Anomaly>,
+}
+
+impl PacketSummary {
+    pub fn parse(bytes: &[u8]) -> PacketSummary {
+        return PacketSummary::parse_with(bytes, CommandVersion::V1);
+    }
+
+    // command tails read in `version`, the capture doesn't tell
+    pub fn parse_with(bytes: &[u8], version: CommandVersion) -> PacketSummary {
+        let mut summary = PacketSummary {
+            typ: None,
+            raw_type: 0,
+            len: bytes.len(),
+            declared: 0,
+            message: None,
+            commands: None,
+            anomalies: Vec::new(),
+        };
+        if bytes.is_empty() {
+            summary.anomalies.push(Anomaly::TooShort(0));
+            return summary;
+        }
+
+        summary.raw_type = bytes[0];
+        summary.typ = match NetType::try_from(bytes[0] as i32) {
+            Err(_) if bytes[0] >= APPLICATION_TYPES => None,
+            Ok(NetType::Unknown) | Err(_) => {
+                summary.anomalies.push(Anomaly::UnknownType(bytes[0]));
+                None
+            }
+            Ok(typ) => Some(typ),
+        };
+        if bytes.len() < KCP_MIN_PACKET {
+            summary.anomalies.push(Anomaly::TooShort(bytes.len()));
+            return summary;
+        }
+        if bytes.len() > KCP_MAX_PACKET {
+            summary.anomalies.push(Anomaly::TooLong(bytes.len()));
+        }
+
+        summary.declared = BigEndian::read_u16(&bytes[1..]) as usize;
+        let available = bytes.len() - KCP_MIN_PACKET;
+        if summary.declared > available {
+            summary.anomalies.push(Anomaly::SizeMismatch {
+                declared: summary.declared,
+                available,
+            });
+        }
+        let offset = KCP_MIN_PACKET + summary.declared;
+        let known = summary.typ.is_some() || summary.raw_type >= APPLICATION_TYPES;
+        if !known || offset > bytes.len() || offset > KCP_MAX_PACKET {
+            return summary;
+        }
+
+        match NetMessage::decode(&bytes[..offset]) {
+            Ok((message, _)) => summary.message = Some(message),
+            Err(_) => {
+                summary.anomalies.push(Anomaly::Protobuf);
+                return summary;
+            }
+        };
+
+        let mut tail = &bytes[offset..];
+        if let Some(NetMessage::Command(msg)) = &summary.message {
+            // only readable with the dictionary
+            if msg.compressed {
+                return summary;
+            }
+            match tail.len().checked_sub(msg.padding as usize) {
+                Some(end) => tail = &tail[..end],
+                None => {
+                    summary.anomalies.push(Anomaly::Commands);
+                    return summary;
+                }
+            };
+        }
+        if let Some(NetType::Command) = summary.typ {
+            match version.read_commands(&mut tail) {
+                Ok(commands) => summary.commands = Some(commands),
+                Err(_) => {
+                    summary.anomalies.push(Anomaly::Commands);
+                    return summary;
+                }
+            };
+        }
+        if !tail.is_empty() {
+            summary.anomalies.push(Anomaly::TrailingBytes(tail.len()));
+        }
+        return summary;
+    }
+}
+
+impl fmt::Display for PacketSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.typ {
+            Some(typ) => write!(f, "{:?}", typ)?,
+            None if self.raw_type >= APPLICATION_TYPES => {
+                write!(f, "Application({})", self.raw_type)?
+            }
+            None => write!(f, "Unknown({})", self.raw_type)?,
+        };
+        write!(f, " len={} size={}", self.len, self.declared)?;
+
+        if let Some(message) = &self.message {
+            message.fmt_fields(f)?;
+        }
+
+        if let Some(commands) = &self.commands {
+            write!(f, " commands={} [", commands.len())?;
+            for (idx, command) in commands.iter().enumerate() {
+                let sep = if idx == 0 { "" } else { ", " };
+                match command {
+                    Command::Aaa(..) => write!(f, "{}Aaa", sep)?,
+                    #[cfg(not(feature = "no-float-commands"))]
+                    Command::Bbb(..) => write!(f, "{}Bbb", sep)?,
+                };
+            }
+            write!(f, "]")?;
+        }
+
+        for anomaly in self.anomalies.iter() {
+            write!(f, " !{}", anomaly)?;
+        }
+        return Ok(());
+    }
+}
+
+// one line per packet, e.g. for hex dumps out of captures
+pub fn describe_packet(bytes: &[u8]) -> String {
+    return PacketSummary::parse(bytes).to_string();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::codec::CommandEncoder;
+    use crate::message::{NetFinishCause, NetPlayerState};
+
+    fn describe(msg: NetMessage) -> String {
+        let mut bytes = Vec::new();
+        msg.encode(&mut bytes).unwrap();
+        return describe_packet(&bytes);
+    }
+
+    #[test]
+    fn test_describe_packet() {
+        assert_eq!(
+            describe(NetMessage::connect("room", "bot", "secret")),
+            r#"Connect len=22 size=19 room_id="room" player_id="bot" password=set"#
+        );
+        assert_eq!(
+            describe(NetMessage::connect_challenge("room", "bot")),
+            r#"Connect len=16 size=13 room_id="room" player_id="bot" password=challenge"#
+        );
+        assert_eq!(
+            describe(NetMessage::challenge(&[7; 16])),
+            "Challenge len=21 size=18 nonce=16 bytes"
+        );
+        assert_eq!(
+            describe(NetMessage::connect_resume("room", "bot", &[1, 2, 3, 4])),
+            r#"Connect len=20 size=17 room_id="room" player_id="bot" password=resume"#
+        );
+        assert_eq!(describe(NetMessage::accept()), "Accept len=3 size=0");
+        assert_eq!(
+            describe(NetMessage::accept_resumable(&[1, 2, 3, 4])),
+            "Accept len=9 size=6"
+        );
+        let mut accept = NetMessage::accept();
+        if let NetMessage::Accept(msg) = &mut accept {
+            msg.capabilities = 3;
+        }
+        assert_eq!(describe(accept), "Accept len=5 size=2 capabilities=0x3");
+        assert_eq!(
+            describe(NetMessage::state(7777, NetPlayerState::Running)),
+            "State len=8 size=5 conv=7777 state=Running"
+        );
+        assert_eq!(describe(NetMessage::start()), "Start len=3 size=0");
+        assert_eq!(
+            describe(NetMessage::finish(300, NetFinishCause::TimeOutOfSync)),
+            "Finish len=8 size=5 frame=300 cause=TimeOutOfSync"
+        );
+        assert_eq!(
+            describe(NetMessage::hash(2, 7777, &[0xab, 0xcd])),
+            "Hash len=12 size=9 frame=2 conv=7777 hash=abcd"
+        );
+
+        // the float variant unless the build compiled it out
+        #[cfg(not(feature = "no-float-commands"))]
+        let (second, name, len) = (Command::Bbb(1.0, 2.0, 3.0, 0), "Bbb", 42);
+        #[cfg(feature = "no-float-commands")]
+        let (second, name, len) = (Command::Aaa(3, 4), "Aaa", 38);
+        let mut encoder = CommandEncoder::new(0);
+        encoder.commands().push(Command::Aaa(1, 2));
+        encoder.commands().push(second);
+        encoder.encode(345).unwrap();
+        let mut bytes = encoder.command_bytes().to_vec();
+        assert_eq!(
+            describe_packet(&bytes),
+            format!(
+                "Command len={} size=3 frame=345 conv=0 commands=2 [Aaa, {}]",
+                len, name
+            )
+        );
+        #[cfg(not(feature = "no-float-commands"))]
+        let latest = Command::Bbb(1.0, 2.0, 3.0, 4);
+        #[cfg(feature = "no-float-commands")]
+        let latest = Command::Aaa(3, 4);
+        let mut v2 = CommandEncoder::new(0);
+        v2.set_version(CommandVersion::V2);
+        v2.commands().push(latest.clone());
+        v2.encode(345).unwrap();
+        let summary = PacketSummary::parse_with(v2.command_bytes(), CommandVersion::V2);
+        assert_eq!(summary.commands, Some(vec![latest]));
+        assert!(summary.anomalies.is_empty());
+
+        // malformed
+        bytes.push(0xff);
+        assert_eq!(
+            describe_packet(&bytes),
+            format!(
+                "Command len={} size=3 frame=345 conv=0 commands=2 [Aaa, {}] !trailing garbage (1 bytes)",
+                len + 1,
+                name
+            )
+        );
+        assert_eq!(
+            describe_packet(&[NetType::State as u8, 0, 5, 0x08]),
+            "State len=4 size=5 !size mismatch (declared 5, available 1)"
+        );
+        assert_eq!(
+            describe_packet(&[11, 0, 0]),
+            "Unknown(11) len=3 size=0 !unknown type 11"
+        );
+        assert_eq!(
+            describe_packet(&[200, 0, 2, 1, 2]),
+            "Application(200) len=5 size=2 payload=2 bytes"
+        );
+        assert_eq!(
+            describe_packet(&[1]),
+            "Connect len=1 size=0 !too short (1 bytes)"
+        );
+    }
+}
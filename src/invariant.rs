@@ -0,0 +1,107 @@
+use crate::base::{KCPError, KCP_MTU, KCP_OVERHEAD};
+use anyhow::{Error, Result};
+use std::collections::BTreeMap;
+use std::fmt;
+
+// Self-checks of the worker for soak tests, only built with the paranoid
+// feature. A violation is logged with its context and counted in
+// NetStats::invariant_violations, with `fatal` it also ends the session
+// as a ClientError.
+#[derive(Debug)]
+pub struct Invariants {
+    fatal: bool,
+    violations: u64,
+    // newest frame decoded per conv
+    frames: BTreeMap<u32, u32>,
+    // kcp segments of everything sent on the current kcp
+    sent_segments: u64,
+}
+
+impl Invariants {
+    pub fn new(fatal: bool) -> Invariants {
+        return Invariants {
+            fatal,
+            violations: 0,
+            frames: BTreeMap::new(),
+            sent_segments: 0,
+        };
+    }
+
+    pub fn violations(&self) -> u64 {
+        return self.violations;
+    }
+
+    // Err when fatal
+    pub fn check<C: fmt::Debug>(&mut self, holds: bool, what: &str, context: C) -> Result<()> {
+        if holds {
+            return Ok(());
+        }
+        self.violations += 1;
+        println!("invariant violated: {} {:?}", what, context);
+        if !self.fatal {
+            return Ok(());
+        }
+        let err = Error::from(KCPError::Unexpected);
+        return Err(err.context(format!("invariant violated: {}", what)));
+    }
+
+    // a conv's frames never go back
+    pub fn decoded(&mut self, conv: u32, frame: u32) -> bool {
+        let last = self.frames.entry(conv).or_insert(frame);
+        let monotonic = frame >= *last;
+        *last = frame.max(*last);
+        return monotonic;
+    }
+
+    // as kcp splits a message, at least one segment even when empty
+    pub fn sent(&mut self, len: usize) {
+        let mss = KCP_MTU - KCP_OVERHEAD;
+        self.sent_segments += ((len + mss - 1) / mss).max(1) as u64;
+    }
+
+    // kcp can't be waiting on more than we gave it
+    pub fn waitsnd_bounded(&self, waitsnd: u32) -> bool {
+        return waitsnd as u64 <= self.sent_segments;
+    }
+
+    // a new kcp after a reconnect, the server may replay frames
+    pub fn reconnected(&mut self) {
+        self.frames.clear();
+        self.sent_segments = 0;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_invariants() {
+        let mut lenient = Invariants::new(false);
+        assert!(lenient.decoded(6666, 1));
+        assert!(lenient.decoded(6666, 1));
+        assert!(lenient.decoded(8888, 1));
+        assert!(lenient.decoded(6666, 3));
+        assert!(!lenient.decoded(6666, 2));
+        assert!(lenient.decoded(6666, 3));
+
+        assert!(lenient.waitsnd_bounded(0));
+        lenient.sent(0);
+        lenient.sent(KCP_MTU);
+        assert!(lenient.waitsnd_bounded(3));
+        assert!(!lenient.waitsnd_bounded(4));
+
+        lenient.check(true, "holds", 1).unwrap();
+        lenient.check(false, "broken", 2).unwrap();
+        assert_eq!(lenient.violations(), 1);
+
+        let mut fatal = Invariants::new(true);
+        let err = fatal.check(false, "broken", 3).unwrap_err();
+        assert!(format!("{:#}", err).contains("invariant violated: broken"));
+        assert!(matches!(
+            err.downcast_ref::<KCPError>(),
+            Some(KCPError::Unexpected)
+        ));
+        assert_eq!(fatal.violations(), 1);
+    }
+}
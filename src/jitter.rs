@@ -0,0 +1,206 @@
+use crate::base::COMMANDS_CAP;
+use crate::codec::CommandBatch;
+use std::collections::VecDeque;
+
+// holds remote command batches and releases them one frame per `interval`,
+// `delay` frames behind the first arrival, times are in ms
+#[derive(Debug)]
+pub struct JitterBuffer {
+    batches: VecDeque<CommandBatch>,
+    interval: u64,
+    max_delay: u32,
+    delay: u32,
+    jitter: f64,
+    anchor: Option<(u32, u64)>,
+    last_transit: Option<i64>,
+    released: Option<u32>,
+}
+
+impl JitterBuffer {
+    pub fn new(interval: u64, max_delay: u32) -> JitterBuffer {
+        return JitterBuffer {
+            batches: VecDeque::with_capacity(COMMANDS_CAP),
+            interval: interval.max(1),
+            max_delay,
+            delay: 0,
+            jitter: 0.0,
+            anchor: None,
+            last_transit: None,
+            released: None,
+        };
+    }
+
+    pub fn delay(&self) -> u32 {
+        return self.delay;
+    }
+
+    pub fn len(&self) -> usize {
+        return self.batches.len();
+    }
+
+    pub fn push(&mut self, batch: CommandBatch, now: u64) {
+        // a frame already released can't be held back anymore
+        if self
+            .released
+            .map_or(false, |released| batch.frame <= released)
+        {
+            self.batches.push_front(batch);
+            return;
+        }
+
+        let newest = self.batches.back().map(|held| held.frame);
+        if newest.map_or(true, |newest| batch.frame > newest) {
+            self.observe(batch.frame, now);
+        }
+        let pos = self
+            .batches
+            .partition_point(|held| held.frame <= batch.frame);
+        self.batches.insert(pos, batch);
+    }
+
+    pub fn pop_ready(&mut self, now: u64, out: &mut Vec<CommandBatch>) {
+        while let Some(batch) = self.batches.front() {
+            if !self.is_due(batch.frame, now) {
+                return;
+            }
+            let batch = self.batches.pop_front().unwrap();
+            self.released = Some(self.released.map_or(batch.frame, |f| f.max(batch.frame)));
+            out.push(batch);
+        }
+    }
+
+    fn is_due(&self, frame: u32, now: u64) -> bool {
+        if self.released.map_or(false, |released| frame <= released) {
+            return true;
+        }
+        let (base_frame, base_at) = match self.anchor {
+            Some(anchor) => anchor,
+            None => return true,
+        };
+        let frames = frame.saturating_sub(base_frame) as u64 + self.delay as u64;
+        return now >= base_at + frames * self.interval;
+    }
+
+    fn observe(&mut self, frame: u32, now: u64) {
+        let (base_frame, base_at) = *self.anchor.get_or_insert((frame, now));
+        let expected = base_at as i64 + (frame as i64 - base_frame as i64) * self.interval as i64;
+        let transit = now as i64 - expected;
+        if let Some(last) = self.last_transit {
+            // rfc 3550 interarrival jitter
+            let d = (transit - last).abs() as f64;
+            self.jitter += (d - self.jitter) / 16.0;
+        }
+        self.last_transit = Some(transit);
+
+        let delay = (2.0 * self.jitter / self.interval as f64).round() as u32;
+        self.delay = delay.min(self.max_delay);
+
+        // arrived after its slot, restart the schedule from here
+        let due = base_at as i64
+            + (frame as i64 - base_frame as i64 + self.delay as i64) * self.interval as i64;
+        if (now as i64) > due {
+            self.anchor = Some((frame, now));
+            self.last_transit = Some(0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::codec::Command;
+
+    fn batch(conv: u32, frame: u32) -> CommandBatch {
+        let mut batch = CommandBatch::new(conv, frame);
+        batch.commands.push(Command::Aaa(frame as i32, 0));
+        return batch;
+    }
+
+    // frames are produced every 50ms but arrive as 2, nothing, then 3
+    #[test]
+    fn test_jitter_buffer_bursts() {
+        let arrival = |frame: u32| {
+            let idx = (frame - 1) % 5;
+            let base = (frame - 1) as u64 / 5 * 250;
+            return if idx < 2 { base + 100 } else { base + 250 };
+        };
+        let mut jitter = JitterBuffer::new(50, 4);
+        let mut out = Vec::new();
+        let mut released = Vec::new();
+        let mut frame = 1;
+        let mut now = 0;
+        while now < 5000 {
+            while arrival(frame) == now {
+                jitter.push(batch(1, frame), now);
+                jitter.push(batch(2, frame), now);
+                frame += 1;
+            }
+            out.clear();
+            jitter.pop_ready(now, &mut out);
+            for batch in out.iter() {
+                released.push((now, batch.frame, batch.conv));
+            }
+            now += 10;
+        }
+        assert!(jitter.delay() > 0);
+        assert!(jitter.delay() <= 4);
+        for pair in released.windows(2) {
+            assert!((pair[0].1, pair[0].2) < (pair[1].1, pair[1].2));
+        }
+
+        // once adapted, frames come out one per interval instead of in bursts
+        let steady: Vec<_> = released
+            .iter()
+            .filter(|(at, _, conv)| *at >= 2000 && *conv == 1)
+            .collect();
+        assert!(steady.len() > 40);
+        for pair in steady.windows(2) {
+            assert_eq!(pair[1].0 - pair[0].0, 50);
+            assert_eq!(pair[1].1 - pair[0].1, 1);
+        }
+
+        // and the delay shrinks back once the network is steady
+        let delay = jitter.delay();
+        for _ in 0..100 {
+            jitter.push(batch(1, frame), now);
+            jitter.pop_ready(now, &mut out);
+            frame += 1;
+            now += 50;
+        }
+        assert!(jitter.delay() < delay);
+        assert_eq!(jitter.len(), jitter.delay() as usize);
+    }
+
+    #[test]
+    fn test_jitter_buffer_steady_and_cap() {
+        let mut jitter = JitterBuffer::new(50, 2);
+        let mut out = Vec::new();
+        for frame in 1..=40 {
+            let now = frame as u64 * 50;
+            jitter.push(batch(1, frame), now);
+            jitter.pop_ready(now, &mut out);
+        }
+        assert_eq!(jitter.delay(), 0);
+        assert_eq!(out.len(), 40);
+
+        // a long stall then a flood never holds more than max_delay frames
+        let mut now = 40 * 50 + 2000;
+        for frame in 41..=60 {
+            jitter.push(batch(1, frame), now);
+            now += 1;
+        }
+        assert_eq!(jitter.delay(), 2);
+        out.clear();
+        jitter.pop_ready(now + 2 * 50, &mut out);
+        assert!(out.len() >= 1);
+        jitter.pop_ready(now + 20 * 50 + 2 * 50, &mut out);
+        assert_eq!(out.len(), 20);
+        assert_eq!(out.last().unwrap().frame, 60);
+
+        // late frames of an already released frame are passed straight through
+        jitter.push(batch(2, 59), now);
+        out.clear();
+        jitter.pop_ready(now, &mut out);
+        assert_eq!(out, vec![batch(2, 59)]);
+    }
+}
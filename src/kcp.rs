@@ -0,0 +1,562 @@
+use crate::bandwidth::{Bandwidth, BandwidthMeter};
+use crate::base::{
+    KCPError, KCPFailure, ACK_LATENCY_SAMPLES, KCP_INTERVAL, KCP_MTU, KCP_WINDOW_SIZE, RECV_BATCH,
+    UNRELIABLE_QUEUE,
+};
+use crate::batch::{RecvBatch, SendBatch};
+use crate::codec::Datagram;
+use crate::ikcp::{
+    ikcp_check, ikcp_create, ikcp_flush, ikcp_input, ikcp_nodelay, ikcp_peeksize, ikcp_recv,
+    ikcp_release, ikcp_send, ikcp_setmtu, ikcp_setoutput, ikcp_update, ikcp_waitsnd, ikcp_wndsize,
+    IKCPCB,
+};
+use crate::latency::{AckLatency, AckLatencyMeter};
+use anyhow::Result;
+use fn_error_context::context;
+use mio::net::UdpSocket;
+use mio::{Events, Interest, Poll, Token};
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::os::raw::{c_char, c_int, c_long, c_void};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+const SOCKET: Token = Token(0);
+
+// collected per tick, update_udp() sends the batch
+unsafe extern "C" fn kcp_output(
+    buf: *const c_char,
+    len: c_int,
+    _kcp: *mut IKCPCB,
+    user: *mut c_void,
+) -> c_int {
+    let output = &mut *(user as *mut SendBatch);
+    output.push(std::slice::from_raw_parts(buf as *const u8, len as usize));
+    return 0;
+}
+
+// One kcp session over a non-blocking socket of its own. Side-channel
+// datagrams share the socket, they skip kcp in both directions.
+pub struct NetKCP {
+    kcp: *mut IKCPCB,
+    // referenced by the kcp output callback, must outlive `kcp`
+    output: Box<SendBatch>,
+    socket: UdpSocket,
+    poll: Poll,
+    events: Events,
+    peer: SocketAddr,
+    conv: u32,
+    // bounds what a tick takes in, the rest waits in the kernel
+    recv: RecvBatch,
+    recv_max: usize,
+    // datagrams the last update_udp() took in
+    drained: usize,
+    // from the peer, on any socket
+    received: u64,
+    // an outgoing side-channel datagram
+    unreliable_bytes: Vec<u8>,
+    // side-channel payloads received, by sender conv
+    unreliable: Vec<(u32, Vec<u8>)>,
+    // what went through the socket, on the clock of update_kcp()
+    bandwidth: BandwidthMeter,
+    ack_latency: AckLatencyMeter,
+    current: u64,
+}
+
+unsafe impl Send for NetKCP {}
+
+impl NetKCP {
+    #[context("NetKCP::new()")]
+    pub fn new(addr: SocketAddr, conv: u32) -> Result<Box<NetKCP>> {
+        let socket = NetKCP::bind(addr)?;
+        return NetKCP::open(socket, addr, conv);
+    }
+
+    // over a socket the caller bound, e.g. to share its port, switched to
+    // non-blocking here
+    #[context("NetKCP::with_socket()")]
+    pub fn with_socket(
+        socket: std::net::UdpSocket,
+        addr: SocketAddr,
+        conv: u32,
+    ) -> Result<Box<NetKCP>> {
+        socket.set_nonblocking(true).map_err(KCPError::IO)?;
+        return NetKCP::open(UdpSocket::from_std(socket), addr, conv);
+    }
+
+    fn open(mut socket: UdpSocket, addr: SocketAddr, conv: u32) -> Result<Box<NetKCP>> {
+        let poll = Poll::new().map_err(KCPError::IO)?;
+        poll.registry()
+            .register(&mut socket, SOCKET, Interest::READABLE)
+            .map_err(KCPError::IO)?;
+
+        let mut output = Box::new(SendBatch::new());
+        let kcp = unsafe {
+            let kcp = ikcp_create(conv, &mut *output as *mut SendBatch as *mut c_void);
+            ikcp_setoutput(kcp, Some(kcp_output));
+            ikcp_setmtu(kcp, KCP_MTU as c_int);
+            ikcp_wndsize(kcp, KCP_WINDOW_SIZE as c_int, KCP_WINDOW_SIZE as c_int);
+            ikcp_nodelay(kcp, 1, KCP_INTERVAL as c_int, 2, 1);
+            kcp
+        };
+        return Ok(Box::new(NetKCP {
+            kcp,
+            output,
+            socket,
+            poll,
+            events: Events::with_capacity(1),
+            peer: addr,
+            conv,
+            recv: RecvBatch::new(RECV_BATCH),
+            recv_max: RECV_BATCH,
+            drained: 0,
+            received: 0,
+            unreliable_bytes: Vec::with_capacity(KCP_MTU),
+            unreliable: Vec::new(),
+            bandwidth: BandwidthMeter::new(0),
+            ack_latency: AckLatencyMeter::new(conv, ACK_LATENCY_SAMPLES),
+            current: 0,
+        }));
+    }
+
+    fn bind(peer: SocketAddr) -> Result<UdpSocket, KCPError> {
+        let any = match peer {
+            SocketAddr::V4(_) => SocketAddr::from(([0; 4], 0)),
+            SocketAddr::V6(_) => SocketAddr::from(([0u16; 8], 0)),
+        };
+        return Ok(UdpSocket::bind(any)?);
+    }
+
+    // a fresh socket on whatever network is up now, kcp carries on over it
+    // with the same conv and state, the server follows the new address
+    #[context("NetKCP::rebind()")]
+    pub fn rebind(&mut self) -> Result<()> {
+        let mut socket = NetKCP::bind(self.peer)?;
+        let registry = self.poll.registry();
+        let _ = registry.deregister(&mut self.socket);
+        registry
+            .register(&mut socket, SOCKET, Interest::READABLE)
+            .map_err(KCPError::IO)?;
+        self.socket = socket;
+        return Ok(());
+    }
+
+    // of the batched sends, see SendBatch
+    pub fn syscalls_saved(&self) -> u64 {
+        return self.output.syscalls_saved();
+    }
+
+    // datagrams update_udp() takes in at most
+    pub fn set_recv_batch(&mut self, max: usize) {
+        self.recv_max = max.max(1);
+        self.recv = RecvBatch::new(self.recv_max);
+    }
+
+    pub fn drained(&self) -> usize {
+        return self.drained;
+    }
+
+    // datagrams from the peer so far
+    pub fn received(&self) -> u64 {
+        return self.received;
+    }
+
+    // segments sent or queued but not acked yet
+    pub fn waitsnd(&self) -> u32 {
+        return unsafe { ikcp_waitsnd(self.kcp) } as u32;
+    }
+
+    #[context("NetKCP::send_kcp()")]
+    pub fn send_kcp(&mut self, bytes: &[u8]) -> Result<()> {
+        if self.waitsnd() as usize >= KCP_WINDOW_SIZE {
+            return Err(KCPError::KCP(KCPFailure::SendQueueFull).into());
+        }
+        let ret = unsafe {
+            ikcp_send(
+                self.kcp,
+                bytes.as_ptr() as *const c_char,
+                bytes.len() as c_int,
+            )
+        };
+        if ret < 0 {
+            return Err(KCPError::KCP(KCPFailure::from_send(ret)).into());
+        }
+        return Ok(());
+    }
+
+    // None when no complete message is queued, one that doesn't fit stays
+    // queued and is Oversized with its length
+    #[context("NetKCP::recv_into()")]
+    pub fn recv_into(&mut self, buffer: &mut [u8]) -> Result<Option<usize>> {
+        let peeked = unsafe { ikcp_peeksize(self.kcp) };
+        if peeked < 0 {
+            return Ok(None);
+        }
+        if peeked as usize > buffer.len() {
+            return Err(KCPError::Oversized(peeked as usize).into());
+        }
+        let ret = unsafe {
+            ikcp_recv(
+                self.kcp,
+                buffer.as_mut_ptr() as *mut c_char,
+                buffer.len() as c_int,
+            )
+        };
+        if ret < 0 {
+            return match KCPFailure::from_recv(ret) {
+                KCPFailure::RecvQueueEmpty => Ok(None),
+                failure => Err(KCPError::KCP(failure).into()),
+            };
+        }
+        return Ok(Some(ret as usize));
+    }
+
+    // best effort, goes out with the next update_udp()
+    #[context("NetKCP::send_unreliable()")]
+    pub fn send_unreliable(&mut self, payload: &[u8]) -> Result<()> {
+        self.unreliable_bytes.clear();
+        Datagram::encode_unreliable(self.conv, payload, &mut self.unreliable_bytes)?;
+        self.output.push(&self.unreliable_bytes);
+        return Ok(());
+    }
+
+    pub fn recv_unreliable(&mut self, payloads: &mut Vec<(u32, Vec<u8>)>) {
+        payloads.append(&mut self.unreliable);
+    }
+
+    pub fn update_kcp(&mut self, current: u64) {
+        self.current = current;
+        unsafe { ikcp_update(self.kcp, current as u32) };
+    }
+
+    pub fn bandwidth(&mut self, current: u64) -> Bandwidth {
+        return self.bandwidth.bandwidth(current);
+    }
+
+    // replaces the meter, e.g. to keep other than ACK_LATENCY_SAMPLES
+    pub fn set_ack_latency(&mut self, meter: AckLatencyMeter) {
+        self.ack_latency = meter;
+    }
+
+    pub fn ack_latency(&mut self) -> AckLatency {
+        return self.ack_latency.latency();
+    }
+
+    // outputs what is queued now rather than at the next interval, nothing
+    // before the first update_kcp()
+    pub fn flush(&mut self) {
+        unsafe { ikcp_flush(self.kcp) };
+    }
+
+    // when update_kcp() next has something to do, `current` if overdue,
+    // kcp's clock wraps at u32 but ours doesn't
+    pub fn check(&self, current: u64) -> u64 {
+        let at = unsafe { ikcp_check(self.kcp, current as u32) };
+        return current + at.wrapping_sub(current as u32) as u64;
+    }
+
+    // sends what kcp output, then takes in datagrams until `until`, at most
+    // the recv batch's max, past it only waits
+    #[context("NetKCP::update_udp()")]
+    pub fn update_udp(&mut self, until: SystemTime) -> Result<()> {
+        let (bandwidth, current) = (&mut self.bandwidth, self.current);
+        let ack_latency = &mut self.ack_latency;
+        let on_sent = |datagram: &[u8]| {
+            bandwidth.on_sent(datagram.len(), current);
+            ack_latency.on_sent(datagram, current);
+        };
+        match self.output.send_with(&self.socket, self.peer, on_sent) {
+            Ok(_) => {}
+            // lost like on the path, kcp retransmits
+            Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+            Err(err) => return Err(KCPError::IO(err).into()),
+        };
+        self.drained = 0;
+        loop {
+            if self.drained < self.recv_max {
+                self.recv_udp()?;
+            }
+            let wait = match until.duration_since(SystemTime::now()) {
+                Ok(wait) if wait > Duration::ZERO => wait,
+                _ => return Ok(()),
+            };
+            if self.drained >= self.recv_max {
+                thread::sleep(wait);
+                return Ok(());
+            }
+            self.poll
+                .poll(&mut self.events, Some(wait))
+                .map_err(KCPError::IO)?;
+        }
+    }
+
+    // what is left of the tick's batch
+    fn recv_udp(&mut self) -> Result<()> {
+        let max = self.recv_max - self.drained;
+        let count = self
+            .recv
+            .recv_at_most(&self.socket, max)
+            .map_err(KCPError::IO)?;
+        self.drained += count;
+        for index in 0..count {
+            self.input(index);
+        }
+        return Ok(());
+    }
+
+    fn input(&mut self, index: usize) {
+        let (bytes, peer) = self.recv.get(index);
+        // strays, only the server talks to us
+        if peer != self.peer {
+            return;
+        }
+        self.received += 1;
+        self.bandwidth.on_recv(bytes.len(), self.current);
+        match Datagram::demux(bytes) {
+            Ok(Datagram::KCP(_)) => {}
+            Ok(Datagram::Unreliable(conv, payload)) => {
+                // the worker takes them every tick, a full queue is a stall
+                if self.unreliable.len() < UNRELIABLE_QUEUE {
+                    self.unreliable.push((conv, payload.to_vec()));
+                }
+                return;
+            }
+            // corrupted on the path, like a segment kcp rejects
+            Err(_) => return,
+        };
+        self.ack_latency.on_recv(bytes, self.current);
+        // segments of other convs are rejected, kcp retransmits the broken
+        unsafe {
+            ikcp_input(
+                self.kcp,
+                bytes.as_ptr() as *const c_char,
+                bytes.len() as c_long,
+            )
+        };
+    }
+
+    #[cfg(test)]
+    pub fn local_addr(&self) -> SocketAddr {
+        return self.socket.local_addr().unwrap();
+    }
+
+    #[cfg(test)]
+    pub fn output_queue(&self) -> &SendBatch {
+        return &self.output;
+    }
+}
+
+impl Drop for NetKCP {
+    fn drop(&mut self) {
+        unsafe { ikcp_release(self.kcp) };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::base::KCP_MAX_PACKET;
+
+    #[test]
+    fn test_net_kcp_check() {
+        let mut kcp = NetKCP::new(SocketAddr::from(([127, 0, 0, 1], 9)), 7777).unwrap();
+        // never updated
+        assert_eq!(kcp.check(1000), 1000);
+        kcp.update_kcp(1000);
+        assert_eq!(kcp.check(1000), 1000 + KCP_INTERVAL);
+        assert_eq!(kcp.check(1000 + KCP_INTERVAL + 5), 1000 + KCP_INTERVAL + 5);
+
+        let current = u32::MAX as u64 - 2;
+        kcp.update_kcp(current);
+        assert_eq!(kcp.check(current), current + KCP_INTERVAL);
+    }
+
+    #[test]
+    fn test_net_kcp_send_batch() {
+        let server = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let mut kcp = NetKCP::new(server.local_addr().unwrap(), 7777).unwrap();
+        // a segment per KCP_MTU, the side channel goes in the same batch
+        kcp.send_kcp(&[1; KCP_MTU * 3]).unwrap();
+        kcp.send_unreliable(&[2]).unwrap();
+        kcp.update_kcp(0);
+        let queued = kcp.output_queue().len();
+        assert_eq!(queued, 5);
+        kcp.update_udp(SystemTime::now()).unwrap();
+        assert!(kcp.output_queue().is_empty());
+
+        let mut datagram = vec![0; KCP_MAX_PACKET];
+        let mut unreliable = 0;
+        for _ in 0..queued {
+            let (len, _) = server.recv_from(&mut datagram).unwrap();
+            if let Ok(Datagram::Unreliable(7777, &[2])) = Datagram::demux(&datagram[..len]) {
+                unreliable += 1;
+            }
+        }
+        assert_eq!(unreliable, 1);
+        #[cfg(target_os = "linux")]
+        assert_eq!(kcp.syscalls_saved(), queued as u64 - 1);
+    }
+
+    #[test]
+    fn test_net_kcp_unreliable() {
+        let server = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let mut kcp = NetKCP::new(server.local_addr().unwrap(), 7777).unwrap();
+        kcp.send_unreliable(&[1, 2, 3]).unwrap();
+        assert!(kcp.send_unreliable(&[0; KCP_MTU]).is_err());
+        kcp.update_udp(SystemTime::now()).unwrap();
+
+        let mut datagram = vec![0; KCP_MAX_PACKET];
+        let (len, client) = server.recv_from(&mut datagram).unwrap();
+        assert_eq!(
+            Datagram::demux(&datagram[..len]).unwrap(),
+            Datagram::Unreliable(7777, &[1, 2, 3])
+        );
+
+        // echoed under another conv, a stray from elsewhere is ignored
+        let mut bytes = Vec::new();
+        Datagram::encode_unreliable(8888, &[4, 5], &mut bytes).unwrap();
+        server.send_to(&bytes, client).unwrap();
+        let stray = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        stray.send_to(&bytes, client).unwrap();
+        let mut payloads = Vec::new();
+        let deadline = SystemTime::now() + Duration::from_secs(1);
+        while payloads.is_empty() && SystemTime::now() < deadline {
+            kcp.update_udp(SystemTime::now() + Duration::from_millis(KCP_INTERVAL))
+                .unwrap();
+            kcp.recv_unreliable(&mut payloads);
+        }
+        assert_eq!(payloads, vec![(8888, vec![4, 5])]);
+        kcp.update_udp(SystemTime::now() + Duration::from_millis(KCP_INTERVAL))
+            .unwrap();
+        kcp.recv_unreliable(&mut payloads);
+        assert_eq!(payloads.len(), 1);
+    }
+
+    #[test]
+    fn test_net_kcp_with_socket() {
+        let server = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let local = socket.local_addr().unwrap();
+        let mut kcp = NetKCP::with_socket(socket, server.local_addr().unwrap(), 7777).unwrap();
+        assert_eq!(kcp.local_addr(), local);
+
+        // from the adopted port, which doesn't block an empty receive
+        kcp.send_unreliable(&[1]).unwrap();
+        kcp.update_udp(SystemTime::now()).unwrap();
+        let mut datagram = vec![0; KCP_MAX_PACKET];
+        let (_, client) = server.recv_from(&mut datagram).unwrap();
+        assert_eq!(client, local);
+        kcp.update_udp(SystemTime::now()).unwrap();
+        assert_eq!(kcp.received(), 0);
+    }
+
+    #[test]
+    fn test_net_kcp_waitsnd() {
+        let server = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut kcp = NetKCP::new(server.local_addr().unwrap(), 7777).unwrap();
+        assert_eq!(kcp.waitsnd(), 0);
+        kcp.send_kcp(&[0; KCP_MTU * 2]).unwrap();
+        assert_eq!(kcp.waitsnd(), 3);
+        // nobody acks
+        kcp.update_kcp(0);
+        kcp.update_udp(SystemTime::now()).unwrap();
+        assert_eq!(kcp.waitsnd(), 3);
+    }
+
+    #[test]
+    fn test_net_kcp_bandwidth() {
+        let server = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let mut kcp = NetKCP::new(server.local_addr().unwrap(), 7777).unwrap();
+        kcp.update_kcp(1000);
+        kcp.send_unreliable(&[1, 2, 3]).unwrap();
+        kcp.update_udp(SystemTime::now()).unwrap();
+
+        let mut datagram = vec![0; KCP_MAX_PACKET];
+        let (len, client) = server.recv_from(&mut datagram).unwrap();
+        server.send_to(&datagram[..len], client).unwrap();
+        let deadline = SystemTime::now() + Duration::from_secs(1);
+        while kcp.received() == 0 && SystemTime::now() < deadline {
+            kcp.update_udp(SystemTime::now() + Duration::from_millis(KCP_INTERVAL))
+                .unwrap();
+        }
+
+        let bandwidth = kcp.bandwidth(1500);
+        assert_eq!(bandwidth.sent_total.datagrams, 1);
+        assert_eq!(bandwidth.sent_total.bytes, len as u64);
+        assert_eq!(bandwidth.recv_total.datagrams, 1);
+        assert_eq!(bandwidth.recv_total.bytes, len as u64);
+        // a second later they are the last full second's
+        assert_eq!(kcp.bandwidth(2000).sent_per_sec.bytes, len as u64);
+    }
+
+    #[test]
+    fn test_net_kcp_ack_latency() {
+        let a = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let b = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let (a_addr, b_addr) = (a.local_addr().unwrap(), b.local_addr().unwrap());
+        let mut a = NetKCP::with_socket(a, b_addr, 7777).unwrap();
+        let mut b = NetKCP::with_socket(b, a_addr, 7777).unwrap();
+        a.set_ack_latency(AckLatencyMeter::new(7777, 4));
+        a.send_kcp(&[1, 2, 3]).unwrap();
+
+        let mut buffer = vec![0; KCP_MAX_PACKET];
+        let mut received = None;
+        let deadline = SystemTime::now() + Duration::from_secs(1);
+        let mut current = 0;
+        while a.ack_latency().samples == 0 && SystemTime::now() < deadline {
+            for kcp in [&mut a, &mut b].iter_mut() {
+                kcp.update_kcp(current);
+                kcp.update_udp(SystemTime::now() + Duration::from_millis(1))
+                    .unwrap();
+            }
+            if let Some(len) = b.recv_into(&mut buffer).unwrap() {
+                received = Some(buffer[..len].to_vec());
+            }
+            current += KCP_INTERVAL;
+        }
+        assert_eq!(received, Some(vec![1, 2, 3]));
+        let latency = a.ack_latency();
+        assert_eq!(latency.samples, 1);
+        assert_eq!(latency.retransmits, 0);
+        // the ack only goes out on b's next flush
+        assert!(latency.max >= KCP_INTERVAL);
+        // the receiving side times nothing
+        assert_eq!(b.ack_latency().samples, 0);
+    }
+
+    #[test]
+    fn test_net_kcp_recv_batch() {
+        let server = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut kcp = NetKCP::new(server.local_addr().unwrap(), 7777).unwrap();
+        kcp.set_recv_batch(8);
+        let client = SocketAddr::from(([127, 0, 0, 1], kcp.local_addr().port()));
+        let mut bytes = Vec::new();
+        Datagram::encode_unreliable(7777, &[1], &mut bytes).unwrap();
+        for _ in 0..50 {
+            server.send_to(&bytes, client).unwrap();
+        }
+        std::thread::sleep(Duration::from_millis(50));
+
+        // ceil(50 / 8) ticks, each its share regardless of the time left
+        let mut drained = Vec::new();
+        while drained.iter().sum::<usize>() < 50 && drained.len() < 10 {
+            kcp.update_udp(SystemTime::now() + Duration::from_millis(10))
+                .unwrap();
+            drained.push(kcp.drained());
+        }
+        assert_eq!(drained, vec![8, 8, 8, 8, 8, 8, 2]);
+        assert_eq!(kcp.received(), 50);
+    }
+}
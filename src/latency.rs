@@ -0,0 +1,333 @@
+use crate::base::{KCP_OVERHEAD, KCP_WINDOW_SIZE};
+use byteorder::{ByteOrder, LittleEndian};
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, VecDeque};
+
+const KCP_CMD_PUSH: u8 = 81;
+const KCP_CMD_ACK: u8 = 82;
+
+// ms from a segment's first transmission to its ack, over the last samples
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AckLatency {
+    pub samples: usize,
+    pub p50: u64,
+    pub p95: u64,
+    pub max: u64,
+    // pushed segments sent again while in flight, since the meter exists
+    pub retransmits: u64,
+}
+
+// Reads the kcp segment headers of every datagram of `conv` going through
+// the socket, times are in ms. A pushed sn is timed from its first send,
+// retransmits don't restart it, until an ack of it or an una past it comes
+// back. Keeps the last `capacity` samples and at most a window of segments
+// in flight, the oldest is given up on past that.
+#[derive(Debug)]
+pub struct AckLatencyMeter {
+    conv: u32,
+    // sn -> first sent
+    in_flight: BTreeMap<u32, u64>,
+    samples: VecDeque<u64>,
+    capacity: usize,
+    // sorted copy of `samples`, reused
+    sorted: Vec<u64>,
+    latency: AckLatency,
+    stale: bool,
+    retransmits: u64,
+}
+
+impl AckLatencyMeter {
+    pub fn new(conv: u32, capacity: usize) -> AckLatencyMeter {
+        let capacity = capacity.max(1);
+        return AckLatencyMeter {
+            conv,
+            in_flight: BTreeMap::new(),
+            samples: VecDeque::with_capacity(capacity),
+            capacity,
+            sorted: Vec::with_capacity(capacity),
+            latency: AckLatency::default(),
+            stale: false,
+            retransmits: 0,
+        };
+    }
+
+    pub fn on_sent(&mut self, datagram: &[u8], now: u64) {
+        for (cmd, sn, _) in Segments::new(datagram, self.conv) {
+            if cmd != KCP_CMD_PUSH {
+                continue;
+            }
+            match self.in_flight.entry(sn) {
+                Entry::Occupied(_) => self.retransmits += 1,
+                Entry::Vacant(entry) => {
+                    entry.insert(now);
+                }
+            };
+            if self.in_flight.len() > KCP_WINDOW_SIZE {
+                let oldest = *self.in_flight.keys().next().unwrap();
+                self.in_flight.remove(&oldest);
+            }
+        }
+    }
+
+    pub fn on_recv(&mut self, datagram: &[u8], now: u64) {
+        for (cmd, sn, una) in Segments::new(datagram, self.conv) {
+            if cmd == KCP_CMD_ACK {
+                if let Some(sent) = self.in_flight.remove(&sn) {
+                    self.sample(now.saturating_sub(sent));
+                }
+            }
+            while let Some((&sn, &sent)) = self.in_flight.iter().next() {
+                if sn >= una {
+                    break;
+                }
+                self.in_flight.remove(&sn);
+                self.sample(now.saturating_sub(sent));
+            }
+        }
+    }
+
+    // percentiles are only worked out again after new samples
+    pub fn latency(&mut self) -> AckLatency {
+        self.latency.retransmits = self.retransmits;
+        if !self.stale {
+            return self.latency;
+        }
+        self.sorted.clear();
+        self.sorted.extend(self.samples.iter().copied());
+        self.sorted.sort_unstable();
+        self.latency = AckLatency {
+            samples: self.sorted.len(),
+            p50: self.percentile(50),
+            p95: self.percentile(95),
+            max: *self.sorted.last().unwrap(),
+            retransmits: self.retransmits,
+        };
+        self.stale = false;
+        return self.latency;
+    }
+
+    fn sample(&mut self, latency: u64) {
+        if self.samples.len() == self.capacity {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(latency);
+        self.stale = true;
+    }
+
+    // nearest rank
+    fn percentile(&self, percent: usize) -> u64 {
+        let rank = (self.sorted.len() * percent + 99) / 100;
+        return self.sorted[rank.max(1) - 1];
+    }
+}
+
+// (cmd, sn, una) of each segment, stops at the first one cut short
+struct Segments<'t> {
+    bytes: &'t [u8],
+    conv: u32,
+}
+
+impl<'t> Segments<'t> {
+    fn new(bytes: &'t [u8], conv: u32) -> Segments<'t> {
+        return Segments { bytes, conv };
+    }
+}
+
+impl<'t> Iterator for Segments<'t> {
+    type Item = (u8, u32, u32);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.bytes.len() < KCP_OVERHEAD || LittleEndian::read_u32(self.bytes) != self.conv {
+            return None;
+        }
+        let cmd = self.bytes[4];
+        let sn = LittleEndian::read_u32(&self.bytes[12..]);
+        let una = LittleEndian::read_u32(&self.bytes[16..]);
+        let len = LittleEndian::read_u32(&self.bytes[20..]) as usize;
+        let end = KCP_OVERHEAD.checked_add(len)?;
+        if end > self.bytes.len() {
+            return None;
+        }
+        self.bytes = &self.bytes[end..];
+        return Some((cmd, sn, una));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::base::{KCP_INTERVAL, KCP_MTU};
+    use crate::ikcp::{
+        ikcp_create, ikcp_input, ikcp_nodelay, ikcp_recv, ikcp_release, ikcp_send, ikcp_setmtu,
+        ikcp_setoutput, ikcp_update, IKCPCB,
+    };
+    use std::cell::RefCell;
+    use std::os::raw::{c_char, c_int, c_long, c_void};
+
+    unsafe extern "C" fn capture(
+        buf: *const c_char,
+        len: c_int,
+        _kcp: *mut IKCPCB,
+        user: *mut c_void,
+    ) -> c_int {
+        let sent = &*(user as *const RefCell<Vec<Vec<u8>>>);
+        let bytes = std::slice::from_raw_parts(buf as *const u8, len as usize);
+        sent.borrow_mut().push(bytes.to_vec());
+        return 0;
+    }
+
+    // a kcp whose datagrams are collected for the link to deliver
+    struct Endpoint {
+        kcp: *mut IKCPCB,
+        // referenced by the kcp output callback, must outlive `kcp`
+        sent: Box<RefCell<Vec<Vec<u8>>>>,
+    }
+
+    impl Endpoint {
+        fn new(conv: u32) -> Endpoint {
+            let sent = Box::new(RefCell::new(Vec::new()));
+            let kcp = unsafe {
+                let kcp = ikcp_create(conv, &*sent as *const RefCell<_> as *mut c_void);
+                ikcp_setoutput(kcp, Some(capture));
+                ikcp_setmtu(kcp, KCP_MTU as c_int);
+                ikcp_nodelay(kcp, 1, KCP_INTERVAL as c_int, 2, 1);
+                kcp
+            };
+            return Endpoint { kcp, sent };
+        }
+
+        fn input(&mut self, datagram: &[u8]) {
+            let ret = unsafe {
+                ikcp_input(
+                    self.kcp,
+                    datagram.as_ptr() as *const c_char,
+                    datagram.len() as c_long,
+                )
+            };
+            assert_eq!(ret, 0);
+        }
+    }
+
+    impl Drop for Endpoint {
+        fn drop(&mut self) {
+            unsafe { ikcp_release(self.kcp) };
+        }
+    }
+
+    // `delay` ms each way, a message sent every `every` ms
+    fn simulate(delay: u64, every: u64, capacity: usize) -> AckLatencyMeter {
+        let conv = 6666;
+        let mut meter = AckLatencyMeter::new(conv, capacity);
+        let (mut client, mut server) = (Endpoint::new(conv), Endpoint::new(conv));
+        // (deliver at, to the server, datagram)
+        let mut link: VecDeque<(u64, bool, Vec<u8>)> = VecDeque::new();
+        let mut buffer = vec![0u8; KCP_MTU];
+        for now in 0..5000 {
+            while let Some((at, to_server, datagram)) = link.pop_front() {
+                if at > now {
+                    link.push_front((at, to_server, datagram));
+                    break;
+                }
+                match to_server {
+                    true => server.input(&datagram),
+                    false => {
+                        meter.on_recv(&datagram, now);
+                        client.input(&datagram);
+                    }
+                };
+            }
+            if now % every == 0 {
+                let message = (now as u32).to_be_bytes();
+                let ret = unsafe { ikcp_send(client.kcp, message.as_ptr() as *const c_char, 4) };
+                assert_eq!(ret, 0);
+            }
+            unsafe {
+                ikcp_update(client.kcp, now as u32);
+                ikcp_update(server.kcp, now as u32);
+                while ikcp_recv(server.kcp, buffer.as_mut_ptr() as *mut c_char, 4) > 0 {}
+            }
+            for datagram in client.sent.borrow_mut().drain(..) {
+                meter.on_sent(&datagram, now);
+                link.push_back((now + delay, true, datagram));
+            }
+            for datagram in server.sent.borrow_mut().drain(..) {
+                link.push_back((now + delay, false, datagram));
+            }
+        }
+        return meter;
+    }
+
+    #[test]
+    fn test_ack_latency() {
+        // the peer acks on its next flush, up to an interval later
+        for delay in [20, 75].iter().copied() {
+            let latency = simulate(delay, 33, 64).latency();
+            assert_eq!(latency.samples, 64);
+            for ms in [latency.p50, latency.p95, latency.max].iter() {
+                assert!(
+                    *ms >= 2 * delay && *ms <= 2 * delay + KCP_INTERVAL,
+                    "{} {:?}",
+                    delay,
+                    latency
+                );
+            }
+            assert!(latency.p50 <= latency.p95 && latency.p95 <= latency.max);
+        }
+    }
+
+    #[test]
+    fn test_ack_latency_meter() {
+        let segment = |conv: u32, cmd: u8, sn: u32, una: u32| {
+            let mut bytes = vec![0; KCP_OVERHEAD + 2];
+            LittleEndian::write_u32(&mut bytes, conv);
+            bytes[4] = cmd;
+            LittleEndian::write_u32(&mut bytes[12..], sn);
+            LittleEndian::write_u32(&mut bytes[16..], una);
+            LittleEndian::write_u32(&mut bytes[20..], 2);
+            return bytes;
+        };
+        let mut meter = AckLatencyMeter::new(6666, 4);
+        assert_eq!(meter.latency(), AckLatency::default());
+
+        let mut both = segment(6666, KCP_CMD_PUSH, 0, 0);
+        both.extend(segment(6666, KCP_CMD_PUSH, 1, 0));
+        meter.on_sent(&both, 100);
+        // a retransmit, and another conv
+        meter.on_sent(&segment(6666, KCP_CMD_PUSH, 1, 0), 150);
+        meter.on_sent(&segment(8888, KCP_CMD_PUSH, 2, 0), 100);
+        meter.on_recv(&segment(6666, KCP_CMD_ACK, 1, 0), 160);
+        // and the una covers sn 0
+        meter.on_recv(&segment(6666, KCP_CMD_ACK, 7, 2), 190);
+        assert_eq!(
+            meter.latency(),
+            AckLatency {
+                samples: 2,
+                p50: 60,
+                p95: 90,
+                max: 90,
+                retransmits: 1,
+            }
+        );
+
+        // cut short, nothing read past it
+        let mut short = segment(6666, KCP_CMD_PUSH, 3, 0);
+        short.truncate(KCP_OVERHEAD + 1);
+        meter.on_sent(&short, 200);
+        assert!(meter.in_flight.is_empty());
+
+        for sn in 10..16 {
+            meter.on_sent(&segment(6666, KCP_CMD_PUSH, sn, 0), 200);
+            meter.on_recv(&segment(6666, KCP_CMD_ACK, sn, 0), 200 + sn as u64);
+        }
+        assert_eq!(
+            meter.latency(),
+            AckLatency {
+                samples: 4,
+                p50: 13,
+                p95: 15,
+                max: 15,
+                retransmits: 1,
+            }
+        );
+    }
+}
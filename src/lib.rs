@@ -0,0 +1,131 @@
+#[cfg(feature = "client")]
+pub mod assembly;
+#[cfg(feature = "client")]
+pub mod bandwidth;
+pub mod base;
+#[cfg(feature = "client")]
+pub mod batch;
+#[cfg(feature = "client")]
+pub mod chan;
+#[cfg(feature = "client")]
+pub mod client;
+#[cfg(feature = "client")]
+pub mod clock;
+pub mod codec;
+#[cfg(feature = "client")]
+pub mod credentials;
+#[cfg(feature = "client")]
+pub mod decision;
+#[cfg(feature = "client")]
+pub mod degrade;
+#[cfg(feature = "client")]
+pub mod delivery;
+#[cfg(feature = "client")]
+pub mod diagnostics;
+#[cfg(feature = "dictionary-compression")]
+pub mod dictionary;
+#[cfg(feature = "client")]
+pub mod estimate;
+pub mod hash;
+#[cfg(feature = "client")]
+pub mod history;
+pub mod inspect;
+#[cfg(feature = "client")]
+#[allow(non_upper_case_globals, non_camel_case_types, non_snake_case, dead_code)]
+mod ikcp;
+#[cfg(feature = "paranoid")]
+pub mod invariant;
+#[cfg(feature = "client")]
+pub mod jitter;
+#[cfg(feature = "client")]
+pub mod latency;
+#[cfg(feature = "client")]
+mod kcp;
+pub mod message;
+#[cfg(feature = "client")]
+pub mod mock;
+#[cfg(feature = "no-float-commands")]
+mod nofloat;
+#[cfg(feature = "client")]
+pub mod offline;
+#[cfg(all(test, feature = "client"))]
+mod perf;
+pub mod pool;
+// crate-private until fragments or snapshots feed it
+#[cfg(feature = "client")]
+#[allow(dead_code)]
+mod reassembly;
+#[cfg(feature = "client")]
+pub mod rebind;
+#[cfg(feature = "client")]
+pub mod resume;
+#[cfg(feature = "client")]
+pub mod roster;
+#[cfg(feature = "client")]
+pub mod schedule;
+#[cfg(all(feature = "client", any(test, feature = "testing")))]
+pub mod selftest;
+#[cfg(feature = "client")]
+pub mod session;
+#[cfg(feature = "client")]
+pub mod summary;
+#[cfg(all(test, feature = "client"))]
+mod testing;
+#[cfg(feature = "client")]
+pub mod timing;
+pub mod validate;
+#[cfg(feature = "client")]
+pub mod warning;
+#[cfg(test)]
+mod wire_compat;
+#[cfg(feature = "client")]
+pub mod worker;
+
+#[cfg(feature = "client")]
+pub use crate::bandwidth::{Bandwidth, Traffic};
+pub use crate::base::{
+    Capabilities, ConfigError, Conv, IgnoredPackets, InputError, ProtocolLimits, StartInfo,
+    ValidationError,
+};
+#[cfg(feature = "client")]
+pub use crate::base::{Acceptance, ClientError, ConnectTimes, FinishInfo};
+#[cfg(feature = "client")]
+pub use crate::chan::{
+    CancelResult, ChanSnapshot, ControlMsg, InputLimits, LagInfo, LagTable, NetEvent, NetStats,
+    NetWarning, OutputLimits, OverflowPolicy, Presence, SendBudget,
+};
+#[cfg(feature = "client")]
+pub use crate::client::{Client, GameHandle, PollStatus};
+#[cfg(feature = "client")]
+pub use crate::credentials::CredentialLimits;
+#[cfg(feature = "client")]
+pub use crate::decision::{Decision, DecisionLog};
+#[cfg(feature = "client")]
+pub use crate::degrade::{DegradeConfig, Rung};
+#[cfg(feature = "client")]
+pub use crate::diagnostics::{DiagnosticsConfig, DiagnosticsReport, DiagnosticsStep};
+#[cfg(feature = "client")]
+pub use crate::estimate::FrameEstimate;
+pub use crate::hash::{hash_commands, FrameHasher};
+#[cfg(feature = "client")]
+pub use crate::history::FrameHistory;
+#[cfg(feature = "client")]
+pub use crate::latency::AckLatency;
+#[cfg(feature = "client")]
+pub use crate::offline::OfflineConfig;
+pub use crate::pool::BufferPool;
+#[cfg(feature = "client")]
+pub use crate::resume::SessionState;
+#[cfg(feature = "client")]
+pub use crate::roster::RosterLimits;
+#[cfg(all(feature = "client", feature = "testing"))]
+pub use crate::selftest::{run_matrix, MatrixReport};
+#[cfg(feature = "client")]
+pub use crate::session::SessionManager;
+#[cfg(feature = "client")]
+pub use crate::summary::{SessionSummary, Timeline, TimelineEvent};
+#[cfg(feature = "client")]
+pub use crate::timing::{TickHistogram, TickTimings};
+pub use crate::validate::{CommandValidator, Verdict};
+#[cfg(feature = "client")]
+pub use crate::worker::{EarlyInputPolicy, LateCommandPolicy, SelfStatePolicy, WorkerConfig};
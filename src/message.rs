@@ -0,0 +1,2 @@
+// generated by build.rs from message.proto
+include!(concat!(env!("OUT_DIR"), "/message.rs"));
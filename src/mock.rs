@@ -0,0 +1,832 @@
+use crate::base::{
+    KCPError, KCPFailure, RateLimitedLogger, KCP_INTERVAL, KCP_MAX_PACKET, KCP_MTU, KCP_OVERHEAD,
+    KCP_WINDOW_SIZE, LOG_INTERVAL,
+};
+use crate::codec::{Datagram, NetMessage};
+use crate::credentials::challenge_response;
+use crate::ikcp::{
+    ikcp_create, ikcp_input, ikcp_nodelay, ikcp_recv, ikcp_release, ikcp_send, ikcp_setmtu,
+    ikcp_setoutput, ikcp_update, ikcp_waitsnd, ikcp_wndsize, IKCPCB,
+};
+use crate::message::{
+    NetConnect, NetFinish, NetFinishCause, NetHash, NetPlayerState, NetStart, NetState,
+};
+use anyhow::Result;
+use byteorder::{ByteOrder, LittleEndian};
+use fn_error_context::context;
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::mem;
+use std::net::{SocketAddr, UdpSocket};
+use std::os::raw::{c_char, c_int, c_long, c_void};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const MOCK_KCP_CMD_PUSH: u8 = 81;
+
+#[derive(Debug, Clone, Default)]
+pub struct MockRecords {
+    pub connects: Vec<(u32, NetConnect)>,
+    pub commands: Vec<(u32, u32)>,
+    pub hashes: Vec<(u32, NetHash)>,
+    pub finishes: Vec<(u32, NetFinish)>,
+    pub presences: Vec<(u32, NetPlayerState)>,
+    pub unreliable: Vec<(u32, Vec<u8>)>,
+    pub malformed: usize,
+    pub migrations: Vec<(u32, SocketAddr)>,
+    // (from, to) each conv, of the kcp datagrams taken in and sent out
+    pub bytes: HashMap<u32, (u64, u64)>,
+}
+
+// without a password every Connect is accepted, a server that doesn't
+// `challenge` behaves like the ones predating the challenge-response, one
+// that does `resume` issues resume tokens on accept, a `silent` one acks
+// the Connect in kcp but never answers it, `dictionary_id` is accepted
+// from clients offering the same, of the Capabilities a client advertises
+// those in `capabilities` are enabled, none by default like a server
+// predating them, with `takeover` a Connect reusing the player_id of a
+// joined conv takes its place, the old conv gets a second Accept, every
+// `drop_every`th datagram from clients is lost, none with 0, `no_echo`
+// relays commands to every client but their sender, and with `acks` the
+// sender gets a State acknowledging each of its frames
+#[derive(Debug, Clone, Default)]
+pub struct MockAuth {
+    pub password: Option<String>,
+    pub challenge: bool,
+    pub resume: bool,
+    pub silent: bool,
+    pub dictionary_id: u64,
+    pub capabilities: u64,
+    pub takeover: bool,
+    pub drop_every: usize,
+    pub no_echo: bool,
+    pub acks: bool,
+}
+
+// A loopback lockstep server: accepts every Connect, starts the match once
+// `players` clients are waiting and relays command packets to all running
+// clients stamped with the sender's conv. Side-channel datagrams bypass kcp
+// and are relayed to every other known client. Like the real server it keys
+// sessions on conv, so a client that rebinds its socket keeps its session.
+// A diagnostic Connect is accepted without joining the match and the
+// side-channel datagrams of its conv are echoed back.
+pub struct MockServer {
+    addr: SocketAddr,
+    records: Arc<Mutex<MockRecords>>,
+    closed: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MockServer {
+    pub fn start(players: usize) -> Result<MockServer> {
+        return MockServer::start_with_auth(players, MockAuth::default());
+    }
+
+    #[context("MockServer::start_with_auth()")]
+    pub fn start_with_auth(players: usize, auth: MockAuth) -> Result<MockServer> {
+        let socket = UdpSocket::bind("127.0.0.1:0").map_err(KCPError::IO)?;
+        socket
+            .set_read_timeout(Some(Duration::from_millis(KCP_INTERVAL)))
+            .map_err(KCPError::IO)?;
+        let addr = socket.local_addr().map_err(KCPError::IO)?;
+
+        let records = Arc::new(Mutex::new(MockRecords::default()));
+        let closed = Arc::new(AtomicBool::new(false));
+        let mut server = MockServerImpl {
+            socket,
+            players,
+            auth,
+            started: false,
+            sessions: HashMap::new(),
+            order: Vec::new(),
+            received: 0,
+            records: records.clone(),
+            closed: closed.clone(),
+            log: RateLimitedLogger::new(LOG_INTERVAL),
+        };
+        let thread = thread::Builder::new()
+            .name("mock-server".to_string())
+            .spawn(move || {
+                if let Err(err) = server.run() {
+                    println!("{:?}", err);
+                }
+            })
+            .map_err(KCPError::IO)?;
+
+        return Ok(MockServer {
+            addr,
+            records,
+            closed,
+            thread: Some(thread),
+        });
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        return self.addr;
+    }
+
+    pub fn records(&self) -> MockRecords {
+        return self.records.lock().unwrap().clone();
+    }
+
+    pub fn stop(&mut self) {
+        self.closed.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+struct MockOutput {
+    socket: UdpSocket,
+    peer: SocketAddr,
+    conv: u32,
+    records: Arc<Mutex<MockRecords>>,
+}
+
+unsafe extern "C" fn mock_output(
+    buf: *const c_char,
+    len: c_int,
+    _kcp: *mut IKCPCB,
+    user: *mut c_void,
+) -> c_int {
+    let output = &*(user as *const MockOutput);
+    let bytes = std::slice::from_raw_parts(buf as *const u8, len as usize);
+    let _ = output.socket.send_to(bytes, output.peer);
+    let records = &mut output.records.lock().unwrap();
+    records.bytes.entry(output.conv).or_default().1 += bytes.len() as u64;
+    return 0;
+}
+
+struct MockSession {
+    kcp: *mut IKCPCB,
+    // referenced by the kcp output callback, must outlive `kcp`
+    output: Box<MockOutput>,
+    state: NetPlayerState,
+    // the last challenge sent
+    nonce: Vec<u8>,
+    resume_token: Vec<u8>,
+    // of the accepted Connect
+    player_id: String,
+    // of a diagnostic Connect, never joins
+    diagnostic: bool,
+}
+
+impl MockSession {
+    #[context("MockSession::new()")]
+    fn new(
+        conv: u32,
+        socket: &UdpSocket,
+        peer: SocketAddr,
+        records: &Arc<Mutex<MockRecords>>,
+    ) -> Result<MockSession> {
+        let output = Box::new(MockOutput {
+            socket: socket.try_clone().map_err(KCPError::IO)?,
+            peer,
+            conv,
+            records: records.clone(),
+        });
+        let kcp = unsafe {
+            let kcp = ikcp_create(conv, &*output as *const MockOutput as *mut c_void);
+            ikcp_setoutput(kcp, Some(mock_output));
+            ikcp_setmtu(kcp, KCP_MTU as c_int);
+            ikcp_wndsize(kcp, KCP_WINDOW_SIZE as c_int, KCP_WINDOW_SIZE as c_int);
+            ikcp_nodelay(kcp, 1, KCP_INTERVAL as c_int, 2, 1);
+            kcp
+        };
+        return Ok(MockSession {
+            kcp,
+            output,
+            state: NetPlayerState::Initing,
+            nonce: Vec::new(),
+            resume_token: Vec::new(),
+            player_id: String::new(),
+            diagnostic: false,
+        });
+    }
+
+    // the first segment of a new kcp stream from another address, on a
+    // session that already received one: the client restarted
+    fn is_restart(&self, bytes: &[u8], peer: SocketAddr) -> bool {
+        let cmd = bytes[4];
+        let sn = LittleEndian::read_u32(&bytes[12..]);
+        let una = LittleEndian::read_u32(&bytes[16..]);
+        let received = unsafe { (*self.kcp).rcv_nxt };
+        return peer != self.output.peer
+            && cmd == MOCK_KCP_CMD_PUSH
+            && sn == 0
+            && una == 0
+            && received > 0;
+    }
+
+    fn input(&mut self, bytes: &[u8]) -> Result<(), KCPError> {
+        let ret = unsafe {
+            ikcp_input(
+                self.kcp,
+                bytes.as_ptr() as *const c_char,
+                bytes.len() as c_long,
+            )
+        };
+        if ret < 0 {
+            return Err(KCPError::KCP(KCPFailure::from_input(ret)));
+        }
+        return Ok(());
+    }
+
+    fn send(&mut self, bytes: &[u8]) -> Result<(), KCPError> {
+        if unsafe { ikcp_waitsnd(self.kcp) } as usize >= KCP_WINDOW_SIZE {
+            return Err(KCPError::KCP(KCPFailure::SendQueueFull));
+        }
+        let ret = unsafe {
+            ikcp_send(
+                self.kcp,
+                bytes.as_ptr() as *const c_char,
+                bytes.len() as c_int,
+            )
+        };
+        if ret < 0 {
+            return Err(KCPError::KCP(KCPFailure::from_send(ret)));
+        }
+        return Ok(());
+    }
+
+    // Ok(0) when no complete message is queued
+    fn recv(&mut self, buffer: &mut Vec<u8>) -> Result<usize, KCPError> {
+        buffer.resize(KCP_MAX_PACKET, 0);
+        let ret = unsafe {
+            ikcp_recv(
+                self.kcp,
+                buffer.as_mut_ptr() as *mut c_char,
+                buffer.len() as c_int,
+            )
+        };
+        if ret < 0 {
+            buffer.clear();
+            return match KCPFailure::from_recv(ret) {
+                KCPFailure::RecvQueueEmpty | KCPFailure::InvalidPeekSize => Ok(0),
+                failure => Err(KCPError::KCP(failure)),
+            };
+        }
+        buffer.truncate(ret as usize);
+        return Ok(ret as usize);
+    }
+
+    fn update(&mut self, current: u32) {
+        unsafe { ikcp_update(self.kcp, current) };
+    }
+}
+
+impl Drop for MockSession {
+    fn drop(&mut self) {
+        unsafe { ikcp_release(self.kcp) };
+    }
+}
+
+struct MockServerImpl {
+    socket: UdpSocket,
+    players: usize,
+    auth: MockAuth,
+    started: bool,
+    sessions: HashMap<u32, MockSession>,
+    order: Vec<u32>,
+    // datagrams from clients, for `drop_every`
+    received: usize,
+    records: Arc<Mutex<MockRecords>>,
+    closed: Arc<AtomicBool>,
+    // a misbehaving client shouldn't flood the test output
+    log: RateLimitedLogger,
+}
+
+unsafe impl Send for MockServerImpl {}
+
+impl MockServerImpl {
+    #[context("MockServerImpl::run()")]
+    fn run(&mut self) -> Result<()> {
+        let started_at = SystemTime::now();
+        let mut datagram = vec![0; KCP_MAX_PACKET];
+        let mut buffer = Vec::with_capacity(KCP_MAX_PACKET);
+
+        while !self.closed.load(Ordering::Relaxed) {
+            let now = started_at.elapsed().unwrap_or(Duration::ZERO).as_millis() as u64;
+            match self.socket.recv_from(&mut datagram) {
+                Ok((len, peer)) => self.handle_datagram(&datagram[..len], peer, now)?,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+                Err(err) if err.kind() == ErrorKind::TimedOut => {}
+                Err(err) => return Err(KCPError::IO(err).into()),
+            };
+
+            let current = started_at.elapsed().unwrap_or(Duration::ZERO).as_millis() as u32;
+            for idx in 0..self.order.len() {
+                let conv = self.order[idx];
+                self.sessions.get_mut(&conv).unwrap().update(current);
+                loop {
+                    let len = self.sessions.get_mut(&conv).unwrap().recv(&mut buffer)?;
+                    if len == 0 {
+                        break;
+                    }
+                    let err = match self.handle_message(conv, &buffer) {
+                        Ok(()) => continue,
+                        Err(err) => err,
+                    };
+                    match err.downcast_ref::<KCPError>() {
+                        Some(kcp_err) if kcp_err.is_malformed() => {
+                            self.log.count("undecodable messages", now);
+                        }
+                        _ => println!("{:?}", err),
+                    };
+                }
+            }
+            self.log.flush(now);
+        }
+        return Ok(());
+    }
+
+    #[context("MockServerImpl::handle_datagram()")]
+    fn handle_datagram(&mut self, bytes: &[u8], peer: SocketAddr, now: u64) -> Result<()> {
+        self.received += 1;
+        let drop_every = self.auth.drop_every;
+        if drop_every > 0 && self.received % drop_every == 0 {
+            return Ok(());
+        }
+        match Datagram::demux(bytes) {
+            Ok(Datagram::KCP(_)) => {}
+            Ok(Datagram::Unreliable(conv, payload)) => {
+                return self.relay_unreliable(conv, payload);
+            }
+            Err(_) => {
+                self.records.lock().unwrap().malformed += 1;
+                self.log.count("malformed datagrams", now);
+                return Ok(());
+            }
+        };
+
+        // shorter datagrams can't carry a conv
+        if bytes.len() < KCP_OVERHEAD {
+            return Ok(());
+        }
+        let conv = LittleEndian::read_u32(bytes);
+        if !self.sessions.contains_key(&conv) {
+            let session = MockSession::new(conv, &self.socket, peer, &self.records)?;
+            self.sessions.insert(conv, session);
+            self.order.push(conv);
+        }
+        let session = self.sessions.get_mut(&conv).unwrap();
+        if session.is_restart(bytes, peer) {
+            let mut restarted = MockSession::new(conv, &self.socket, peer, &self.records)?;
+            restarted.state = session.state;
+            restarted.resume_token = mem::take(&mut session.resume_token);
+            *session = restarted;
+        }
+        if session.output.peer != peer {
+            session.output.peer = peer;
+            self.records.lock().unwrap().migrations.push((conv, peer));
+        }
+        let _ = session.input(bytes);
+        let records = &mut self.records.lock().unwrap();
+        records.bytes.entry(conv).or_default().0 += bytes.len() as u64;
+        return Ok(());
+    }
+
+    #[context("MockServerImpl::relay_unreliable()")]
+    fn relay_unreliable(&mut self, conv: u32, payload: &[u8]) -> Result<()> {
+        self.records
+            .lock()
+            .unwrap()
+            .unreliable
+            .push((conv, payload.to_vec()));
+
+        let mut bytes = Vec::with_capacity(KCP_MTU);
+        Datagram::encode_unreliable(conv, payload, &mut bytes)?;
+        if let Some(session) = self
+            .sessions
+            .get(&conv)
+            .filter(|session| session.diagnostic)
+        {
+            let _ = self.socket.send_to(&bytes, session.output.peer);
+            return Ok(());
+        }
+        for idx in 0..self.order.len() {
+            let session = &self.sessions[&self.order[idx]];
+            if self.order[idx] != conv && !session.diagnostic {
+                let _ = self.socket.send_to(&bytes, session.output.peer);
+            }
+        }
+        return Ok(());
+    }
+
+    #[context("MockServerImpl::handle_message()")]
+    fn handle_message(&mut self, conv: u32, bytes: &[u8]) -> Result<()> {
+        let (msg, offset) = NetMessage::decode(bytes)?;
+        match msg {
+            NetMessage::Connect(connect) => {
+                self.records
+                    .lock()
+                    .unwrap()
+                    .connects
+                    .push((conv, connect.clone()));
+                if self.auth.silent {
+                    return Ok(());
+                }
+                if connect.diagnostic {
+                    self.sessions.get_mut(&conv).unwrap().diagnostic = true;
+                    return self.send_to(conv, &NetMessage::accept());
+                }
+                if !connect.resume_token.is_empty() {
+                    return self.resume(conv, &connect);
+                }
+                if self.authenticate(conv, &connect)? {
+                    self.take_over(conv, &connect.player_id)?;
+                    let accept = self.accept(conv, &connect);
+                    self.send_to(conv, &accept)?;
+                    self.set_state(conv, NetPlayerState::Waiting)?;
+                    self.try_start()?;
+                }
+            }
+            NetMessage::Command(mut command) => {
+                self.records
+                    .lock()
+                    .unwrap()
+                    .commands
+                    .push((conv, command.frame));
+                command.conv = conv;
+                let frame = command.frame;
+                let mut relay = Vec::with_capacity(bytes.len());
+                NetMessage::Command(command).encode(&mut relay)?;
+                relay.extend_from_slice(&bytes[offset..]);
+                for idx in 0..self.order.len() {
+                    if self.auth.no_echo && self.order[idx] == conv {
+                        continue;
+                    }
+                    let session = self.sessions.get_mut(&self.order[idx]).unwrap();
+                    if session.state == NetPlayerState::Running {
+                        session.send(&relay)?;
+                    }
+                }
+                if self.auth.acks {
+                    let state = self.sessions[&conv].state;
+                    let mut ack = NetMessage::state(conv, state);
+                    if let NetMessage::State(msg) = &mut ack {
+                        msg.acked_frame = frame;
+                    }
+                    self.send_to(conv, &ack)?;
+                }
+            }
+            NetMessage::Hash(hash) => {
+                self.records.lock().unwrap().hashes.push((conv, hash));
+            }
+            // presence, relayed without touching the session state
+            NetMessage::State(state) => {
+                let presence = state.state();
+                self.records
+                    .lock()
+                    .unwrap()
+                    .presences
+                    .push((conv, presence));
+                let msg = NetMessage::state(conv, presence);
+                for idx in 0..self.order.len() {
+                    let other = self.order[idx];
+                    if other != conv && self.sessions[&other].state == NetPlayerState::Running {
+                        self.send_to(other, &msg)?;
+                    }
+                }
+            }
+            NetMessage::Finish(finish) => {
+                self.records.lock().unwrap().finishes.push((conv, finish));
+                if !self.sessions[&conv].diagnostic {
+                    self.set_state(conv, NetPlayerState::Stopped)?;
+                }
+            }
+            _ => return Err(KCPError::UnexpectedPacket.into()),
+        };
+        return Ok(());
+    }
+
+    // false when the Connect was answered with a challenge or refused, a
+    // refused session stays Initing and may connect again
+    #[context("MockServerImpl::authenticate()")]
+    fn authenticate(&mut self, conv: u32, connect: &NetConnect) -> Result<bool> {
+        let password = match &self.auth.password {
+            Some(password) => password.clone(),
+            None => return Ok(true),
+        };
+        let session = self.sessions.get_mut(&conv).unwrap();
+        if self.auth.challenge && connect.wants_challenge {
+            session.nonce = Self::random_bytes(conv);
+            let challenge = NetMessage::challenge(&session.nonce);
+            self.send_to(conv, &challenge)?;
+            return Ok(false);
+        }
+
+        let accepted = match self.auth.challenge && !connect.response.is_empty() {
+            true => {
+                let expected = challenge_response(
+                    &password,
+                    &session.nonce,
+                    &connect.room_id,
+                    &connect.player_id,
+                );
+                !session.nonce.is_empty() && connect.response == expected
+            }
+            false => connect.password == password,
+        };
+        if !accepted {
+            self.send_to(conv, &NetMessage::finish(0, NetFinishCause::AuthFailed))?;
+        }
+        return Ok(accepted);
+    }
+
+    // the conv that joined as `player_id` before is stopped and told with an
+    // Accept it can't expect, like servers predating NetFinishCause::Superseded
+    #[context("MockServerImpl::take_over()")]
+    fn take_over(&mut self, conv: u32, player_id: &str) -> Result<()> {
+        self.sessions.get_mut(&conv).unwrap().player_id = player_id.to_string();
+        if !self.auth.takeover {
+            return Ok(());
+        }
+        let replaced = self.order.iter().copied().find(|other| {
+            let session = &self.sessions[other];
+            let joined = matches!(
+                session.state,
+                NetPlayerState::Waiting | NetPlayerState::Running
+            );
+            return *other != conv && joined && session.player_id == player_id;
+        });
+        let replaced = match replaced {
+            Some(replaced) => replaced,
+            None => return Ok(()),
+        };
+        self.send_to(replaced, &NetMessage::accept())?;
+        return self.set_state(replaced, NetPlayerState::Stopped);
+    }
+
+    // issues a resume token when the server supports resuming
+    fn accept(&mut self, conv: u32, connect: &NetConnect) -> NetMessage {
+        let mut accept = match self.auth.resume {
+            true => {
+                let session = self.sessions.get_mut(&conv).unwrap();
+                session.resume_token = Self::random_bytes(conv);
+                NetMessage::accept_resumable(&session.resume_token)
+            }
+            false => NetMessage::accept(),
+        };
+        if let NetMessage::Accept(msg) = &mut accept {
+            let dictionary_id = connect.dictionary_id;
+            if dictionary_id != 0 && dictionary_id == self.auth.dictionary_id {
+                msg.dictionary_id = dictionary_id;
+            }
+            msg.capabilities = connect.capabilities & self.auth.capabilities;
+        }
+        return accept;
+    }
+
+    // a restarted client rejoining with its token, the match goes on where
+    // the session left it
+    #[context("MockServerImpl::resume()")]
+    fn resume(&mut self, conv: u32, connect: &NetConnect) -> Result<()> {
+        let session = &self.sessions[&conv];
+        let state = session.state;
+        let valid = self.auth.resume && connect.resume_token == session.resume_token;
+        let joined = matches!(state, NetPlayerState::Waiting | NetPlayerState::Running);
+        if !valid || !joined {
+            return self.send_to(conv, &NetMessage::finish(0, NetFinishCause::AuthFailed));
+        }
+        let mut accept = NetMessage::accept_resumable(&connect.resume_token);
+        if let NetMessage::Accept(msg) = &mut accept {
+            msg.capabilities = connect.capabilities & self.auth.capabilities;
+        }
+        self.send_to(conv, &accept)?;
+        if state == NetPlayerState::Running {
+            self.send_to(conv, &NetMessage::Start(NetStart::default()))?;
+        }
+        return Ok(());
+    }
+
+    // unique enough for tests, not secure
+    fn random_bytes(conv: u32) -> Vec<u8> {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or(Duration::ZERO)
+            .as_nanos() as u64;
+        let seed = nanos ^ ((conv as u64) << 32);
+        return [seed.to_be_bytes(), (!seed).to_be_bytes()].concat();
+    }
+
+    #[context("MockServerImpl::try_start()")]
+    fn try_start(&mut self) -> Result<()> {
+        let waiting = self
+            .sessions
+            .values()
+            .filter(|session| session.state == NetPlayerState::Waiting)
+            .count();
+        if !self.started && waiting < self.players {
+            return Ok(());
+        }
+        self.started = true;
+
+        for idx in 0..self.order.len() {
+            let conv = self.order[idx];
+            if self.sessions[&conv].state == NetPlayerState::Waiting {
+                self.send_to(conv, &NetMessage::Start(NetStart::default()))?;
+                self.set_state(conv, NetPlayerState::Running)?;
+            }
+        }
+        return Ok(());
+    }
+
+    // broadcasts the new state to everyone past the handshake
+    #[context("MockServerImpl::set_state()")]
+    fn set_state(&mut self, conv: u32, state: NetPlayerState) -> Result<()> {
+        self.sessions.get_mut(&conv).unwrap().state = state;
+
+        let mut net_state = NetState::default();
+        net_state.conv = conv;
+        net_state.set_state(state);
+        let msg = NetMessage::State(net_state);
+        for idx in 0..self.order.len() {
+            let other = self.order[idx];
+            if self.sessions[&other].state != NetPlayerState::Initing {
+                self.send_to(other, &msg)?;
+            }
+        }
+        return Ok(());
+    }
+
+    #[context("MockServerImpl::send_to()")]
+    fn send_to(&mut self, conv: u32, msg: &NetMessage) -> Result<()> {
+        let mut bytes = Vec::with_capacity(KCP_MAX_PACKET);
+        msg.encode(&mut bytes)?;
+        self.sessions.get_mut(&conv).unwrap().send(&bytes)?;
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::base::UNRELIABLE_HEADER;
+    use crate::codec::CommandEncoder;
+    use crate::message::NetFinishCause;
+
+    fn deliver(socket: &UdpSocket, session: &mut MockSession) {
+        let mut datagram = vec![0; KCP_MAX_PACKET];
+        while let Ok((len, _)) = socket.recv_from(&mut datagram) {
+            session.input(&datagram[..len]).unwrap();
+        }
+    }
+
+    fn recv_until<F: Fn(&NetMessage) -> bool>(
+        session: &mut MockSession,
+        socket: &UdpSocket,
+        started_at: SystemTime,
+        pred: F,
+    ) -> bool {
+        let mut datagram = vec![0; KCP_MAX_PACKET];
+        let mut buffer = Vec::new();
+        while started_at.elapsed().unwrap().as_secs() < 5 {
+            let current = started_at.elapsed().unwrap().as_millis() as u32;
+            session.update(current);
+            if let Ok((len, _)) = socket.recv_from(&mut datagram) {
+                let _ = session.input(&datagram[..len]);
+            }
+            while session.recv(&mut buffer).unwrap() > 0 {
+                let (msg, _) = NetMessage::decode(&buffer).unwrap();
+                if pred(&msg) {
+                    return true;
+                }
+            }
+        }
+        return false;
+    }
+
+    #[test]
+    fn test_mock_session_failures() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        let addr = socket.local_addr().unwrap();
+        let records = Arc::default();
+        let mut sender = MockSession::new(7777, &socket, addr, &records).unwrap();
+        let mut receiver = MockSession::new(7777, &socket, addr, &records).unwrap();
+
+        sender.send(&vec![1; KCP_MAX_PACKET + 1]).unwrap();
+        sender.update(0);
+        deliver(&socket, &mut receiver);
+        let mut buffer = Vec::new();
+        let err = receiver.recv(&mut buffer).unwrap_err();
+        assert_eq!(err.kcp_failure(), Some(KCPFailure::BufferTooSmall));
+        assert_eq!(err.cause(), NetFinishCause::ClientError);
+
+        let err = sender.send(&vec![1; KCP_MTU * 256]).unwrap_err();
+        assert_eq!(
+            err.kcp_failure(),
+            Some(KCPFailure::MessageTooLargeForWindow)
+        );
+
+        let mut flooder = MockSession::new(8888, &socket, addr, &records).unwrap();
+        let mut sent = 0;
+        let err = loop {
+            match flooder.send(&[1, 2, 3]) {
+                Ok(()) => sent += 1,
+                Err(err) => break err,
+            }
+        };
+        assert_eq!(sent, KCP_WINDOW_SIZE);
+        assert_eq!(err.kcp_failure(), Some(KCPFailure::SendQueueFull));
+        assert_eq!(err.cause(), NetFinishCause::NetworkBroken);
+
+        let err = receiver.input(&[0; 8]).unwrap_err();
+        assert_eq!(err.kcp_failure(), Some(KCPFailure::InputRejected));
+        assert_eq!(err.cause(), NetFinishCause::InvalidPacket);
+    }
+
+    #[test]
+    fn test_mock_server_unreliable() {
+        let server = MockServer::start(2).unwrap();
+        let client = |conv: u32| {
+            let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+            socket
+                .set_read_timeout(Some(Duration::from_millis(500)))
+                .unwrap();
+            // any kcp sized datagram registers the conv and its address
+            let mut hello = vec![0; KCP_OVERHEAD];
+            LittleEndian::write_u32(&mut hello, conv);
+            socket.send_to(&hello, server.addr()).unwrap();
+            return socket;
+        };
+        let alice = client(7777);
+        let bob = client(8888);
+        thread::sleep(Duration::from_millis(50));
+
+        let mut bytes = Vec::new();
+        bytes.resize(UNRELIABLE_HEADER + 1, 0xff);
+        alice.send_to(&bytes[..6], server.addr()).unwrap();
+        alice.send_to(&bytes, server.addr()).unwrap();
+        bytes.clear();
+        Datagram::encode_unreliable(7777, b"ping", &mut bytes).unwrap();
+        alice.send_to(&bytes, server.addr()).unwrap();
+
+        let mut datagram = vec![0; KCP_MAX_PACKET];
+        let len = loop {
+            let (len, _) = bob.recv_from(&mut datagram).unwrap();
+            if let Ok(Datagram::Unreliable(..)) = Datagram::demux(&datagram[..len]) {
+                break len;
+            }
+        };
+        assert_eq!(
+            Datagram::demux(&datagram[..len]).unwrap(),
+            Datagram::Unreliable(7777, b"ping")
+        );
+
+        let records = server.records();
+        assert_eq!(records.unreliable, vec![(7777, b"ping".to_vec())]);
+        assert_eq!(records.malformed, 2);
+        assert!(records.connects.is_empty());
+    }
+
+    #[test]
+    fn test_mock_server_rebind() {
+        let server = MockServer::start(1).unwrap();
+        let wifi = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let lte = UdpSocket::bind("127.0.0.1:0").unwrap();
+        for socket in [&wifi, &lte] {
+            socket
+                .set_read_timeout(Some(Duration::from_millis(KCP_INTERVAL)))
+                .unwrap();
+        }
+        let started_at = SystemTime::now();
+        let records = Arc::default();
+        let mut client = MockSession::new(7777, &wifi, server.addr(), &records).unwrap();
+
+        let mut bytes = Vec::new();
+        NetMessage::Connect(NetConnect::default())
+            .encode(&mut bytes)
+            .unwrap();
+        client.send(&bytes).unwrap();
+        assert!(recv_until(&mut client, &wifi, started_at, |msg| {
+            matches!(msg, NetMessage::Start(_))
+        }));
+
+        // the device switched networks, same conv and kcp state
+        client.output.socket = lte.try_clone().unwrap();
+        let mut ce = CommandEncoder::new(0);
+        ce.encode(1).unwrap();
+        client.send(ce.command_bytes()).unwrap();
+        assert!(recv_until(&mut client, &lte, started_at, |msg| {
+            matches!(msg, NetMessage::Command(cmd) if cmd.conv == 7777 && cmd.frame == 1)
+        }));
+
+        let records = server.records();
+        assert_eq!(records.connects.len(), 1);
+        assert_eq!(records.commands, vec![(7777, 1)]);
+        assert_eq!(records.migrations, vec![(7777, lte.local_addr().unwrap())]);
+    }
+}
@@ -0,0 +1,76 @@
+// Compile-time checks for no-float-commands: a float field added to a
+// command layout or to message.proto fails the build here instead of
+// reaching the wire. Nothing here is called, it only has to compile.
+#![allow(dead_code)]
+
+use crate::codec::{Command, CommandV1};
+
+// what command fields may be, floats deliberately have no impl
+trait WireField {}
+
+impl WireField for bool {}
+impl WireField for u8 {}
+impl WireField for u16 {}
+impl WireField for u32 {}
+impl WireField for u64 {}
+impl WireField for i8 {}
+impl WireField for i16 {}
+impl WireField for i32 {}
+impl WireField for i64 {}
+impl WireField for String {}
+impl<T: WireField> WireField for Vec<T> {}
+impl<T: WireField> WireField for Option<T> {}
+
+fn field<T: WireField>(_: &T) {}
+
+// no wildcard arms: a new variant must have its fields listed here
+fn command_fields(command: &Command) {
+    match command {
+        Command::Aaa(a, b) => {
+            field(a);
+            field(b);
+        }
+    };
+}
+
+fn command_v1_fields(command: &CommandV1) {
+    match command {
+        CommandV1::Aaa(a, b) => {
+            field(a);
+            field(b);
+        }
+    };
+}
+
+const PROTO: &[u8] = include_bytes!("message.proto");
+
+const fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    let mut start = 0;
+    while start + needle.len() <= haystack.len() {
+        let mut idx = 0;
+        while idx < needle.len() && haystack[start + idx] == needle[idx] {
+            idx += 1;
+        }
+        if idx == needle.len() {
+            return true;
+        }
+        start += 1;
+    }
+    return false;
+}
+
+// the scalar types of protobuf floats, as fields are declared
+const _: () = assert!(!contains(PROTO, b"float ") && !contains(PROTO, b"double "));
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_contains() {
+        assert!(contains(b"  double x = 1;", b"double "));
+        assert!(!contains(b"  uint32 doubled = 1;", b"double "));
+        assert!(!contains(b"", b"float "));
+        assert!(!contains(PROTO, b"float "));
+    }
+}
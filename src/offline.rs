@@ -0,0 +1,256 @@
+use crate::bandwidth::{Bandwidth, BandwidthMeter};
+use crate::base::{Capabilities, KCPError};
+use crate::codec::NetMessage;
+use crate::message::NetPlayerState;
+use anyhow::Result;
+use fn_error_context::context;
+use std::collections::VecDeque;
+
+// the null server behind Client::offline(), times are in ms
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OfflineConfig {
+    // from the accept until the match starts
+    pub start_delay: u64,
+    // until a command frame is echoed back
+    pub latency: u64,
+}
+
+// Stands in for kcp and the server of a single player match: accepts every
+// Connect, starts `start_delay` ms later and echoes command packets back
+// stamped with our conv, with the same messages in the same order as the
+// mock server, so the worker can't tell it from a real session. Padding and
+// compressed tails are echoed as they came, the worker unpacks its own.
+#[derive(Debug)]
+pub struct NullServer {
+    conv: u32,
+    config: OfflineConfig,
+    state: NetPlayerState,
+    current: u64,
+    start_at: Option<u64>,
+    // encoded messages for the worker and when they are due, in order
+    queue: VecDeque<(u64, Vec<u8>)>,
+    meter: BandwidthMeter,
+    // what the worker sends still arrives, nothing comes back
+    #[cfg(test)]
+    muted: bool,
+}
+
+impl NullServer {
+    pub fn new(conv: u32, config: OfflineConfig) -> NullServer {
+        return NullServer {
+            conv,
+            config,
+            state: NetPlayerState::Initing,
+            current: 0,
+            start_at: None,
+            queue: VecDeque::new(),
+            meter: BandwidthMeter::new(0),
+            #[cfg(test)]
+            muted: false,
+        };
+    }
+
+    pub fn state(&self) -> NetPlayerState {
+        return self.state;
+    }
+
+    // a packet from the worker
+    #[context("NullServer::send()")]
+    pub fn send(&mut self, bytes: &[u8]) -> Result<()> {
+        // frames without a hash
+        if bytes.is_empty() {
+            return Ok(());
+        }
+        self.meter.on_sent(bytes.len(), self.current);
+        let (msg, offset) = NetMessage::decode(bytes)?;
+        match msg {
+            NetMessage::Connect(connect) => {
+                if self.state != NetPlayerState::Initing {
+                    return Ok(());
+                }
+                let mut accept = NetMessage::accept();
+                if let NetMessage::Accept(msg) = &mut accept {
+                    let mut granted = Capabilities::PADDING;
+                    granted.insert(Capabilities::COMPRESSION);
+                    msg.capabilities = connect.capabilities & granted.bits();
+                    msg.dictionary_id = connect.dictionary_id;
+                }
+                self.push(&accept)?;
+                self.set_state(NetPlayerState::Waiting)?;
+                self.start_at = Some(self.current + self.config.start_delay);
+                self.try_start()?;
+            }
+            NetMessage::Command(mut command) => {
+                if self.state != NetPlayerState::Running {
+                    return Ok(());
+                }
+                command.conv = self.conv;
+                let mut echo = Vec::with_capacity(bytes.len());
+                NetMessage::Command(command).encode(&mut echo)?;
+                echo.extend_from_slice(&bytes[offset..]);
+                self.queue
+                    .push_back((self.current + self.config.latency, echo));
+            }
+            // nobody else to compare with or tell
+            NetMessage::Hash(_) | NetMessage::State(_) => {}
+            NetMessage::Finish(_) => self.set_state(NetPlayerState::Stopped)?,
+            _ => return Err(KCPError::UnexpectedPacket.into()),
+        };
+        return Ok(());
+    }
+
+    // the next due message, like ikcp_recv it stays queued when it doesn't
+    // fit `buffer`
+    #[context("NullServer::recv()")]
+    pub fn recv(&mut self, buffer: &mut [u8]) -> Result<Option<usize>> {
+        self.try_start()?;
+        #[cfg(test)]
+        if self.muted {
+            return Ok(None);
+        }
+        let bytes = match self.queue.front() {
+            Some((at, bytes)) if *at <= self.current => bytes,
+            _ => return Ok(None),
+        };
+        let len = bytes.len();
+        if len > buffer.len() {
+            return Err(KCPError::Oversized(len).into());
+        }
+        buffer[..len].copy_from_slice(bytes);
+        self.queue.pop_front();
+        self.meter.on_recv(len, self.current);
+        return Ok(Some(len));
+    }
+
+    pub fn update(&mut self, current: u64) {
+        self.current = current;
+    }
+
+    // as if the server sent `msg` at `at`
+    #[cfg(test)]
+    pub fn inject(&mut self, at: u64, msg: &NetMessage) -> Result<()> {
+        let mut bytes = Vec::new();
+        msg.encode(&mut bytes)?;
+        self.queue.push_back((at, bytes));
+        return Ok(());
+    }
+
+    #[cfg(test)]
+    pub fn mute(&mut self) {
+        self.muted = true;
+    }
+
+    pub fn bandwidth(&mut self, current: u64) -> Bandwidth {
+        return self.meter.bandwidth(current);
+    }
+
+    fn try_start(&mut self) -> Result<()> {
+        match self.start_at {
+            Some(at) if at <= self.current => {}
+            _ => return Ok(()),
+        };
+        self.start_at = None;
+        self.push(&NetMessage::start())?;
+        return self.set_state(NetPlayerState::Running);
+    }
+
+    fn set_state(&mut self, state: NetPlayerState) -> Result<()> {
+        self.state = state;
+        return self.push(&NetMessage::state(self.conv, state));
+    }
+
+    fn push(&mut self, msg: &NetMessage) -> Result<()> {
+        let mut bytes = Vec::new();
+        msg.encode(&mut bytes)?;
+        self.queue.push_back((self.current, bytes));
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::base::KCP_MAX_PACKET;
+    use crate::codec::{Command, CommandDecoder, CommandEncoder};
+    use crate::message::NetFinishCause;
+
+    fn recv_all(server: &mut NullServer) -> Vec<NetMessage> {
+        let mut buffer = vec![0; KCP_MAX_PACKET];
+        let mut msgs = Vec::new();
+        while let Some(len) = server.recv(&mut buffer).unwrap() {
+            msgs.push(NetMessage::decode(&buffer[..len]).unwrap().0);
+        }
+        return msgs;
+    }
+
+    #[test]
+    fn test_null_server() {
+        let config = OfflineConfig {
+            start_delay: 100,
+            latency: 30,
+        };
+        let mut server = NullServer::new(6666, config);
+        let mut connect = NetMessage::connect("room", "player", "");
+        if let NetMessage::Connect(msg) = &mut connect {
+            msg.capabilities = Capabilities::COMPRESSION.bits() | Capabilities::COMMANDS_V2.bits();
+            msg.dictionary_id = 42;
+        }
+        let mut bytes = Vec::new();
+        connect.encode(&mut bytes).unwrap();
+        server.send(&bytes).unwrap();
+        let msgs = recv_all(&mut server);
+        // compressed tails are echoed untouched, so any dictionary will do
+        match &msgs[0] {
+            NetMessage::Accept(accept) => {
+                assert_eq!(accept.capabilities, Capabilities::COMPRESSION.bits());
+                assert_eq!(accept.dictionary_id, 42);
+            }
+            msg => panic!("unexpected {:?}", msg),
+        };
+        assert!(
+            matches!(&msgs[1], NetMessage::State(state) if state.state() == NetPlayerState::Waiting)
+        );
+        assert_eq!(msgs.len(), 2);
+
+        // not running yet, dropped like the server does
+        let mut ce = CommandEncoder::new(0);
+        ce.commands().push(Command::Aaa(97, -101));
+        ce.encode(1).unwrap();
+        server.send(ce.command_bytes()).unwrap();
+        server.update(99);
+        assert!(recv_all(&mut server).is_empty());
+        server.update(100);
+        let msgs = recv_all(&mut server);
+        assert!(matches!(msgs[0], NetMessage::Start(_)));
+        assert!(
+            matches!(&msgs[1], NetMessage::State(state) if state.state() == NetPlayerState::Running)
+        );
+        assert_eq!(server.state(), NetPlayerState::Running);
+
+        ce.encode(2).unwrap();
+        server.send(ce.command_bytes()).unwrap();
+        server.update(129);
+        assert!(recv_all(&mut server).is_empty());
+        server.update(130);
+        let mut buffer = vec![0; KCP_MAX_PACKET];
+        // too big for the buffer, still queued
+        let err = server.recv(&mut buffer[..4]).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<KCPError>(),
+            Some(KCPError::Oversized(_))
+        ));
+        let len = server.recv(&mut buffer).unwrap().unwrap();
+        let mut decoder = CommandDecoder::new(0);
+        decoder.decode(&buffer[..len]).unwrap();
+        assert_eq!(decoder.conv(), 6666);
+        assert_eq!(decoder.frame(), 2);
+        assert_eq!(server.bandwidth(130).recv_total.datagrams, 5);
+
+        let mut finish = Vec::new();
+        NetMessage::finish(2, NetFinishCause::GameOver)
+            .encode(&mut finish)
+            .unwrap();
+        server.send(&finish).unwrap();
+        assert_eq!(server.state(), NetPlayerState::Stopped);
+    }
+}
@@ -0,0 +1,117 @@
+use crate::chan::NetChan;
+use crate::client::GameHandle;
+use crate::codec::Command;
+use crate::mock::{MockAuth, MockServer};
+use crate::timing::TickTimings;
+use crate::worker::{NetWorker, WorkerConfig};
+use std::collections::BTreeMap;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// The standard loopback workload the tick timings are checked on. A failure
+// here means a tick got slower, raise the budget only when that is intended.
+const PLAYERS: usize = 16;
+const FRAMES: u32 = 1000;
+// 60 Hz
+const FRAME_INTERVAL: Duration = Duration::from_micros(16_667);
+// of the datagrams from clients, 5%
+const DROP_EVERY: usize = 20;
+// p99 of the processing time per tick, udp wait excluded
+const TICK_P99_BUDGET: Duration = Duration::from_micros(2048);
+
+// every worker on this thread like a SessionManager, each sends a frame per
+// FRAME_INTERVAL and is stepped in between, the timings of all merged
+fn perf_report() -> TickTimings {
+    let auth = MockAuth {
+        drop_every: DROP_EVERY,
+        ..MockAuth::default()
+    };
+    let server = MockServer::start_with_auth(PLAYERS, auth).unwrap();
+    let mut players = Vec::with_capacity(PLAYERS);
+    for i in 0..PLAYERS {
+        let conv = 6666 + i as u32;
+        let config = WorkerConfig {
+            tick_timings: true,
+            ..WorkerConfig::default()
+        };
+        let chan = NetChan::new();
+        let player = format!("player{}", i);
+        let mut worker = NetWorker::with_config(
+            server.addr(),
+            conv,
+            "room",
+            &player,
+            "",
+            chan.clone(),
+            config,
+        )
+        .unwrap();
+        worker.start().unwrap();
+        players.push((worker, GameHandle::new(conv, chan.clone()), chan));
+    }
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while players
+        .iter()
+        .any(|(_, _, chan)| chan.start_info().is_none())
+    {
+        assert!(Instant::now() < deadline, "timeout");
+        step(&mut players);
+    }
+
+    let (mut commands, mut states, mut events) = (Vec::new(), BTreeMap::new(), Vec::new());
+    let mut next = Instant::now();
+    for frame in 1..=FRAMES {
+        for (_, handle, _) in &players {
+            let command = Command::Aaa(handle.conv() as i32, frame as i32);
+            handle.send_input(frame, &[command], &[]).unwrap();
+        }
+        next += FRAME_INTERVAL;
+        while Instant::now() < next {
+            step(&mut players);
+            for (_, handle, _) in &players {
+                handle.recv_output(&mut commands, &mut states).unwrap();
+                handle.recv_events(&mut events);
+            }
+            commands.clear();
+            events.clear();
+        }
+    }
+
+    let mut timings = TickTimings::default();
+    for (_, handle, _) in &players {
+        timings.merge(&handle.stats().tick_timings.unwrap());
+    }
+    return timings;
+}
+
+fn step(players: &mut [(NetWorker, GameHandle, NetChan)]) {
+    for (worker, _, _) in players.iter_mut() {
+        worker.step().unwrap();
+    }
+    thread::sleep(Duration::from_millis(1));
+}
+
+// a regression gate on wall time, run on its own in release:
+// cargo test --release test_perf_report -- --ignored
+#[test]
+#[ignore]
+fn test_perf_report() {
+    let timings = perf_report();
+    let p99 = timings.processing.percentile(99);
+    let report = format!(
+        "ticks {} processing p50 {:?} p99 {:?} max {:?}, p99 output {:?} input {:?} kcp {:?}",
+        timings.processing.count,
+        timings.processing.percentile(50),
+        p99,
+        timings.processing.max,
+        timings.output.percentile(99),
+        timings.input.percentile(99),
+        timings.kcp.percentile(99),
+    );
+    assert!(
+        timings.processing.count >= PLAYERS as u64 * FRAMES as u64,
+        "{}",
+        report
+    );
+    assert!(p99 <= TICK_P99_BUDGET, "p99 over budget: {}", report);
+}
@@ -0,0 +1,79 @@
+use crate::base::COMMANDS_INLINE;
+use crate::codec::{Command, Commands};
+use std::fmt;
+use std::mem;
+use std::sync::Arc;
+
+// Hands out the per-frame buffers otherwise taken from the global allocator,
+// e.g. from a frame arena. `hint` is the capacity wanted, what is acquired
+// comes back empty and grows past it like any Vec. Shared by the chan, the
+// worker and the game thread.
+pub trait BufferPool: fmt::Debug + Send + Sync {
+    fn acquire_bytes(&self, hint: usize) -> Vec<u8>;
+    fn release_bytes(&self, bytes: Vec<u8>);
+    fn acquire_commands(&self, hint: usize) -> Vec<Command>;
+    fn release_commands(&self, commands: Vec<Command>);
+}
+
+pub type SharedPool = Option<Arc<dyn BufferPool>>;
+
+pub fn acquire_bytes(pool: &SharedPool, hint: usize) -> Vec<u8> {
+    return match pool {
+        Some(pool) => pool.acquire_bytes(hint),
+        None => Vec::with_capacity(hint),
+    };
+}
+
+pub fn release_bytes(pool: &SharedPool, bytes: &mut Vec<u8>) {
+    if let Some(pool) = pool {
+        let mut bytes = mem::take(bytes);
+        bytes.clear();
+        pool.release_bytes(bytes);
+    }
+}
+
+// empty, with room for `len`: inline up to COMMANDS_INLINE, spilled into a
+// pool buffer past it
+pub fn acquire_commands(pool: &SharedPool, len: usize) -> Commands {
+    return match pool {
+        Some(pool) if len > COMMANDS_INLINE => Commands::from_vec(pool.acquire_commands(len)),
+        _ => Commands::new(),
+    };
+}
+
+// back to inline and empty, a spilled buffer goes to the pool, or is freed
+// without one
+pub fn release_commands(pool: &SharedPool, commands: &mut Commands) {
+    if !commands.spilled() {
+        commands.clear();
+        return;
+    }
+    let mut spilled = mem::take(commands).into_vec();
+    match pool {
+        Some(pool) => {
+            spilled.clear();
+            pool.release_commands(spilled);
+        }
+        None => drop(spilled),
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pool_without_pool() {
+        let mut commands = acquire_commands(&None, 8);
+        assert!(!commands.spilled());
+        commands.extend((0..8).map(|idx| Command::Aaa(idx, 0)));
+        assert!(commands.spilled());
+        release_commands(&None, &mut commands);
+        assert!(commands.is_empty() && !commands.spilled());
+
+        let mut bytes = acquire_bytes(&None, 16);
+        assert_eq!(bytes.capacity(), 16);
+        release_bytes(&None, &mut bytes);
+        assert_eq!(bytes.capacity(), 16);
+    }
+}
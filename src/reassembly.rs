@@ -0,0 +1,305 @@
+use crate::base::{KCPError, REASSEMBLY_MAX_AGE, REASSEMBLY_MAX_BYTES, REASSEMBLY_MAX_TRANSFER};
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReassemblyLimits {
+    // all incomplete transfers together
+    pub max_bytes: usize,
+    // declared size of one transfer, at most max_bytes
+    pub max_transfer: usize,
+    // ms since the last chunk
+    pub max_age: u64,
+}
+
+impl Default for ReassemblyLimits {
+    fn default() -> ReassemblyLimits {
+        return ReassemblyLimits {
+            max_bytes: REASSEMBLY_MAX_BYTES,
+            max_transfer: REASSEMBLY_MAX_TRANSFER,
+            max_age: REASSEMBLY_MAX_AGE,
+        };
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TransferKind {
+    Fragment,
+    Snapshot,
+}
+
+// ids are per kind, both features share one Reassembler and its budget
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TransferKey {
+    pub kind: TransferKind,
+    pub id: u32,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReassemblyStats {
+    pub completed: u64,
+    // incomplete transfers dropped for the budget, least recently fed first
+    pub evicted: u64,
+    // incomplete transfers dropped after max_age without a chunk
+    pub expired: u64,
+    // transfers aborted for a chunk past or a size other than declared
+    pub aborted: u64,
+    // buffered by the transfers evicted or expired
+    pub dropped_bytes: u64,
+}
+
+#[derive(Debug)]
+struct Transfer {
+    declared: usize,
+    data: Vec<u8>,
+    // ms and order of the last chunk, the eviction order
+    fed_at: u64,
+    seq: u64,
+}
+
+// puts transfers the server sends in chunks back together, chunks arrive in
+// order over kcp. Holds at most max_bytes of incomplete transfers: a chunk
+// that would go over evicts others, least recently fed first
+#[derive(Debug)]
+pub struct Reassembler {
+    limits: ReassemblyLimits,
+    transfers: BTreeMap<TransferKey, Transfer>,
+    bytes: usize,
+    seq: u64,
+    stats: ReassemblyStats,
+}
+
+impl Reassembler {
+    pub fn new(limits: ReassemblyLimits) -> Reassembler {
+        let limits = ReassemblyLimits {
+            max_transfer: limits.max_transfer.min(limits.max_bytes),
+            ..limits
+        };
+        return Reassembler {
+            limits,
+            transfers: BTreeMap::new(),
+            bytes: 0,
+            seq: 0,
+            stats: ReassemblyStats::default(),
+        };
+    }
+
+    pub fn stats(&self) -> ReassemblyStats {
+        return self.stats;
+    }
+
+    // of the incomplete transfers
+    pub fn bytes(&self) -> usize {
+        return self.bytes;
+    }
+
+    pub fn len(&self) -> usize {
+        return self.transfers.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.transfers.is_empty();
+    }
+
+    pub fn contains(&self, key: TransferKey) -> bool {
+        return self.transfers.contains_key(&key);
+    }
+
+    // the whole transfer once its last chunk is in. Every chunk repeats the
+    // declared size, an error aborts only this transfer
+    pub fn push(
+        &mut self,
+        key: TransferKey,
+        declared: usize,
+        chunk: &[u8],
+        now: u64,
+    ) -> Result<Option<Vec<u8>>, KCPError> {
+        if declared > self.limits.max_transfer {
+            self.abort(key);
+            return Err(KCPError::Oversized(declared));
+        }
+        let received = match self.transfers.get(&key) {
+            Some(transfer) if transfer.declared != declared => {
+                self.abort(key);
+                return Err(KCPError::PacketBroken);
+            }
+            Some(transfer) => transfer.data.len(),
+            None => 0,
+        };
+        if received + chunk.len() > declared {
+            self.abort(key);
+            return Err(KCPError::PacketTooLong);
+        }
+        if received + chunk.len() == declared {
+            let mut data = self
+                .remove(key)
+                .map_or_else(Vec::new, |transfer| transfer.data);
+            data.extend_from_slice(chunk);
+            self.stats.completed += 1;
+            return Ok(Some(data));
+        }
+
+        self.make_room(key, chunk.len());
+        self.seq += 1;
+        let seq = self.seq;
+        let transfer = self.transfers.entry(key).or_insert_with(|| Transfer {
+            declared,
+            data: Vec::new(),
+            fed_at: now,
+            seq,
+        });
+        transfer.data.extend_from_slice(chunk);
+        transfer.fed_at = now;
+        transfer.seq = seq;
+        self.bytes += chunk.len();
+        return Ok(None);
+    }
+
+    // drops the incomplete transfers fed last over max_age ago
+    pub fn expire(&mut self, now: u64) {
+        let max_age = self.limits.max_age;
+        let stale: Vec<TransferKey> = self
+            .transfers
+            .iter()
+            .filter(|(_, transfer)| now.saturating_sub(transfer.fed_at) > max_age)
+            .map(|(key, _)| *key)
+            .collect();
+        for key in stale {
+            if let Some(transfer) = self.remove(key) {
+                self.stats.expired += 1;
+                self.stats.dropped_bytes += transfer.data.len() as u64;
+            }
+        }
+    }
+
+    // `key` itself fits as max_transfer is at most max_bytes
+    fn make_room(&mut self, key: TransferKey, len: usize) {
+        while self.bytes + len > self.limits.max_bytes {
+            let victim = self
+                .transfers
+                .iter()
+                .filter(|(other, _)| **other != key)
+                .min_by_key(|(_, transfer)| (transfer.fed_at, transfer.seq))
+                .map(|(other, _)| *other);
+            let transfer = match victim.and_then(|victim| self.remove(victim)) {
+                Some(transfer) => transfer,
+                None => return,
+            };
+            self.stats.evicted += 1;
+            self.stats.dropped_bytes += transfer.data.len() as u64;
+        }
+    }
+
+    fn abort(&mut self, key: TransferKey) {
+        self.remove(key);
+        self.stats.aborted += 1;
+    }
+
+    fn remove(&mut self, key: TransferKey) -> Option<Transfer> {
+        let transfer = self.transfers.remove(&key)?;
+        self.bytes -= transfer.data.len();
+        return Some(transfer);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::message::NetFinishCause;
+
+    fn fragment(id: u32) -> TransferKey {
+        return TransferKey {
+            kind: TransferKind::Fragment,
+            id,
+        };
+    }
+
+    fn snapshot(id: u32) -> TransferKey {
+        return TransferKey {
+            kind: TransferKind::Snapshot,
+            id,
+        };
+    }
+
+    #[test]
+    fn test_reassembler_budget() {
+        let limits = ReassemblyLimits {
+            max_bytes: 100,
+            max_transfer: 60,
+            max_age: 1000,
+        };
+        let mut reassembler = Reassembler::new(limits);
+        let (a, b, c) = (fragment(1), snapshot(1), fragment(2));
+        assert_eq!(reassembler.push(a, 60, &[1; 30], 0).unwrap(), None);
+        assert_eq!(reassembler.push(b, 60, &[2; 30], 0).unwrap(), None);
+        assert_eq!(reassembler.push(c, 60, &[3; 30], 10).unwrap(), None);
+        assert_eq!(reassembler.bytes(), 90);
+
+        // b was fed after a in the same ms, a goes first
+        assert_eq!(reassembler.push(b, 60, &[2; 20], 20).unwrap(), None);
+        assert!(!reassembler.contains(a));
+        assert_eq!(reassembler.bytes(), 80);
+        // then c, fed before b
+        assert_eq!(reassembler.push(b, 60, &[2; 5], 30).unwrap(), None);
+        assert_eq!(reassembler.push(a, 60, &[1; 20], 30).unwrap(), None);
+        assert!(!reassembler.contains(c));
+        assert!(reassembler.bytes() <= limits.max_bytes);
+        let stats = reassembler.stats();
+        assert_eq!((stats.evicted, stats.dropped_bytes), (2, 60));
+
+        let data = reassembler.push(b, 60, &[2; 5], 40).unwrap();
+        assert_eq!(data, Some(vec![2; 60]));
+        assert_eq!(reassembler.len(), 1);
+        assert_eq!(reassembler.bytes(), 20);
+        assert_eq!(reassembler.stats().completed, 1);
+    }
+
+    #[test]
+    fn test_reassembler_abort() {
+        let mut reassembler = Reassembler::new(ReassemblyLimits::default());
+        let (a, b) = (fragment(1), fragment(2));
+        reassembler.push(a, 10, &[1; 6], 0).unwrap();
+        reassembler.push(b, 10, &[2; 6], 0).unwrap();
+        let err = reassembler.push(a, 10, &[1; 6], 0).unwrap_err();
+        assert!(matches!(err, KCPError::PacketTooLong));
+        assert_eq!(err.cause(), NetFinishCause::InvalidPacket);
+        assert!(!reassembler.contains(a));
+        assert_eq!(
+            reassembler.push(b, 10, &[2; 4], 0).unwrap(),
+            Some(vec![2; 10])
+        );
+
+        reassembler.push(a, 10, &[1; 6], 0).unwrap();
+        let err = reassembler.push(a, 12, &[1; 2], 0).unwrap_err();
+        assert!(matches!(err, KCPError::PacketBroken));
+        let max = REASSEMBLY_MAX_TRANSFER + 1;
+        let err = reassembler.push(b, max, &[2; 1], 0).unwrap_err();
+        assert!(matches!(err, KCPError::Oversized(_)));
+        assert!(reassembler.is_empty());
+        assert_eq!(reassembler.stats().aborted, 3);
+
+        // a single chunk needs no buffering
+        assert_eq!(
+            reassembler.push(a, 3, &[1; 3], 0).unwrap(),
+            Some(vec![1; 3])
+        );
+        assert_eq!(reassembler.push(b, 0, &[], 0).unwrap(), Some(vec![]));
+    }
+
+    #[test]
+    fn test_reassembler_expire() {
+        let mut reassembler = Reassembler::new(ReassemblyLimits::default());
+        reassembler.push(fragment(1), 10, &[1; 4], 0).unwrap();
+        reassembler.push(snapshot(1), 10, &[2; 4], 0).unwrap();
+        reassembler
+            .push(snapshot(1), 10, &[2; 4], REASSEMBLY_MAX_AGE)
+            .unwrap();
+        reassembler.expire(REASSEMBLY_MAX_AGE);
+        assert_eq!(reassembler.len(), 2);
+        reassembler.expire(REASSEMBLY_MAX_AGE + 1);
+        assert!(!reassembler.contains(fragment(1)));
+        assert!(reassembler.contains(snapshot(1)));
+        let stats = reassembler.stats();
+        assert_eq!((stats.expired, stats.dropped_bytes), (1, 4));
+        assert_eq!(reassembler.bytes(), 8);
+    }
+}
@@ -0,0 +1,115 @@
+use crate::base::{REBIND_ATTEMPTS, REBIND_COOLDOWN, REBIND_SILENCE};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RebindConfig {
+    pub attempts: u32,
+    // ms between two rebinds
+    pub cooldown: u64,
+    // ms without a datagram before the socket is considered dead
+    pub silence: u64,
+}
+
+impl Default for RebindConfig {
+    fn default() -> RebindConfig {
+        return RebindConfig {
+            attempts: REBIND_ATTEMPTS,
+            cooldown: REBIND_COOLDOWN,
+            silence: REBIND_SILENCE,
+        };
+    }
+}
+
+// Decides when the game socket should be rebound after the device switched
+// networks, times are in ms. The kcp conv and state survive a rebind.
+#[derive(Debug)]
+pub struct RebindMonitor {
+    config: RebindConfig,
+    attempts: u32,
+    rebinds: u32,
+    send_failed: bool,
+    recv_at: u64,
+    rebind_at: Option<u64>,
+}
+
+impl RebindMonitor {
+    pub fn new(config: RebindConfig, now: u64) -> RebindMonitor {
+        return RebindMonitor {
+            config,
+            attempts: 0,
+            rebinds: 0,
+            send_failed: false,
+            recv_at: now,
+            rebind_at: None,
+        };
+    }
+
+    // the current socket works again, later failures get a fresh budget
+    pub fn on_recv(&mut self, now: u64) {
+        self.recv_at = now;
+        self.send_failed = false;
+        self.attempts = 0;
+    }
+
+    pub fn on_send_error(&mut self) {
+        self.send_failed = true;
+    }
+
+    pub fn should_rebind(&self, now: u64) -> bool {
+        if self.attempts >= self.config.attempts {
+            return false;
+        }
+        if let Some(rebind_at) = self.rebind_at {
+            if now < rebind_at + self.config.cooldown {
+                return false;
+            }
+        }
+        return self.send_failed || now >= self.recv_at + self.config.silence;
+    }
+
+    pub fn on_rebind(&mut self, now: u64) {
+        self.attempts += 1;
+        self.rebinds += 1;
+        self.send_failed = false;
+        self.rebind_at = Some(now);
+    }
+
+    pub fn rebinds(&self) -> u32 {
+        return self.rebinds;
+    }
+
+    pub fn exhausted(&self) -> bool {
+        return self.attempts >= self.config.attempts;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rebind_monitor() {
+        let config = RebindConfig {
+            attempts: 2,
+            cooldown: 1000,
+            silence: 500,
+        };
+        let mut monitor = RebindMonitor::new(config, 0);
+        monitor.on_recv(100);
+        assert!(!monitor.should_rebind(599));
+        assert!(monitor.should_rebind(600));
+
+        monitor.on_rebind(600);
+        monitor.on_send_error();
+        assert!(!monitor.should_rebind(1599));
+        assert!(monitor.should_rebind(1600));
+        monitor.on_rebind(1600);
+        assert!(monitor.exhausted());
+        assert!(!monitor.should_rebind(10000));
+
+        monitor.on_recv(10000);
+        assert!(!monitor.exhausted());
+        assert!(!monitor.should_rebind(10100));
+        assert!(monitor.should_rebind(10500));
+        assert_eq!(monitor.rebinds(), 2);
+    }
+}
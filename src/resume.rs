@@ -0,0 +1,147 @@
+use crate::base::{ConfigError, PROTOCOL_VERSION, SESSION_STATE_VERSION};
+use crate::message::NetSessionState;
+use prost::Message;
+use std::net::SocketAddr;
+
+// what a restarted game needs to rejoin its match without asking the user
+// again, the password is never kept, the resume token replaces it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionState {
+    pub addr: SocketAddr,
+    pub conv: u32,
+    pub room_id: String,
+    pub player_id: String,
+    // issued by the server on accept, empty until then or when the server
+    // doesn't support resuming
+    pub resume_token: Vec<u8>,
+    // the newest frame sent, a resumed client continues after it
+    pub last_frame: u32,
+    pub protocol_version: u32,
+}
+
+impl SessionState {
+    pub fn new(addr: SocketAddr, conv: u32, room_id: &str, player_id: &str) -> SessionState {
+        return SessionState {
+            addr,
+            conv,
+            room_id: room_id.to_string(),
+            player_id: player_id.to_string(),
+            resume_token: Vec::new(),
+            last_frame: 0,
+            protocol_version: PROTOCOL_VERSION,
+        };
+    }
+
+    pub fn can_resume(&self) -> bool {
+        return !self.resume_token.is_empty() && self.protocol_version == PROTOCOL_VERSION;
+    }
+
+    // protobuf, so readers skip the fields added after them
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let state = NetSessionState {
+            version: SESSION_STATE_VERSION,
+            min_version: 1,
+            addr: self.addr.to_string(),
+            conv: self.conv,
+            room_id: self.room_id.clone(),
+            player_id: self.player_id.clone(),
+            resume_token: self.resume_token.clone(),
+            last_frame: self.last_frame,
+            protocol_version: self.protocol_version,
+        };
+        return state.encode_to_vec();
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<SessionState, ConfigError> {
+        let state = NetSessionState::decode(bytes).map_err(|_| ConfigError::InvalidField {
+            field: "session_state",
+            reason: "malformed",
+        })?;
+        if state.min_version > SESSION_STATE_VERSION {
+            return Err(ConfigError::InvalidField {
+                field: "session_state",
+                reason: "unsupported version",
+            });
+        }
+        let addr = state.addr.parse().map_err(|_| ConfigError::InvalidField {
+            field: "addr",
+            reason: "malformed",
+        })?;
+        return Ok(SessionState {
+            addr,
+            conv: state.conv,
+            room_id: state.room_id,
+            player_id: state.player_id,
+            resume_token: state.resume_token,
+            last_frame: state.last_frame,
+            protocol_version: state.protocol_version,
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn state() -> SessionState {
+        let mut state = SessionState::new(
+            SocketAddr::from(([138, 128, 196, 233], 33303)),
+            6666,
+            "room",
+            "player",
+        );
+        state.resume_token = vec![1, 2, 3];
+        state.last_frame = 42;
+        return state;
+    }
+
+    #[test]
+    fn test_session_state_round_trip() {
+        let state = state();
+        assert!(state.can_resume());
+        assert_eq!(SessionState::from_bytes(&state.to_bytes()), Ok(state));
+
+        let fresh = SessionState::new("[::1]:9000".parse().unwrap(), 1, "", "");
+        assert!(!fresh.can_resume());
+        assert_eq!(SessionState::from_bytes(&fresh.to_bytes()), Ok(fresh));
+
+        let mut old = state();
+        old.protocol_version = PROTOCOL_VERSION + 1;
+        assert!(!old.can_resume());
+    }
+
+    #[test]
+    fn test_session_state_versions() {
+        // a newer writer: unknown fields are skipped while min_version allows
+        let mut bytes = state().to_bytes();
+        let mut newer = NetSessionState::decode(&bytes[..]).unwrap();
+        newer.version = SESSION_STATE_VERSION + 1;
+        bytes = newer.encode_to_vec();
+        // field 15, varint
+        bytes.extend_from_slice(&[15 << 3, 7]);
+        assert_eq!(SessionState::from_bytes(&bytes), Ok(state()));
+
+        newer.min_version = SESSION_STATE_VERSION + 1;
+        assert_eq!(
+            SessionState::from_bytes(&newer.encode_to_vec()),
+            Err(ConfigError::InvalidField {
+                field: "session_state",
+                reason: "unsupported version"
+            })
+        );
+
+        assert_eq!(
+            SessionState::from_bytes(&[0xff, 0xff]),
+            Err(ConfigError::InvalidField {
+                field: "session_state",
+                reason: "malformed"
+            })
+        );
+        newer.min_version = 1;
+        newer.addr = "nowhere".to_string();
+        assert!(matches!(
+            SessionState::from_bytes(&newer.encode_to_vec()),
+            Err(ConfigError::InvalidField { field: "addr", .. })
+        ));
+    }
+}
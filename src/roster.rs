@@ -0,0 +1,144 @@
+use crate::base::{ROSTER_HINT_PER_CONV, ROSTER_HINT_TOTAL, ROSTER_HINT_WINDOW};
+use crate::codec::CommandBatch;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RosterLimits {
+    // ms from a conv's first held batch until its State must arrive
+    pub window: u64,
+    // batches held of one conv, and of all convs together
+    pub per_conv: usize,
+    pub total: usize,
+}
+
+impl Default for RosterLimits {
+    fn default() -> RosterLimits {
+        return RosterLimits {
+            window: ROSTER_HINT_WINDOW,
+            per_conv: ROSTER_HINT_PER_CONV,
+            total: ROSTER_HINT_TOTAL,
+        };
+    }
+}
+
+#[derive(Debug)]
+struct Held {
+    since: u64,
+    batches: Vec<CommandBatch>,
+}
+
+// commands of convs no State introduced yet, mid-match the relay may
+// forward a joining player's packets a tick or two before the roster
+// update, times are in ms
+#[derive(Debug)]
+pub struct RosterHints {
+    limits: RosterLimits,
+    held: HashMap<u32, Held>,
+    total: usize,
+}
+
+impl RosterHints {
+    pub fn new(limits: RosterLimits) -> RosterHints {
+        return RosterHints {
+            limits,
+            held: HashMap::new(),
+            total: 0,
+        };
+    }
+
+    pub fn len(&self) -> usize {
+        return self.total;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.total == 0;
+    }
+
+    // false when a bound is hit and the batch was dropped
+    pub fn hold(&mut self, batch: CommandBatch, now: u64) -> bool {
+        if self.total >= self.limits.total || self.limits.per_conv == 0 {
+            return false;
+        }
+        let held = self.held.entry(batch.conv).or_insert_with(|| Held {
+            since: now,
+            batches: Vec::new(),
+        });
+        if held.batches.len() >= self.limits.per_conv {
+            return false;
+        }
+        held.batches.push(batch);
+        self.total += 1;
+        return true;
+    }
+
+    // in arrival order, none when nothing was held
+    pub fn release(&mut self, conv: u32) -> Vec<CommandBatch> {
+        let batches = match self.held.remove(&conv) {
+            Some(held) => held.batches,
+            None => return Vec::new(),
+        };
+        self.total -= batches.len();
+        return batches;
+    }
+
+    // appends the convs whose window passed, with the batches dropped of
+    // each, ordered by conv
+    pub fn expire(&mut self, now: u64, expired: &mut Vec<(u32, usize)>) {
+        let from = expired.len();
+        let window = self.limits.window;
+        let total = &mut self.total;
+        self.held.retain(|conv, held| {
+            if now < held.since + window {
+                return true;
+            }
+            *total -= held.batches.len();
+            expired.push((*conv, held.batches.len()));
+            return false;
+        });
+        expired[from..].sort_unstable();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::codec::Command;
+
+    fn batch(conv: u32, frame: u32) -> CommandBatch {
+        let mut batch = CommandBatch::new(conv, frame);
+        batch.commands.push(Command::Aaa(frame as i32, 0));
+        return batch;
+    }
+
+    #[test]
+    fn test_roster_hints() {
+        let limits = RosterLimits {
+            window: 100,
+            per_conv: 2,
+            total: 3,
+        };
+        let mut hints = RosterHints::new(limits);
+        assert!(hints.hold(batch(7, 1), 0));
+        assert!(hints.hold(batch(7, 2), 10));
+        // per conv
+        assert!(!hints.hold(batch(7, 3), 20));
+        assert!(hints.hold(batch(8, 1), 50));
+        // all convs
+        assert!(!hints.hold(batch(9, 1), 60));
+        assert_eq!(hints.len(), 3);
+
+        let released = hints.release(7);
+        assert_eq!(released, vec![batch(7, 1), batch(7, 2)]);
+        assert!(hints.release(7).is_empty());
+        assert_eq!(hints.len(), 1);
+
+        // from its first batch on, not its last
+        let mut expired = Vec::new();
+        assert!(hints.hold(batch(9, 1), 60));
+        hints.expire(149, &mut expired);
+        assert!(expired.is_empty());
+        hints.expire(160, &mut expired);
+        assert_eq!(expired, vec![(8, 1), (9, 1)]);
+        assert!(hints.is_empty());
+    }
+}
@@ -0,0 +1,92 @@
+use crate::base::TIMER_JITTER_MAX;
+
+// the periodic timers a Schedule shifts, each by its own offset
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Timer {
+    Tick = 1,
+    Presence = 2,
+    Reconnect = 3,
+}
+
+// Shifts periodic timers by at most `percent` of their interval, by an offset
+// derived from the conv: clients started together fire apart instead of in
+// lockstep, and a run is still reproducible. 0 keeps them aligned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Schedule {
+    conv: u32,
+    percent: u64,
+}
+
+impl Schedule {
+    pub fn new(conv: u32, percent: u64) -> Schedule {
+        return Schedule {
+            conv,
+            percent: percent.min(TIMER_JITTER_MAX),
+        };
+    }
+
+    // in [0, interval * percent / 100), the same for every call
+    pub fn offset(&self, timer: Timer, interval: u64) -> u64 {
+        let span = interval * self.percent / 100;
+        if span == 0 {
+            return 0;
+        }
+        // splitmix64, consecutive convs land far apart
+        let mut x = (self.conv as u64) << 8 | timer as u64;
+        x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        return (x ^ (x >> 31)) % span;
+    }
+
+    // the first time after `current` the timer fires, every `interval` ms
+    // from its offset on
+    pub fn next(&self, timer: Timer, current: u64, interval: u64) -> u64 {
+        let interval = interval.max(1);
+        let offset = self.offset(timer, interval);
+        if current < offset {
+            return offset;
+        }
+        return (current - offset + interval) / interval * interval + offset;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_schedule() {
+        // no jitter, aligned to the interval as before
+        let aligned = Schedule::new(6666, 0);
+        assert_eq!(aligned.offset(Timer::Tick, 10), 0);
+        assert_eq!(aligned.next(Timer::Tick, 0, 10), 10);
+        assert_eq!(aligned.next(Timer::Tick, 9, 10), 10);
+        assert_eq!(aligned.next(Timer::Tick, 10, 10), 20);
+
+        let schedule = Schedule::new(6666, 20);
+        let offset = schedule.offset(Timer::Presence, 1000);
+        assert!(offset < 200);
+        assert_eq!(schedule.offset(Timer::Presence, 1000), offset);
+        assert_eq!(
+            Schedule::new(6666, 20).offset(Timer::Presence, 1000),
+            offset
+        );
+        assert_eq!(schedule.next(Timer::Presence, offset, 1000), 1000 + offset);
+        assert_eq!(
+            schedule.next(Timer::Presence, 1000 + offset, 1000),
+            2000 + offset
+        );
+
+        // capped, and spread over the convs
+        let capped = Schedule::new(6666, 1000);
+        assert_eq!(capped, Schedule::new(6666, TIMER_JITTER_MAX));
+        let offsets: Vec<u64> = (1..=8)
+            .map(|conv| Schedule::new(conv, TIMER_JITTER_MAX).offset(Timer::Reconnect, 5000))
+            .collect();
+        for (idx, offset) in offsets.iter().enumerate() {
+            assert!(*offset < 2500);
+            assert!(!offsets[..idx].contains(offset), "{:?}", offsets);
+        }
+    }
+}
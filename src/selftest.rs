@@ -0,0 +1,389 @@
+use crate::base::{PADDING_BUCKETS, SELFTEST_COMMANDS, SELFTEST_FRAMES, SELFTEST_TIMEOUT};
+use crate::chan::NetEvent;
+use crate::client::{Client, GameHandle, PollStatus};
+use crate::codec::Command;
+use crate::message::NetFinishCause;
+use crate::roster::RosterLimits;
+use crate::worker::WorkerConfig;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// the optional behaviors of WorkerConfig that change what goes over the
+// wire or how remote commands reach the game
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Feature {
+    JitterBuffer,
+    FrameAssembly,
+    Roster,
+    Padding,
+    HashCheck,
+    HashCommands,
+    DeliveryHash,
+    LowLatency,
+    // with the dictionary of the base config, left out without one
+    #[cfg(feature = "dictionary-compression")]
+    Compression,
+}
+
+impl Feature {
+    pub const ALL: &'static [Feature] = &[
+        Feature::JitterBuffer,
+        Feature::FrameAssembly,
+        Feature::Roster,
+        Feature::Padding,
+        Feature::HashCheck,
+        Feature::HashCommands,
+        Feature::DeliveryHash,
+        Feature::LowLatency,
+        #[cfg(feature = "dictionary-compression")]
+        Feature::Compression,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        return match self {
+            Feature::JitterBuffer => "jitter_buffer",
+            Feature::FrameAssembly => "frame_assembly",
+            Feature::Roster => "roster",
+            Feature::Padding => "padding",
+            Feature::HashCheck => "hash_check",
+            Feature::HashCommands => "hash_commands",
+            Feature::DeliveryHash => "delivery_hash",
+            Feature::LowLatency => "low_latency",
+            #[cfg(feature = "dictionary-compression")]
+            Feature::Compression => "compression",
+        };
+    }
+
+    // those `base` can run
+    pub fn available(base: &WorkerConfig) -> Vec<Feature> {
+        let mut available = Feature::ALL.to_vec();
+        #[cfg(feature = "dictionary-compression")]
+        if base.dictionary.is_none() {
+            available.retain(|feature| *feature != Feature::Compression);
+        }
+        #[cfg(not(feature = "dictionary-compression"))]
+        let _ = base;
+        return available;
+    }
+
+    fn set(&self, config: &mut WorkerConfig, base: &WorkerConfig, on: bool) {
+        match self {
+            Feature::JitterBuffer => config.jitter_buffer = on,
+            Feature::FrameAssembly => config.frame_assembly = on,
+            Feature::Roster => {
+                config.roster = match on {
+                    true => Some(base.roster.unwrap_or_default()),
+                    false => None,
+                };
+            }
+            Feature::Padding => {
+                config.padding = match (on, base.padding.is_empty()) {
+                    (true, true) => PADDING_BUCKETS.to_vec(),
+                    (true, false) => base.padding.clone(),
+                    (false, _) => Vec::new(),
+                };
+            }
+            Feature::HashCheck => config.hash_check = on,
+            Feature::HashCommands => config.hash_commands = on,
+            Feature::DeliveryHash => config.delivery_hash = on,
+            Feature::LowLatency => config.low_latency = on,
+            #[cfg(feature = "dictionary-compression")]
+            Feature::Compression => {
+                config.dictionary = match on {
+                    true => base.dictionary.clone(),
+                    false => None,
+                };
+            }
+        };
+    }
+}
+
+// how far a session got, in order
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    Connect,
+    Start,
+    Exchange,
+    Finish,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Stage::Connect => "connect",
+            Stage::Start => "start",
+            Stage::Exchange => "exchange",
+            Stage::Finish => "finish",
+        };
+        return f.write_str(name);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MatrixResult {
+    pub features: Vec<Feature>,
+    // the stage that failed and why, None when the session passed
+    pub failure: Option<(Stage, String)>,
+}
+
+impl MatrixResult {
+    pub fn passed(&self) -> bool {
+        return self.failure.is_none();
+    }
+
+    // e.g. "jitter_buffer+padding", "none" without any
+    pub fn combination(&self) -> String {
+        if self.features.is_empty() {
+            return "none".to_string();
+        }
+        let names: Vec<_> = self.features.iter().map(Feature::name).collect();
+        return names.join("+");
+    }
+}
+
+impl fmt::Display for MatrixResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return match &self.failure {
+            Some((stage, reason)) => {
+                write!(f, "{}: failed at {}: {}", self.combination(), stage, reason)
+            }
+            None => write!(f, "{}: passed", self.combination()),
+        };
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MatrixReport {
+    pub results: Vec<MatrixResult>,
+}
+
+impl MatrixReport {
+    pub fn passed(&self) -> bool {
+        return self.results.iter().all(MatrixResult::passed);
+    }
+
+    pub fn failures(&self) -> impl Iterator<Item = &MatrixResult> {
+        return self.results.iter().filter(|result| !result.passed());
+    }
+}
+
+// the failures only, one per line
+impl fmt::Display for MatrixReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let failed = self.failures().count();
+        write!(
+            f,
+            "{}/{} combinations passed",
+            self.results.len() - failed,
+            self.results.len()
+        )?;
+        for result in self.failures() {
+            write!(f, "\n{}", result)?;
+        }
+        return Ok(());
+    }
+}
+
+// none, each feature alone, every pair and all of them
+pub fn combinations(features: &[Feature]) -> Vec<Vec<Feature>> {
+    let mut combinations = vec![Vec::new()];
+    combinations.extend(features.iter().map(|feature| vec![*feature]));
+    for (pos, first) in features.iter().enumerate() {
+        for second in &features[pos + 1..] {
+            combinations.push(vec![*first, *second]);
+        }
+    }
+    if features.len() > 2 {
+        combinations.push(features.to_vec());
+    }
+    return combinations;
+}
+
+// Runs a scripted offline session for every combination of the features
+// `base` can run, everything else taken from `base`, so integrators can
+// check their own configuration in one call.
+pub fn run_matrix(base: &WorkerConfig) -> MatrixReport {
+    let available = Feature::available(base);
+    let results = combinations(&available)
+        .into_iter()
+        .map(|features| {
+            let mut config = base.clone();
+            for feature in &available {
+                feature.set(&mut config, base, features.contains(feature));
+            }
+            let failure = run_session(config).err();
+            return MatrixResult { features, failure };
+        })
+        .collect();
+    return MatrixReport { results };
+}
+
+// connects offline, sends SELFTEST_FRAMES frames, expects them echoed back
+// in order, then ends the match with game_over()
+fn run_session(config: WorkerConfig) -> Result<(), (Stage, String)> {
+    let delivery_hash = config.delivery_hash;
+    let client = Client::offline(config).map_err(|err| (Stage::Connect, err.to_string()))?;
+    let mut handle = client.handle().clone();
+    let conv = handle.conv();
+    let mut events = Vec::new();
+
+    poll_until(&mut handle, &mut events, Stage::Start, |status, _| {
+        return *status == PollStatus::Active;
+    })?;
+
+    let mut sent = BTreeMap::new();
+    for frame in 1..=SELFTEST_FRAMES {
+        let commands: Vec<_> = (0..SELFTEST_COMMANDS)
+            .map(|pos| Command::Aaa(frame as i32, pos as i32))
+            .collect();
+        // every other frame leaves its hash to hash_commands
+        let hash = match frame % 2 {
+            0 => vec![frame as u8],
+            _ => Vec::new(),
+        };
+        handle
+            .send_input(frame, &commands, &hash)
+            .map_err(|err| (Stage::Exchange, format!("frame {}: {}", frame, err)))?;
+        sent.insert(frame, commands);
+    }
+    let mut received = BTreeMap::new();
+    let result = poll_until(&mut handle, &mut events, Stage::Exchange, |_, events| {
+        for event in events.drain(..) {
+            match event {
+                NetEvent::Commands { frame, commands } => {
+                    let delivered = received.entry(frame).or_insert_with(Vec::new);
+                    delivered.extend(
+                        commands
+                            .into_iter()
+                            .filter(|command| command.conv == conv)
+                            .map(|command| command.command),
+                    );
+                }
+                NetEvent::FrameReady {
+                    frame, commands, ..
+                } => {
+                    let delivered = received.entry(frame).or_insert_with(Vec::new);
+                    for (from, commands) in commands {
+                        if from == conv {
+                            delivered.extend(commands);
+                        }
+                    }
+                }
+                _ => {}
+            };
+        }
+        return received.len() >= sent.len();
+    });
+    if let Err((stage, reason)) = result {
+        let missing = sent.keys().find(|frame| !received.contains_key(frame));
+        return match missing {
+            Some(frame) => Err((
+                stage,
+                format!("frame {} never delivered, {}", frame, reason),
+            )),
+            None => Err((stage, reason)),
+        };
+    }
+    if let Some(frame) = sent
+        .keys()
+        .find(|frame| sent.get(frame) != received.get(frame))
+    {
+        return Err((
+            Stage::Exchange,
+            format!("frame {} delivered altered", frame),
+        ));
+    }
+
+    handle
+        .game_over()
+        .map_err(|err| (Stage::Finish, err.to_string()))?;
+    let status = poll_until(&mut handle, &mut events, Stage::Finish, |status, _| {
+        return matches!(status, PollStatus::Finished(_));
+    });
+    let info = match status {
+        Ok(PollStatus::Finished(info)) => info,
+        Ok(status) => return Err((Stage::Finish, format!("{:?}", status))),
+        Err(failure) => return Err(failure),
+    };
+    client.join();
+    if info.cause != NetFinishCause::GameOver {
+        return Err((Stage::Finish, format!("{:?} {}", info.cause, info.message)));
+    }
+    if delivery_hash && info.delivery_hash.is_none() {
+        return Err((Stage::Finish, "no delivery hash".to_string()));
+    }
+    return Ok(());
+}
+
+// a session ending early fails the stage it was in, as does
+// SELFTEST_TIMEOUT passing
+fn poll_until<F: FnMut(&PollStatus, &mut Vec<NetEvent>) -> bool>(
+    handle: &mut GameHandle,
+    events: &mut Vec<NetEvent>,
+    stage: Stage,
+    mut done: F,
+) -> Result<PollStatus, (Stage, String)> {
+    let deadline = Instant::now() + Duration::from_millis(SELFTEST_TIMEOUT);
+    loop {
+        let status = handle.poll(events);
+        if done(&status, events) {
+            return Ok(status);
+        }
+        if let PollStatus::Finished(info) = status {
+            return Err((stage, format!("{:?} {}", info.cause, info.message)));
+        }
+        if Instant::now() > deadline {
+            return Err((stage, "timed out".to_string()));
+        }
+        thread::sleep(Duration::from_millis(5));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_combinations() {
+        let features = [Feature::JitterBuffer, Feature::Padding, Feature::Roster];
+        let combinations = combinations(&features);
+        // none, 3 alone, 3 pairs, all
+        assert_eq!(combinations.len(), 8);
+        assert!(combinations.contains(&vec![Feature::Padding, Feature::Roster]));
+        assert_eq!(combinations.last().unwrap(), &features.to_vec());
+
+        let result = MatrixResult {
+            features: vec![Feature::JitterBuffer, Feature::Padding],
+            failure: Some((Stage::Exchange, "frame 3 never delivered".to_string())),
+        };
+        assert_eq!(
+            result.to_string(),
+            "jitter_buffer+padding: failed at exchange: frame 3 never delivered"
+        );
+        let report = MatrixReport {
+            results: vec![
+                result,
+                MatrixResult {
+                    features: Vec::new(),
+                    failure: None,
+                },
+            ],
+        };
+        assert!(!report.passed());
+        assert_eq!(
+            report.to_string(),
+            "1/2 combinations passed\n\
+             jitter_buffer+padding: failed at exchange: frame 3 never delivered"
+        );
+    }
+
+    #[test]
+    fn test_run_matrix() {
+        let report = run_matrix(&WorkerConfig::default());
+        assert!(report.passed(), "{}", report);
+        let features = Feature::available(&WorkerConfig::default()).len();
+        assert_eq!(report.results.len(), 1 + features * (features + 1) / 2 + 1);
+    }
+}
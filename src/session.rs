@@ -0,0 +1,311 @@
+use crate::base::{ClientError, FinishInfo};
+use crate::chan::NetChan;
+use crate::client::GameHandle;
+use crate::message::NetFinishCause;
+use crate::worker::{NetWorker, WorkerConfig};
+use std::net::SocketAddr;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+
+// scheduled on Instant, a wall clock step moves neither wakeups nor deadlines
+enum SessionPhase {
+    Running,
+    Draining(Instant),
+}
+
+struct Session {
+    worker: NetWorker,
+    chan: NetChan,
+    phase: SessionPhase,
+    // not stepped before
+    wakeup: Instant,
+}
+
+impl Session {
+    // false once the session is done and can be dropped
+    fn start(&mut self) -> bool {
+        let worker = &mut self.worker;
+        return Self::guard(&self.chan, || match worker.start() {
+            Ok(()) => true,
+            Err(err) => {
+                worker.begin_finish(err, false);
+                false
+            }
+        });
+    }
+
+    fn step(&mut self, now: Instant) -> bool {
+        let worker = &mut self.worker;
+        let phase = &mut self.phase;
+        let wakeup = &mut self.wakeup;
+        return Self::guard(&self.chan, || match *phase {
+            SessionPhase::Running => {
+                if let Err(err) = worker.step() {
+                    match worker.begin_finish(err, true) {
+                        Some(deadline) => *phase = SessionPhase::Draining(deadline),
+                        None => return false,
+                    };
+                }
+                *wakeup = worker.next_wakeup();
+                true
+            }
+            // done once everything was acked
+            SessionPhase::Draining(deadline) => {
+                // without waiting on the socket
+                let done = now >= deadline || worker.drain(SystemTime::now());
+                *wakeup = worker.next_wakeup().min(deadline);
+                !done
+            }
+        });
+    }
+
+    // a panicking session only takes itself down
+    fn guard<F: FnOnce() -> bool>(chan: &NetChan, f: F) -> bool {
+        return match panic::catch_unwind(AssertUnwindSafe(f)) {
+            Ok(keep) => keep,
+            Err(_) => {
+                let mut info = FinishInfo::new(NetFinishCause::ClientError);
+                info.message = "session panicked".to_string();
+                chan.finish_with(info);
+                false
+            }
+        };
+    }
+}
+
+// Drives many workers cooperatively on a fixed number of threads, sessions
+// are spread round robin and each is stepped at its NetWorker::next_wakeup(),
+// a thread sleeps until the first of its sessions is due.
+pub struct SessionManager {
+    senders: Vec<Sender<Session>>,
+    threads: Vec<JoinHandle<()>>,
+    next: usize,
+}
+
+impl SessionManager {
+    pub fn new(threads: usize) -> Result<SessionManager, ClientError> {
+        let threads = threads.max(1);
+        let mut manager = SessionManager {
+            senders: Vec::with_capacity(threads),
+            threads: Vec::with_capacity(threads),
+            next: 0,
+        };
+        for idx in 0..threads {
+            let (sender, receiver) = mpsc::channel();
+            let thread = thread::Builder::new()
+                .name(format!("net-session-{}", idx))
+                .spawn(move || run_sessions(receiver))?;
+            manager.senders.push(sender);
+            manager.threads.push(thread);
+        }
+        return Ok(manager);
+    }
+
+    pub fn register(
+        &mut self,
+        addr: SocketAddr,
+        conv: u32,
+        room_id: &str,
+        player_id: &str,
+        password: &str,
+        config: WorkerConfig,
+    ) -> Result<GameHandle, ClientError> {
+        let chan = NetChan::with_limits(config.input_limits, config.output_limits);
+        let validator = config.validator.clone();
+        let worker = NetWorker::with_config(
+            addr,
+            conv,
+            room_id,
+            player_id,
+            password,
+            chan.clone(),
+            config,
+        )?;
+        let session = Session {
+            worker,
+            chan: chan.clone(),
+            phase: SessionPhase::Running,
+            wakeup: Instant::now(),
+        };
+
+        let sender = &self.senders[self.next % self.senders.len()];
+        self.next += 1;
+        if sender.send(session).is_err() {
+            return Err(ClientError::Other("session thread exited".to_string()));
+        }
+        return Ok(GameHandle::new(conv, chan).with_validator(validator));
+    }
+
+    // waits until every registered session has finished
+    pub fn join(mut self) {
+        self.senders.clear();
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn run_sessions(receiver: Receiver<Session>) {
+    let mut sessions: Vec<Session> = Vec::new();
+    let mut open = true;
+    while open || !sessions.is_empty() {
+        // a registration cuts the sleep short
+        let wait = sessions
+            .iter()
+            .map(|session| session.wakeup)
+            .min()
+            .map(|at| at.saturating_duration_since(Instant::now()));
+        let mut received = match (open, wait) {
+            (true, Some(wait)) => receiver.recv_timeout(wait),
+            (true, None) => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            (false, wait) => {
+                thread::sleep(wait.unwrap_or(Duration::ZERO));
+                Err(RecvTimeoutError::Timeout)
+            }
+        };
+        loop {
+            match received {
+                Ok(mut session) => {
+                    if session.start() {
+                        sessions.push(session);
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => {
+                    open = false;
+                    break;
+                }
+            };
+            received = receiver.try_recv().map_err(|_| RecvTimeoutError::Timeout);
+        }
+
+        let now = Instant::now();
+        sessions.retain_mut(|session| session.wakeup > now || session.step(now));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::base::KCP_INTERVAL;
+    use crate::codec::CommandEx;
+    use crate::message::NetPlayerState;
+    use crate::mock::MockServer;
+    use std::collections::{BTreeMap, HashSet};
+    use std::sync::atomic::{AtomicI64, Ordering};
+
+    // ms the wall clock of test_session_wall_clock_step() is off
+    static WALL_OFFSET: AtomicI64 = AtomicI64::new(0);
+
+    fn stepped_wall() -> SystemTime {
+        let offset = WALL_OFFSET.load(Ordering::SeqCst);
+        let now = SystemTime::now();
+        return match offset >= 0 {
+            true => now + Duration::from_millis(offset as u64),
+            false => now - Duration::from_millis(-offset as u64),
+        };
+    }
+
+    #[test]
+    fn test_session_wall_clock_step() {
+        let server = MockServer::start(1).unwrap();
+        let chan = NetChan::new();
+        let mut worker =
+            NetWorker::new(server.addr(), 6666, "room", "player", "", chan.clone()).unwrap();
+        worker.set_wall(stepped_wall);
+        let handle = GameHandle::new(6666, chan.clone());
+        let (sender, receiver) = mpsc::channel();
+        let thread = thread::spawn(move || run_sessions(receiver));
+        let session = Session {
+            worker,
+            chan,
+            phase: SessionPhase::Running,
+            wakeup: Instant::now(),
+        };
+        assert!(sender.send(session).is_ok());
+        let started = handle.wait_for_start(Duration::from_secs(5)).unwrap();
+        assert!(started.is_some());
+
+        // stepped back an hour mid-match, the session is still stepped
+        WALL_OFFSET.store(-3600 * 1000, Ordering::SeqCst);
+        handle.send_input(1, &[], &[1]).unwrap();
+        let begin = Instant::now();
+        while server.records().commands.is_empty() {
+            assert!(begin.elapsed() < Duration::from_secs(5), "not stepped");
+            thread::sleep(Duration::from_millis(KCP_INTERVAL));
+        }
+
+        // and drained by its deadline, stepped back once more meanwhile
+        handle.game_over().unwrap();
+        WALL_OFFSET.store(-2 * 3600 * 1000, Ordering::SeqCst);
+        drop(sender);
+        thread.join().unwrap();
+        assert!(begin.elapsed() < Duration::from_secs(5));
+        assert_eq!(
+            handle.finish_info().unwrap().cause,
+            NetFinishCause::GameOver
+        );
+        assert_eq!(server.records().finishes.len(), 1);
+    }
+
+    #[test]
+    fn test_session_manager_loopback() {
+        let server = MockServer::start(20).unwrap();
+        let mut manager = SessionManager::new(2).unwrap();
+        let handles: Vec<GameHandle> = (1..=20)
+            .map(|conv| {
+                let player_id = format!("player-{}", conv);
+                manager
+                    .register(
+                        server.addr(),
+                        conv,
+                        "room",
+                        &player_id,
+                        "",
+                        WorkerConfig::default(),
+                    )
+                    .unwrap()
+            })
+            .collect();
+
+        let mut running = HashSet::new();
+        let mut commands = Vec::<CommandEx>::new();
+        let mut states = BTreeMap::<u32, NetPlayerState>::new();
+        let deadline = SystemTime::now() + Duration::from_secs(10);
+        while running.len() < handles.len() && SystemTime::now() < deadline {
+            for handle in handles.iter() {
+                handle.recv_output(&mut commands, &mut states).unwrap();
+                if states.get(&handle.conv()) == Some(&NetPlayerState::Running) {
+                    running.insert(handle.conv());
+                }
+            }
+            thread::sleep(Duration::from_millis(KCP_INTERVAL));
+        }
+        assert_eq!(running.len(), 20);
+
+        // game_over() discards inputs the worker hasn't taken yet
+        for handle in handles.iter() {
+            handle.send_input(1, &[], &[1]).unwrap();
+        }
+        while server.records().commands.len() < 20 && SystemTime::now() < deadline {
+            thread::sleep(Duration::from_millis(KCP_INTERVAL));
+        }
+        for handle in handles.iter() {
+            handle.game_over().unwrap();
+        }
+        manager.join();
+
+        for handle in handles.iter() {
+            assert_eq!(
+                handle.finish_info().unwrap().cause,
+                NetFinishCause::GameOver
+            );
+        }
+        let records = server.records();
+        assert_eq!(records.connects.len(), 20);
+        assert_eq!(records.commands.len(), 20);
+        assert_eq!(records.finishes.len(), 20);
+    }
+}
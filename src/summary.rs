@@ -0,0 +1,174 @@
+use crate::base::{TIMELINE_CAP, WARNING_KINDS};
+use crate::chan::NetWarning;
+use crate::message::{NetFinishCause, NetPlayerState};
+use std::collections::BTreeMap;
+
+// One-shot telemetry of a finished session, assembled by the worker from its
+// counters. Totals span reconnects, times are in ms. Features with counters
+// of their own add() them under a name instead of growing the struct.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SessionSummary {
+    pub initing_ms: u64,
+    pub waiting_ms: u64,
+    // Background and Paused are part of the match
+    pub running_ms: u64,
+    pub stopped_ms: u64,
+    // command packets handed to kcp and received from the server
+    pub frames_sent: u64,
+    pub frames_received: u64,
+    // through the UDP socket
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    // kcp segments sent again before their ack
+    pub retransmits: u64,
+    // slowest ack seen, see AckLatency
+    pub max_rtt: u64,
+    // raised per NetWarning::kind(), suppressed ones included
+    pub warnings: [u64; WARNING_KINDS],
+    pub reconnects: u64,
+    pub cause: Option<NetFinishCause>,
+    pub timeline: Timeline,
+    counters: BTreeMap<&'static str, u64>,
+}
+
+impl SessionSummary {
+    pub fn add(&mut self, name: &'static str, value: u64) {
+        let counter = self.counters.entry(name).or_insert(0);
+        *counter = counter.saturating_add(value);
+    }
+
+    pub fn counter(&self, name: &str) -> Option<u64> {
+        return self.counters.get(name).copied();
+    }
+
+    pub fn counters(&self) -> impl Iterator<Item = (&'static str, u64)> + '_ {
+        return self.counters.iter().map(|(name, value)| (*name, *value));
+    }
+
+    pub fn add_state_time(&mut self, state: NetPlayerState, ms: u64) {
+        let total = match state {
+            NetPlayerState::Initing => &mut self.initing_ms,
+            NetPlayerState::Waiting => &mut self.waiting_ms,
+            NetPlayerState::Running | NetPlayerState::Background | NetPlayerState::Paused => {
+                &mut self.running_ms
+            }
+            NetPlayerState::Stopped => &mut self.stopped_ms,
+        };
+        *total = total.saturating_add(ms);
+    }
+
+    pub fn warned(&mut self, warning: &NetWarning) {
+        self.warnings[warning.kind()] += 1;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimelineEvent {
+    State(NetPlayerState),
+    // a new kcp opened, the states after it are of the next attempt
+    Reconnect,
+}
+
+// what the connection went through, oldest first with ms since the worker
+// was created on its clock, fixed size so NetStats stays Copy
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeline {
+    entries: [(TimelineEvent, u64); TIMELINE_CAP],
+    len: usize,
+    // the oldest entries pushed out over TIMELINE_CAP
+    pub dropped: u64,
+}
+
+impl Default for Timeline {
+    fn default() -> Timeline {
+        return Timeline {
+            entries: [(TimelineEvent::Reconnect, 0); TIMELINE_CAP],
+            len: 0,
+            dropped: 0,
+        };
+    }
+}
+
+impl Timeline {
+    pub fn push(&mut self, event: TimelineEvent, at: u64) {
+        if self.len == TIMELINE_CAP {
+            self.entries.copy_within(1.., 0);
+            self.len -= 1;
+            self.dropped += 1;
+        }
+        self.entries[self.len] = (event, at);
+        self.len += 1;
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (TimelineEvent, u64)> + '_ {
+        return self.entries[..self.len].iter().copied();
+    }
+
+    pub fn len(&self) -> usize {
+        return self.len;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.len == 0;
+    }
+
+    // when the current state was entered, a reconnect doesn't change it
+    pub fn state_entered_at(&self) -> Option<u64> {
+        return self.entries[..self.len]
+            .iter()
+            .rev()
+            .find(|(event, _)| matches!(event, TimelineEvent::State(_)))
+            .map(|(_, at)| *at);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_session_summary() {
+        let mut summary = SessionSummary::default();
+        summary.add_state_time(NetPlayerState::Running, 100);
+        summary.add_state_time(NetPlayerState::Paused, 20);
+        summary.add_state_time(NetPlayerState::Initing, 5);
+        assert_eq!(summary.running_ms, 120);
+        assert_eq!(summary.initing_ms, 5);
+
+        summary.warned(&NetWarning::DroppedPackets(3));
+        summary.warned(&NetWarning::DroppedPackets(4));
+        assert_eq!(summary.warnings[NetWarning::DroppedPackets(0).kind()], 2);
+
+        assert_eq!(summary.counter("late_commands"), None);
+        summary.add("late_commands", 2);
+        summary.add("late_commands", 3);
+        summary.add("budget_overruns", 0);
+        assert_eq!(summary.counter("late_commands"), Some(5));
+        let counters: Vec<_> = summary.counters().collect();
+        assert_eq!(counters, vec![("budget_overruns", 0), ("late_commands", 5)]);
+    }
+
+    #[test]
+    fn test_timeline() {
+        let mut timeline = Timeline::default();
+        assert!(timeline.is_empty());
+        assert_eq!(timeline.state_entered_at(), None);
+        timeline.push(TimelineEvent::State(NetPlayerState::Initing), 0);
+        timeline.push(TimelineEvent::Reconnect, 30);
+        assert_eq!(timeline.state_entered_at(), Some(0));
+        timeline.push(TimelineEvent::State(NetPlayerState::Waiting), 50);
+        assert_eq!(timeline.state_entered_at(), Some(50));
+
+        for i in 0..TIMELINE_CAP as u64 {
+            timeline.push(TimelineEvent::State(NetPlayerState::Running), 100 + i);
+        }
+        assert_eq!(timeline.len(), TIMELINE_CAP);
+        assert_eq!(timeline.dropped, 3);
+        let first = timeline.iter().next().unwrap();
+        assert_eq!(first, (TimelineEvent::State(NetPlayerState::Running), 100));
+        assert_eq!(
+            timeline.state_entered_at(),
+            Some(100 + TIMELINE_CAP as u64 - 1)
+        );
+    }
+}
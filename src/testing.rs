@@ -0,0 +1,107 @@
+use crate::codec::Command;
+use crate::pool::BufferPool;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+// counts heap allocations per thread so tests can assert on hot paths
+pub struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = Cell::new(0);
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        return System.alloc(layout);
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        return System.realloc(ptr, layout, new_size);
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+pub fn allocations() -> usize {
+    return ALLOCATIONS.with(|count| count.get());
+}
+
+// Preallocates every buffer it hands out and remembers them by address,
+// so a buffer released to it proves where it came from. Never allocates
+// past new(), running dry is a test failure.
+#[derive(Debug)]
+pub struct TaggedPool {
+    bytes: Mutex<Vec<Vec<u8>>>,
+    commands: Mutex<Vec<Vec<Command>>>,
+    tags: HashSet<usize>,
+    acquired: Mutex<usize>,
+}
+
+impl TaggedPool {
+    pub fn new(buffers: usize, bytes_cap: usize, commands_cap: usize) -> TaggedPool {
+        let bytes: Vec<Vec<u8>> = (0..buffers)
+            .map(|_| Vec::with_capacity(bytes_cap))
+            .collect();
+        let commands: Vec<Vec<Command>> = (0..buffers)
+            .map(|_| Vec::with_capacity(commands_cap))
+            .collect();
+        let mut tags = HashSet::new();
+        tags.extend(bytes.iter().map(|buffer| buffer.as_ptr() as usize));
+        tags.extend(commands.iter().map(|buffer| buffer.as_ptr() as usize));
+        return TaggedPool {
+            bytes: Mutex::new(bytes),
+            commands: Mutex::new(commands),
+            tags,
+            acquired: Mutex::new(0),
+        };
+    }
+
+    pub fn tagged(&self, ptr: *const u8) -> bool {
+        return self.tags.contains(&(ptr as usize));
+    }
+
+    pub fn acquired(&self) -> usize {
+        return *self.acquired.lock().unwrap();
+    }
+}
+
+impl BufferPool for TaggedPool {
+    fn acquire_bytes(&self, hint: usize) -> Vec<u8> {
+        *self.acquired.lock().unwrap() += 1;
+        let bytes = self.bytes.lock().unwrap().pop().expect("out of bytes");
+        assert!(bytes.capacity() >= hint);
+        return bytes;
+    }
+
+    fn release_bytes(&self, bytes: Vec<u8>) {
+        assert!(self.tagged(bytes.as_ptr()), "not from the pool");
+        self.bytes.lock().unwrap().push(bytes);
+    }
+
+    fn acquire_commands(&self, hint: usize) -> Vec<Command> {
+        *self.acquired.lock().unwrap() += 1;
+        let commands = self
+            .commands
+            .lock()
+            .unwrap()
+            .pop()
+            .expect("out of commands");
+        assert!(commands.capacity() >= hint);
+        return commands;
+    }
+
+    fn release_commands(&self, commands: Vec<Command>) {
+        let ptr = commands.as_ptr() as *const u8;
+        assert!(self.tagged(ptr), "not from the pool");
+        self.commands.lock().unwrap().push(commands);
+    }
+}
@@ -0,0 +1,192 @@
+use crate::base::TICK_TIMING_BUCKETS;
+use std::time::{Duration, Instant};
+
+// durations in power of two µs buckets, bucket i counts those under 2^i µs
+// and the last everything longer, fixed size so NetStats stays Copy
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TickHistogram {
+    pub buckets: [u64; TICK_TIMING_BUCKETS],
+    pub count: u64,
+    pub max: Duration,
+}
+
+impl Default for TickHistogram {
+    fn default() -> TickHistogram {
+        return TickHistogram {
+            buckets: [0; TICK_TIMING_BUCKETS],
+            count: 0,
+            max: Duration::default(),
+        };
+    }
+}
+
+impl TickHistogram {
+    pub fn record(&mut self, duration: Duration) {
+        let micros = duration.as_micros();
+        let bucket = (128 - micros.leading_zeros()) as usize;
+        self.buckets[bucket.min(TICK_TIMING_BUCKETS - 1)] += 1;
+        self.count += 1;
+        self.max = self.max.max(duration);
+    }
+
+    pub fn merge(&mut self, other: &TickHistogram) {
+        for (bucket, count) in self.buckets.iter_mut().zip(other.buckets.iter()) {
+            *bucket += count;
+        }
+        self.count += other.count;
+        self.max = self.max.max(other.max);
+    }
+
+    // upper bound of the bucket holding the nearest rank, at most max
+    pub fn percentile(&self, percent: u64) -> Duration {
+        if self.count == 0 {
+            return Duration::default();
+        }
+        let rank = ((self.count * percent + 99) / 100).max(1);
+        let mut seen = 0;
+        for (i, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank && i < TICK_TIMING_BUCKETS - 1 {
+                return Duration::from_micros(1 << i).min(self.max);
+            }
+        }
+        return self.max;
+    }
+}
+
+// where a tick spends its time, `processing` is all of it but the udp wait
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TickTimings {
+    pub output: TickHistogram,
+    pub input: TickHistogram,
+    pub kcp: TickHistogram,
+    pub udp: TickHistogram,
+    pub processing: TickHistogram,
+}
+
+impl TickTimings {
+    pub fn merge(&mut self, other: &TickTimings) {
+        self.output.merge(&other.output);
+        self.input.merge(&other.input);
+        self.kcp.merge(&other.kcp);
+        self.udp.merge(&other.udp);
+        self.processing.merge(&other.processing);
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum TickStage {
+    Output,
+    Input,
+    Kcp,
+    Udp,
+}
+
+// laps of one tick on the worker's clock, reads nothing when disabled
+pub struct TickTimer {
+    clock: fn() -> Instant,
+    last: Option<Instant>,
+    // indexed by TickStage, the rest of the tick goes to processing only
+    laps: [Duration; 4],
+    rest: Duration,
+}
+
+impl TickTimer {
+    pub fn start(clock: fn() -> Instant, enabled: bool) -> TickTimer {
+        return TickTimer {
+            clock,
+            last: if enabled { Some(clock()) } else { None },
+            laps: [Duration::default(); 4],
+            rest: Duration::default(),
+        };
+    }
+
+    pub fn lap(&mut self, stage: TickStage) {
+        if let Some(elapsed) = self.elapsed() {
+            self.laps[stage as usize] += elapsed;
+        }
+    }
+
+    pub fn finish(mut self, timings: &mut TickTimings) {
+        match self.elapsed() {
+            Some(elapsed) => self.rest += elapsed,
+            None => return,
+        };
+        let [output, input, kcp, udp] = self.laps;
+        timings.output.record(output);
+        timings.input.record(input);
+        timings.kcp.record(kcp);
+        timings.udp.record(udp);
+        timings.processing.record(output + input + kcp + self.rest);
+    }
+
+    fn elapsed(&mut self) -> Option<Duration> {
+        let last = self.last?;
+        let now = (self.clock)();
+        self.last = Some(now);
+        return Some(now.saturating_duration_since(last));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::Cell;
+
+    #[test]
+    fn test_tick_histogram() {
+        let mut histogram = TickHistogram::default();
+        assert_eq!(histogram.percentile(99), Duration::default());
+        for micros in 1..=100 {
+            histogram.record(Duration::from_micros(micros));
+        }
+        histogram.record(Duration::from_secs(3600));
+        assert_eq!(histogram.count, 101);
+        assert_eq!(histogram.buckets[1], 1);
+        // 64..=100
+        assert_eq!(histogram.buckets[7], 37);
+        assert_eq!(histogram.buckets[TICK_TIMING_BUCKETS - 1], 1);
+        assert_eq!(histogram.percentile(50), Duration::from_micros(64));
+        assert_eq!(histogram.percentile(99), Duration::from_micros(128));
+        assert_eq!(histogram.percentile(100), Duration::from_secs(3600));
+
+        let mut merged = TickHistogram::default();
+        merged.record(Duration::from_micros(5));
+        merged.merge(&histogram);
+        assert_eq!(merged.count, 102);
+        assert_eq!(merged.buckets[3], 5);
+        assert_eq!(merged.max, Duration::from_secs(3600));
+    }
+
+    thread_local! {
+        static NOW: Cell<Option<Instant>> = Cell::new(None);
+    }
+
+    // 100µs later on every read
+    fn stepping_clock() -> Instant {
+        return NOW.with(|now| {
+            let next = now.get().unwrap_or_else(Instant::now) + Duration::from_micros(100);
+            now.set(Some(next));
+            return next;
+        });
+    }
+
+    #[test]
+    fn test_tick_timer() {
+        let mut timings = TickTimings::default();
+        let mut timer = TickTimer::start(stepping_clock, true);
+        timer.lap(TickStage::Output);
+        timer.lap(TickStage::Input);
+        timer.lap(TickStage::Kcp);
+        timer.lap(TickStage::Udp);
+        timer.finish(&mut timings);
+        assert_eq!(timings.udp.max, Duration::from_micros(100));
+        assert_eq!(timings.processing.max, Duration::from_micros(400));
+        assert_eq!(timings.processing.count, 1);
+
+        let mut timer = TickTimer::start(|| panic!("read"), false);
+        timer.lap(TickStage::Output);
+        timer.finish(&mut timings);
+        assert_eq!(timings.processing.count, 1);
+    }
+}
@@ -0,0 +1,20 @@
+use crate::base::ValidationError;
+use crate::codec::{Command, CommandEx};
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Accept,
+    // discarded and counted in NetStats::dropped_commands
+    Drop,
+    // finishes the connection with InvalidPacket
+    Fatal,
+}
+
+// Application rules for commands at the network boundary, outgoing ones are
+// checked on the game thread in GameHandle::send_input(), incoming ones on
+// the worker before they are queued.
+pub trait CommandValidator: fmt::Debug + Send + Sync {
+    fn validate_outgoing(&self, command: &Command) -> Result<(), ValidationError>;
+    fn validate_incoming(&self, command: &CommandEx) -> Verdict;
+}
@@ -0,0 +1,74 @@
+use crate::base::{WARNING_BURST, WARNING_INTERVAL, WARNING_KINDS};
+use crate::chan::NetWarning;
+
+// At most `burst` warnings of each kind per `interval`, the rest are only
+// counted, times are in ms.
+#[derive(Debug)]
+pub struct WarningLimiter {
+    interval: u64,
+    burst: u32,
+    // start and count of the current window per kind
+    windows: [(u64, u32); WARNING_KINDS],
+    suppressed: u64,
+}
+
+impl WarningLimiter {
+    pub fn new() -> WarningLimiter {
+        return WarningLimiter::with_limits(WARNING_INTERVAL, WARNING_BURST);
+    }
+
+    pub fn with_limits(interval: u64, burst: u32) -> WarningLimiter {
+        return WarningLimiter {
+            interval,
+            burst,
+            windows: [(0, 0); WARNING_KINDS],
+            suppressed: 0,
+        };
+    }
+
+    pub fn allow(&mut self, warning: &NetWarning, now: u64) -> bool {
+        let (start, count) = &mut self.windows[warning.kind()];
+        if now >= *start + self.interval {
+            *start = now;
+            *count = 0;
+        }
+        if *count >= self.burst {
+            self.suppressed += 1;
+            return false;
+        }
+        *count += 1;
+        return true;
+    }
+
+    pub fn suppressed(&self) -> u64 {
+        return self.suppressed;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_warning_limiter() {
+        let mut limiter = WarningLimiter::with_limits(1000, 2);
+        let dropped = |frame| NetWarning::InputDropped { frame };
+        let degraded = NetWarning::Degraded { jitter_delay: 1 };
+        assert!(limiter.allow(&dropped(1), 0));
+        assert!(limiter.allow(&dropped(2), 10));
+        assert!(!limiter.allow(&dropped(3), 20));
+        assert!(!limiter.allow(&dropped(4), 999));
+
+        // kinds are limited separately
+        assert!(limiter.allow(&degraded, 30));
+        assert!(limiter.allow(&degraded, 40));
+        assert!(!limiter.allow(&degraded, 50));
+        assert_eq!(limiter.suppressed(), 3);
+
+        // a new window per kind
+        assert!(limiter.allow(&dropped(5), 1000));
+        assert!(!limiter.allow(&degraded, 1000));
+        assert!(limiter.allow(&degraded, 1030));
+        assert_eq!(limiter.suppressed(), 4);
+    }
+}
@@ -0,0 +1,211 @@
+use crate::base::Capabilities;
+use crate::codec::{
+    Command, CommandDecoder, CommandEncoder, CommandEx, CommandV1, CommandVersion, NetMessage,
+};
+use crate::message::{NetFinishCause, NetPlayerState};
+use std::fs;
+use std::path::PathBuf;
+
+// Deployed servers decode exactly these bytes. A failure here means the wire
+// format changed, regenerate with `cargo test regenerate -- --ignored` only
+// when that is intended.
+
+// adding a variant without a fixture fails to compile
+fn fixture_name(msg: &NetMessage) -> &'static str {
+    return match msg {
+        NetMessage::Connect(_) => "connect",
+        NetMessage::Accept(_) => "accept",
+        NetMessage::State(_) => "state",
+        NetMessage::Start(_) => "start",
+        NetMessage::Finish(_) => "finish",
+        NetMessage::Command(_) => "command",
+        NetMessage::Hash(_) => "hash",
+        NetMessage::Challenge(_) => "challenge",
+        NetMessage::Pause(_) => "pause",
+        NetMessage::Resume(_) => "resume",
+        NetMessage::Application(..) => "application",
+    };
+}
+
+fn messages() -> Vec<NetMessage> {
+    return vec![
+        NetMessage::connect("room", "player", "secret"),
+        NetMessage::accept(),
+        NetMessage::state(7777, NetPlayerState::Running),
+        NetMessage::start(),
+        NetMessage::finish(345, NetFinishCause::DataOutOfSync),
+        NetMessage::command(345, 7777),
+        NetMessage::hash(345, 7777, &[0x85, 0x94, 0x41, 0x71, 0xf7, 0x39, 0x67, 0xe8]),
+        NetMessage::challenge(&[1, 2, 3, 4, 5, 6, 7, 8]),
+        NetMessage::pause(345),
+        NetMessage::resume(350),
+        NetMessage::Application(200, b"hello".to_vec()),
+    ];
+}
+
+// adding a version without a fixture fails to compile
+#[cfg(not(feature = "no-float-commands"))]
+fn command_list_name(version: CommandVersion) -> &'static str {
+    return match version {
+        CommandVersion::V1 => "command_list",
+        CommandVersion::V2 => "command_list_v2",
+    };
+}
+
+// the same list without its float command
+#[cfg(feature = "no-float-commands")]
+fn command_list_name(version: CommandVersion) -> &'static str {
+    return match version {
+        CommandVersion::V1 => "command_list_int",
+        CommandVersion::V2 => "command_list_v2_int",
+    };
+}
+
+#[cfg(not(feature = "no-float-commands"))]
+fn commands() -> Vec<Command> {
+    return vec![Command::Aaa(47, -57), Command::Bbb(3.0, -0.5, 8.25, 7)];
+}
+
+#[cfg(feature = "no-float-commands")]
+fn commands() -> Vec<Command> {
+    return vec![Command::Aaa(47, -57)];
+}
+
+// what is left of commands() after a trip through `version`
+fn decoded_commands(version: CommandVersion) -> Vec<CommandEx> {
+    return commands()
+        .into_iter()
+        .map(|command| match version {
+            CommandVersion::V1 => Command::from(CommandV1::from(&command)),
+            CommandVersion::V2 => command,
+        })
+        .map(|command| CommandEx {
+            conv: 7777,
+            frame: 345,
+            command,
+            stamps: None,
+        })
+        .collect();
+}
+
+fn encode_commands(version: CommandVersion) -> Vec<u8> {
+    let mut ce = CommandEncoder::new(0);
+    ce.set_version(version);
+    ce.set_conv(7777);
+    ce.commands().extend(commands());
+    ce.encode(345).unwrap();
+    return ce.command_bytes().to_vec();
+}
+
+fn fixture_path(name: &str) -> PathBuf {
+    return PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("fixtures")
+        .join("wire")
+        .join(format!("{}.hex", name));
+}
+
+fn read_fixture(name: &str) -> Vec<u8> {
+    let text = fs::read_to_string(fixture_path(name)).unwrap();
+    let digits: Vec<u8> = text
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .map(|c| c.to_digit(16).unwrap() as u8)
+        .collect();
+    return digits
+        .chunks(2)
+        .map(|pair| pair[0] << 4 | pair[1])
+        .collect();
+}
+
+fn write_fixture(name: &str, bytes: &[u8]) {
+    let text: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    fs::write(fixture_path(name), text + "\n").unwrap();
+}
+
+#[test]
+fn test_wire_messages() {
+    for msg in messages() {
+        let name = fixture_name(&msg);
+        let bytes = read_fixture(name);
+
+        let (decoded, offset) = NetMessage::decode(&bytes).unwrap();
+        assert_eq!(decoded, msg, "{}", name);
+        assert_eq!(offset, bytes.len(), "{}", name);
+
+        let mut encoded = Vec::new();
+        msg.encode(&mut encoded).unwrap();
+        assert_eq!(encoded, bytes, "{}", name);
+    }
+}
+
+#[test]
+fn test_wire_command_list() {
+    for version in CommandVersion::ALL.iter().copied() {
+        let name = command_list_name(version);
+        let bytes = read_fixture(name);
+        assert_eq!(encode_commands(version), bytes, "{}", name);
+
+        let mut cd = CommandDecoder::new(0);
+        cd.set_version(version);
+        cd.decode(&bytes).unwrap();
+        assert_eq!(cd.commands(), &decoded_commands(version)[..], "{}", name);
+    }
+}
+
+#[test]
+fn test_wire_command_versions() {
+    let v1 = read_fixture(command_list_name(CommandVersion::V1));
+    let v2 = read_fixture(command_list_name(CommandVersion::V2));
+
+    // a V2 client with a V1 server talks V1, with the new fields defaulted
+    let legacy = CommandVersion::negotiate(Capabilities::default());
+    assert_eq!(legacy, CommandVersion::V1);
+    let mut cd = CommandDecoder::new(0);
+    cd.set_version(legacy);
+    cd.decode(&v1).unwrap();
+    assert_eq!(cd.commands(), &decoded_commands(CommandVersion::V1)[..]);
+    assert_eq!(encode_commands(legacy), v1);
+    // the layouts only differ in the float command
+    #[cfg(not(feature = "no-float-commands"))]
+    assert!(cd.decode(&v2).is_err());
+
+    // and V2 once enabled, which a V1 client never advertises
+    let enabled = CommandVersion::supported(CommandVersion::LATEST);
+    let current = CommandVersion::negotiate(enabled);
+    assert_eq!(current, CommandVersion::V2);
+    assert!(CommandVersion::supported(CommandVersion::V1).is_empty());
+    cd.set_version(current);
+    cd.decode(&v2).unwrap();
+    assert_eq!(cd.commands(), &decoded_commands(CommandVersion::V2)[..]);
+    assert_eq!(encode_commands(current), v2);
+    #[cfg(not(feature = "no-float-commands"))]
+    assert!(cd.decode(&v1).is_err());
+}
+
+// what peers with float commands send can't be decoded
+#[cfg(feature = "no-float-commands")]
+#[test]
+fn test_wire_float_commands() {
+    let fixtures = [
+        (CommandVersion::V1, "command_list"),
+        (CommandVersion::V2, "command_list_v2"),
+    ];
+    for (version, name) in fixtures {
+        let mut cd = CommandDecoder::new(0);
+        cd.set_version(version);
+        assert!(cd.decode(&read_fixture(name)).is_err(), "{}", name);
+    }
+}
+
+#[test]
+#[ignore]
+fn regenerate() {
+    for msg in messages() {
+        let mut bytes = Vec::new();
+        msg.encode(&mut bytes).unwrap();
+        write_fixture(fixture_name(&msg), &bytes);
+    }
+    for version in CommandVersion::ALL.iter().copied() {
+        write_fixture(command_list_name(version), &encode_commands(version));
+    }
+}
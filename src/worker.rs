@@ -1,33 +1,507 @@
+use crate::assembly::FrameAssembler;
+use crate::bandwidth::{Bandwidth, LinkActivity};
 use crate::base::{
-    KCPError, COMMANDS_CAP, CONNECT_TIMEOUT, FINISH_TIMEOUT, KCP_INTERVAL, KCP_MAX_PACKET,
-    KCP_MIN_PACKET, START_TIMEOUT, UPDATE_TIMEOUT,
+    Acceptance, Capabilities, ConfigError, ConnectTimes, Conv, FinishInfo, KCPError,
+    ProtocolLimits, RateLimitedLogger, StartInfo, WorkerContext, ACCEPT_TIMEOUT,
+    ACK_LATENCY_SAMPLES, APPLICATION_TYPES, ASSEMBLY_MAX_WAIT, BACKGROUND_INTERVAL, COMMANDS_CAP,
+    COMMANDS_INLINE, DECISION_LOG_CAP, FINISH_TIMEOUT, FRAME_ACK_INTERVAL, FRAME_INTERVAL,
+    HASH_CADENCE, HASH_HISTORY, JITTER_MAX_DELAY, KCP_INTERVAL, KCP_MAX_PACKET, KCP_MIN_PACKET,
+    KCP_MTU, KCP_OVERHEAD, LOG_INTERVAL, OFFLINE_CONV, OFFLINE_ID, ONE_WAY_WARNING,
+    PACKET_WARN_PERCENT, PLAYERS_CAP, PRESENCE_INTERVAL, PROTOCOL_VERSION, REACH_TIMEOUT,
+    RECV_BATCH, START_TIMEOUT, STOP_GRACE, TICK_BUDGET, TIMER_JITTER_MAX, UPDATE_TIMEOUT,
 };
-use crate::chan::{NetChan, NetInputState};
-use crate::codec::{CommandDecoder, CommandEncoder, NetMessage};
+use crate::chan::{
+    ControlMsg, InputLimits, LagInfo, NetChan, NetEvent, NetInput, NetInputState, NetOutput,
+    NetWarning, OutputLimits, Presence, WorkerHandle,
+};
+use crate::clock::WallClock;
+use crate::codec::{
+    CommandBatch, CommandDecoder, CommandEncoder, CommandEx, CommandPadder, CommandStamps,
+    CommandVersion, Commands, MessageCategory, NetMessage,
+};
+use crate::credentials::{CredentialLimits, Credentials};
+use crate::decision::DecisionLog;
+use crate::degrade::{DegradeConfig, DegradeController, Rung};
+#[cfg(feature = "dictionary-compression")]
+use crate::dictionary::{CommandPacker, Dictionary};
+use crate::estimate::FrameEstimator;
+use crate::hash::{hash_commands, HashHistory};
+#[cfg(feature = "paranoid")]
+use crate::invariant::Invariants;
+use crate::jitter::JitterBuffer;
 use crate::kcp::NetKCP;
-use crate::message::{NetConnect, NetFinishCause, NetPlayerState, NetType};
+use crate::latency::{AckLatency, AckLatencyMeter};
+use crate::message::{NetFinishCause, NetPlayerState, NetType};
+use crate::offline::{NullServer, OfflineConfig};
+use crate::rebind::{RebindConfig, RebindMonitor};
+use crate::resume::SessionState;
+use crate::roster::{RosterHints, RosterLimits};
+use crate::schedule::{Schedule, Timer};
+use crate::summary::{SessionSummary, TimelineEvent};
+use crate::timing::{TickStage, TickTimer, TickTimings};
+use crate::validate::{CommandValidator, Verdict};
+use crate::warning::WarningLimiter;
 use anyhow::{Error, Result};
 use fn_error_context::context;
-use protobuf::{Clear, ProtobufEnum};
-use std::net::SocketAddr;
-use std::time::{Duration, SystemTime};
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryFrom;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+#[derive(Debug, Clone)]
+pub struct WorkerConfig {
+    // compare the hashes relayed by the server against our own
+    pub hash_check: bool,
+    pub hash_history: usize,
+    // pace remote commands at `frame_interval`, adding at most
+    // `jitter_max_delay` frames of latency
+    pub jitter_buffer: bool,
+    pub frame_interval: u64,
+    pub jitter_max_delay: u32,
+    // deliver remote commands as FrameReady events once every running conv
+    // reported the frame, waiting at most `assembly_max_wait` ms for
+    // stragglers, takes over from the jitter buffer
+    pub frame_assembly: bool,
+    pub assembly_max_wait: u64,
+    // hold commands of convs no State introduced yet until it arrives, the
+    // relay may forward a joining player's packets ahead of the roster
+    // update, none passes them through as before
+    pub roster: Option<RosterLimits>,
+    // application checks on outgoing and incoming commands
+    pub validator: Option<Arc<dyn CommandValidator>>,
+    // applied by the chan Client and SessionManager create
+    pub input_limits: InputLimits,
+    pub output_limits: OutputLimits,
+    // checked before anything is sent
+    pub credential_limits: CredentialLimits,
+    // of every message received, max_hash caps our own hashes too
+    pub protocol_limits: ProtocolLimits,
+    // prove the password with a challenge-response instead of sending it,
+    // servers without challenges are only sent the plaintext password when
+    // `plaintext_fallback` allows it
+    pub challenge: bool,
+    pub plaintext_fallback: bool,
+    // flush kcp right after a frame's packets are queued instead of waiting
+    // for the next interval, costs a flush per frame
+    pub low_latency: bool,
+    // inputs with commands or a hash submitted before Start
+    pub early_input: EarlyInputPolicy,
+    // commands resent for frames already delivered
+    pub late_commands: LateCommandPolicy,
+    // ms between game_over() and the Finish, kcp keeps acking what the
+    // server still sends without delivering it, under UPDATE_TIMEOUT s
+    pub stop_grace: u64,
+    // ms the finish is drained for at most when the server doesn't ack it
+    pub finish_timeout: u64,
+    // ms until anything comes back from the server, Unreachable after, and
+    // from then on until it accepts, AcceptTimeout after
+    pub reach_timeout: u64,
+    pub accept_timeout: u64,
+    // once stopped, time out UPDATE_TIMEOUT s after the server went quiet
+    // rather than after we stopped
+    pub stopped_keepalive: bool,
+    // states the server sends for our own conv
+    pub self_state: SelfStatePolicy,
+    // every state the server sends for other convs reaches the game, not
+    // only changes, for games using them as a heartbeat
+    pub forward_redundant_states: bool,
+    // a NetEvent::FrameAcked every FRAME_ACK_INTERVAL ms at most while the
+    // server acknowledges new frames, for input delay tuning
+    pub frame_acked_events: bool,
+    // CommandStamps on every batch received, when its packet was decoded
+    // and when the game drained it
+    pub timestamps: bool,
+    // ms of packet decoding per tick, kcp is updated regardless
+    pub tick_budget: u64,
+    // acked segments NetStats::ack_latency covers, kept per connection
+    pub ack_latency_samples: usize,
+    // datagrams a tick takes in at most, the rest waits in the kernel for
+    // the next one so a burst can't stall the tick
+    pub recv_batch: usize,
+    // time each tick's stages on the worker's clock into
+    // NetStats::tick_timings, for performance regression tests
+    pub tick_timings: bool,
+    // frames without commands send only their NetHash, for verification
+    // clients that re-simulate the match and never send commands
+    pub hash_only: bool,
+    // step down its ladder while upstream or the kcp backlog are over its
+    // thresholds and back up once they recovered, a QualityChanged warning
+    // each step, none keeps everything as configured
+    pub degradation: Option<DegradeConfig>,
+    // past the handshake, rebind the game socket after a socket error or
+    // `silence` ms without a datagram, e.g. the device switched networks,
+    // kcp carries on over the new one with the same conv, none never
+    // rebinds, offline neither
+    pub rebind: Option<RebindConfig>,
+    // frames the game sends without a hash get the canonical hash of their
+    // commands, see hash_commands()
+    pub hash_commands: bool,
+    // pad command packets to the next of these sizes, e.g. PADDING_BUCKETS,
+    // so their size doesn't give away the commands, none when empty or the
+    // server didn't enable Capabilities::PADDING
+    pub padding: Vec<usize>,
+    // shift the tick, presence and reconnect timers by up to this % of
+    // their interval, per conv so clients started together spread out, at
+    // most TIMER_JITTER_MAX, 0 keeps them aligned
+    pub timer_jitter: u64,
+    // the null server of NetWorker::offline()
+    pub offline: OfflineConfig,
+    // hash everything the chan delivers to the game, see DeliveryHasher,
+    // read from NetStats::delivery_hash and FinishInfo
+    pub delivery_hash: bool,
+    // ticks of Decisions kept, at most DECISION_LOG_CAP, attached to
+    // FinishInfo when the session ends other than with GameOver, 0 records
+    // nothing
+    pub decision_log: usize,
+    // type bytes from APPLICATION_TYPES up the game handles itself, their
+    // payloads are delivered as NetEvent::Application
+    pub application_types: Vec<u8>,
+    // the newest command tail layout advertised, older ones during a
+    // rollout, the server picks among them
+    pub command_version: CommandVersion,
+    // compress command tails with it once the server accepted its id and
+    // enabled Capabilities::COMPRESSION
+    #[cfg(feature = "dictionary-compression")]
+    pub dictionary: Option<Dictionary>,
+    // end the session on the first violated Invariants check instead of
+    // only counting it
+    #[cfg(feature = "paranoid")]
+    pub paranoid_fatal: bool,
+}
+
+impl Default for WorkerConfig {
+    fn default() -> WorkerConfig {
+        return WorkerConfig {
+            hash_check: false,
+            hash_history: HASH_HISTORY,
+            jitter_buffer: false,
+            frame_interval: FRAME_INTERVAL,
+            jitter_max_delay: JITTER_MAX_DELAY,
+            frame_assembly: false,
+            assembly_max_wait: ASSEMBLY_MAX_WAIT,
+            roster: None,
+            validator: None,
+            input_limits: InputLimits::default(),
+            output_limits: OutputLimits::default(),
+            credential_limits: CredentialLimits::default(),
+            protocol_limits: ProtocolLimits::default(),
+            challenge: false,
+            plaintext_fallback: false,
+            low_latency: false,
+            early_input: EarlyInputPolicy::Error,
+            late_commands: LateCommandPolicy::Drop,
+            stop_grace: STOP_GRACE,
+            finish_timeout: FINISH_TIMEOUT * 1000,
+            reach_timeout: REACH_TIMEOUT * 1000,
+            accept_timeout: ACCEPT_TIMEOUT * 1000,
+            stopped_keepalive: true,
+            self_state: SelfStatePolicy::ApplyStopped,
+            forward_redundant_states: false,
+            frame_acked_events: false,
+            timestamps: false,
+            tick_budget: TICK_BUDGET,
+            ack_latency_samples: ACK_LATENCY_SAMPLES,
+            recv_batch: RECV_BATCH,
+            tick_timings: false,
+            hash_only: false,
+            degradation: None,
+            rebind: None,
+            hash_commands: false,
+            padding: Vec::new(),
+            timer_jitter: 0,
+            offline: OfflineConfig::default(),
+            delivery_hash: false,
+            decision_log: 0,
+            application_types: Vec::new(),
+            command_version: CommandVersion::LATEST,
+            #[cfg(feature = "dictionary-compression")]
+            dictionary: None,
+            #[cfg(feature = "paranoid")]
+            paranoid_fatal: false,
+        };
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EarlyInputPolicy {
+    // fail with Unexpected, the game loop shouldn't tick before Start
+    Error,
+    // send them right after Start, every input counts towards `max_frames`
+    // and more fail like Error
+    Buffer { max_frames: usize },
+    // counted in NetStats::dropped_inputs, `warn` adds an InputDropped
+    // warning each
+    Drop { warn: bool },
+}
+
+// Commands for a frame of their conv the game already got, a server may
+// resend them after a reconnect or retransmit them. With frame assembly it
+// is any frame already released.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LateCommandPolicy {
+    // counted in NetStats::late_commands
+    Drop,
+    // as NetEvent::LateCommands, for replay and verification tools
+    DeliverFlagged,
+    // fail with LateCommands, for strict test environments
+    Fatal,
+}
+
+// Only Running and Stopped are compared, Initing and Waiting follow the
+// handshake and Background and Paused are only reported. Once stopped all
+// packets are ignored, so in practice it is the server stopping us.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SelfStatePolicy {
+    Ignore,
+    // the server is authoritative, a Stopped starts the Stopped timeout
+    Apply,
+    // Apply for Stopped, e.g. kicked, Ignore otherwise
+    ApplyStopped,
+    // a StateDiverged warning each time the server disagrees
+    Verify,
+}
+
+// the server behind the worker, over kcp or in-process when offline
+enum Transport {
+    Kcp(Box<NetKCP>),
+    Null(NullServer),
+}
+
+impl Transport {
+    // exactly one message per kcp message, a buffer left over from an
+    // error path is caught here rather than by the server
+    fn send_kcp(&mut self, bytes: &[u8]) -> Result<()> {
+        if NetMessage::validate_stream(bytes)? != 1 {
+            return Err(KCPError::Misframed.into());
+        }
+        match self {
+            Transport::Kcp(kcp) => kcp.send_kcp(bytes)?,
+            Transport::Null(server) => server.send(bytes)?,
+        };
+        return Ok(());
+    }
+
+    fn recv_into(&mut self, buffer: &mut [u8]) -> Result<Option<usize>> {
+        return match self {
+            Transport::Kcp(kcp) => Ok(kcp.recv_into(buffer)?),
+            Transport::Null(server) => server.recv(buffer),
+        };
+    }
+
+    // offline nobody else hears the side channel
+    fn send_unreliable(&mut self, payload: &[u8]) -> Result<()> {
+        if let Transport::Kcp(kcp) = self {
+            kcp.send_unreliable(payload)?;
+        }
+        return Ok(());
+    }
+
+    fn recv_unreliable(&mut self, payloads: &mut Vec<(u32, Vec<u8>)>) {
+        if let Transport::Kcp(kcp) = self {
+            kcp.recv_unreliable(payloads);
+        }
+    }
+
+    fn update_kcp(&mut self, current: u64) {
+        match self {
+            Transport::Kcp(kcp) => kcp.update_kcp(current),
+            Transport::Null(server) => server.update(current),
+        };
+    }
+
+    fn syscalls_saved(&self) -> u64 {
+        return match self {
+            Transport::Kcp(kcp) => kcp.syscalls_saved(),
+            Transport::Null(_) => 0,
+        };
+    }
+
+    fn drained(&self) -> usize {
+        return match self {
+            Transport::Kcp(kcp) => kcp.drained(),
+            Transport::Null(_) => 0,
+        };
+    }
+
+    // nothing is received offline, and nothing rebound
+    fn received(&self) -> u64 {
+        return match self {
+            Transport::Kcp(kcp) => kcp.received(),
+            Transport::Null(_) => 0,
+        };
+    }
+
+    fn rebind(&mut self) -> Result<()> {
+        if let Transport::Kcp(kcp) = self {
+            kcp.rebind()?;
+        }
+        return Ok(());
+    }
+
+    // offline nothing is retransmitted, the ticks are all there is
+    fn check(&self, current: u64) -> u64 {
+        return match self {
+            Transport::Kcp(kcp) => kcp.check(current),
+            Transport::Null(_) => current + KCP_INTERVAL,
+        };
+    }
+
+    // offline there is no socket to wait on, the tick is paced all the same
+    fn update_udp(&mut self, until: SystemTime) -> Result<()> {
+        match self {
+            Transport::Kcp(kcp) => kcp.update_udp(until)?,
+            Transport::Null(_) => {
+                // a clock stepped back since `until` doesn't stretch it
+                if let Ok(wait) = until.duration_since(SystemTime::now()) {
+                    thread::sleep(wait.min(Duration::from_millis(BACKGROUND_INTERVAL)));
+                }
+            }
+        };
+        return Ok(());
+    }
+
+    fn flush(&mut self) {
+        if let Transport::Kcp(kcp) = self {
+            kcp.flush();
+        }
+    }
+
+    fn waitsnd(&self) -> u32 {
+        return match self {
+            Transport::Kcp(kcp) => kcp.waitsnd(),
+            Transport::Null(_) => 0,
+        };
+    }
+
+    fn bandwidth(&mut self, current: u64) -> Bandwidth {
+        return match self {
+            Transport::Kcp(kcp) => kcp.bandwidth(current),
+            Transport::Null(server) => server.bandwidth(current),
+        };
+    }
+
+    // nothing is acked offline
+    fn ack_latency(&mut self) -> AckLatency {
+        return match self {
+            Transport::Kcp(kcp) => kcp.ack_latency(),
+            Transport::Null(_) => AckLatency::default(),
+        };
+    }
+
+    #[cfg(test)]
+    fn net(&mut self) -> &mut NetKCP {
+        return match self {
+            Transport::Kcp(kcp) => kcp,
+            Transport::Null(_) => panic!("offline"),
+        };
+    }
+}
+
+// where the connect handshake is, Initing only
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Handshake {
+    Plaintext,
+    AwaitingChallenge,
+    Responded,
+}
 
 pub struct NetWorker {
-    chan: NetChan,
-    kcp: Box<NetKCP>,
+    config: WorkerConfig,
+    chan: WorkerHandle,
+    inputs: Vec<NetInput>,
+    output: NetOutput,
+    kcp: Transport,
     kcp_buffer: Vec<u8>,
-    conv: u32,
-    room_id: String,
-    player_id: String,
-    password: String,
+    // side-channel payloads between the chan and the socket
+    unreliable_out: Vec<Vec<u8>>,
+    unreliable_in: Vec<(u32, Vec<u8>)>,
+    addr: SocketAddr,
+    // adopted from the caller, kept across reconnects
+    socket: Option<UdpSocket>,
+    conv: Conv,
+    credentials: Credentials,
+    handshake: Handshake,
+    // the last one the server issued, presented on connect when resuming
+    resume_token: Vec<u8>,
+    resuming: bool,
 
+    // negotiated in the Accept, nothing optional is sent before or without
+    capabilities: Capabilities,
     cmd_encoder: CommandEncoder,
+    padder: Option<CommandPadder>,
+    // unpacks whatever arrives compressed, packs ours once `packing` was
+    // agreed on in the Accept
+    #[cfg(feature = "dictionary-compression")]
+    packer: Option<CommandPacker>,
+    #[cfg(feature = "dictionary-compression")]
+    packing: bool,
+    early_inputs: VecDeque<(u32, Commands, Vec<u8>)>,
+    controls: Vec<ControlMsg>,
     cmd_decoder: CommandDecoder,
+    hashes: HashHistory,
+    jitter: Option<JitterBuffer>,
+    jitter_input: Vec<CommandBatch>,
+    assembler: Option<FrameAssembler>,
+    estimator: FrameEstimator,
+    roster: Option<RosterHints>,
+    degrade: Option<DegradeController>,
+    lag: HashMap<u32, LagInfo>,
+    // last state of each other conv forwarded to the chan, convs beyond
+    // PLAYERS_CAP are always forwarded
+    states: HashMap<u32, NetPlayerState>,
+    // newest frame of the commands in `output`, states are tagged with it
+    delivered_frame: u32,
+    // and of each conv, convs beyond PLAYERS_CAP aren't checked for late
+    // commands
+    conv_delivered: HashMap<u32, u32>,
+    // last reported, and when in ms
+    presence: Presence,
+    presence_at: Option<u64>,
+    // newest of our frames the server has, and the last reported, when in ms
+    acked_frame: Option<u32>,
+    acked_reported: Option<(u32, u64)>,
+    schedule: Schedule,
+    packet_log: RateLimitedLogger,
+    large_packet_warned: bool,
+    warnings: WarningLimiter,
+    // measures the tick budget, replaced in tests
+    clock: fn() -> Instant,
+    // every other time, in ms since start()
+    wall: WallClock,
+    #[cfg(feature = "paranoid")]
+    invariants: Invariants,
+    // of the connections replaced so far, see summarize()
+    summary: SessionSummary,
 
+    // never Background or Paused, those are only reported
     state: NetPlayerState,
+    // on `clock`, for the time per state of the summary
+    state_since: Instant,
+    // on `clock`, what the timeline counts from
+    created: Instant,
     frame: u32,
-    started_at: SystemTime,
-    stopped_at: SystemTime,
+    // set by the Start, inputs before this frame are rejected
+    first_frame: u32,
+    // set by the server, inputs after this frame are rejected
+    paused: Option<u32>,
+    // ms the server first answered, Initing only
+    reached_at: Option<u64>,
+    stopped_at: u64,
+    // ms the Finish is sent at after game_over()
+    stopping_at: Option<u64>,
+    // the last packet ignored while Stopped
+    heard_at: u64,
+    // of the current connection, at the socket
+    link: LinkActivity,
+    // with WorkerConfig::rebind, and the datagrams received it last saw
+    rebind: Option<RebindMonitor>,
+    rebind_received: u64,
+    // NetWarning::NoInboundTraffic was sent for the current silence
+    one_way_warned: bool,
+    // with WorkerConfig::decision_log
+    decisions: Option<DecisionLog>,
     updated_at: SystemTime,
 }
 
@@ -43,304 +517,5620 @@ impl NetWorker {
         password: &str,
         chan: NetChan,
     ) -> Result<NetWorker> {
-        return Ok(NetWorker {
+        return NetWorker::with_config(
+            addr,
+            conv,
+            room_id,
+            player_id,
+            password,
             chan,
-            kcp: NetKCP::new(addr, conv)?,
+            WorkerConfig::default(),
+        );
+    }
+
+    #[context("NetWorker::with_config()")]
+    pub fn with_config(
+        addr: SocketAddr,
+        conv: u32,
+        room_id: &str,
+        player_id: &str,
+        password: &str,
+        chan: NetChan,
+        config: WorkerConfig,
+    ) -> Result<NetWorker> {
+        let conv = Conv::try_from(conv)?;
+        let credentials =
+            Credentials::new(room_id, player_id, password, &config.credential_limits)?;
+        return NetWorker::create(addr, None, None, conv, credentials, chan, config);
+    }
+
+    // adopts a socket the caller already bound, e.g. after punching a hole
+    // through a NAT, instead of binding a new one, so the mapping survives
+    #[allow(clippy::too_many_arguments)]
+    #[context("NetWorker::with_socket()")]
+    pub fn with_socket(
+        socket: UdpSocket,
+        peer: SocketAddr,
+        conv: u32,
+        room_id: &str,
+        player_id: &str,
+        password: &str,
+        chan: NetChan,
+        config: WorkerConfig,
+    ) -> Result<NetWorker> {
+        let local = socket.local_addr().map_err(KCPError::IO)?;
+        if local.is_ipv4() != peer.is_ipv4() {
+            return Err(ConfigError::InvalidField {
+                field: "socket",
+                reason: "address family",
+            }
+            .into());
+        }
+        if let Ok(connected) = socket.peer_addr() {
+            if connected != peer {
+                return Err(ConfigError::InvalidField {
+                    field: "socket",
+                    reason: "connected to another peer",
+                }
+                .into());
+            }
+        }
+        socket.set_read_timeout(None).map_err(KCPError::IO)?;
+        socket.set_nonblocking(true).map_err(KCPError::IO)?;
+
+        let conv = Conv::try_from(conv)?;
+        let credentials =
+            Credentials::new(room_id, player_id, password, &config.credential_limits)?;
+        return NetWorker::create(peer, Some(socket), None, conv, credentials, chan, config);
+    }
+
+    // a single player match against an in-process NullServer, nothing is
+    // sent over the network but the worker runs exactly as it would online
+    #[context("NetWorker::offline()")]
+    pub fn offline(chan: NetChan, config: WorkerConfig) -> Result<NetWorker> {
+        let conv = Conv::try_from(OFFLINE_CONV)?;
+        let credentials = Credentials::new(OFFLINE_ID, OFFLINE_ID, "", &config.credential_limits)?;
+        let server = NullServer::new(conv.get(), config.offline);
+        let addr = SocketAddr::from(([127, 0, 0, 1], 0));
+        return NetWorker::create(addr, None, Some(server), conv, credentials, chan, config);
+    }
+
+    #[context("NetWorker::create()")]
+    fn create(
+        addr: SocketAddr,
+        socket: Option<UdpSocket>,
+        server: Option<NullServer>,
+        conv: Conv,
+        credentials: Credentials,
+        chan: NetChan,
+        config: WorkerConfig,
+    ) -> Result<NetWorker> {
+        // given back if anything below fails
+        let handle = chan.attach()?;
+        if config.timer_jitter > TIMER_JITTER_MAX {
+            return Err(ConfigError::InvalidField {
+                field: "timer_jitter",
+                reason: "too large",
+            }
+            .into());
+        }
+        if config
+            .application_types
+            .iter()
+            .any(|typ| *typ < APPLICATION_TYPES)
+        {
+            return Err(ConfigError::InvalidField {
+                field: "application_types",
+                reason: "reserved",
+            }
+            .into());
+        }
+        if config.output_limits.max_commands == 0 {
+            return Err(ConfigError::InvalidField {
+                field: "output_limits.max_commands",
+                reason: "zero",
+            }
+            .into());
+        }
+        if config.stop_grace >= UPDATE_TIMEOUT * 1000 {
+            return Err(ConfigError::InvalidField {
+                field: "stop_grace",
+                reason: "over UPDATE_TIMEOUT",
+            }
+            .into());
+        }
+        let history = match config.hash_check {
+            true => config.hash_history,
+            false => 0,
+        };
+        let jitter = match config.jitter_buffer {
+            true => Some(JitterBuffer::new(
+                config.frame_interval,
+                config.jitter_max_delay,
+            )),
+            false => None,
+        };
+        let assembler = match config.frame_assembly {
+            true => Some(FrameAssembler::new(config.assembly_max_wait)),
+            false => None,
+        };
+        let estimator = FrameEstimator::new(config.frame_interval);
+        let roster = config.roster.map(RosterHints::new);
+        let decisions = match config.decision_log.min(DECISION_LOG_CAP) {
+            0 => None,
+            cap => Some(DecisionLog::new(cap)),
+        };
+        let degrade = config.degradation.clone().map(DegradeController::new);
+        let pool = handle.pool();
+        let mut cmd_encoder = CommandEncoder::with_pool(COMMANDS_INLINE, pool.clone())
+            .with_max_hash(config.protocol_limits.max_hash);
+        cmd_encoder.set_conv(conv.get());
+        let padder = match config.padding.is_empty() {
+            true => None,
+            false => Some(CommandPadder::new(&config.padding)),
+        };
+        #[cfg(feature = "dictionary-compression")]
+        let packer = match &config.dictionary {
+            Some(dictionary) => Some(CommandPacker::new(dictionary)?),
+            None => None,
+        };
+        let schedule = Schedule::new(conv.get(), config.timer_jitter);
+        chan.set_max_hash(config.protocol_limits.max_hash);
+        if config.delivery_hash {
+            chan.enable_delivery_hash();
+        }
+        let rebind = match server {
+            Some(_) => None,
+            None => config.rebind.map(|rebind| RebindMonitor::new(rebind, 0)),
+        };
+        let kcp = match server {
+            Some(server) => Transport::Null(server),
+            None => Transport::Kcp(NetWorker::open_kcp(addr, conv, socket.as_ref(), &config)?),
+        };
+        let mut output = NetOutput::new();
+        output.stats.kcp_mtu = KCP_MTU;
+        output.stats.quality_level = degrade.as_ref().map_or(0, DegradeController::level);
+        if config.tick_timings {
+            output.stats.tick_timings = Some(TickTimings::default());
+        }
+        #[cfg(feature = "paranoid")]
+        let invariants = Invariants::new(config.paranoid_fatal);
+        let mut worker = NetWorker {
+            config,
+            chan: handle,
+            inputs: Vec::with_capacity(3),
+            output,
+            kcp,
             kcp_buffer: Vec::with_capacity(KCP_MAX_PACKET),
+            unreliable_out: Vec::new(),
+            unreliable_in: Vec::new(),
+            addr,
+            socket,
             conv,
-            room_id: room_id.to_string(),
-            player_id: player_id.to_string(),
-            password: password.to_string(),
+            credentials,
+            handshake: Handshake::Plaintext,
+            resume_token: Vec::new(),
+            resuming: false,
 
-            cmd_encoder: CommandEncoder::new(COMMANDS_CAP),
-            cmd_decoder: CommandDecoder::new(COMMANDS_CAP * 2),
+            capabilities: Capabilities::default(),
+            cmd_encoder,
+            padder,
+            #[cfg(feature = "dictionary-compression")]
+            packer,
+            #[cfg(feature = "dictionary-compression")]
+            packing: false,
+            early_inputs: VecDeque::new(),
+            controls: Vec::new(),
+            cmd_decoder: CommandDecoder::new(COMMANDS_INLINE).with_pool(pool),
+            hashes: HashHistory::new(history),
+            jitter,
+            jitter_input: Vec::with_capacity(1),
+            assembler,
+            estimator,
+            roster,
+            degrade,
+            lag: HashMap::with_capacity(PLAYERS_CAP),
+            states: HashMap::with_capacity(PLAYERS_CAP),
+            delivered_frame: 0,
+            conv_delivered: HashMap::with_capacity(PLAYERS_CAP),
+            presence: Presence::Active,
+            presence_at: None,
+            acked_frame: None,
+            acked_reported: None,
+            schedule,
+            packet_log: RateLimitedLogger::new(LOG_INTERVAL),
+            large_packet_warned: false,
+            warnings: WarningLimiter::new(),
+            clock: Instant::now,
+            wall: WallClock::new(SystemTime::now),
+            #[cfg(feature = "paranoid")]
+            invariants,
+            summary: SessionSummary::default(),
 
             state: NetPlayerState::Initing,
+            state_since: Instant::now(),
+            created: Instant::now(),
             frame: 0,
-            started_at: SystemTime::now() + Duration::from_secs(60 * 60 * 24 * 3650),
-            stopped_at: SystemTime::now() + Duration::from_secs(60 * 60 * 24 * 3650),
+            first_frame: 1,
+            paused: None,
+            reached_at: None,
+            stopped_at: u64::MAX,
+            stopping_at: None,
+            heard_at: 0,
+            link: LinkActivity::default(),
+            rebind,
+            rebind_received: 0,
+            one_way_warned: false,
+            decisions,
             updated_at: SystemTime::now(),
-        });
+        };
+        let initing = TimelineEvent::State(NetPlayerState::Initing);
+        worker.output.stats.timeline.push(initing, 0);
+        worker.publish_session();
+        return Ok(worker);
+    }
+
+    // rejoins the match `state` was captured from instead of joining anew,
+    // inputs continue after its last frame
+    pub fn resume(&mut self, state: &SessionState) -> Result<(), ConfigError> {
+        if state.protocol_version != PROTOCOL_VERSION {
+            return Err(ConfigError::InvalidField {
+                field: "protocol_version",
+                reason: "unsupported",
+            });
+        }
+        if state.resume_token.is_empty() {
+            return Err(ConfigError::InvalidField {
+                field: "resume_token",
+                reason: "missing",
+            });
+        }
+        self.resume_token = state.resume_token.clone();
+        self.resuming = true;
+        self.frame = state.last_frame;
+        self.output.stats.sent_frame = state.last_frame;
+        if let Some(assembler) = &mut self.assembler {
+            assembler.start_at(state.last_frame + 1);
+        }
+        self.publish_session();
+        return Ok(());
     }
 
     pub fn run(&mut self) {
-        self.started_at = SystemTime::now();
-        if let Err(err) = self.connect() {
-            self.finish(err, false);
-        } else {
-            if let Err(err) = self.update() {
+        let mut attempts = 0;
+        loop {
+            // past it when connect_blocking() got us in
+            let started = match self.state {
+                NetPlayerState::Initing => self.start(),
+                _ => Ok(()),
+            };
+            if let Err(err) = started {
+                self.finish(err, false);
+                return;
+            }
+            let err = match self.update() {
+                Ok(()) => return,
+                Err(err) => err,
+            };
+            if !self.should_reconnect(&err, attempts) {
                 self.finish(err, true);
+                return;
             }
+            attempts += 1;
+            let delay = self
+                .schedule
+                .offset(Timer::Reconnect, self.config.reach_timeout);
+            thread::sleep(Duration::from_millis(delay));
+            if let Err(err) = self.reconnect() {
+                self.finish(err, false);
+                return;
+            }
+        }
+    }
+
+    // only the handshake is retried, later the server has moved on
+    fn should_reconnect(&self, err: &Error, attempts: usize) -> bool {
+        if self.state != NetPlayerState::Initing || matches!(self.kcp, Transport::Null(_)) {
+            return false;
         }
+        return match err.downcast_ref::<KCPError>() {
+            Some(err) => err.is_retryable().allows(attempts),
+            None => false,
+        };
+    }
+
+    #[context("NetWorker::reconnect()")]
+    fn reconnect(&mut self) -> Result<()> {
+        let current = self.current();
+        let (bandwidth, latency) = (self.kcp.bandwidth(current), self.kcp.ack_latency());
+        Self::add_connection(&mut self.summary, bandwidth, latency);
+        self.summary.reconnects += 1;
+        let at = self.since_created();
+        self.output
+            .stats
+            .timeline
+            .push(TimelineEvent::Reconnect, at);
+        let kcp = NetWorker::open_kcp(self.addr, self.conv, self.socket.as_ref(), &self.config)?;
+        self.kcp = Transport::Kcp(kcp);
+        self.kcp_buffer.clear();
+        self.link = LinkActivity::default();
+        self.rebind_received = 0;
+        #[cfg(feature = "paranoid")]
+        self.invariants.reconnected();
+        return Ok(());
+    }
+
+    fn add_connection(summary: &mut SessionSummary, bandwidth: Bandwidth, latency: AckLatency) {
+        summary.bytes_up += bandwidth.sent_total.bytes;
+        summary.bytes_down += bandwidth.recv_total.bytes;
+        summary.retransmits += latency.retransmits;
+        summary.max_rtt = summary.max_rtt.max(latency.max);
+    }
+
+    // the current connection and state added to what came before, counters
+    // of other features are registered by name
+    fn summarize(&mut self) -> SessionSummary {
+        let current = self.current();
+        let mut summary = self.summary.clone();
+        let now = (self.clock)();
+        let ms = now.saturating_duration_since(self.state_since).as_millis() as u64;
+        summary.add_state_time(self.state, ms);
+        summary.timeline = self.output.stats.timeline;
+        let (bandwidth, latency) = (self.kcp.bandwidth(current), self.kcp.ack_latency());
+        Self::add_connection(&mut summary, bandwidth, latency);
+        let stats = &self.output.stats;
+        summary.add("dropped_commands", stats.dropped_commands);
+        summary.add("late_commands", stats.late_commands);
+        summary.add("undecodable_packets", stats.undecodable_packets);
+        summary.add("suppressed_warnings", stats.suppressed_warnings);
+        summary.add("budget_overruns", stats.budget_overruns);
+        summary.add("clock_anomalies", stats.clock_anomalies);
+        summary.add("redundant_states", stats.redundant_states);
+        summary.add("dropped_inputs", stats.dropped_inputs);
+        summary.add("padding_bytes", stats.padding_bytes);
+        summary.add("rebinds", stats.rebinds);
+        return summary;
+    }
+
+    // a fresh socket each time, unless one was adopted
+    fn open_kcp(
+        addr: SocketAddr,
+        conv: Conv,
+        socket: Option<&UdpSocket>,
+        config: &WorkerConfig,
+    ) -> Result<Box<NetKCP>> {
+        let mut kcp = match socket {
+            Some(socket) => {
+                let socket = socket.try_clone().map_err(KCPError::IO)?;
+                NetKCP::with_socket(socket, addr, conv.get())?
+            }
+            None => NetKCP::new(addr, conv.get())?,
+        };
+        kcp.set_ack_latency(AckLatencyMeter::new(conv.get(), config.ack_latency_samples));
+        kcp.set_recv_batch(config.recv_batch);
+        return Ok(kcp);
+    }
+
+    // of the adopted socket, None when the worker bound its own
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        return self
+            .socket
+            .as_ref()
+            .and_then(|socket| socket.local_addr().ok());
+    }
+
+    // for other modules' tests, before start()
+    #[cfg(test)]
+    pub fn set_wall(&mut self, now: fn() -> SystemTime) {
+        self.wall = WallClock::new(now);
+    }
+
+    pub fn start(&mut self) -> Result<()> {
+        self.wall.reset();
+        self.reached_at = None;
+        self.output.stats.connect = ConnectTimes::default();
+        self.negotiate(0);
+        return self.connect();
     }
 
     #[context("NetWorker::update()")]
     pub fn connect(&mut self) -> Result<()> {
-        let mut connect = NetConnect::default();
-        connect.room_id = self.room_id.clone();
-        connect.player_id = self.player_id.clone();
-        connect.password = self.password.clone();
+        let credentials = &self.credentials;
+        let (connect, handshake) = match (self.resuming, self.config.challenge) {
+            (true, _) => (
+                NetMessage::connect_resume(
+                    credentials.room_id(),
+                    credentials.player_id(),
+                    &self.resume_token,
+                ),
+                Handshake::Plaintext,
+            ),
+            (false, true) => (
+                NetMessage::connect_challenge(credentials.room_id(), credentials.player_id()),
+                Handshake::AwaitingChallenge,
+            ),
+            (false, false) => (
+                NetMessage::connect(
+                    credentials.room_id(),
+                    credentials.player_id(),
+                    credentials.password(),
+                ),
+                Handshake::Plaintext,
+            ),
+        };
+        self.handshake = handshake;
+        let connect = self.advertise(connect);
+        return self.send_message(&connect);
+    }
+
+    // the extra Initing step of the challenge-response handshake
+    #[context("NetWorker::handle_handshake()")]
+    fn handle_handshake(&mut self, msg: NetMessage) -> Result<()> {
+        let credentials = &self.credentials;
+        let connect = match (self.handshake, msg) {
+            (Handshake::AwaitingChallenge, NetMessage::Challenge(challenge)) => {
+                self.handshake = Handshake::Responded;
+                NetMessage::connect_response(
+                    credentials.room_id(),
+                    credentials.player_id(),
+                    &credentials.response(&challenge.nonce),
+                )
+            }
+            // a server without challenges checked the empty password
+            (Handshake::AwaitingChallenge, NetMessage::Finish(finish))
+                if finish.cause() == NetFinishCause::AuthFailed
+                    && self.config.plaintext_fallback =>
+            {
+                self.handshake = Handshake::Plaintext;
+                NetMessage::connect(
+                    credentials.room_id(),
+                    credentials.player_id(),
+                    credentials.password(),
+                )
+            }
+            (_, NetMessage::Finish(finish)) => {
+                return Err(KCPError::RemoteFinished(finish.cause()).into());
+            }
+            _ => return Err(KCPError::UnexpectedPacket.into()),
+        };
+        let connect = self.advertise(connect);
+        return self.send_message(&connect);
+    }
+
+    fn supported(&self) -> Capabilities {
+        let mut supported = CommandVersion::supported(self.config.command_version);
+        if self.padder.is_some() {
+            supported.insert(Capabilities::PADDING);
+        }
+        #[cfg(feature = "dictionary-compression")]
+        if self.config.dictionary.is_some() {
+            supported.insert(Capabilities::COMPRESSION);
+        }
+        return supported;
+    }
+
+    // what we support, on every Connect of the handshake
+    fn advertise(&self, mut connect: NetMessage) -> NetMessage {
+        if let NetMessage::Connect(msg) = &mut connect {
+            msg.capabilities = self.supported().bits();
+        }
+        #[cfg(feature = "dictionary-compression")]
+        let connect = self.offer_dictionary(connect);
+        return connect;
+    }
 
+    // only what we advertised and the server enabled, before Waiting
+    fn negotiate(&mut self, enabled: u64) {
+        self.capabilities = Capabilities::from_bits(enabled).intersect(self.supported());
+        self.output.stats.capabilities = self.capabilities;
+        let version = CommandVersion::negotiate(self.capabilities);
+        self.cmd_encoder.set_version(version);
+        self.cmd_decoder.set_version(version);
+    }
+
+    #[context("NetWorker::send_message()")]
+    fn send_message(&mut self, msg: &NetMessage) -> Result<()> {
         self.kcp_buffer.clear();
-        NetMessage::Connect(connect).encode(&mut self.kcp_buffer)?;
+        msg.encode(&mut self.kcp_buffer)?;
         self.kcp.send_kcp(&self.kcp_buffer)?;
+        #[cfg(feature = "paranoid")]
+        self.invariants.sent(self.kcp_buffer.len());
+        self.track_packet_size(self.kcp_buffer.len());
         self.kcp_buffer.clear();
 
         return Ok(());
     }
 
+    // the handshake on the caller's thread, the same ticks update() runs
+    // until the server accepts, refuses or `deadline` passes, then run() or
+    // update() take over from Waiting
+    #[context("NetWorker::connect_blocking()")]
+    pub fn connect_blocking(&mut self, deadline: Duration) -> Result<Acceptance> {
+        if self.state != NetPlayerState::Initing {
+            return Err(KCPError::Unexpected.into());
+        }
+        self.start()?;
+        // on the worker clock, a wall clock step neither stretches nor ends it
+        let until = self.current() + deadline.as_millis() as u64;
+        while self.state == NetPlayerState::Initing {
+            let current = self.current();
+            if current >= until {
+                return Err(KCPError::Timeout.into());
+            }
+            let wait = (self.next_tick(current) - current).min(until - current);
+            self.tick(current, WallClock::until(wait))?;
+        }
+        let mut roster: Vec<_> = self.states.iter().map(|(c, s)| (*c, *s)).collect();
+        roster.sort_unstable_by_key(|(conv, _)| *conv);
+        return Ok(Acceptance {
+            conv: self.conv.get(),
+            roster,
+            resume_token: self.resume_token.clone(),
+            capabilities: self.capabilities,
+        });
+    }
+
     #[context("NetWorker::update()")]
     pub fn update(&mut self) -> Result<()> {
         loop {
-            let current = match SystemTime::now().duration_since(self.started_at) {
-                Ok(current) => current.as_millis() as u64,
-                Err(_) => return Err(KCPError::Unexpected.into()),
-            };
+            let current = self.current();
+            let next_at = WallClock::until(self.next_tick(current) - current);
+            self.tick(current, next_at)?;
+        }
+    }
+
+    fn next_tick(&self, current: u64) -> u64 {
+        // the OS throttles us in background anyway
+        let interval = match self.chan.presence() {
+            Presence::Background => BACKGROUND_INTERVAL,
+            Presence::Active | Presence::Paused => KCP_INTERVAL,
+        };
+        return self.schedule.next(Timer::Tick, current, interval);
+    }
+
+    // a single tick that doesn't wait on the socket, for callers that
+    // schedule many workers on one thread
+    #[context("NetWorker::step()")]
+    pub fn step(&mut self) -> Result<()> {
+        let current = self.current();
+        return self.tick(current, SystemTime::now());
+    }
 
-            let next = (current + KCP_INTERVAL) / KCP_INTERVAL * KCP_INTERVAL;
-            let next_at = self.started_at + Duration::from_millis(next);
+    // when step() is next needed: kcp's next flush or retransmit, or the
+    // next tick for the chan and the timers, whichever comes first, on
+    // `clock` so a wall clock step doesn't move it
+    pub fn next_wakeup(&mut self) -> Instant {
+        let current = self.current();
+        let at = self.kcp.check(current).min(self.next_tick(current));
+        return (self.clock)() + Duration::from_millis(at.saturating_sub(current));
+    }
 
-            self.handle_input()?;
-            self.kcp.update_kcp(current);
-            self.handle_output()?;
-            self.kcp.update_udp(next_at)?;
-            self.handle_timeout()?;
+    fn tick(&mut self, current: u64, until: SystemTime) -> Result<()> {
+        #[cfg(feature = "paranoid")]
+        self.check_tick()?;
+        let mut timer = TickTimer::start(self.clock, self.output.stats.tick_timings.is_some());
+        if let Some(log) = &mut self.decisions {
+            log.begin(self.state);
+        }
+        self.handle_controls()?;
+        if let Some(from) = self.chan.take_cancel() {
+            self.cancel_early_inputs(from);
+        }
+        // output first so the exchange in handle_input() publishes it
+        self.handle_output(current)?;
+        self.packet_log.flush(current);
+        self.expire_hints(current);
+        self.release_jitter(current);
+        self.release_frames(current);
+        self.output.stats.server_frame = self.estimator.estimate(current);
+        self.report_acked(current);
+        self.report_presence(current)?;
+        timer.lap(TickStage::Output);
+        self.handle_input()?;
+        self.exchange_unreliable()?;
+        timer.lap(TickStage::Input);
+        self.kcp.update_kcp(current);
+        timer.lap(TickStage::Kcp);
+        self.update_udp(current, until)?;
+        timer.lap(TickStage::Udp);
+        // after this tick's sends and acks, published by the next exchange
+        self.output.stats.kcp_waitsnd = self.kcp.waitsnd();
+        self.output.stats.syscalls_saved = self.kcp.syscalls_saved();
+        self.output.stats.recv_drained = self.kcp.drained();
+        self.output.stats.bandwidth = self.kcp.bandwidth(current);
+        self.output.stats.ack_latency = self.kcp.ack_latency();
+        if let Some(log) = &mut self.decisions {
+            log.sent(self.output.stats.bandwidth.sent_total.bytes);
+        }
+        self.link.update(&self.output.stats.bandwidth, current);
+        self.check_one_way();
+        self.update_quality(current);
+        let max_rtt = self.output.stats.ack_latency.max;
+        self.summary.max_rtt = self.summary.max_rtt.max(max_rtt);
+        let entered_at = self.output.stats.timeline.state_entered_at().unwrap_or(0);
+        self.output.stats.state_ms = self.since_created().saturating_sub(entered_at);
+        self.track_reach(current);
+        self.handle_timeout()
+            .map_err(|err| err.context(self.context(None)))?;
+        if let Some(timings) = &mut self.output.stats.tick_timings {
+            timer.finish(timings);
         }
+        return Ok(());
     }
 
     pub fn finish(&mut self, err: Error, delay: bool) {
+        if self.begin_finish(err, delay).is_none() {
+            return;
+        }
+        // never waits on the socket past the deadline
+        let deadline = self.current() + self.config.finish_timeout;
+        loop {
+            let current = self.current();
+            if current >= deadline {
+                return;
+            }
+            let until = WallClock::until((deadline - current).min(KCP_INTERVAL));
+            if self.drain(until) {
+                return;
+            }
+        }
+    }
+
+    // publishes the finish, returns until when kcp should be drained, on
+    // `clock` like the rest of the worker's deadlines
+    pub fn begin_finish(&mut self, err: Error, delay: bool) -> Option<Instant> {
+        let err = self.classify(err);
         println!("{:?}", err);
 
-        let cause = match err.downcast::<KCPError>() {
-            Ok(err) => err.cause(),
-            Err(_) => NetFinishCause::ClientError,
+        let context = err.downcast_ref::<WorkerContext>().cloned();
+        let message = format!("{:#}", err);
+        // already finishing, only counted
+        #[cfg(feature = "paranoid")]
+        let _ = self.invariant(
+            err.downcast_ref::<KCPError>().is_some(),
+            "error mapped to a finish cause",
+            &message,
+        );
+        let (cause, remote) = match err.downcast::<KCPError>() {
+            Ok(err) => (err.cause(), matches!(err, KCPError::RemoteFinished(_))),
+            Err(_) => (NetFinishCause::ClientError, false),
+        };
+        self.summary.cause = Some(cause);
+        let summary = self.summarize();
+        let decisions = match &mut self.decisions {
+            Some(log) if cause != NetFinishCause::GameOver => {
+                log.fail(&message);
+                Some(log.clone())
+            }
+            _ => None,
         };
-        self.chan.finish(cause);
+        self.chan.finish(FinishInfo {
+            cause,
+            context,
+            message,
+            ignored_packets: self.output.stats.ignored_packets,
+            connect: self.output.stats.connect,
+            delivery_hash: None,
+            summary: Some(summary),
+            decisions,
+        });
 
         if !delay {
-            return;
+            return None;
         }
 
-        let deadline = SystemTime::now() + Duration::from_secs(FINISH_TIMEOUT);
-        while SystemTime::now() < deadline {
-            let now = SystemTime::now();
-            let current = now.duration_since(self.started_at).unwrap().as_millis() as u64;
-
-            self.kcp.update_kcp(current);
-            let _ = self
-                .kcp
-                .update_udp(now + Duration::from_millis(KCP_INTERVAL));
+        // tell the server why we are leaving, drain() flushes it
+        if !remote {
+            let _ = self.send_finish(cause);
         }
+        return Some((self.clock)() + Duration::from_millis(self.config.finish_timeout));
     }
 
-    #[context("NetWorker::handle_input()")]
-    fn handle_input(&mut self) -> Result<()> {
-        loop {
-            let mut frame = 0;
-            let (commands, hash) = self.cmd_encoder.buffers();
-            let state = self.chan.recv_input(&mut frame, commands, hash);
-            match state {
-                NetInputState::NonEmpty => {}
-                NetInputState::Empty => return Ok(()),
-                NetInputState::Finish => {
-                    self.set_self_state(NetPlayerState::Stopped);
-                    return Err(KCPError::GameOver.into());
-                }
-            };
-            self.handle_input_impl(frame)?;
+    // the handshake has reconnect() instead
+    #[context("NetWorker::update_udp()")]
+    fn update_udp(&mut self, current: u64, until: SystemTime) -> Result<()> {
+        let updated = self.kcp.update_udp(until);
+        let monitor = match &mut self.rebind {
+            Some(monitor) if self.state != NetPlayerState::Initing => monitor,
+            _ => return updated,
+        };
+        if let Err(err) = updated {
+            let socket_error = matches!(err.downcast_ref::<KCPError>(), Some(KCPError::IO(_)));
+            if !socket_error || monitor.exhausted() {
+                return Err(err);
+            }
+            monitor.on_send_error();
         }
+        let received = self.kcp.received();
+        if received != self.rebind_received {
+            self.rebind_received = received;
+            monitor.on_recv(current);
+        }
+        if !monitor.should_rebind(current) {
+            return Ok(());
+        }
+        monitor.on_rebind(current);
+        self.output.stats.rebinds = monitor.rebinds() as u64;
+        self.kcp.rebind()?;
+        return Ok(());
     }
 
-    #[context("NetWorker::handle_input_impl()")]
-    fn handle_input_impl(&mut self, frame: u32) -> Result<()> {
-        match self.state {
-            NetPlayerState::Initing | NetPlayerState::Waiting => {
-                let ce = &mut self.cmd_encoder;
-                if !ce.commands().is_empty() || !ce.hash().is_empty() {
-                    return Err(KCPError::Unexpected.into());
-                }
-            }
-            NetPlayerState::Running => {
-                if frame <= self.frame {
-                    return Err(KCPError::InvalidFrame.into());
-                }
-                self.frame = frame;
-                self.cmd_encoder.encode(self.frame)?;
-                self.kcp.send_kcp(self.cmd_encoder.hash_bytes())?;
-                self.kcp.send_kcp(self.cmd_encoder.command_bytes())?;
-            }
-            NetPlayerState::Stopped => {}
+    // true once everything sent was acked
+    pub fn drain(&mut self, until: SystemTime) -> bool {
+        let current = self.current();
+        self.kcp.update_kcp(current);
+        let _ = self.kcp.update_udp(until);
+        // what the drain sends and receives counts too
+        let summary = self.summarize();
+        self.chan.set_summary(summary);
+        if self.kcp.waitsnd() > 0 {
+            return false;
         }
+        self.chan.set_flushed();
+        return true;
+    }
+
+    #[context("NetWorker::send_finish()")]
+    fn send_finish(&mut self, cause: NetFinishCause) -> Result<()> {
+        self.kcp_buffer.clear();
+        NetMessage::finish(self.frame, cause).encode(&mut self.kcp_buffer)?;
+        self.kcp.send_kcp(&self.kcp_buffer)?;
+        #[cfg(feature = "paranoid")]
+        self.invariants.sent(self.kcp_buffer.len());
+        self.kcp_buffer.clear();
+
         return Ok(());
     }
 
-    #[context("NetWorker::handle_output()")]
-    fn handle_output(&mut self) -> Result<()> {
-        loop {
-            self.kcp_buffer.clear();
-            let len = self.kcp.recv_kcp(&mut self.kcp_buffer)?;
-            if len == 0 {
-                return Ok(());
-            }
-            self.handle_output_impl()?;
+    // 0 is no acknowledgment
+    fn ack_frame(&mut self, frame: u32) {
+        if frame == 0 || self.acked_frame.map_or(false, |acked| acked >= frame) {
+            return;
         }
+        self.acked_frame = Some(frame);
+        self.output.stats.last_acked_frame = Some(frame);
     }
 
-    #[context("NetWorker::handle_output_impl()")]
-    fn handle_output_impl(&mut self) -> Result<()> {
-        match self.state {
-            NetPlayerState::Initing => {
-                let (msg, _) = NetMessage::decode(&self.kcp_buffer)?;
-                match msg {
-                    NetMessage::Accept(_) => {
-                        self.set_self_state(NetPlayerState::Waiting);
-                    }
-                    NetMessage::Finish(finish) => {
-                        return Err(KCPError::RemoteFinished(finish.cause).into());
-                    }
-                    _ => return Err(KCPError::UnexpectedPacket.into()),
-                };
+    fn report_acked(&mut self, current: u64) {
+        let frame = match self.acked_frame {
+            Some(frame) if self.config.frame_acked_events => frame,
+            _ => return,
+        };
+        match self.acked_reported {
+            Some((reported, _)) if reported >= frame => return,
+            Some((_, at)) if current < at + FRAME_ACK_INTERVAL => return,
+            _ => {}
+        };
+        self.acked_reported = Some((frame, current));
+        self.output.events.push(NetEvent::FrameAcked { frame });
+    }
+
+    // sends our presence as a state of our own conv, only changes and at most
+    // every PRESENCE_INTERVAL ms (plus its jitter) so flapping doesn't flood
+    // the server
+    #[context("NetWorker::report_presence()")]
+    fn report_presence(&mut self, current: u64) -> Result<()> {
+        if self.state != NetPlayerState::Running {
+            return Ok(());
+        }
+        let presence = self.chan.presence();
+        if presence == self.presence {
+            return Ok(());
+        }
+        if let Some(at) = self.presence_at {
+            let delay = self.schedule.offset(Timer::Presence, PRESENCE_INTERVAL);
+            if current < at + PRESENCE_INTERVAL + delay {
+                return Ok(());
             }
-            NetPlayerState::Waiting => {
-                let (msg, _) = NetMessage::decode(&self.kcp_buffer)?;
-                match msg {
-                    NetMessage::State(state) => {
-                        self.set_state(state.conv, state.state);
+        }
+        self.presence = presence;
+        self.presence_at = Some(current);
+
+        self.kcp_buffer.clear();
+        NetMessage::state(self.conv.get(), presence.state()).encode(&mut self.kcp_buffer)?;
+        self.kcp.send_kcp(&self.kcp_buffer)?;
+        #[cfg(feature = "paranoid")]
+        self.invariants.sent(self.kcp_buffer.len());
+        self.kcp_buffer.clear();
+        return Ok(());
+    }
+
+    #[context("NetWorker::handle_input()")]
+    fn handle_input(&mut self) -> Result<()> {
+        let state = self.exchange();
+        if let Some(log) = &mut self.decisions {
+            log.inputs(self.inputs.len());
+        }
+        for idx in 0..self.inputs.len() {
+            let input = &self.inputs[idx];
+            self.cmd_encoder.reserve_commands(input.commands.len());
+            let (commands, hash) = self.cmd_encoder.buffers();
+            commands.extend(input.commands.iter().cloned());
+            hash.extend_from_slice(&input.hash);
+            let frame = input.frame;
+            self.handle_input_impl(frame)
+                .map_err(|err| err.context(self.context(Some(frame))))?;
+        }
+        if state != NetInputState::Finish || self.stopping_at.is_some() {
+            return Ok(());
+        }
+        if self.state != NetPlayerState::Stopped {
+            self.set_self_state(NetPlayerState::Stopped);
+        }
+        let grace = self.config.stop_grace;
+        self.stopping_at = Some(self.current() + grace);
+        self.output.stats.stopping_deadline = Some(WallClock::until(grace));
+        self.chan.send_output(&mut self.output);
+        if grace == 0 {
+            return Err(KCPError::GameOver.into());
+        }
+        return Ok(());
+    }
+
+    // the only chan lock taken in a regular tick, besides the side channel's
+    fn exchange(&mut self) -> NetInputState {
+        if self.config.timestamps {
+            self.output.session_ms = Some(self.current());
+        }
+        let state = self.chan.tick_exchange(&mut self.inputs, &mut self.output);
+        if self.output.commands.capacity() > COMMANDS_CAP {
+            self.output.commands.shrink_to(COMMANDS_CAP);
+        }
+        return state;
+    }
+
+    // received in the last update_udp(), sent in this one
+    #[context("NetWorker::exchange_unreliable()")]
+    fn exchange_unreliable(&mut self) -> Result<()> {
+        self.kcp.recv_unreliable(&mut self.unreliable_in);
+        self.chan
+            .exchange_unreliable(&mut self.unreliable_out, &mut self.unreliable_in);
+        for payload in self.unreliable_out.drain(..) {
+            self.kcp.send_unreliable(&payload)?;
+        }
+        return Ok(());
+    }
+
+    #[context("NetWorker::handle_input_impl()")]
+    fn handle_input_impl(&mut self, frame: u32) -> Result<()> {
+        match self.state {
+            NetPlayerState::Initing | NetPlayerState::Waiting => {
+                return self.handle_early_input(frame);
+            }
+            NetPlayerState::Running | NetPlayerState::Background | NetPlayerState::Paused => {
+                if let Some(paused) = self.paused {
+                    if frame > paused {
+                        self.drop_input();
+                        self.warn(NetWarning::InputPaused { frame, paused });
+                        return Ok(());
                     }
-                    NetMessage::Start(_) => {
-                        self.set_self_state(NetPlayerState::Running);
+                }
+                if frame < self.first_frame {
+                    let first_frame = self.first_frame;
+                    return Err(KCPError::BeforeFirstFrame { frame, first_frame }.into());
+                }
+                if frame <= self.frame {
+                    return Err(KCPError::InvalidFrame.into());
+                }
+                self.frame = frame;
+                if self.config.hash_commands && self.cmd_encoder.hash().is_empty() {
+                    let (commands, hash) = self.cmd_encoder.buffers();
+                    hash.extend_from_slice(&hash_commands(commands).to_be_bytes());
+                }
+                if self.config.hash_check && !self.cmd_encoder.hash().is_empty() {
+                    self.hashes.record(frame, self.cmd_encoder.hash());
+                }
+                // checked against our own history all the same
+                if self.engaged(Rung::ReduceHashes) && frame % HASH_CADENCE != 0 {
+                    self.cmd_encoder.buffers().1.clear();
+                }
+                let skip_empty = self.config.hash_only || self.engaged(Rung::SkipEmptyFrames);
+                let hash_only = skip_empty && self.cmd_encoder.commands().is_empty();
+                self.cmd_encoder.encode(self.frame)?;
+                self.kcp.send_kcp(self.cmd_encoder.hash_bytes())?;
+                #[cfg(feature = "paranoid")]
+                self.invariants.sent(self.cmd_encoder.hash_bytes().len());
+                if hash_only {
+                    self.track_packet_size(self.cmd_encoder.hash_bytes().len());
+                } else {
+                    self.send_commands()?;
+                    self.track_frame_size(frame);
+                }
+                if self.config.low_latency {
+                    self.kcp.flush();
+                }
+                self.output.stats.sent_frame = frame;
+                self.summary.frames_sent += 1;
+            }
+            NetPlayerState::Stopped => {
+                self.drop_input();
+            }
+        }
+        return Ok(());
+    }
+
+    // packed and padded in kcp_buffer, which is free between
+    // handle_output() calls
+    fn send_commands(&mut self) -> Result<()> {
+        self.kcp_buffer.clear();
+        self.kcp_buffer
+            .extend_from_slice(self.cmd_encoder.command_bytes());
+        // unless the ladder holds it back until its rung
+        #[cfg(feature = "dictionary-compression")]
+        let packing = self.packing
+            && (self.engaged(Rung::Compression)
+                || !matches!(&self.degrade, Some(degrade) if degrade.has(Rung::Compression)));
+        #[cfg(feature = "dictionary-compression")]
+        if let (Some(packer), true) = (&mut self.packer, packing) {
+            packer.pack(&mut self.kcp_buffer)?;
+        }
+        let padding = self.capabilities.contains(Capabilities::PADDING);
+        if let (Some(padder), true) = (&mut self.padder, padding) {
+            let padding = padder.pad(&mut self.kcp_buffer)?;
+            self.output.stats.padding_bytes += padding as u64;
+        }
+        self.kcp.send_kcp(&self.kcp_buffer)?;
+        #[cfg(feature = "paranoid")]
+        self.invariants.sent(self.kcp_buffer.len());
+        self.kcp_buffer.clear();
+        return Ok(());
+    }
+
+    #[cfg(feature = "dictionary-compression")]
+    fn offer_dictionary(&self, mut connect: NetMessage) -> NetMessage {
+        if let (NetMessage::Connect(msg), Some(dictionary)) =
+            (&mut connect, &self.config.dictionary)
+        {
+            msg.dictionary_id = dictionary.id();
+        }
+        return connect;
+    }
+
+    // both ends have to agree on compression and the dictionary, otherwise
+    // nothing is compressed
+    #[cfg(feature = "dictionary-compression")]
+    fn accept_dictionary(&mut self, accepted: u64) {
+        let enabled = self.capabilities.contains(Capabilities::COMPRESSION);
+        self.packing = match &self.config.dictionary {
+            Some(dictionary) => enabled && accepted != 0 && accepted == dictionary.id(),
+            None => false,
+        };
+    }
+
+    fn engaged(&self, rung: Rung) -> bool {
+        return matches!(&self.degrade, Some(degrade) if degrade.engaged(rung));
+    }
+
+    // on what the last tick measured, while running only
+    fn update_quality(&mut self, current: u64) {
+        let degrade = match (&mut self.degrade, self.state) {
+            (Some(degrade), NetPlayerState::Running) => degrade,
+            _ => return,
+        };
+        let upstream = self.output.stats.bandwidth.sent_per_sec.bytes;
+        let waitsnd = self.output.stats.kcp_waitsnd;
+        if let Some(level) = degrade.update(upstream, waitsnd, current) {
+            self.output.stats.quality_level = level;
+            self.warn(NetWarning::QualityChanged(level));
+        }
+    }
+
+    fn track_frame_size(&mut self, frame: u32) {
+        let ce = &self.cmd_encoder;
+        let size = ce.hash_bytes().len().max(ce.command_bytes().len());
+        self.track_packet_size(size);
+        if !self.large_packet_warned && size * 100 > KCP_MAX_PACKET * PACKET_WARN_PERCENT {
+            self.large_packet_warned = true;
+            self.warn(NetWarning::LargePacket {
+                frame,
+                size,
+                limit: KCP_MAX_PACKET,
+            });
+        }
+    }
+
+    fn track_packet_size(&mut self, size: usize) {
+        let stats = &mut self.output.stats;
+        stats.largest_packet = stats.largest_packet.max(size);
+        stats.segmented |= size > KCP_MTU - KCP_OVERHEAD;
+    }
+
+    fn handle_early_input(&mut self, frame: u32) -> Result<()> {
+        let (commands, hash) = self.cmd_encoder.buffers();
+        let empty = commands.is_empty() && hash.is_empty();
+        match self.config.early_input {
+            EarlyInputPolicy::Buffer { max_frames } => {
+                if self.early_inputs.len() >= max_frames {
+                    return Err(KCPError::Unexpected.into());
+                }
+                if matches!(self.early_inputs.back(), Some((last, _, _)) if frame <= *last) {
+                    return Err(KCPError::InvalidFrame.into());
+                }
+                let input = (
+                    frame,
+                    commands.drain(..).collect(),
+                    hash.drain(..).collect(),
+                );
+                self.early_inputs.push_back(input);
+                self.chan.park_input(frame);
+            }
+            _ if empty => {}
+            EarlyInputPolicy::Error => return Err(KCPError::Unexpected.into()),
+            EarlyInputPolicy::Drop { warn } => {
+                self.drop_input();
+                if warn {
+                    self.warn(NetWarning::InputDropped { frame });
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    // any datagram from the server, the ack of the Connect at the latest,
+    // tells the transport works
+    fn track_reach(&mut self, current: u64) {
+        if self.state != NetPlayerState::Initing || self.reached_at.is_some() {
+            return;
+        }
+        if self.output.stats.bandwidth.recv_total.datagrams > 0 {
+            self.reached_at = Some(current);
+            self.output.stats.connect.reach = Some(current);
+        }
+    }
+
+    // warned once per silence, the next datagram back resets it
+    fn check_one_way(&mut self) {
+        let one_way = self.link.one_way();
+        if one_way < ONE_WAY_WARNING {
+            self.one_way_warned = false;
+            return;
+        }
+        if !self.one_way_warned {
+            self.one_way_warned = true;
+            self.warn(NetWarning::NoInboundTraffic(one_way));
+        }
+    }
+
+    // whatever timed out first on a link that only works one way, the
+    // original error stays in the chain
+    fn classify(&self, err: Error) -> Error {
+        if self.link.one_way() < ONE_WAY_WARNING {
+            return err;
+        }
+        return match err.downcast_ref::<KCPError>() {
+            Some(KCPError::Timeout) | Some(KCPError::WindowExhausted) => {
+                err.context(KCPError::NoInboundTraffic)
+            }
+            _ => err,
+        };
+    }
+
+    fn set_paused(&mut self, paused: Option<u32>) {
+        self.paused = paused;
+        self.output.stats.paused = paused;
+    }
+
+    // our conv only comes back on the server's relay of what we sent, a
+    // frame ahead of ours was never ours
+    fn spoofed(&mut self, conv: u32, frame: u32) -> bool {
+        if conv != self.conv.get() || frame <= self.frame {
+            return false;
+        }
+        self.warn(NetWarning::SpoofedConv { frame });
+        return true;
+    }
+
+    // rate limited per kind, in order with the other events
+    fn warn(&mut self, warning: NetWarning) {
+        let current = self.current();
+        self.summary.warned(&warning);
+        if self.warnings.allow(&warning, current) {
+            self.output.events.push(NetEvent::Warning(warning));
+        }
+        self.output.stats.suppressed_warnings = self.warnings.suppressed();
+    }
+
+    fn drop_input(&mut self) {
+        let (commands, hash) = self.cmd_encoder.buffers();
+        if !commands.is_empty() || !hash.is_empty() {
+            commands.clear();
+            hash.clear();
+            self.output.stats.dropped_inputs += 1;
+        }
+    }
+
+    // buffered before Start, sent in order as if just submitted
+    #[context("NetWorker::release_early_inputs()")]
+    fn release_early_inputs(&mut self) -> Result<()> {
+        if let Some(from) = self.chan.release_parked() {
+            self.cancel_early_inputs(from);
+        }
+        while let Some((frame, commands, hash)) = self.early_inputs.pop_front() {
+            let (ce_commands, ce_hash) = self.cmd_encoder.buffers();
+            ce_commands.extend(commands);
+            ce_hash.extend_from_slice(&hash);
+            self.handle_input_impl(frame)
+                .map_err(|err| err.context(self.context(Some(frame))))?;
+        }
+        return Ok(());
+    }
+
+    // before anything else in the tick, so none of the inputs queued behind
+    // a control is handled first
+    #[context("NetWorker::handle_controls()")]
+    fn handle_controls(&mut self) -> Result<()> {
+        self.chan.drain_controls(&mut self.controls);
+        for idx in 0..self.controls.len() {
+            match self.controls[idx] {
+                ControlMsg::Abort => {
+                    self.controls.clear();
+                    return Err(KCPError::GameOver.into());
+                }
+                // picked up by take_cancel() right after
+                ControlMsg::CancelInputs(from) => self.chan.cancel_pending_inputs(from),
+                // reported by report_presence() in this tick
+                ControlMsg::Presence(presence) => self.chan.set_presence(presence),
+            }
+        }
+        self.controls.clear();
+        return Ok(());
+    }
+
+    // of GameHandle::cancel_pending_inputs(), counted by the chan already
+    fn cancel_early_inputs(&mut self, from: u32) {
+        self.early_inputs.retain(|(frame, _, _)| *frame < from);
+    }
+
+    #[context("NetWorker::handle_output()")]
+    fn handle_output(&mut self, current: u64) -> Result<()> {
+        let deadline = (self.clock)() + Duration::from_millis(self.config.tick_budget);
+        loop {
+            // received in place, kcp_buffer never grows past KCP_MAX_PACKET
+            self.kcp_buffer.resize(KCP_MAX_PACKET, 0);
+            let len = match self.kcp.recv_into(&mut self.kcp_buffer)? {
+                Some(len) => len,
+                None => {
+                    self.kcp_buffer.clear();
+                    return Ok(());
+                }
+            };
+            self.kcp_buffer.truncate(len);
+            self.handle_packet(current)?;
+            // the rest stays queued in kcp, past the handshake: states are
+            // published once the loop is done, so an Accept and the Finish
+            // right behind it end in one finish without a Waiting first
+            if (self.clock)() >= deadline && !self.handshaking() {
+                self.output.stats.budget_overruns += 1;
+                return Ok(());
+            }
+        }
+    }
+
+    fn handshaking(&self) -> bool {
+        return matches!(
+            self.state,
+            NetPlayerState::Initing | NetPlayerState::Waiting
+        );
+    }
+
+    // one undecodable packet mid-match is dropped instead of ending it, the
+    // handshake has to be exact
+    fn handle_packet(&mut self, current: u64) -> Result<()> {
+        if let Some(log) = &mut self.decisions {
+            log.packet(&self.kcp_buffer);
+        }
+        let err = match self.handle_output_impl() {
+            Ok(()) => return Ok(()),
+            Err(err) => err,
+        };
+        let malformed = match err.downcast_ref::<KCPError>() {
+            Some(err) => err.is_malformed(),
+            None => false,
+        };
+        if self.state == NetPlayerState::Running && malformed {
+            self.output.stats.undecodable_packets += 1;
+            self.packet_log.count("undecodable packets", current);
+            return Ok(());
+        }
+        return Err(err.context(self.context(None)));
+    }
+
+    #[context("NetWorker::handle_output_impl()")]
+    fn handle_output_impl(&mut self) -> Result<()> {
+        match self.state {
+            // ignore all data, only counted
+            NetPlayerState::Stopped => {
+                self.output.stats.ignored_packets.record(&self.kcp_buffer);
+                self.heard_at = self.current();
+                return Ok(());
+            }
+            NetPlayerState::Running | NetPlayerState::Background | NetPlayerState::Paused
+                if Self::is_message_command(&self.kcp_buffer) =>
+            {
+                return self.handle_commands();
+            }
+            _ => {}
+        };
+        let (msg, _) = NetMessage::decode_with(&self.kcp_buffer, &self.config.protocol_limits)?;
+        return match msg.category() {
+            MessageCategory::Handshake => self.handle_session(msg),
+            MessageCategory::State => self.handle_control(msg),
+            MessageCategory::Gameplay => self.handle_gameplay(msg),
+            MessageCategory::Application => self.handle_application(msg),
+        };
+    }
+
+    // a command packet, mid-match
+    #[context("NetWorker::handle_commands()")]
+    fn handle_commands(&mut self) -> Result<()> {
+        #[cfg(feature = "dictionary-compression")]
+        if let Some(packer) = &mut self.packer {
+            packer.unpack(&mut self.kcp_buffer)?;
+        }
+        self.updated_at = SystemTime::now();
+        self.summary.frames_received += 1;
+        let current = self.current();
+        let validator = self.config.validator.as_deref();
+        let dropped = &mut self.output.stats.dropped_commands;
+        self.cmd_decoder
+            .decode_batch_into(&self.kcp_buffer, &mut self.jitter_input)?;
+        if self.config.timestamps {
+            let stamps = CommandStamps {
+                received_at: current,
+                delivered_at: 0,
+            };
+            self.jitter_input
+                .iter_mut()
+                .for_each(|b| b.stamps = Some(stamps));
+        }
+        Self::validate(validator, &mut self.jitter_input, 0, dropped)?;
+        let (conv, frame) = (self.cmd_decoder.conv(), self.cmd_decoder.frame());
+        if self.spoofed(conv, frame) {
+            self.jitter_input.clear();
+            return Ok(());
+        }
+        self.estimator.observe(frame, current);
+        // the server relays our own commands back once it has them
+        if conv == self.conv.get() {
+            self.ack_frame(frame);
+        }
+        if self.is_hint(conv) {
+            self.hold_hints(current);
+            return Ok(());
+        }
+        if self.is_late(conv, frame) {
+            return self.handle_late(conv, frame);
+        }
+        self.route_commands(conv, frame, current);
+        self.track_lag(conv, frame);
+        #[cfg(feature = "paranoid")]
+        {
+            let monotonic = self.invariants.decoded(conv, frame);
+            self.invariant(
+                monotonic,
+                "decoded frames monotonic per conv",
+                (conv, frame),
+            )?;
+        }
+        return Ok(());
+    }
+
+    // Connect, Accept and Challenge
+    #[context("NetWorker::handle_session()")]
+    fn handle_session(&mut self, msg: NetMessage) -> Result<()> {
+        match (self.state, msg) {
+            (NetPlayerState::Initing, NetMessage::Accept(accept)) => {
+                let current = self.current();
+                let reached = self.reached_at.unwrap_or(current);
+                self.output.stats.connect.accept = Some(current.saturating_sub(reached));
+                self.negotiate(accept.capabilities);
+                #[cfg(feature = "dictionary-compression")]
+                self.accept_dictionary(accept.dictionary_id);
+                if !accept.resume_token.is_empty() {
+                    self.resume_token = accept.resume_token;
+                    self.publish_session();
+                }
+                self.set_self_state(NetPlayerState::Waiting);
+            }
+            (NetPlayerState::Initing, msg) => self.handle_handshake(msg)?,
+            (_, NetMessage::Accept(_)) => return Err(NetWorker::superseded()),
+            _ => return Err(KCPError::UnexpectedPacket.into()),
+        };
+        return Ok(());
+    }
+
+    // State, Start, Finish, Pause and Resume
+    #[context("NetWorker::handle_control()")]
+    fn handle_control(&mut self, msg: NetMessage) -> Result<()> {
+        let running = matches!(
+            self.state,
+            NetPlayerState::Running | NetPlayerState::Background | NetPlayerState::Paused
+        );
+        match (self.state, msg) {
+            (NetPlayerState::Initing, msg) => self.handle_handshake(msg)?,
+            (_, NetMessage::Finish(finish)) => {
+                return Err(KCPError::RemoteFinished(finish.cause()).into());
+            }
+            (_, NetMessage::State(state)) => {
+                if state.conv == self.conv.get() {
+                    self.ack_frame(state.acked_frame);
+                }
+                self.set_state(state.conv, state.state());
+            }
+            (NetPlayerState::Waiting, NetMessage::Start(start)) => {
+                self.set_self_state(NetPlayerState::Running);
+                // a resumed session may already be past it
+                self.first_frame = start.first_frame.max(1);
+                self.frame = self.frame.max(self.first_frame - 1);
+                if let Some(assembler) = &mut self.assembler {
+                    assembler.start_at(self.frame + 1);
+                }
+                let start = StartInfo {
+                    conv: self.conv.get(),
+                    started_at: SystemTime::now(),
+                    first_frame: self.frame + 1,
+                };
+                self.output.events.push(NetEvent::Started(start.clone()));
+                self.output.start = Some(start);
+                self.release_early_inputs()?;
+            }
+            (_, NetMessage::Pause(pause)) if running => {
+                self.set_paused(Some(pause.frame));
+                let event = NetEvent::Paused { frame: pause.frame };
+                self.output.events.push(event);
+            }
+            (_, NetMessage::Resume(resume)) if running => {
+                // the server can't take back frames it acknowledged
+                if self
+                    .acked_frame
+                    .map_or(false, |acked| resume.frame <= acked)
+                {
+                    return Err(KCPError::InvalidFrame.into());
+                }
+                self.set_paused(None);
+                // nor replay from before the first
+                self.frame = resume.frame.saturating_sub(1).max(self.first_frame - 1);
+                let event = NetEvent::Resumed {
+                    frame: self.frame + 1,
+                };
+                self.output.events.push(event);
+            }
+            _ => return Err(KCPError::UnexpectedPacket.into()),
+        };
+        return Ok(());
+    }
+
+    // Hash, command packets go to handle_commands() before decoding
+    #[context("NetWorker::handle_gameplay()")]
+    fn handle_gameplay(&mut self, msg: NetMessage) -> Result<()> {
+        let running = matches!(
+            self.state,
+            NetPlayerState::Running | NetPlayerState::Background | NetPlayerState::Paused
+        );
+        match msg {
+            // over ProtocolLimits::max_hash it failed to decode already
+            NetMessage::Hash(hash) if running => {
+                if !self.spoofed(hash.conv, hash.frame) {
+                    self.check_hash(hash.conv, hash.frame, &hash.hash);
+                }
+            }
+            _ => return Err(KCPError::UnexpectedPacket.into()),
+        };
+        return Ok(());
+    }
+
+    // in any state until Stopped, types not registered are dropped and
+    // counted as undecodable packets, here since handle_packet() only
+    // forgives those while Running
+    #[context("NetWorker::handle_application()")]
+    fn handle_application(&mut self, msg: NetMessage) -> Result<()> {
+        let (kind, payload) = match msg {
+            NetMessage::Application(kind, payload) => (kind, payload),
+            _ => return Err(KCPError::Unexpected.into()),
+        };
+        if !self.config.application_types.contains(&kind) {
+            let current = self.current();
+            self.output.stats.undecodable_packets += 1;
+            self.packet_log.count("undecodable packets", current);
+            return Ok(());
+        }
+        self.output
+            .events
+            .push(NetEvent::Application { kind, payload });
+        return Ok(());
+    }
+
+    // a second Accept: another login of our player_id took the match over,
+    // newer servers send a Finish with NetFinishCause::Superseded instead
+    fn superseded() -> Error {
+        return KCPError::RemoteFinished(NetFinishCause::Superseded).into();
+    }
+
+    #[context("CommandEncoder::handle_timeout()")]
+    fn handle_timeout(&mut self) -> Result<()> {
+        match self.state {
+            NetPlayerState::Initing => {
+                let current = self.current();
+                match self.reached_at {
+                    None if current > self.config.reach_timeout => {
+                        return Err(KCPError::Unreachable.into());
                     }
-                    NetMessage::Finish(finish) => {
-                        return Err(KCPError::RemoteFinished(finish.cause).into());
+                    Some(at) if current.saturating_sub(at) > self.config.accept_timeout => {
+                        return Err(KCPError::AcceptTimeout.into());
                     }
-                    _ => return Err(KCPError::UnexpectedPacket.into()),
+                    _ => {}
                 };
             }
-            NetPlayerState::Running => {
-                if Self::is_message_command(&self.kcp_buffer) {
-                    self.updated_at = SystemTime::now();
-                    self.cmd_decoder.decode(&self.kcp_buffer)?;
-                    self.chan.send_output_commands(self.cmd_decoder.commands());
-                } else {
-                    let (msg, _) = NetMessage::decode(&self.kcp_buffer)?;
-                    match msg {
-                        NetMessage::State(state) => {
-                            self.set_state(state.conv, state.state);
-                        }
-                        NetMessage::Finish(finish) => {
-                            return Err(KCPError::RemoteFinished(finish.cause).into());
-                        }
-                        _ => return Err(KCPError::UnexpectedPacket.into()),
-                    };
+            NetPlayerState::Waiting => {
+                if self.current() / 1000 > START_TIMEOUT {
+                    return Err(KCPError::Timeout.into());
                 }
             }
-            // ignore all data
-            NetPlayerState::Stopped => {}
+            NetPlayerState::Running | NetPlayerState::Background | NetPlayerState::Paused => {
+                if self.link.one_way() / 1000 > UPDATE_TIMEOUT {
+                    return Err(KCPError::NoInboundTraffic.into());
+                }
+            }
+            NetPlayerState::Stopped => {
+                if let Some(at) = self.stopping_at {
+                    if self.current() >= at {
+                        return Err(KCPError::GameOver.into());
+                    }
+                }
+                let since = match self.config.stopped_keepalive {
+                    true => self.stopped_at.max(self.heard_at),
+                    false => self.stopped_at,
+                };
+                if self.current().saturating_sub(since) / 1000 > UPDATE_TIMEOUT {
+                    return Err(KCPError::Timeout.into());
+                }
+            }
+        };
+        return Ok(());
+    }
+
+    fn context(&self, input_frame: Option<u32>) -> WorkerContext {
+        return WorkerContext {
+            addr: self.addr,
+            conv: self.conv.get(),
+            state: self.state,
+            frame: self.frame,
+            input_frame,
+        };
+    }
+
+    // our own state only changes through set_self_state(), and no player is
+    // behind an unassigned conv
+    fn set_state(&mut self, conv: u32, state: NetPlayerState) {
+        let conv = match Conv::new(conv) {
+            Some(conv) if conv == self.conv => {
+                self.set_server_state(state);
+                return;
+            }
+            Some(conv) => conv.get(),
+            None => return,
+        };
+        if let Some(assembler) = &mut self.assembler {
+            assembler.set_state(conv, state);
+        }
+        if let Some(info) = self.lag.get_mut(&conv) {
+            info.stopped = state == NetPlayerState::Stopped;
+            self.output.stats.lag.insert(conv, *info);
+        }
+        // servers rebroadcast the whole table, the game only hears changes
+        let known = self.states.get(&conv).copied();
+        if known == Some(state) && !self.config.forward_redundant_states {
+            self.output.stats.redundant_states += 1;
+            return;
+        }
+        if known.is_some() || self.states.len() < PLAYERS_CAP {
+            self.states.insert(conv, state);
+        }
+        self.output.states.insert(conv, state);
+        let frame = self.delivered_frame;
+        self.output
+            .events
+            .push(NetEvent::State { conv, state, frame });
+        self.release_hints(conv);
+    }
+
+    // our own conv as the server sees it
+    fn set_server_state(&mut self, state: NetPlayerState) {
+        let diverged = match state {
+            NetPlayerState::Stopped => self.state != NetPlayerState::Stopped,
+            // before Start it comes with the Start
+            NetPlayerState::Running => self.state == NetPlayerState::Stopped,
+            _ => false,
+        };
+        if !diverged {
+            return;
+        }
+        match self.config.self_state {
+            SelfStatePolicy::Apply => self.set_self_state(state),
+            SelfStatePolicy::ApplyStopped if state == NetPlayerState::Stopped => {
+                self.set_self_state(state);
+            }
+            SelfStatePolicy::Verify => {
+                self.warn(NetWarning::StateDiverged {
+                    local: self.state,
+                    server: state,
+                });
+            }
+            SelfStatePolicy::Ignore | SelfStatePolicy::ApplyStopped => {}
+        };
+    }
+
+    fn set_self_state(&mut self, state: NetPlayerState) {
+        let now = (self.clock)();
+        let ms = now.saturating_duration_since(self.state_since).as_millis() as u64;
+        self.summary.add_state_time(self.state, ms);
+        self.state_since = now;
+        self.state = state;
+        let at = self.since_created();
+        self.output
+            .stats
+            .timeline
+            .push(TimelineEvent::State(state), at);
+        if state == NetPlayerState::Stopped {
+            self.stopped_at = self.current();
+        }
+        let conv = self.conv.get();
+        if let Some(assembler) = &mut self.assembler {
+            assembler.set_state(conv, state);
+        }
+        self.output.states.insert(conv, state);
+        let frame = self.delivered_frame;
+        self.output
+            .events
+            .push(NetEvent::State { conv, state, frame });
+    }
+
+    // states are ordered against the commands delivered before them
+    fn note_delivered(&mut self, from: usize) {
+        for batch in &self.output.commands[from..] {
+            self.delivered_frame = self.delivered_frame.max(batch.frame);
+            match self.conv_delivered.get_mut(&batch.conv) {
+                Some(frame) => *frame = (*frame).max(batch.frame),
+                None if self.conv_delivered.len() < PLAYERS_CAP => {
+                    self.conv_delivered.insert(batch.conv, batch.frame);
+                }
+                None => {}
+            };
+        }
+    }
+
+    fn is_late(&self, conv: u32, frame: u32) -> bool {
+        if let Some(assembler) = &self.assembler {
+            return assembler.is_released(frame);
+        }
+        return self
+            .conv_delivered
+            .get(&conv)
+            .map_or(false, |delivered| frame <= *delivered);
+    }
+
+    // the decoded jitter_input, see LateCommandPolicy
+    #[context("NetWorker::handle_late()")]
+    fn handle_late(&mut self, conv: u32, frame: u32) -> Result<()> {
+        let commands = match self.jitter_input.pop() {
+            Some(batch) => batch.commands.to_vec(),
+            None => Vec::new(),
+        };
+        match self.config.late_commands {
+            LateCommandPolicy::Drop => {
+                self.output.stats.late_commands += commands.len() as u64;
+            }
+            LateCommandPolicy::DeliverFlagged if !commands.is_empty() => {
+                let event = NetEvent::LateCommands {
+                    conv,
+                    frame,
+                    commands,
+                };
+                self.output.events.push(event);
+            }
+            LateCommandPolicy::DeliverFlagged => {}
+            LateCommandPolicy::Fatal => return Err(KCPError::LateCommands(frame).into()),
+        };
+        return Ok(());
+    }
+
+    // the newest frame is published through the stats every tick
+    fn publish_session(&mut self) {
+        let credentials = &self.credentials;
+        let mut session = SessionState::new(
+            self.addr,
+            self.conv.get(),
+            credentials.room_id(),
+            credentials.player_id(),
+        );
+        session.resume_token = self.resume_token.clone();
+        session.last_frame = self.frame;
+        self.output.session = Some(session);
+    }
+
+    // the decoded jitter_input to the assembler, the jitter buffer or the
+    // chan, whichever this worker delivers through
+    fn route_commands(&mut self, conv: u32, frame: u32, current: u64) {
+        match (&mut self.assembler, &mut self.jitter) {
+            (Some(assembler), _) => {
+                let commands = match self.jitter_input.first() {
+                    Some(batch) => &batch.commands[..],
+                    None => &[][..],
+                };
+                assembler.push(conv, frame, commands, current);
+                self.jitter_input.clear();
+            }
+            (None, Some(jitter)) => {
+                for batch in self.jitter_input.drain(..) {
+                    jitter.push(batch, current);
+                }
+            }
+            (None, None) => {
+                let from = self.output.commands.len();
+                self.output.commands.append(&mut self.jitter_input);
+                self.note_delivered(from);
+            }
+        };
+    }
+
+    // with WorkerConfig::roster, a plausible conv that isn't ours and no
+    // State introduced, once PLAYERS_CAP states are known every conv passes
+    fn is_hint(&self, conv: u32) -> bool {
+        if self.roster.is_none() {
+            return false;
+        }
+        return match Conv::new(conv) {
+            Some(conv) if conv != self.conv => {
+                !self.states.contains_key(&conv.get()) && self.states.len() < PLAYERS_CAP
+            }
+            _ => false,
+        };
+    }
+
+    fn hold_hints(&mut self, current: u64) {
+        let roster = match &mut self.roster {
+            Some(roster) => roster,
+            None => return,
+        };
+        let mut discarded = Vec::new();
+        for batch in self.jitter_input.drain(..) {
+            let conv = batch.conv;
+            if !roster.hold(batch, current) {
+                discarded.push(conv);
+            }
+        }
+        self.output.stats.held_hints = roster.len();
+        for conv in discarded {
+            self.output.stats.discarded_hints += 1;
+            self.warn(NetWarning::UnknownConv { conv, batches: 1 });
+        }
+    }
+
+    // in the order they arrived, after the State introducing their conv
+    fn release_hints(&mut self, conv: u32) {
+        let batches = match &mut self.roster {
+            Some(roster) => roster.release(conv),
+            None => return,
+        };
+        let current = self.current();
+        for batch in batches {
+            let frame = batch.frame;
+            self.jitter_input.push(batch);
+            self.route_commands(conv, frame, current);
+            self.track_lag(conv, frame);
+            self.output.stats.released_hints += 1;
+        }
+        self.output.stats.held_hints = self.roster.as_ref().map_or(0, RosterHints::len);
+    }
+
+    fn expire_hints(&mut self, current: u64) {
+        let mut expired = Vec::new();
+        match &mut self.roster {
+            Some(roster) if !roster.is_empty() => roster.expire(current, &mut expired),
+            _ => return,
+        };
+        self.output.stats.held_hints = self.roster.as_ref().map_or(0, RosterHints::len);
+        for (conv, batches) in expired {
+            self.output.stats.discarded_hints += batches as u64;
+            self.warn(NetWarning::UnknownConv { conv, batches });
+        }
+    }
+
+    fn release_jitter(&mut self, current: u64) {
+        if let Some(jitter) = &mut self.jitter {
+            let from = self.output.commands.len();
+            jitter.pop_ready(current, &mut self.output.commands);
+            self.output.stats.jitter_delay = jitter.delay();
+            self.note_delivered(from);
+        }
+    }
+
+    // bounded like the LagTable it is published to, commands the server
+    // didn't stamp have no conv to track
+    fn track_lag(&mut self, conv: u32, frame: u32) {
+        if conv == 0 {
+            return;
+        }
+        if !self.lag.contains_key(&conv) && self.lag.len() >= PLAYERS_CAP {
+            return;
+        }
+        let info = self.lag.entry(conv).or_insert(LagInfo {
+            last_frame: frame,
+            last_seen: Instant::now(),
+            stopped: false,
+        });
+        info.last_frame = info.last_frame.max(frame);
+        info.last_seen = Instant::now();
+        self.output.stats.lag.insert(conv, *info);
+    }
+
+    fn release_frames(&mut self, current: u64) {
+        if let Some(assembler) = &mut self.assembler {
+            assembler.pop_ready(current, &mut self.output.events);
+        }
+    }
+
+    // at the start of every tick
+    #[cfg(feature = "paranoid")]
+    #[context("NetWorker::check_tick()")]
+    fn check_tick(&mut self) -> Result<()> {
+        let context = self.context(None);
+        let (commands, hash) = self.cmd_encoder.buffers();
+        let idle = commands.is_empty() && hash.is_empty();
+        self.invariant(idle, "encoder buffers empty at tick start", &context)?;
+        let balanced = self.chan.pool_balanced();
+        self.invariant(balanced, "chan input pool balanced", &context)?;
+        let waitsnd = self.kcp.waitsnd();
+        let bounded = self.invariants.waitsnd_bounded(waitsnd);
+        self.invariant(
+            bounded,
+            "kcp waitsnd within the segments sent",
+            (waitsnd, &context),
+        )?;
+        return Ok(());
+    }
+
+    #[cfg(feature = "paranoid")]
+    fn invariant<C: std::fmt::Debug>(&mut self, holds: bool, what: &str, context: C) -> Result<()> {
+        let result = self.invariants.check(holds, what, context);
+        self.output.stats.invariant_violations = self.invariants.violations();
+        return result;
+    }
+
+    // ms on `clock`, unlike current() not reset by a reconnect
+    fn since_created(&self) -> u64 {
+        let now = (self.clock)();
+        return now.saturating_duration_since(self.created).as_millis() as u64;
+    }
+
+    // every time the worker measures goes through here
+    fn current(&mut self) -> u64 {
+        let current = self.wall.elapsed();
+        self.output.stats.clock_anomalies = self.wall.anomalies();
+        return current;
+    }
+
+    fn check_hash(&mut self, conv: u32, frame: u32, hash: &[u8]) {
+        if !self.config.hash_check || Conv::new(conv).map_or(true, |conv| conv == self.conv) {
+            return;
+        }
+        if self.hashes.matches(frame, hash) == Some(false) {
+            self.output
+                .events
+                .push(NetEvent::HashMismatch { frame, conv });
+        }
+    }
+
+    // drops the rejected commands of batches[from..], on a fatal verdict all
+    // of them, batches emptied by dropped commands are removed
+    fn validate(
+        validator: Option<&dyn CommandValidator>,
+        batches: &mut Vec<CommandBatch>,
+        from: usize,
+        dropped: &mut u64,
+    ) -> Result<()> {
+        let validator = match validator {
+            Some(validator) => validator,
+            None => return Ok(()),
+        };
+        for idx in from..batches.len() {
+            let batch = &mut batches[idx];
+            let mut keep = 0;
+            for pos in 0..batch.commands.len() {
+                let command = CommandEx {
+                    conv: batch.conv,
+                    frame: batch.frame,
+                    command: batch.commands[pos].clone(),
+                    stamps: batch.stamps,
+                };
+                match validator.validate_incoming(&command) {
+                    Verdict::Accept => {
+                        batch.commands.swap(keep, pos);
+                        keep += 1;
+                    }
+                    Verdict::Drop => *dropped += 1,
+                    Verdict::Fatal => {
+                        batches.truncate(from);
+                        return Err(KCPError::InvalidCommand.into());
+                    }
+                };
+            }
+            batch.commands.truncate(keep);
+        }
+        batches.retain(|batch| !batch.is_empty());
+        return Ok(());
+    }
+
+    fn is_message_command(bytes: &[u8]) -> bool {
+        if bytes.len() < KCP_MIN_PACKET {
+            return false;
+        }
+        return bytes[0] == NetType::Command as u8;
+    }
+}
+
+// the socket and ikcp are released with `kcp`, handles waiting on a worker
+// that is dropped without finishing (e.g. unwinding) see it finish
+impl Drop for NetWorker {
+    fn drop(&mut self) {
+        let mut info = FinishInfo::new(NetFinishCause::ClientError);
+        info.message = "worker dropped".to_string();
+        self.chan.finish_if_running(info);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::base::{
+        ClientError, ValidationError, BOUNDED_RETRIES, CONNECT_RETRIES, KCP_FRAME_SEGMENTS,
+        KCP_WINDOW_SIZE, PADDING_BUCKETS, UNRELIABLE_CONV, WARNING_INTERVAL, WARNING_KINDS,
+    };
+    use crate::chan::CancelResult;
+    use crate::client::{Client, GameHandle};
+    use crate::codec::{Command, CommandEx};
+    use crate::message::{NetAccept, NetConnect, NetFinish, NetHash, NetStart};
+    use crate::mock::{MockAuth, MockServer};
+    use crate::pool::BufferPool;
+    use crate::testing::{allocations, TaggedPool};
+    use std::cell::Cell;
+    use std::collections::BTreeMap;
+    use std::mem;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Barrier, Mutex};
+
+    #[test]
+    fn test_net_worker_input() {
+        let chan = NetChan::new();
+        let mut worker = NetWorker::new(
+            SocketAddr::from(([138, 128, 196, 233], 33303)),
+            6666,
+            "",
+            "",
+            "",
+            chan.clone(),
+        )
+        .unwrap();
+
+        chan.send_input(1, &[], &[1, 2, 3]).unwrap();
+        let err = worker.handle_input().unwrap_err();
+        assert_eq!(
+            err.downcast::<KCPError>().unwrap().to_string(),
+            "unexpected error"
+        );
+
+        worker.state = NetPlayerState::Waiting;
+        chan.send_input(2, &[], &[1, 2, 3]).unwrap();
+        let err = worker.handle_input().unwrap_err();
+        assert_eq!(
+            err.downcast::<KCPError>().unwrap().to_string(),
+            "unexpected error"
+        );
+
+        worker.state = NetPlayerState::Running;
+        worker.frame = 3;
+        chan.send_input(2, &[], &[]).unwrap();
+        let err = worker.handle_input().unwrap_err();
+        let rendered = format!("{:#}", err);
+        assert!(rendered.contains("state Running frame 3 input frame 2"));
+        assert!(rendered.contains("conv 6666"));
+        assert!(rendered.contains("138.128.196.233:33303"));
+        assert_eq!(
+            err.downcast::<KCPError>().unwrap().to_string(),
+            "invalid frame"
+        );
+        worker.frame = 0;
+
+        chan.send_input(3, &[Command::Aaa(3, 3)], &[9, 0, 9, 0])
+            .unwrap();
+        worker.handle_input().unwrap();
+        worker.kcp.update_kcp(0);
+        assert_eq!(worker.kcp.net().output_queue().len(), 1);
+
+        // inputs queued before game_over() are not sent
+        chan.send_input(4, &[Command::Aaa(1, 1)], &[]).unwrap();
+        chan.send_input(5, &[Command::Aaa(2, 2)], &[]).unwrap();
+        chan.game_over().unwrap();
+        chan.game_over().unwrap();
+        worker.handle_input().unwrap();
+        worker.handle_input().unwrap();
+        assert_eq!(worker.frame, 3);
+        assert_eq!(worker.state, NetPlayerState::Stopped);
+    }
+
+    #[test]
+    fn test_net_worker_should_reconnect() {
+        let chan = NetChan::new();
+        let mut worker = NetWorker::new(
+            SocketAddr::from(([138, 128, 196, 233], 33303)),
+            6666,
+            "",
+            "",
+            "",
+            chan.clone(),
+        )
+        .unwrap();
+
+        let timeout: Error = KCPError::Timeout.into();
+        assert!(worker.should_reconnect(&timeout, 0));
+        assert!(!worker.should_reconnect(&timeout, CONNECT_RETRIES));
+
+        let broken: Error = KCPError::PacketBroken.into();
+        assert!(worker.should_reconnect(&broken, BOUNDED_RETRIES - 1));
+        assert!(!worker.should_reconnect(&broken, BOUNDED_RETRIES));
+
+        let banned: Error = KCPError::RemoteFinished(NetFinishCause::AuthFailed).into();
+        assert!(!worker.should_reconnect(&banned, 0));
+
+        worker.state = NetPlayerState::Running;
+        assert!(!worker.should_reconnect(&timeout, 0));
+    }
+
+    #[test]
+    fn test_net_worker_output() {
+        let chan = NetChan::new();
+        let mut worker = NetWorker::new(
+            SocketAddr::from(([138, 128, 196, 233], 33303)),
+            6666,
+            "",
+            "",
+            "",
+            chan.clone(),
+        )
+        .unwrap();
+        worker.handle_output(0).unwrap();
+
+        let mut commands = Vec::<CommandEx>::new();
+        let mut states = BTreeMap::<u32, NetPlayerState>::new();
+
+        worker.kcp_buffer.clear();
+        NetMessage::Accept(NetAccept::default())
+            .encode(&mut worker.kcp_buffer)
+            .unwrap();
+        worker.handle_output_impl().unwrap();
+        assert_eq!(worker.state, NetPlayerState::Waiting);
+        worker.exchange();
+        chan.recv_output(&mut commands, &mut states).unwrap();
+        assert_eq!(states[&worker.conv.get()], NetPlayerState::Waiting);
+
+        worker.kcp_buffer.clear();
+        NetMessage::Start(NetStart::default())
+            .encode(&mut worker.kcp_buffer)
+            .unwrap();
+        worker.handle_output_impl().unwrap();
+        assert_eq!(worker.state, NetPlayerState::Running);
+        worker.exchange();
+        chan.recv_output(&mut commands, &mut states).unwrap();
+        assert_eq!(states[&worker.conv.get()], NetPlayerState::Running);
+
+        worker.kcp_buffer.clear();
+        let mut ce = CommandEncoder::new(0);
+        ce.commands().push(Command::Aaa(10, 1));
+        ce.encode(10).unwrap();
+        worker.kcp_buffer.extend_from_slice(ce.command_bytes());
+        worker.handle_output_impl().unwrap();
+        worker.exchange();
+        chan.recv_output(&mut commands, &mut states).unwrap();
+        assert_eq!(commands[0].command, Command::Aaa(10, 1));
+        assert_eq!(commands[0].frame, 10);
+
+        for state in [
+            NetPlayerState::Initing,
+            NetPlayerState::Waiting,
+            NetPlayerState::Running,
+        ] {
+            worker.state = state;
+            worker.kcp_buffer.clear();
+            NetMessage::Finish(NetFinish::default())
+                .encode(&mut worker.kcp_buffer)
+                .unwrap();
+            let err = worker.handle_output_impl().unwrap_err();
+            assert_eq!(
+                err.downcast::<KCPError>().unwrap().to_string(),
+                "remote finished"
+            );
+        }
+
+        for state in [
+            NetPlayerState::Initing,
+            NetPlayerState::Waiting,
+            NetPlayerState::Running,
+        ] {
+            worker.state = state;
+            worker.kcp_buffer.clear();
+            NetMessage::Connect(NetConnect::default())
+                .encode(&mut worker.kcp_buffer)
+                .unwrap();
+            let err = worker.handle_output_impl().unwrap_err();
+            assert_eq!(
+                err.downcast::<KCPError>().unwrap().to_string(),
+                "unexpected packet"
+            );
+        }
+    }
+
+    #[test]
+    fn test_net_worker_max_hash() {
+        let config = WorkerConfig {
+            hash_check: true,
+            protocol_limits: ProtocolLimits {
+                max_hash: 8,
+                ..ProtocolLimits::default()
+            },
+            ..WorkerConfig::default()
+        };
+        let chan = NetChan::with_limits(config.input_limits, config.output_limits);
+        let mut worker = NetWorker::with_config(
+            SocketAddr::from(([138, 128, 196, 233], 33303)),
+            6666,
+            "",
+            "",
+            "",
+            chan.clone(),
+            config,
+        )
+        .unwrap();
+        worker.state = NetPlayerState::Running;
+        chan.send_input(1, &[], &[1; 8]).unwrap();
+        assert!(chan.send_input(2, &[], &[1; 9]).is_err());
+        worker.handle_input().unwrap();
+
+        let remote = |worker: &mut NetWorker, len: usize| {
+            let mut hash = NetHash::default();
+            hash.conv = 7777;
+            hash.frame = 1;
+            hash.hash = vec![0; len];
+            worker.kcp_buffer.clear();
+            NetMessage::Hash(hash)
+                .encode(&mut worker.kcp_buffer)
+                .unwrap();
+            return worker.handle_output_impl();
+        };
+        remote(&mut worker, 8).unwrap();
+        assert_eq!(
+            worker.output.events,
+            vec![NetEvent::HashMismatch {
+                frame: 1,
+                conv: 7777
+            }]
+        );
+
+        // rejected by the decode already
+        let err = remote(&mut worker, 9).unwrap_err();
+        let err = err.downcast_ref::<KCPError>().unwrap();
+        assert!(matches!(err, KCPError::FieldTooLong("NetHash.hash")));
+        assert_eq!(err.cause(), NetFinishCause::InvalidPacket);
+        assert_eq!(worker.output.events.len(), 1);
+
+        // mid-match it is dropped like any other broken packet
+        worker.handle_packet(0).unwrap();
+        assert_eq!(worker.output.stats.undecodable_packets, 1);
+    }
+
+    #[test]
+    fn test_net_worker_low_latency() {
+        // ms from the input to the wire on a simulated clock
+        let latency = |low_latency: bool| {
+            let config = WorkerConfig {
+                low_latency,
+                ..WorkerConfig::default()
+            };
+            let chan = NetChan::new();
+            let mut worker = NetWorker::with_config(
+                SocketAddr::from(([138, 128, 196, 233], 33303)),
+                6666,
+                "",
+                "",
+                "",
+                chan.clone(),
+                config,
+            )
+            .unwrap();
+            worker.state = NetPlayerState::Running;
+            worker.kcp.update_kcp(0);
+            let sent_at = KCP_INTERVAL / 2;
+            chan.send_input(1, &[Command::Aaa(1, 1)], &[]).unwrap();
+            worker.handle_input().unwrap();
+            let mut current = sent_at;
+            while worker.kcp.net().output_queue().is_empty() {
+                current += 1;
+                worker.kcp.update_kcp(current);
+            }
+            return current - sent_at;
+        };
+        assert_eq!(latency(false), KCP_INTERVAL / 2);
+        assert_eq!(latency(true), 0);
+    }
+
+    #[test]
+    fn test_net_worker_degradation() {
+        let config = WorkerConfig {
+            degradation: Some(DegradeConfig {
+                max_upstream: u64::MAX / 100,
+                max_waitsnd: 4,
+                hold: 100,
+                ..DegradeConfig::default()
+            }),
+            ..WorkerConfig::default()
+        };
+        let chan = NetChan::new();
+        // nothing acks, the backlog grows like on a congested link
+        let mut worker = NetWorker::with_config(
+            SocketAddr::from(([138, 128, 196, 233], 33303)),
+            6666,
+            "",
+            "",
+            "",
+            chan.clone(),
+            config,
+        )
+        .unwrap();
+        worker.state = NetPlayerState::Running;
+        let send = |worker: &mut NetWorker, frame: u32| {
+            chan.send_input(frame, &[], &[1; 8]).unwrap();
+            worker.handle_input().unwrap();
+            worker.output.stats.kcp_waitsnd = worker.kcp.waitsnd();
+            return worker.cmd_encoder.hash_bytes().len();
+        };
+        for frame in 1..=2 {
+            send(&mut worker, frame);
+            worker.update_quality(0);
+        }
+        assert_eq!(worker.kcp.waitsnd(), 4);
+        assert_eq!(worker.output.stats.quality_level, 0);
+        send(&mut worker, 3);
+        worker.update_quality(0);
+        assert_eq!(worker.output.stats.quality_level, 1);
+
+        // empty frames send only their hash
+        send(&mut worker, 4);
+        assert_eq!(worker.kcp.waitsnd(), 7);
+        worker.update_quality(50);
+        assert_eq!(worker.output.stats.quality_level, 1);
+        worker.update_quality(100);
+        assert_eq!(worker.output.stats.quality_level, 2);
+
+        // every HASH_CADENCE-th frame carries it
+        let without = send(&mut worker, 5);
+        assert_eq!(send(&mut worker, 6), without);
+        assert_eq!(send(&mut worker, 7), without);
+        assert!(send(&mut worker, 8) > without);
+
+        // the acks caught up, one step per hold and only well under
+        worker.output.stats.kcp_waitsnd = 3;
+        worker.update_quality(200);
+        assert_eq!(worker.output.stats.quality_level, 2);
+        worker.output.stats.kcp_waitsnd = 2;
+        worker.update_quality(200);
+        worker.update_quality(250);
+        assert_eq!(worker.output.stats.quality_level, 1);
+        worker.update_quality(300);
+        assert_eq!(worker.output.stats.quality_level, 0);
+        worker.update_quality(400);
+
+        worker.exchange();
+        let mut events = Vec::new();
+        chan.recv_events(&mut events);
+        let levels: Vec<usize> = events
+            .iter()
+            .filter_map(|event| match event {
+                NetEvent::Warning(NetWarning::QualityChanged(level)) => Some(*level),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(levels, vec![1, 2, 1, 0]);
+
+        // pinned, nothing ever steps
+        let config = WorkerConfig {
+            degradation: Some(DegradeConfig {
+                max_waitsnd: 0,
+                pinned: Some(1),
+                ..DegradeConfig::default()
+            }),
+            ..WorkerConfig::default()
+        };
+        let chan = NetChan::new();
+        let mut worker = NetWorker::with_config(
+            SocketAddr::from(([138, 128, 196, 233], 33303)),
+            6666,
+            "",
+            "",
+            "",
+            chan.clone(),
+            config,
+        )
+        .unwrap();
+        worker.state = NetPlayerState::Running;
+        assert_eq!(worker.output.stats.quality_level, 1);
+        chan.send_input(1, &[], &[1; 8]).unwrap();
+        worker.handle_input().unwrap();
+        assert_eq!(worker.kcp.waitsnd(), 1);
+        worker.output.stats.kcp_waitsnd = worker.kcp.waitsnd();
+        worker.update_quality(0);
+        assert_eq!(worker.output.stats.quality_level, 1);
+        assert!(worker.output.events.is_empty());
+    }
+
+    #[test]
+    fn test_net_worker_send_budget() {
+        let chan = NetChan::new();
+        // nothing acks, the window only fills
+        let mut worker = NetWorker::new(
+            SocketAddr::from(([138, 128, 196, 233], 33303)),
+            6666,
+            "",
+            "",
+            "",
+            chan.clone(),
+        )
+        .unwrap();
+        worker.state = NetPlayerState::Running;
+        let handle = GameHandle::new(6666, chan.clone()).with_budget_margin(0);
+        let budget = handle.send_budget();
+        assert_eq!(budget.kcp_waitsnd, 0);
+        assert_eq!(budget.window_size, KCP_WINDOW_SIZE as u32);
+        assert_eq!(
+            budget.est_frames_remaining,
+            KCP_WINDOW_SIZE as u32 / KCP_FRAME_SEGMENTS
+        );
+
+        chan.send_input(1, &[Command::Aaa(1, 1)], &[1; 8]).unwrap();
+        let queued = handle.send_budget();
+        assert_eq!(queued.queued_inputs, 1);
+        assert_eq!(queued.est_frames_remaining, budget.est_frames_remaining - 1);
+
+        // sent while the budget allows, published like at the end of a tick
+        let mut frame = 1;
+        while handle.send_budget().est_frames_remaining > 0 {
+            worker.handle_input().unwrap();
+            worker.output.stats.kcp_waitsnd = worker.kcp.waitsnd();
+            worker.exchange();
+            frame += 1;
+            chan.send_input(frame, &[Command::Aaa(1, 1)], &[1; 8])
+                .unwrap();
+        }
+        let budget = handle.send_budget();
+        assert_eq!(budget.queued_inputs, 1);
+        assert_eq!(
+            budget.kcp_waitsnd,
+            KCP_WINDOW_SIZE as u32 - KCP_FRAME_SEGMENTS
+        );
+        assert!(!handle.can_send_frame());
+        assert_eq!(worker.frame, frame - 1);
+
+        // the queued frame is the last the window takes
+        worker.handle_input().unwrap();
+        assert_eq!(worker.kcp.waitsnd(), KCP_WINDOW_SIZE as u32);
+    }
+
+    #[test]
+    fn test_net_worker_hash_commands() {
+        let config = WorkerConfig {
+            hash_commands: true,
+            hash_check: true,
+            ..WorkerConfig::default()
+        };
+        let chan = NetChan::new();
+        let mut worker = NetWorker::with_config(
+            SocketAddr::from(([138, 128, 196, 233], 33303)),
+            6666,
+            "",
+            "",
+            "",
+            chan.clone(),
+            config,
+        )
+        .unwrap();
+        worker.state = NetPlayerState::Running;
+        // their hash_commands() test vectors
+        #[cfg(not(feature = "no-float-commands"))]
+        let (commands, canonical) = (
+            [Command::Aaa(7, -3), Command::Bbb(1.5, -0.0, f32::NAN, 4)],
+            0x44fd_91ae_785a_1b5f_u64,
+        );
+        #[cfg(feature = "no-float-commands")]
+        let (commands, canonical) = ([Command::Aaa(7, -3)], 0x9ed7_a211_c6de_02d1_u64);
+        chan.send_input(1, &commands, &[]).unwrap();
+        chan.send_input(2, &commands, &[9; 8]).unwrap();
+        chan.send_input(3, &[], &[]).unwrap();
+        worker.handle_input().unwrap();
+        let canonical = canonical.to_be_bytes();
+        assert_eq!(worker.hashes.get(1), Some(&canonical[..]));
+        // the game's own is kept
+        assert_eq!(worker.hashes.get(2), Some(&[9; 8][..]));
+        let empty = hash_commands(&[]).to_be_bytes();
+        assert_eq!(worker.hashes.get(3), Some(&empty[..]));
+    }
+
+    #[test]
+    fn test_net_worker_hash_only() {
+        let config = WorkerConfig {
+            hash_only: true,
+            ..WorkerConfig::default()
+        };
+        let chan = NetChan::new();
+        let mut worker = NetWorker::with_config(
+            SocketAddr::from(([138, 128, 196, 233], 33303)),
+            6666,
+            "",
+            "",
+            "",
+            chan.clone(),
+            config,
+        )
+        .unwrap();
+        worker.state = NetPlayerState::Running;
+        // one segment for a hash-only frame, two with commands
+        for (frame, commands, segments) in [
+            (1, vec![], 1),
+            (2, vec![Command::Aaa(1, 1)], 3),
+            (3, vec![], 4),
+        ] {
+            chan.send_input(frame, &commands, &[1; 8]).unwrap();
+            worker.handle_input().unwrap();
+            assert_eq!(worker.kcp.waitsnd(), segments);
+            assert_eq!(worker.output.stats.sent_frame, frame);
+        }
+        chan.send_input(3, &[], &[1; 8]).unwrap();
+        let err = worker.handle_input().unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<KCPError>(),
+            Some(KCPError::InvalidFrame)
+        ));
+
+        // the server relays the frame with commands only
+        let server = MockServer::start(1).unwrap();
+        let chan = NetChan::new();
+        let config = WorkerConfig {
+            hash_only: true,
+            ..WorkerConfig::default()
+        };
+        let mut worker = NetWorker::with_config(
+            server.addr(),
+            6666,
+            "room",
+            "player",
+            "",
+            chan.clone(),
+            config,
+        )
+        .unwrap();
+        worker.start().unwrap();
+        drive(&mut worker, || chan.start_info().is_some());
+        chan.send_input(1, &[], &[1; 8]).unwrap();
+        chan.send_input(2, &[Command::Aaa(2, 2)], &[2; 8]).unwrap();
+        chan.send_input(3, &[], &[3; 8]).unwrap();
+        drive(&mut worker, || server.records().hashes.len() == 3);
+        let hashes = server.records().hashes;
+        let frames: Vec<u32> = hashes.iter().map(|(_, hash)| hash.frame).collect();
+        assert_eq!(frames, vec![1, 2, 3]);
+        assert_eq!(sent_frames(&server, 6666), vec![2]);
+        assert_eq!(worker.state, NetPlayerState::Running);
+    }
+
+    #[test]
+    fn test_net_worker_misframed() {
+        let chan = NetChan::new();
+        let mut worker = NetWorker::new(
+            SocketAddr::from(([138, 128, 196, 233], 33303)),
+            6666,
+            "",
+            "",
+            "",
+            chan.clone(),
+        )
+        .unwrap();
+        worker.state = NetPlayerState::Running;
+
+        // a partial write left behind, then the next message appended
+        NetMessage::hash(1, 6666, &[1; 8])
+            .encode(&mut worker.kcp_buffer)
+            .unwrap();
+        worker.kcp_buffer.pop();
+        NetMessage::state(6666, NetPlayerState::Running)
+            .encode(&mut worker.kcp_buffer)
+            .unwrap();
+        let err = worker.kcp.send_kcp(&worker.kcp_buffer).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<KCPError>(),
+            Some(KCPError::Misframed)
+        ));
+
+        // whole, but two messages in one
+        worker.kcp_buffer.clear();
+        NetMessage::start().encode(&mut worker.kcp_buffer).unwrap();
+        NetMessage::start().encode(&mut worker.kcp_buffer).unwrap();
+        assert!(worker.kcp.send_kcp(&worker.kcp_buffer).is_err());
+        assert!(worker.kcp.send_kcp(&[]).is_err());
+        assert_eq!(worker.kcp.waitsnd(), 0);
+
+        worker.kcp_buffer.clear();
+        chan.send_input(1, &[Command::Aaa(1, 1)], &[1; 8]).unwrap();
+        worker.handle_input().unwrap();
+        assert_eq!(worker.kcp.waitsnd(), KCP_FRAME_SEGMENTS);
+    }
+
+    #[test]
+    fn test_net_worker_packet_size() {
+        let config = WorkerConfig {
+            input_limits: InputLimits {
+                max_bytes: 4 * KCP_MAX_PACKET,
+                ..InputLimits::default()
+            },
+            ..WorkerConfig::default()
+        };
+        let chan = NetChan::with_limits(config.input_limits, config.output_limits);
+        let mut worker = NetWorker::with_config(
+            SocketAddr::from(([138, 128, 196, 233], 33303)),
+            6666,
+            "",
+            "",
+            "",
+            chan.clone(),
+            config,
+        )
+        .unwrap();
+        worker.state = NetPlayerState::Running;
+        assert_eq!(worker.output.stats.kcp_mtu, KCP_MTU);
+
+        chan.send_input(1, &[Command::Aaa(1, 1)], &[1; 8]).unwrap();
+        worker.handle_input().unwrap();
+        let small = worker.output.stats.largest_packet;
+        assert!(small > 0 && small < KCP_MTU - KCP_OVERHEAD);
+        assert!(!worker.output.stats.segmented);
+        assert!(worker.output.events.is_empty());
+
+        let large = vec![Command::Aaa(1, 1); 135];
+        chan.send_input(2, &large, &[]).unwrap();
+        chan.send_input(3, &large, &[]).unwrap();
+        worker.handle_input().unwrap();
+        let stats = worker.output.stats;
+        assert!(stats.largest_packet * 100 > KCP_MAX_PACKET * PACKET_WARN_PERCENT);
+        assert!(stats.largest_packet <= KCP_MAX_PACKET);
+        assert!(stats.segmented);
+        assert_eq!(
+            worker.output.events,
+            vec![NetEvent::Warning(NetWarning::LargePacket {
+                frame: 2,
+                size: stats.largest_packet,
+                limit: KCP_MAX_PACKET
+            })]
+        );
+
+        // only the first one is warned about
+        chan.send_input(4, &large, &[]).unwrap();
+        worker.handle_input().unwrap();
+        assert!(worker.output.events.is_empty());
+    }
+
+    #[test]
+    fn test_net_worker_hash_check() {
+        let chan = NetChan::new();
+        let config = WorkerConfig {
+            hash_check: true,
+            hash_history: 2,
+            ..WorkerConfig::default()
+        };
+        let mut worker = NetWorker::with_config(
+            SocketAddr::from(([138, 128, 196, 233], 33303)),
+            6666,
+            "",
+            "",
+            "",
+            chan.clone(),
+            config,
+        )
+        .unwrap();
+        worker.state = NetPlayerState::Running;
+
+        chan.send_input(1, &[], &[1, 1]).unwrap();
+        chan.send_input(2, &[], &[]).unwrap();
+        chan.send_input(3, &[], &[3, 3]).unwrap();
+        chan.send_input(4, &[], &[4, 4]).unwrap();
+        worker.handle_input().unwrap();
+
+        let remote = |worker: &mut NetWorker, conv: u32, frame: u32, bytes: &[u8]| {
+            let mut hash = NetHash::default();
+            hash.conv = conv;
+            hash.frame = frame;
+            hash.hash = bytes.to_vec();
+            worker.kcp_buffer.clear();
+            NetMessage::Hash(hash)
+                .encode(&mut worker.kcp_buffer)
+                .unwrap();
+            worker.handle_output_impl().unwrap();
+        };
+        remote(&mut worker, 7777, 4, &[4, 4]);
+        remote(&mut worker, 7777, 3, &[3, 0]);
+        remote(&mut worker, 8888, 4, &[0, 4]);
+        // evicted, never hashed, our own relayed back
+        remote(&mut worker, 7777, 1, &[0, 0]);
+        remote(&mut worker, 7777, 2, &[0, 0]);
+        remote(&mut worker, 7777, 9, &[0, 0]);
+        remote(&mut worker, 6666, 3, &[0, 0]);
+        worker.exchange();
+
+        let mut events = Vec::new();
+        chan.recv_events(&mut events);
+        assert_eq!(
+            events,
+            vec![
+                NetEvent::HashMismatch {
+                    frame: 3,
+                    conv: 7777
+                },
+                NetEvent::HashMismatch {
+                    frame: 4,
+                    conv: 8888
+                },
+            ]
+        );
+        assert_eq!(worker.hashes.len(), 2);
+
+        // disabled by default, relayed hashes are accepted and ignored, a
+        // chan only takes one worker
+        let chan = NetChan::new();
+        let mut worker = NetWorker::new(
+            SocketAddr::from(([138, 128, 196, 233], 33303)),
+            6666,
+            "",
+            "",
+            "",
+            chan.clone(),
+        )
+        .unwrap();
+        worker.state = NetPlayerState::Running;
+        chan.send_input(1, &[], &[1, 1]).unwrap();
+        worker.handle_input().unwrap();
+        remote(&mut worker, 7777, 1, &[0, 0]);
+        worker.exchange();
+        chan.recv_events(&mut events);
+        assert_eq!(events.len(), 2);
+    }
+
+    #[test]
+    fn test_net_worker_jitter_buffer() {
+        let chan = NetChan::new();
+        let config = WorkerConfig {
+            jitter_buffer: true,
+            ..WorkerConfig::default()
+        };
+        let mut worker = NetWorker::with_config(
+            SocketAddr::from(([138, 128, 196, 233], 33303)),
+            6666,
+            "",
+            "",
+            "",
+            chan.clone(),
+            config,
+        )
+        .unwrap();
+        worker.state = NetPlayerState::Running;
+
+        // a burst of 3 frames is held and released one frame per interval
+        let mut ce = CommandEncoder::new(0);
+        for frame in 1..=3 {
+            ce.commands().push(Command::Aaa(frame as i32, 0));
+            ce.encode(frame).unwrap();
+            worker.kcp_buffer.clear();
+            worker.kcp_buffer.extend_from_slice(ce.command_bytes());
+            worker.handle_output_impl().unwrap();
+        }
+        let estimate = worker.estimator.estimate(0).unwrap();
+        assert_eq!(estimate.frame, 3);
+
+        let mut commands = Vec::<CommandEx>::new();
+        let mut states = BTreeMap::<u32, NetPlayerState>::new();
+        for (current, frames) in [(0, vec![1]), (30, vec![]), (50, vec![2]), (100, vec![3])] {
+            worker.release_jitter(current);
+            worker.exchange();
+            commands.clear();
+            chan.recv_output(&mut commands, &mut states).unwrap();
+            let released: Vec<u32> = commands.iter().map(|cmd| cmd.frame).collect();
+            assert_eq!(released, frames);
+        }
+        assert_eq!(chan.stats().jitter_delay, 0);
+    }
+
+    thread_local! {
+        static FROZEN_WALL: Cell<u64> = Cell::new(0);
+    }
+
+    // ms after the epoch, stands still until set
+    fn frozen_wall() -> SystemTime {
+        return SystemTime::UNIX_EPOCH + Duration::from_millis(FROZEN_WALL.with(Cell::get));
+    }
+
+    #[test]
+    fn test_net_worker_timestamps() {
+        let chan = NetChan::new();
+        let config = WorkerConfig {
+            jitter_buffer: true,
+            timestamps: true,
+            ..WorkerConfig::default()
+        };
+        let mut worker = NetWorker::with_config(
+            SocketAddr::from(([138, 128, 196, 233], 33303)),
+            6666,
+            "",
+            "",
+            "",
+            chan.clone(),
+            config,
+        )
+        .unwrap();
+        worker.state = NetPlayerState::Running;
+        FROZEN_WALL.with(|wall| wall.set(0));
+        worker.wall = WallClock::new(frozen_wall);
+        worker.wall.reset();
+
+        // arriving early, the jitter buffer holds them until their slot
+        for frame in 1..=3 {
+            FROZEN_WALL.with(|wall| wall.set((frame as u64 - 1) * 10));
+            relay(&mut worker, 7777, frame, &[Command::Aaa(frame as i32, 0)]);
+        }
+        let mut batches = Vec::new();
+        let mut events = Vec::new();
+        for (current, frame) in [(20, 1), (50, 2), (100, 3)] {
+            FROZEN_WALL.with(|wall| wall.set(current));
+            worker.release_jitter(current);
+            worker.exchange();
+            batches.clear();
+            chan.drain_batches(&mut batches, &mut events);
+            assert_eq!(batches.len(), 1);
+            assert_eq!(batches[0].frame, frame);
+            let stamps = batches[0].stamps.unwrap();
+            assert_eq!(stamps.received_at, (frame as u64 - 1) * 10);
+            // plus the real time between the exchange and the drain
+            assert!(stamps.delivered_at >= current);
+            assert!(stamps.delivered_at < current + 1000);
+        }
+
+        // flattened, and nothing stamped without the flag
+        FROZEN_WALL.with(|wall| wall.set(120));
+        relay(&mut worker, 7777, 4, &[Command::Aaa(4, 0)]);
+        worker.release_jitter(150);
+        worker.exchange();
+        let mut commands = Vec::new();
+        chan.drain_output(&mut commands, &mut events);
+        assert_eq!(commands.len(), 1);
+        assert_eq!(commands[0].stamps.unwrap().received_at, 120);
+
+        let chan = NetChan::new();
+        let mut worker = NetWorker::new(
+            SocketAddr::from(([138, 128, 196, 233], 33303)),
+            6666,
+            "",
+            "",
+            "",
+            chan.clone(),
+        )
+        .unwrap();
+        worker.state = NetPlayerState::Running;
+        relay(&mut worker, 7777, 1, &[Command::Aaa(1, 0)]);
+        worker.exchange();
+        assert!(worker.output.session_ms.is_none());
+        chan.drain_batches(&mut batches, &mut events);
+        assert_eq!(batches.last().unwrap().stamps, None);
+    }
+
+    // Aaa(x, y): negative x is dropped, negative y is fatal
+    #[derive(Debug)]
+    struct NonNegative;
+
+    impl CommandValidator for NonNegative {
+        fn validate_outgoing(&self, command: &Command) -> Result<(), ValidationError> {
+            return match command {
+                Command::Aaa(x, y) if *x < 0 || *y < 0 => Err(ValidationError::new("negative")),
+                _ => Ok(()),
+            };
+        }
+
+        fn validate_incoming(&self, command: &CommandEx) -> Verdict {
+            return match command.command {
+                Command::Aaa(_, y) if y < 0 => Verdict::Fatal,
+                Command::Aaa(x, _) if x < 0 => Verdict::Drop,
+                _ => Verdict::Accept,
+            };
+        }
+    }
+
+    #[test]
+    fn test_net_worker_command_validator() {
+        let chan = NetChan::new();
+        let config = WorkerConfig {
+            validator: Some(Arc::new(NonNegative)),
+            ..WorkerConfig::default()
+        };
+        let handle = GameHandle::new(6666, chan.clone()).with_validator(config.validator.clone());
+        let mut worker = NetWorker::with_config(
+            SocketAddr::from(([138, 128, 196, 233], 33303)),
+            6666,
+            "",
+            "",
+            "",
+            chan.clone(),
+            config,
+        )
+        .unwrap();
+        worker.state = NetPlayerState::Running;
+
+        let err = handle
+            .send_input(1, &[Command::Aaa(1, 1), Command::Aaa(-1, 1)], &[])
+            .unwrap_err();
+        assert!(matches!(err, ClientError::Validation(_)));
+        handle.send_input(1, &[Command::Aaa(1, 1)], &[]).unwrap();
+
+        let mut ce = CommandEncoder::new(0);
+        let mut remote = |worker: &mut NetWorker, commands: &[Command]| {
+            ce.commands().extend(commands.iter().cloned());
+            ce.encode(1).unwrap();
+            worker.kcp_buffer.clear();
+            worker.kcp_buffer.extend_from_slice(ce.command_bytes());
+            return worker.handle_output_impl();
+        };
+        remote(
+            &mut worker,
+            &[Command::Aaa(1, 1), Command::Aaa(-1, 1), Command::Aaa(2, 2)],
+        )
+        .unwrap();
+        let aaa = |x: i32, y: i32| CommandEx {
+            conv: 0,
+            frame: 1,
+            command: Command::Aaa(x, y),
+            stamps: None,
+        };
+        let commands: Vec<_> = CommandBatch::flatten(&worker.output.commands).collect();
+        assert_eq!(commands, vec![aaa(1, 1), aaa(2, 2)]);
+        assert_eq!(worker.output.stats.dropped_commands, 1);
+
+        let err = remote(&mut worker, &[Command::Aaa(3, 3), Command::Aaa(3, -3)]).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<KCPError>(),
+            Some(KCPError::InvalidCommand)
+        ));
+        assert_eq!(worker.output.commands.len(), 1);
+        assert_eq!(
+            KCPError::InvalidCommand.cause(),
+            NetFinishCause::InvalidPacket
+        );
+    }
+
+    // a command packet as relayed by the server, which sets the sender's conv
+    fn relay(worker: &mut NetWorker, conv: u32, frame: u32, commands: &[Command]) {
+        try_relay(worker, conv, frame, commands).unwrap();
+    }
+
+    fn try_relay(
+        worker: &mut NetWorker,
+        conv: u32,
+        frame: u32,
+        commands: &[Command],
+    ) -> Result<()> {
+        let mut ce = CommandEncoder::new(0);
+        ce.commands().extend(commands.iter().cloned());
+        ce.encode(frame).unwrap();
+        let bytes = ce.command_bytes();
+        let (msg, offset) = NetMessage::decode(bytes).unwrap();
+        let mut command = match msg {
+            NetMessage::Command(command) => command,
+            msg => panic!("unexpected {:?}", msg),
+        };
+        command.conv = conv;
+        worker.kcp_buffer.clear();
+        NetMessage::Command(command)
+            .encode(&mut worker.kcp_buffer)
+            .unwrap();
+        worker.kcp_buffer.extend_from_slice(&bytes[offset..]);
+        return worker.handle_output_impl();
+    }
+
+    #[cfg(feature = "paranoid")]
+    #[test]
+    fn test_net_worker_paranoid() {
+        let addr = SocketAddr::from(([138, 128, 196, 233], 33303));
+        let mut worker = NetWorker::new(addr, 6666, "", "", "", NetChan::new()).unwrap();
+        worker.state = NetPlayerState::Running;
+        worker.check_tick().unwrap();
+        relay(&mut worker, 7777, 5, &[Command::Aaa(7, 5)]);
+        assert_eq!(worker.output.stats.invariant_violations, 0);
+
+        // a conv's frame going back
+        relay(&mut worker, 7777, 3, &[Command::Aaa(7, 3)]);
+        assert_eq!(worker.output.stats.invariant_violations, 1);
+
+        // commands left behind by a tick that bailed out midway
+        worker.cmd_encoder.commands().push(Command::Aaa(1, 1));
+        worker.check_tick().unwrap();
+        assert_eq!(worker.output.stats.invariant_violations, 2);
+
+        // promoted to fatal
+        let config = WorkerConfig {
+            paranoid_fatal: true,
+            ..WorkerConfig::default()
+        };
+        let mut worker =
+            NetWorker::with_config(addr, 6666, "", "", "", NetChan::new(), config).unwrap();
+        worker.cmd_encoder.commands().push(Command::Aaa(1, 1));
+        let err = worker.check_tick().unwrap_err();
+        assert!(format!("{:#}", err).contains("encoder buffers empty at tick start"));
+        assert_eq!(worker.output.stats.invariant_violations, 1);
+    }
+
+    #[test]
+    fn test_net_worker_undecodable_flood() {
+        let chan = NetChan::new();
+        let mut worker = NetWorker::new(
+            SocketAddr::from(([138, 128, 196, 233], 33303)),
+            6666,
+            "",
+            "",
+            "",
+            chan.clone(),
+        )
+        .unwrap();
+        let lines = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sink = lines.clone();
+        worker.packet_log = RateLimitedLogger::with_sink(
+            LOG_INTERVAL,
+            Box::new(move |line| sink.lock().unwrap().push(line.to_string())),
+        );
+        let mut ce = CommandEncoder::new(0);
+        ce.commands().push(Command::Aaa(1, 1));
+        ce.encode(1).unwrap();
+        let mut broken = ce.command_bytes().to_vec();
+        broken.push(0xff);
+
+        // the handshake has to be exact
+        worker.state = NetPlayerState::Waiting;
+        worker.kcp_buffer.clear();
+        worker.kcp_buffer.extend_from_slice(&broken);
+        assert!(worker.handle_packet(0).is_err());
+
+        // 1000 packets over 10s, logged once per LOG_INTERVAL
+        worker.state = NetPlayerState::Running;
+        for idx in 0..1000 {
+            worker.kcp_buffer.clear();
+            worker.kcp_buffer.extend_from_slice(&broken);
+            worker.handle_packet(idx * 10).unwrap();
+            worker.packet_log.flush(idx * 10);
+        }
+        worker.packet_log.flush(10_000);
+        assert_eq!(worker.output.stats.undecodable_packets, 1000);
+        assert!(worker.output.commands.is_empty());
+        assert_eq!(
+            *lines.lock().unwrap(),
+            vec![
+                "dropped 500 undecodable packets in last 5s",
+                "dropped 500 undecodable packets in last 5s",
+            ]
+        );
+
+        // decodable but out of place is still fatal
+        worker.kcp_buffer.clear();
+        NetMessage::Accept(NetAccept::default())
+            .encode(&mut worker.kcp_buffer)
+            .unwrap();
+        let err = worker.handle_packet(10_000).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<KCPError>(),
+            Some(KCPError::UnexpectedPacket)
+        ));
+        relay(&mut worker, 8, 1, &[Command::Aaa(8, 1)]);
+        assert_eq!(worker.output.commands.len(), 1);
+    }
+
+    #[test]
+    fn test_net_worker_frame_assembly() {
+        let config = WorkerConfig {
+            frame_assembly: true,
+            ..WorkerConfig::default()
+        };
+        let mut worker = NetWorker::with_config(
+            SocketAddr::from(([138, 128, 196, 233], 33303)),
+            6666,
+            "",
+            "",
+            "",
+            NetChan::new(),
+            config,
+        )
+        .unwrap();
+        worker.set_self_state(NetPlayerState::Running);
+        worker.set_state(7, NetPlayerState::Running);
+
+        let frames = |worker: &mut NetWorker, current: u64| {
+            worker.output.events.clear();
+            worker.release_frames(current);
+            return worker.output.events.clone();
+        };
+
+        worker.frame = 3;
+        relay(&mut worker, 6666, 1, &[Command::Aaa(1, 1)]);
+        assert_eq!(frames(&mut worker, 0), vec![]);
+        relay(&mut worker, 7, 1, &[]);
+        assert_eq!(
+            frames(&mut worker, 0),
+            vec![NetEvent::FrameReady {
+                frame: 1,
+                commands: vec![(7, vec![]), (6666, vec![Command::Aaa(1, 1)])],
+                complete: true,
+            }]
+        );
+        assert!(worker.output.commands.is_empty());
+
+        // 7 leaves mid-frame
+        relay(&mut worker, 6666, 2, &[]);
+        worker.set_state(7, NetPlayerState::Stopped);
+        assert_eq!(
+            frames(&mut worker, 10),
+            vec![NetEvent::FrameReady {
+                frame: 2,
+                commands: vec![(6666, vec![])],
+                complete: true,
+            }]
+        );
+
+        // a straggler holds a frame for assembly_max_wait at most
+        worker.set_state(8, NetPlayerState::Running);
+        relay(&mut worker, 6666, 3, &[Command::Aaa(3, 3)]);
+        assert_eq!(frames(&mut worker, 0), vec![]);
+        assert_eq!(
+            frames(&mut worker, ASSEMBLY_MAX_WAIT),
+            vec![NetEvent::FrameReady {
+                frame: 3,
+                commands: vec![(6666, vec![Command::Aaa(3, 3)])],
+                complete: false,
+            }]
+        );
+        relay(&mut worker, 8, 3, &[Command::Aaa(8, 8)]);
+        worker.release_frames(ASSEMBLY_MAX_WAIT);
+        assert_eq!(worker.output.stats.late_commands, 1);
+    }
+
+    #[test]
+    fn test_net_worker_frame_assembly_first_frame() {
+        let addr = SocketAddr::from(([138, 128, 196, 233], 33303));
+        let config = WorkerConfig {
+            frame_assembly: true,
+            ..WorkerConfig::default()
+        };
+        let start = |first_frame: u32, resumed: Option<u32>| {
+            let chan = NetChan::new();
+            let mut worker =
+                NetWorker::with_config(addr, 6666, "", "", "", chan, config.clone()).unwrap();
+            if let Some(last_frame) = resumed {
+                let mut state = SessionState::new(addr, 6666, "room", "player");
+                state.resume_token = vec![1];
+                state.last_frame = last_frame;
+                worker.resume(&state).unwrap();
+            }
+            worker.state = NetPlayerState::Waiting;
+            worker.set_state(7, NetPlayerState::Running);
+            worker.kcp_buffer.clear();
+            NetMessage::start_at(first_frame)
+                .encode(&mut worker.kcp_buffer)
+                .unwrap();
+            worker.handle_output_impl().unwrap();
+            return worker;
+        };
+        let frames = |worker: &mut NetWorker, current: u64| {
+            worker.output.events.clear();
+            worker.release_frames(current);
+            return worker.output.events.clone();
+        };
+
+        // from the first frame, or the one after the resumed session's last
+        for (mut worker, first) in [(start(100, None), 100), (start(100, Some(250)), 251)] {
+            worker.frame = first + 1;
+            relay(&mut worker, 6666, first, &[Command::Aaa(1, 1)]);
+            relay(&mut worker, 7, first, &[]);
+            assert_eq!(
+                frames(&mut worker, 0),
+                vec![NetEvent::FrameReady {
+                    frame: first,
+                    commands: vec![(7, vec![]), (6666, vec![Command::Aaa(1, 1)])],
+                    complete: true,
+                }]
+            );
+
+            // a straggler holds only its own frame
+            relay(&mut worker, 6666, first + 1, &[]);
+            assert_eq!(
+                frames(&mut worker, ASSEMBLY_MAX_WAIT),
+                vec![NetEvent::FrameReady {
+                    frame: first + 1,
+                    commands: vec![(6666, vec![])],
+                    complete: false,
+                }]
+            );
+        }
+    }
+
+    // ticks by hand instead of run(), so dropping the worker is a crash: it
+    // never sends a Finish
+    fn drive<F: Fn() -> bool>(worker: &mut NetWorker, done: F) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !done() {
+            assert!(Instant::now() < deadline, "timeout");
+            let current = worker.current();
+            let until = SystemTime::now() + Duration::from_millis(KCP_INTERVAL);
+            worker.tick(current, until).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_net_worker_connect_stages() {
+        let connect = |addr: SocketAddr| {
+            let config = WorkerConfig {
+                reach_timeout: 200,
+                accept_timeout: 300,
+                ..WorkerConfig::default()
+            };
+            let chan = NetChan::new();
+            let mut worker =
+                NetWorker::with_config(addr, 6666, "room", "player", "", chan.clone(), config)
+                    .unwrap();
+            worker.start().unwrap();
+            let deadline = Instant::now() + Duration::from_secs(5);
+            loop {
+                assert!(Instant::now() < deadline, "timeout");
+                let current = worker.current();
+                let until = SystemTime::now() + Duration::from_millis(KCP_INTERVAL);
+                if let Err(err) = worker.tick(current, until) {
+                    worker.begin_finish(err, false);
+                    return (worker, chan.finish_info().unwrap());
+                }
+            }
+        };
+
+        // bound but never read, nothing comes back
+        let blackhole = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let (worker, info) = connect(blackhole.local_addr().unwrap());
+        assert_eq!(info.cause, NetFinishCause::NetworkBroken);
+        assert!(info.message.contains("unreachable"));
+        assert_eq!(info.connect, ConnectTimes::default());
+        assert_eq!(worker.output.stats.connect, ConnectTimes::default());
+        assert_eq!(worker.state, NetPlayerState::Initing);
+
+        // kcp acks the Connect, the Accept never comes
+        let auth = MockAuth {
+            silent: true,
+            ..MockAuth::default()
+        };
+        let server = MockServer::start_with_auth(1, auth).unwrap();
+        let (worker, info) = connect(server.addr());
+        assert_eq!(info.cause, NetFinishCause::NetworkBroken);
+        assert!(info.message.contains("accept timeout"));
+        let reach = info.connect.reach.unwrap();
+        assert!(reach < 200);
+        assert_eq!(info.connect.accept, None);
+        assert_eq!(worker.output.stats.connect, info.connect);
+        assert_eq!(server.records().connects.len(), 1);
+        assert!(!worker.should_reconnect(&KCPError::AcceptTimeout.into(), BOUNDED_RETRIES));
+        assert!(worker.should_reconnect(&KCPError::Unreachable.into(), BOUNDED_RETRIES));
+
+        // both stages are measured on a regular connect
+        let server = MockServer::start(1).unwrap();
+        let chan = NetChan::new();
+        let mut worker =
+            NetWorker::new(server.addr(), 6666, "room", "player", "", chan.clone()).unwrap();
+        worker.start().unwrap();
+        drive(&mut worker, || chan.start_info().is_some());
+        let connect = worker.output.stats.connect;
+        assert!(connect.reach.is_some());
+        assert!(connect.accept.is_some());
+    }
+
+    #[test]
+    fn test_net_worker_superseded() {
+        let auth = MockAuth {
+            takeover: true,
+            ..MockAuth::default()
+        };
+        let login = |server: &MockServer, conv| {
+            let chan = NetChan::new();
+            let mut worker =
+                NetWorker::new(server.addr(), conv, "room", "player", "", chan.clone()).unwrap();
+            worker.start().unwrap();
+            return (worker, chan);
+        };
+        let reach = |worker: &mut NetWorker, state| {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while worker.state != state {
+                assert!(Instant::now() < deadline, "timeout");
+                let current = worker.current();
+                let until = SystemTime::now() + Duration::from_millis(KCP_INTERVAL);
+                worker.tick(current, until).unwrap();
+            }
+        };
+        let taken_over = |worker: &mut NetWorker, chan: &NetChan| {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            loop {
+                assert!(Instant::now() < deadline, "timeout");
+                let current = worker.current();
+                let until = SystemTime::now() + Duration::from_millis(KCP_INTERVAL);
+                if let Err(err) = worker.tick(current, until) {
+                    worker.begin_finish(err, false);
+                    return chan.finish_info().unwrap();
+                }
+            }
+        };
+
+        // while waiting for the other player
+        let server = MockServer::start_with_auth(2, auth.clone()).unwrap();
+        let (mut phone, phone_chan) = login(&server, 6666);
+        reach(&mut phone, NetPlayerState::Waiting);
+        let (mut tablet, _) = login(&server, 8888);
+        reach(&mut tablet, NetPlayerState::Waiting);
+        let info = taken_over(&mut phone, &phone_chan);
+        assert_eq!(info.cause, NetFinishCause::Superseded);
+        assert!(info.message.contains("remote finished"));
+        assert_eq!(
+            ClientError::from(KCPError::RemoteFinished(info.cause)).cause(),
+            NetFinishCause::Superseded
+        );
+
+        // mid-match, the new login joins the running match
+        let server = MockServer::start_with_auth(1, auth).unwrap();
+        let (mut phone, phone_chan) = login(&server, 6666);
+        reach(&mut phone, NetPlayerState::Running);
+        let (mut tablet, _) = login(&server, 8888);
+        reach(&mut tablet, NetPlayerState::Running);
+        let info = taken_over(&mut phone, &phone_chan);
+        assert_eq!(info.cause, NetFinishCause::Superseded);
+        // we were told, not the other way around
+        assert!(server.records().finishes.is_empty());
+        assert_eq!(tablet.state, NetPlayerState::Running);
+    }
+
+    #[test]
+    fn test_net_worker_unreliable() {
+        let server = MockServer::start(2).unwrap();
+        let join = |conv: u32| {
+            let chan = NetChan::new();
+            let mut worker =
+                NetWorker::new(server.addr(), conv, "room", "player", "", chan.clone()).unwrap();
+            worker.start().unwrap();
+            return (worker, chan);
+        };
+        let (mut sender, sender_chan) = join(6666);
+        let (mut receiver, receiver_chan) = join(8888);
+
+        // relayed to the convs the mock has heard from, resent until then
+        let mut payloads = Vec::new();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while payloads.is_empty() {
+            assert!(Instant::now() < deadline, "timeout");
+            sender_chan.send_unreliable(&[1, 2, 3]).unwrap();
+            for worker in [&mut sender, &mut receiver].iter_mut() {
+                let current = worker.current();
+                let until = SystemTime::now() + Duration::from_millis(KCP_INTERVAL);
+                worker.tick(current, until).unwrap();
+            }
+            receiver_chan.recv_unreliable(&mut payloads);
+        }
+        assert_eq!(payloads[0], (6666, vec![1, 2, 3]));
+        assert!(server.records().unreliable.contains(&(6666, vec![1, 2, 3])));
+        sender_chan.recv_unreliable(&mut payloads);
+        assert!(payloads.iter().all(|(conv, _)| *conv == 6666));
+    }
+
+    #[test]
+    fn test_net_worker_adopted_socket() {
+        let server = MockServer::start(1).unwrap();
+        let adopt = |socket: UdpSocket, peer: SocketAddr| {
+            let chan = NetChan::new();
+            let config = WorkerConfig::default();
+            let worker = NetWorker::with_socket(socket, peer, 6666, "", "", "", chan, config);
+            return match worker {
+                Ok(_) => None,
+                Err(err) => err.downcast::<ConfigError>().ok(),
+            };
+        };
+
+        let v6 = SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 1], server.addr().port()));
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        assert!(matches!(
+            adopt(socket, v6),
+            Some(ConfigError::InvalidField {
+                field: "socket",
+                ..
+            })
+        ));
+        let elsewhere = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.connect(elsewhere.local_addr().unwrap()).unwrap();
+        assert!(matches!(
+            adopt(socket, server.addr()),
+            Some(ConfigError::InvalidField {
+                field: "socket",
+                ..
+            })
+        ));
+
+        // as a hole punch leaves it, bound and connected to the server
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.connect(server.addr()).unwrap();
+        let local = socket.local_addr().unwrap();
+        let chan = NetChan::new();
+        let mut worker = NetWorker::with_socket(
+            socket,
+            server.addr(),
+            6666,
+            "room",
+            "player",
+            "",
+            chan.clone(),
+            WorkerConfig::default(),
+        )
+        .unwrap();
+        let handle = GameHandle::new(6666, chan.clone());
+        assert_eq!(worker.local_addr(), Some(local));
+        worker.start().unwrap();
+        drive(&mut worker, || chan.start_info().is_some());
+        for frame in 1..=5 {
+            handle
+                .send_input(frame, &[Command::Aaa(frame as i32, 0)], &[])
+                .unwrap();
+        }
+        drive(&mut worker, || sent_frames(&server, 6666).len() == 5);
+        assert_eq!(sent_frames(&server, 6666), (1..=5).collect::<Vec<_>>());
+        assert!(server.records().migrations.is_empty());
+    }
+
+    #[test]
+    fn test_net_worker_padding() {
+        let auth = MockAuth {
+            capabilities: Capabilities::PADDING.bits(),
+            ..MockAuth::default()
+        };
+        let server = MockServer::start_with_auth(1, auth).unwrap();
+        let config = WorkerConfig {
+            padding: PADDING_BUCKETS.to_vec(),
+            ..WorkerConfig::default()
+        };
+        let chan = NetChan::new();
+        let mut worker = NetWorker::with_config(
+            server.addr(),
+            6666,
+            "room",
+            "player",
+            "",
+            chan.clone(),
+            config,
+        )
+        .unwrap();
+        worker.start().unwrap();
+        drive(&mut worker, || chan.start_info().is_some());
+
+        // relayed back to us by the server, padding and all
+        let sent: Vec<Command> = (0..10).map(|idx| Command::Aaa(idx, idx)).collect();
+        for frame in 1..=10 {
+            chan.send_input(frame, &sent[..frame as usize], &[])
+                .unwrap();
+        }
+        let mut commands = Vec::new();
+        let mut states = BTreeMap::new();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while commands.len() < 55 {
+            assert!(Instant::now() < deadline, "timeout");
+            let current = worker.current();
+            let until = SystemTime::now() + Duration::from_millis(KCP_INTERVAL);
+            worker.tick(current, until).unwrap();
+            chan.recv_output(&mut commands, &mut states).unwrap();
+        }
+        let mut idx = 0;
+        for frame in 1..=10 {
+            for command in sent[..frame as usize].iter() {
+                assert_eq!(commands[idx].frame, frame);
+                assert_eq!(&commands[idx].command, command);
+                idx += 1;
+            }
+        }
+        assert!(worker.output.stats.padding_bytes > 0);
+        assert_eq!(worker.output.stats.undecodable_packets, 0);
+    }
+
+    #[test]
+    fn test_net_worker_capabilities() {
+        let session = |enabled: u64| {
+            let auth = MockAuth {
+                capabilities: enabled,
+                ..MockAuth::default()
+            };
+            #[cfg(feature = "dictionary-compression")]
+            let dictionary = {
+                let path = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+                    .join("fixtures")
+                    .join("dictionary")
+                    .join("commands.dict");
+                Dictionary::new(std::fs::read(path).unwrap())
+            };
+            #[cfg(feature = "dictionary-compression")]
+            let auth = MockAuth {
+                dictionary_id: dictionary.id(),
+                ..auth
+            };
+            let server = MockServer::start_with_auth(1, auth).unwrap();
+            let config = WorkerConfig {
+                padding: PADDING_BUCKETS.to_vec(),
+                #[cfg(feature = "dictionary-compression")]
+                dictionary: Some(dictionary),
+                ..WorkerConfig::default()
+            };
+            let chan = NetChan::new();
+            let mut worker = NetWorker::with_config(
+                server.addr(),
+                6666,
+                "room",
+                "player",
+                "",
+                chan.clone(),
+                config,
+            )
+            .unwrap();
+            worker.start().unwrap();
+            drive(&mut worker, || chan.start_info().is_some());
+
+            let mut commands = Vec::new();
+            let mut states = BTreeMap::new();
+            chan.send_input(1, &[Command::Aaa(1, 1)], &[]).unwrap();
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while commands.is_empty() {
+                assert!(Instant::now() < deadline, "timeout");
+                let current = worker.current();
+                let until = SystemTime::now() + Duration::from_millis(KCP_INTERVAL);
+                worker.tick(current, until).unwrap();
+                chan.recv_output(&mut commands, &mut states).unwrap();
+            }
+            let advertised = server.records().connects[0].1.capabilities;
+            assert_eq!(Capabilities::from_bits(advertised), worker.supported());
+            return (worker, chan.stats().capabilities);
+        };
+
+        // everything we support, and bits we don't know about
+        let (worker, negotiated) = session(u64::MAX);
+        assert_eq!(negotiated, worker.supported());
+        assert!(negotiated.contains(Capabilities::PADDING));
+        assert!(worker.output.stats.padding_bytes > 0);
+        assert_eq!(worker.cmd_encoder.version(), CommandVersion::LATEST);
+        assert_eq!(worker.cmd_decoder.version(), CommandVersion::LATEST);
+        #[cfg(feature = "dictionary-compression")]
+        assert!(worker.packing);
+
+        // padding only
+        let (worker, negotiated) = session(Capabilities::PADDING.bits() | 1 << 40);
+        assert_eq!(negotiated, Capabilities::PADDING);
+        assert!(worker.output.stats.padding_bytes > 0);
+        assert_eq!(worker.cmd_decoder.version(), CommandVersion::V1);
+        #[cfg(feature = "dictionary-compression")]
+        assert!(!worker.packing);
+
+        // a legacy server, nothing optional is sent
+        let (worker, negotiated) = session(0);
+        assert!(negotiated.is_empty());
+        assert_eq!(worker.output.stats.padding_bytes, 0);
+        #[cfg(feature = "dictionary-compression")]
+        assert!(!worker.packing);
+    }
+
+    #[test]
+    #[cfg(feature = "dictionary-compression")]
+    fn test_net_worker_dictionary() {
+        let path = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("fixtures")
+            .join("dictionary")
+            .join("commands.dict");
+        let dictionary = Dictionary::new(std::fs::read(path).unwrap());
+        let session = |dictionary_id: u64| {
+            let auth = MockAuth {
+                dictionary_id,
+                capabilities: Capabilities::COMPRESSION.bits(),
+                ..MockAuth::default()
+            };
+            let server = MockServer::start_with_auth(1, auth).unwrap();
+            let config = WorkerConfig {
+                dictionary: Some(dictionary.clone()),
+                ..WorkerConfig::default()
+            };
+            let chan = NetChan::new();
+            let mut worker = NetWorker::with_config(
+                server.addr(),
+                6666,
+                "room",
+                "player",
+                "",
+                chan.clone(),
+                config,
+            )
+            .unwrap();
+            worker.start().unwrap();
+            drive(&mut worker, || chan.start_info().is_some());
+
+            // relayed back to us by the server
+            let sent: Vec<Command> = (0..6).map(|idx| Command::Aaa(100 + idx, -100)).collect();
+            for frame in 1..=5 {
+                chan.send_input(frame, &sent, &[]).unwrap();
+            }
+            let mut commands = Vec::new();
+            let mut states = BTreeMap::new();
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while commands.len() < 5 * sent.len() {
+                assert!(Instant::now() < deadline, "timeout");
+                let current = worker.current();
+                let until = SystemTime::now() + Duration::from_millis(KCP_INTERVAL);
+                worker.tick(current, until).unwrap();
+                chan.recv_output(&mut commands, &mut states).unwrap();
+            }
+            for (idx, command) in commands.iter().enumerate() {
+                assert_eq!(command.frame, 1 + idx as u32 / 6);
+                assert_eq!(command.command, sent[idx % 6]);
+            }
+            assert_eq!(sent_frames(&server, 6666), (1..=5).collect::<Vec<_>>());
+            return worker.packing;
+        };
+
+        assert!(session(dictionary.id()));
+        // the server has another dictionary or none, nothing is compressed
+        assert!(!session(dictionary.id() ^ 1));
+        assert!(!session(0));
+    }
+
+    fn sent_frames(server: &MockServer, conv: u32) -> Vec<u32> {
+        let records = server.records();
+        return records
+            .commands
+            .iter()
+            .filter(|(sender, _)| *sender == conv)
+            .map(|(_, frame)| *frame)
+            .collect();
+    }
+
+    #[test]
+    fn test_net_worker_rebind() {
+        let server = MockServer::start(1).unwrap();
+        // the network in front of the server, the client addresses in `down`
+        // lost their interface and nothing reaches or leaves them anymore
+        let front = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let back = UdpSocket::bind("127.0.0.1:0").unwrap();
+        front.set_nonblocking(true).unwrap();
+        back.set_nonblocking(true).unwrap();
+        let addr = front.local_addr().unwrap();
+        let down = Arc::new(Mutex::new(Vec::<SocketAddr>::new()));
+        let closed = Arc::new(AtomicBool::new(false));
+        let network = {
+            let (down, closed, server_addr) = (down.clone(), closed.clone(), server.addr());
+            thread::spawn(move || {
+                let mut datagram = vec![0; KCP_MAX_PACKET];
+                let mut client = None;
+                while !closed.load(Ordering::Relaxed) {
+                    let mut idle = true;
+                    if let Ok((len, from)) = front.recv_from(&mut datagram) {
+                        idle = false;
+                        if !down.lock().unwrap().contains(&from) {
+                            client = Some(from);
+                            let _ = back.send_to(&datagram[..len], server_addr);
+                        }
+                    }
+                    if let Ok((len, _)) = back.recv_from(&mut datagram) {
+                        idle = false;
+                        match client {
+                            Some(client) if !down.lock().unwrap().contains(&client) => {
+                                let _ = front.send_to(&datagram[..len], client);
+                            }
+                            _ => {}
+                        };
+                    }
+                    if idle {
+                        thread::sleep(Duration::from_millis(1));
+                    }
+                }
+            })
+        };
+
+        let chan = NetChan::new();
+        let config = WorkerConfig {
+            rebind: Some(RebindConfig {
+                attempts: 2,
+                cooldown: 500,
+                silence: 300,
+            }),
+            ..WorkerConfig::default()
+        };
+        let mut worker =
+            NetWorker::with_config(addr, 6666, "room", "player", "", chan.clone(), config).unwrap();
+        worker.start().unwrap();
+        drive(&mut worker, || chan.start_info().is_some());
+        // the echoed commands keep the socket from going silent
+        let mut frame = 0;
+        let mut tick = |worker: &mut NetWorker| {
+            frame += 1;
+            chan.send_input(frame, &[Command::Aaa(1, 1)], &[]).unwrap();
+            let current = worker.current();
+            let until = SystemTime::now() + Duration::from_millis(KCP_INTERVAL);
+            worker.tick(current, until).unwrap();
+            return frame;
+        };
+        for _ in 0..50 {
+            tick(&mut worker);
+        }
+        assert_eq!(worker.output.stats.rebinds, 0);
+
+        let old = worker.kcp.net().local_addr();
+        down.lock().unwrap().push(old);
+        let swapped_at = tick(&mut worker);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !sent_frames(&server, 6666).contains(&(swapped_at + 20)) {
+            assert!(Instant::now() < deadline, "timeout");
+            tick(&mut worker);
+        }
+        // same conv and kcp session, nothing sent before the swap was lost
+        assert_eq!(worker.output.stats.rebinds, 1);
+        assert_ne!(worker.kcp.net().local_addr(), old);
+        assert_eq!(worker.state, NetPlayerState::Running);
+        let frames = sent_frames(&server, 6666);
+        assert_eq!(frames, (1..=frames.len() as u32).collect::<Vec<_>>());
+        assert_eq!(worker.summarize().counter("rebinds"), Some(1));
+
+        closed.store(true, Ordering::Relaxed);
+        network.join().unwrap();
+    }
+
+    #[test]
+    fn test_net_worker_decision_log() {
+        let server = MockServer::start(1).unwrap();
+        let chan = NetChan::new();
+        let config = WorkerConfig {
+            decision_log: 1000,
+            ..WorkerConfig::default()
+        };
+        let mut worker = NetWorker::with_config(
+            server.addr(),
+            6666,
+            "room",
+            "player",
+            "",
+            chan.clone(),
+            config,
+        )
+        .unwrap();
+        worker.start().unwrap();
+        drive(&mut worker, || chan.start_info().is_some());
+        let first = chan.start_info().unwrap().first_frame;
+
+        chan.send_input(first, &[Command::Aaa(1, 1)], &[1; 8])
+            .unwrap();
+        chan.send_input(first + 1, &[], &[2; 8]).unwrap();
+        let current = worker.current();
+        worker.tick(current, SystemTime::now()).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while worker.output.stats.last_acked_frame != Some(first + 1) {
+            assert!(Instant::now() < deadline, "timeout");
+            let current = worker.current();
+            let until = SystemTime::now() + Duration::from_millis(KCP_INTERVAL);
+            worker.tick(current, until).unwrap();
+        }
+        chan.send_input(first + 1, &[], &[2; 8]).unwrap();
+        let current = worker.current();
+        let err = worker.tick(current, SystemTime::now()).unwrap_err();
+        worker.begin_finish(err, false);
+
+        // what telemetry gets
+        let mut bytes = Vec::new();
+        chan.finish_info()
+            .unwrap()
+            .decisions
+            .unwrap()
+            .encode(&mut bytes);
+        let log = DecisionLog::decode(&bytes).unwrap();
+        assert!(log.len() <= DECISION_LOG_CAP);
+        assert!(log.error.as_deref().unwrap().contains("invalid frame"));
+        let records: Vec<_> = log.records().copied().collect();
+        assert!(records.windows(2).all(|w| w[1].tick == w[0].tick + 1));
+
+        // the inputs, the echo, the failure
+        let sent = records.iter().rposition(|r| r.inputs == 2).unwrap();
+        let (last, before) = records[sent..].split_last().unwrap();
+        assert_eq!(last.inputs, 1);
+        assert_eq!(last.state, NetPlayerState::Running);
+        assert!(last.failed);
+        assert!(before
+            .iter()
+            .all(|r| !r.failed && r.state == NetPlayerState::Running));
+        assert!(before.iter().map(|r| r.bytes_sent).sum::<u32>() > 0);
+        let echoed: u16 = before.iter().map(|r| r.packets(NetType::Command)).sum();
+        assert_eq!(echoed, 2);
+
+        // nothing kept without it
+        let worker = NetWorker::new(server.addr(), 7777, "room", "other", "", NetChan::new());
+        assert!(worker.unwrap().decisions.is_none());
+    }
+
+    #[test]
+    fn test_net_worker_crash_resume() {
+        let auth = MockAuth {
+            resume: true,
+            ..MockAuth::default()
+        };
+        let server = MockServer::start_with_auth(1, auth).unwrap();
+        let chan = NetChan::new();
+        let handle = GameHandle::new(6666, chan.clone());
+        let mut worker = NetWorker::new(
+            server.addr(),
+            6666,
+            "room",
+            "player",
+            "secret",
+            chan.clone(),
+        )
+        .unwrap();
+        worker.start().unwrap();
+        drive(&mut worker, || chan.start_info().is_some());
+        for frame in 1..=5 {
+            handle
+                .send_input(frame, &[Command::Aaa(frame as i32, 0)], &[])
+                .unwrap();
+        }
+        drive(&mut worker, || {
+            let state = handle.session_state().unwrap();
+            return state.last_frame == 5 && sent_frames(&server, 6666).len() == 5;
+        });
+        let bytes = handle.session_state().unwrap().to_bytes();
+        drop(worker);
+        drop(handle);
+
+        // relaunched with nothing but the persisted bytes
+        let state = SessionState::from_bytes(&bytes).unwrap();
+        assert_eq!(state.addr, server.addr());
+        assert_eq!(state.conv, 6666);
+        assert_eq!(state.last_frame, 5);
+        assert!(state.can_resume());
+        let client = Client::resume(&state, WorkerConfig::default()).unwrap();
+        let handle = client.handle();
+        assert!(handle
+            .wait_for_start(Duration::from_secs(5))
+            .unwrap()
+            .is_some());
+        for frame in 6..=8 {
+            handle
+                .send_input(frame, &[Command::Aaa(frame as i32, 0)], &[])
+                .unwrap();
+        }
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while sent_frames(&server, 6666).len() < 8 && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(KCP_INTERVAL));
+        }
+        assert_eq!(sent_frames(&server, 6666), (1..=8).collect::<Vec<_>>());
+
+        // the token stands in for the password, which was never persisted
+        let records = server.records();
+        let (_, connect) = records.connects.last().unwrap();
+        assert_eq!(connect.resume_token, state.resume_token);
+        assert!(connect.password.is_empty());
+        assert_eq!(
+            client.session_state().unwrap().resume_token,
+            state.resume_token
+        );
+        client.disconnect();
+
+        let mut fresh = state.clone();
+        fresh.resume_token.clear();
+        assert!(matches!(
+            Client::resume(&fresh, WorkerConfig::default()),
+            Err(ClientError::Config(ConfigError::InvalidField {
+                field: "resume_token",
+                ..
+            }))
+        ));
+    }
+
+    #[test]
+    fn test_net_worker_controls() {
+        let server = MockServer::start(1).unwrap();
+        let chan = NetChan::new();
+        let handle = GameHandle::new(6666, chan.clone());
+        let mut worker = NetWorker::new(
+            server.addr(),
+            6666,
+            "room",
+            "player",
+            "secret",
+            chan.clone(),
+        )
+        .unwrap();
+        worker.start().unwrap();
+        drive(&mut worker, || chan.start_info().is_some());
+
+        for frame in 1..=100 {
+            handle
+                .send_input(frame, &[Command::Aaa(frame as i32, 0)], &[])
+                .unwrap();
+        }
+        handle
+            .send_control(ControlMsg::Presence(Presence::Paused))
+            .unwrap();
+        handle.send_control(ControlMsg::Abort).unwrap();
+        let current = worker.current();
+        let until = SystemTime::now() + Duration::from_millis(KCP_INTERVAL);
+        let err = worker.tick(current, until).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<KCPError>(),
+            Some(KCPError::GameOver)
+        ));
+        // handled in order, none of the inputs was taken
+        assert_eq!(chan.presence(), Presence::Paused);
+        assert_eq!(worker.frame, 0);
+        assert_eq!(handle.cancel_pending_inputs(1).cancelled, 100);
+        assert!(sent_frames(&server, 6666).is_empty());
+    }
+
+    #[test]
+    fn test_net_worker_bandwidth() {
+        let server = MockServer::start(1).unwrap();
+        let chan = NetChan::new();
+        let handle = GameHandle::new(6666, chan.clone());
+        let mut worker = NetWorker::new(
+            server.addr(),
+            6666,
+            "room",
+            "player",
+            "secret",
+            chan.clone(),
+        )
+        .unwrap();
+        worker.start().unwrap();
+        drive(&mut worker, || chan.start_info().is_some());
+        let before = worker.output.stats.bandwidth.sent_total;
+
+        let mut ce = CommandEncoder::new(0);
+        ce.set_conv(6666);
+        let mut payload = 0;
+        for frame in 1..=120 {
+            let commands = [Command::Aaa(frame as i32, 0)];
+            handle.send_input(frame, &commands, &[1; 8]).unwrap();
+            ce.commands().extend_from_slice(&commands);
+            ce.hash().extend_from_slice(&[1; 8]);
+            ce.encode(frame).unwrap();
+            payload += ce.hash_bytes().len() + ce.command_bytes().len();
+            let current = worker.current();
+            let until = SystemTime::now() + Duration::from_millis(KCP_INTERVAL);
+            worker.tick(current, until).unwrap();
+        }
+        drive(&mut worker, || sent_frames(&server, 6666).len() == 120);
+
+        let current = worker.current();
+        let bandwidth = worker.kcp.bandwidth(current);
+        assert!(bandwidth.recv_total.bytes > 0);
+        assert_eq!(
+            worker.output.stats.bandwidth.recv_total,
+            bandwidth.recv_total
+        );
+        let sent = (bandwidth.sent_total.bytes - before.bytes) as usize;
+        // a header per segment at least, acks of the relayed frames and the
+        // odd retransmit at most
+        let segments = 120 * KCP_FRAME_SEGMENTS as usize;
+        assert!(sent >= payload + segments * KCP_OVERHEAD);
+        assert!(sent <= payload + 3 * segments * KCP_OVERHEAD);
+
+        // over loopback, a few intervals at most
+        let latency = worker.kcp.ack_latency();
+        assert!(latency.samples >= segments.min(ACK_LATENCY_SAMPLES));
+        assert!(latency.p50 <= latency.p95 && latency.p95 <= latency.max);
+        assert!(latency.max < 1000, "{:?}", latency);
+        assert_eq!(worker.output.stats.ack_latency.samples, latency.samples);
+    }
+
+    // steps through the stop grace after game_over()
+    fn stop(worker: &mut NetWorker) -> Error {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            assert!(Instant::now() < deadline, "timeout");
+            if let Err(err) = worker.step() {
+                return err;
+            }
+        }
+    }
+
+    #[test]
+    fn test_net_worker_session_summary() {
+        let server = MockServer::start(1).unwrap();
+        let chan = NetChan::new();
+        let handle = GameHandle::new(6666, chan.clone());
+        let mut worker =
+            NetWorker::new(server.addr(), 6666, "room", "player", "", chan.clone()).unwrap();
+        worker.start().unwrap();
+        drive(&mut worker, || chan.start_info().is_some());
+        for frame in 1..=30 {
+            handle
+                .send_input(frame, &[Command::Aaa(frame as i32, 0)], &[1; 8])
+                .unwrap();
+            worker.step().unwrap();
+        }
+        drive(&mut worker, || sent_frames(&server, 6666).len() == 30);
+        // every relayed frame back and acked both ways, the link goes quiet
+        let quiet = Instant::now() + Duration::from_millis(200);
+        drive(&mut worker, || Instant::now() >= quiet);
+        chan.game_over().unwrap();
+        let err = stop(&mut worker);
+        worker.finish(err, false);
+
+        let info = chan.finish_info().unwrap();
+        let summary = info.summary.unwrap();
+        assert_eq!(summary.cause, Some(NetFinishCause::GameOver));
+        let records = server.records();
+        assert_eq!(summary.frames_sent, 30);
+        assert_eq!(summary.frames_sent, sent_frames(&server, 6666).len() as u64);
+        assert_eq!(summary.frames_received, records.commands.len() as u64);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while server.records().bytes[&6666].0 != summary.bytes_up {
+            assert!(Instant::now() < deadline, "timeout");
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(server.records().bytes[&6666].1, summary.bytes_down);
+        assert!(summary.running_ms >= 200);
+        assert_eq!(summary.warnings, [0; WARNING_KINDS]);
+        assert_eq!(summary.reconnects, 0);
+        assert_eq!(summary.counter("late_commands"), Some(0));
+    }
+
+    #[test]
+    fn test_net_worker_output_limits() {
+        let addr = SocketAddr::from(([138, 128, 196, 233], 33303));
+        let config = WorkerConfig {
+            output_limits: OutputLimits {
+                max_commands: 0,
+                ..OutputLimits::default()
+            },
+            ..WorkerConfig::default()
+        };
+        let err = NetWorker::with_config(addr, 6666, "", "", "", NetChan::new(), config);
+        assert!(matches!(
+            err.err().unwrap().downcast::<ConfigError>().unwrap(),
+            ConfigError::InvalidField {
+                field: "output_limits.max_commands",
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_net_worker_stop_grace() {
+        let addr = SocketAddr::from(([138, 128, 196, 233], 33303));
+        let config = WorkerConfig {
+            stop_grace: UPDATE_TIMEOUT * 1000,
+            ..WorkerConfig::default()
+        };
+        let err = NetWorker::with_config(addr, 6666, "", "", "", NetChan::new(), config);
+        assert!(matches!(
+            err.err().unwrap().downcast::<ConfigError>().unwrap(),
+            ConfigError::InvalidField {
+                field: "stop_grace",
+                ..
+            }
+        ));
+
+        let config = WorkerConfig {
+            stop_grace: 500,
+            ..WorkerConfig::default()
+        };
+        let chan = NetChan::new();
+        let mut worker =
+            NetWorker::with_config(addr, 6666, "", "", "", chan.clone(), config).unwrap();
+        fake_wall(&mut worker);
+        worker.state = NetPlayerState::Running;
+        assert_eq!(worker.stopped_at, u64::MAX);
+        step_wall(0, 1000);
+        chan.game_over().unwrap();
+        let stopped = SystemTime::now();
+        worker.step().unwrap();
+        assert_eq!(worker.state, NetPlayerState::Stopped);
+        assert!((1000..1100).contains(&worker.stopped_at));
+        let deadline = chan.stats().stopping_deadline.unwrap();
+        assert!(deadline >= stopped + Duration::from_millis(500));
+        assert!(deadline < stopped + Duration::from_millis(600));
+
+        // still acking the server, the Finish goes out once the grace is over
+        step_wall(0, 400);
+        worker.step().unwrap();
+        assert_eq!(worker.state, NetPlayerState::Stopped);
+        step_wall(0, 100);
+        let err = worker.step().unwrap_err();
+        assert_eq!(err.downcast::<KCPError>().unwrap().to_string(), "game over");
+    }
+
+    thread_local! {
+        static MANUAL_NOW: Cell<Option<Instant>> = Cell::new(None);
+    }
+
+    // stands still until advanced
+    fn manual_clock() -> Instant {
+        return MANUAL_NOW.with(|now| {
+            if now.get().is_none() {
+                now.set(Some(Instant::now()));
+            }
+            return now.get().unwrap();
+        });
+    }
+
+    fn advance(ms: u64) {
+        MANUAL_NOW.with(|now| now.set(Some(manual_clock() + Duration::from_millis(ms))));
+    }
+
+    #[test]
+    fn test_net_worker_connect_blocking() {
+        // accepted, then waiting for the other player
+        let auth = MockAuth {
+            resume: true,
+            ..MockAuth::default()
+        };
+        let server = MockServer::start_with_auth(2, auth).unwrap();
+        let chan = NetChan::new();
+        let mut worker =
+            NetWorker::new(server.addr(), 6666, "room", "player", "", chan.clone()).unwrap();
+        let acceptance = worker.connect_blocking(Duration::from_secs(5)).unwrap();
+        assert_eq!(acceptance.conv, 6666);
+        assert!(!acceptance.resume_token.is_empty());
+        assert_eq!(acceptance.roster, vec![]);
+        assert_eq!(worker.state, NetPlayerState::Waiting);
+        assert_eq!(server.records().connects.len(), 1);
+        assert!(worker.connect_blocking(Duration::from_secs(5)).is_err());
+
+        // the tick loop takes over from Waiting
+        let mut other =
+            NetWorker::new(server.addr(), 7777, "room", "other", "", NetChan::new()).unwrap();
+        other.connect_blocking(Duration::from_secs(5)).unwrap();
+        drive(&mut worker, || chan.start_info().is_some());
+        assert_eq!(server.records().connects.len(), 2);
+
+        // refused with a Finish
+        let auth = MockAuth {
+            password: Some("secret".to_string()),
+            ..MockAuth::default()
+        };
+        let server = MockServer::start_with_auth(1, auth).unwrap();
+        let mut worker =
+            NetWorker::new(server.addr(), 6666, "room", "player", "", NetChan::new()).unwrap();
+        let err = worker.connect_blocking(Duration::from_secs(5)).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<KCPError>(),
+            Some(KCPError::RemoteFinished(NetFinishCause::AuthFailed))
+        ));
+        assert_eq!(worker.state, NetPlayerState::Initing);
+
+        // the Accept never comes
+        let auth = MockAuth {
+            silent: true,
+            ..MockAuth::default()
+        };
+        let server = MockServer::start_with_auth(1, auth).unwrap();
+        let mut worker =
+            NetWorker::new(server.addr(), 6666, "room", "player", "", NetChan::new()).unwrap();
+        let started = Instant::now();
+        let err = worker
+            .connect_blocking(Duration::from_millis(100))
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<KCPError>(),
+            Some(KCPError::Timeout)
+        ));
+        assert!(started.elapsed() < Duration::from_millis(100 + 10 * KCP_INTERVAL));
+        assert_eq!(worker.state, NetPlayerState::Initing);
+
+        // the wall clock stepped back midway doesn't stretch it
+        let mut worker =
+            NetWorker::new(server.addr(), 6666, "room", "player", "", NetChan::new()).unwrap();
+        fake_wall(&mut worker);
+        step_wall(3, -3600 * 1000);
+        let started = Instant::now();
+        let err = worker
+            .connect_blocking(Duration::from_millis(100))
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<KCPError>(),
+            Some(KCPError::Timeout)
+        ));
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        assert!(started.elapsed() < Duration::from_millis(100 + 10 * KCP_INTERVAL));
+        assert_eq!(worker.output.stats.clock_anomalies, 1);
+    }
+
+    #[test]
+    fn test_net_worker_timeline() {
+        let server = MockServer::start(1).unwrap();
+        let chan = NetChan::new();
+        let mut worker =
+            NetWorker::new(server.addr(), 6666, "room", "player", "", chan.clone()).unwrap();
+        worker.clock = manual_clock;
+        worker.created = manual_clock();
+        worker.state_since = manual_clock();
+
+        advance(10);
+        worker.reconnect().unwrap();
+        advance(20);
+        worker.start().unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while worker.state == NetPlayerState::Initing {
+            assert!(Instant::now() < deadline, "timeout");
+            worker.step().unwrap();
+        }
+        let running_at = match worker.state {
+            NetPlayerState::Waiting => {
+                advance(20);
+                drive(&mut worker, || chan.start_info().is_some());
+                50
+            }
+            _ => 30,
+        };
+        advance(40);
+        worker.step().unwrap();
+        assert_eq!(
+            worker.output.stats.elapsed_in_state(),
+            Duration::from_millis(40)
+        );
+        advance(5);
+        chan.game_over().unwrap();
+        let err = stop(&mut worker);
+        worker.finish(err, false);
+
+        let expected = vec![
+            (TimelineEvent::State(NetPlayerState::Initing), 0),
+            (TimelineEvent::Reconnect, 10),
+            (TimelineEvent::State(NetPlayerState::Waiting), 30),
+            (TimelineEvent::State(NetPlayerState::Running), running_at),
+            (
+                TimelineEvent::State(NetPlayerState::Stopped),
+                running_at + 45,
+            ),
+        ];
+        let timeline = worker.output.stats.timeline;
+        assert_eq!(timeline.iter().collect::<Vec<_>>(), expected);
+        let summary = chan.finish_info().unwrap().summary.unwrap();
+        assert_eq!(summary.timeline, timeline);
+        assert_eq!(summary.reconnects, 1);
+        assert_eq!(summary.initing_ms, 30);
+        assert_eq!(summary.running_ms, 45);
+    }
+
+    thread_local! {
+        static SLOW_NOW: Cell<Option<Instant>> = Cell::new(None);
+    }
+
+    // every read is 1ms later, the clock is read once per packet
+    fn slow_clock() -> Instant {
+        return SLOW_NOW.with(|now| {
+            let next = now.get().unwrap_or_else(Instant::now) + Duration::from_millis(1);
+            now.set(Some(next));
+            return next;
+        });
+    }
+
+    thread_local! {
+        // ms the wall clock is off, and after how many more reads it steps
+        // by how many
+        static WALL: Cell<(i64, u32, i64)> = Cell::new((0, 0, 0));
+    }
+
+    fn stepped_wall() -> SystemTime {
+        let offset = WALL.with(|wall| {
+            let (mut offset, reads, step) = wall.get();
+            if reads == 1 {
+                offset += step;
+            }
+            wall.set((offset, reads.saturating_sub(1), step));
+            return offset;
+        });
+        let now = SystemTime::now();
+        return match offset >= 0 {
+            true => now + Duration::from_millis(offset as u64),
+            false => now - Duration::from_millis(-offset as u64),
+        };
+    }
+
+    // steps the wall clock by `ms` on the `after`th read from now, at once
+    // for 0
+    fn step_wall(after: u32, ms: i64) {
+        WALL.with(|wall| {
+            let (offset, _, _) = wall.get();
+            match after {
+                0 => wall.set((offset + ms, 0, 0)),
+                _ => wall.set((offset, after, ms)),
+            };
+        });
+    }
+
+    // the worker clock on stepped_wall(), counting from now
+    fn fake_wall(worker: &mut NetWorker) {
+        WALL.with(|wall| wall.set((0, 0, 0)));
+        worker.wall = WallClock::new(stepped_wall);
+        worker.wall.reset();
+    }
+
+    #[test]
+    fn test_net_worker_clock_steps() {
+        let hour = 3600 * 1000;
+
+        // connecting: the reach timeout still fires, after real time
+        let blackhole = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let config = WorkerConfig {
+            reach_timeout: 200,
+            finish_timeout: 300,
+            ..WorkerConfig::default()
+        };
+        let chan = NetChan::new();
+        let mut worker = NetWorker::with_config(
+            blackhole.local_addr().unwrap(),
+            6666,
+            "room",
+            "player",
+            "",
+            chan.clone(),
+            config,
+        )
+        .unwrap();
+        fake_wall(&mut worker);
+        let begin = Instant::now();
+        worker.start().unwrap();
+        step_wall(0, -hour);
+        let err = loop {
+            assert!(begin.elapsed() < Duration::from_secs(5), "timeout");
+            if let Err(err) = worker.step() {
+                break err;
+            }
+            thread::sleep(Duration::from_millis(KCP_INTERVAL));
+        };
+        assert!(matches!(
+            err.downcast_ref::<KCPError>(),
+            Some(KCPError::Unreachable)
+        ));
+        assert!(begin.elapsed() >= Duration::from_millis(200));
+        assert_eq!(worker.output.stats.clock_anomalies, 1);
+
+        // draining the Connect nobody acks: stepped back midway, it still
+        // ends after finish_timeout
+        step_wall(3, -hour);
+        let begin = Instant::now();
+        worker.finish(err, true);
+        assert!(begin.elapsed() >= Duration::from_millis(300));
+        assert!(begin.elapsed() < Duration::from_secs(2));
+        assert_eq!(worker.output.stats.clock_anomalies, 2);
+        assert!(!chan.flushed());
+
+        // mid-match, stepped either way, no timeout and nothing lost
+        let server = MockServer::start(1).unwrap();
+        let chan = NetChan::new();
+        let handle = GameHandle::new(6666, chan.clone());
+        let mut worker =
+            NetWorker::new(server.addr(), 6666, "room", "player", "", chan.clone()).unwrap();
+        fake_wall(&mut worker);
+        worker.start().unwrap();
+        drive(&mut worker, || chan.start_info().is_some());
+        for (frame, step) in [(1, -hour), (2, hour), (3, -1000)] {
+            step_wall(0, step);
+            handle
+                .send_input(frame, &[Command::Aaa(frame as i32, 0)], &[])
+                .unwrap();
+            let begin = Instant::now();
+            while sent_frames(&server, 6666).len() < frame as usize {
+                assert!(begin.elapsed() < Duration::from_secs(5), "timeout");
+                worker.step().unwrap();
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+        assert_eq!(worker.output.stats.clock_anomalies, 3);
+        assert_eq!(worker.state, NetPlayerState::Running);
+    }
+
+    #[test]
+    fn test_net_worker_tick_budget() {
+        let server = MockServer::start(1).unwrap();
+        let chan = NetChan::new();
+        let handle = GameHandle::new(6666, chan.clone());
+        let config = WorkerConfig {
+            tick_budget: 5,
+            ..WorkerConfig::default()
+        };
+        let mut worker = NetWorker::with_config(
+            server.addr(),
+            6666,
+            "room",
+            "player",
+            "secret",
+            chan.clone(),
+            config,
+        )
+        .unwrap();
+        worker.start().unwrap();
+        drive(&mut worker, || chan.start_info().is_some());
+
+        // a backlog of relayed frames queued in kcp, as after a reconnect
+        for frame in 1..=40 {
+            handle
+                .send_input(frame, &[Command::Aaa(frame as i32, 0)], &[])
+                .unwrap();
+        }
+        worker.handle_input().unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while sent_frames(&server, 6666).len() < 40 || worker.kcp.waitsnd() > 0 {
+            assert!(Instant::now() < deadline, "timeout");
+            let current = worker.current();
+            worker.kcp.update_kcp(current);
+            let until = SystemTime::now() + Duration::from_millis(KCP_INTERVAL);
+            worker.kcp.update_udp(until).unwrap();
+        }
+        for _ in 0..20 {
+            let current = worker.current();
+            worker.kcp.update_kcp(current);
+            let until = SystemTime::now() + Duration::from_millis(KCP_INTERVAL);
+            worker.kcp.update_udp(until).unwrap();
+        }
+
+        worker.clock = slow_clock;
+        let mut frames = Vec::new();
+        let mut calls = 0;
+        while frames.len() < 40 {
+            assert!(calls < 40, "not all frames delivered");
+            let start = slow_clock();
+            worker.handle_output(0).unwrap();
+            // the budget plus the packet that used it up
+            assert!(slow_clock() - start <= Duration::from_millis(5 + 2));
+            frames.extend(worker.output.commands.drain(..).map(|batch| batch.frame));
+            calls += 1;
+        }
+        assert!(calls >= 8);
+        assert!(worker.output.stats.budget_overruns >= 7);
+        assert_eq!(frames, (1..=40).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_net_worker_one_way() {
+        let chan = NetChan::new();
+        let config = WorkerConfig {
+            offline: OfflineConfig {
+                start_delay: 0,
+                latency: 0,
+            },
+            ..WorkerConfig::default()
+        };
+        let mut worker = NetWorker::offline(chan.clone(), config).unwrap();
+        worker.start().unwrap();
+        let mut current = 0;
+        let mut tick = |worker: &mut NetWorker| {
+            current += 100;
+            worker.kcp.update_kcp(current);
+            return worker.tick(current, SystemTime::now());
+        };
+        while chan.start_info().is_none() {
+            tick(&mut worker).unwrap();
+        }
+        match &mut worker.kcp {
+            Transport::Null(server) => server.mute(),
+            Transport::Kcp(_) => unreachable!(),
+        };
+
+        // frames keep going out, nothing comes back
+        let mut frame = 0;
+        let mut warned_at = None;
+        let err = loop {
+            frame += 1;
+            chan.send_input(frame, &[Command::Aaa(1, 1)], &[]).unwrap();
+            if let Err(err) = tick(&mut worker) {
+                break err;
+            }
+            let mut events = Vec::new();
+            chan.recv_events(&mut events);
+            for event in events {
+                if let NetEvent::Warning(NetWarning::NoInboundTraffic(ms)) = event {
+                    assert!(warned_at.is_none());
+                    assert!(ms >= ONE_WAY_WARNING);
+                    warned_at = Some(frame);
+                }
+            }
+            if warned_at == Some(frame) {
+                // a failure of the link from here on is told apart
+                let err = worker.classify(KCPError::Timeout.into());
+                assert!(matches!(
+                    err.downcast_ref::<KCPError>(),
+                    Some(KCPError::NoInboundTraffic)
+                ));
+                let err = worker.classify(KCPError::InvalidFrame.into());
+                assert!(matches!(
+                    err.downcast_ref::<KCPError>(),
+                    Some(KCPError::InvalidFrame)
+                ));
+            }
+        };
+        // well before the hard timeout
+        assert!(warned_at.unwrap() < frame / 2);
+        assert!(matches!(
+            err.downcast_ref::<KCPError>(),
+            Some(KCPError::NoInboundTraffic)
+        ));
+        worker.begin_finish(err, false);
+        let info = chan.finish_info().unwrap();
+        assert_eq!(info.cause, NetFinishCause::NetworkBroken);
+        assert!(info.message.contains("no inbound traffic"));
+    }
+
+    #[test]
+    fn test_net_worker_accept_then_finish() {
+        // the room is torn down right after the Accept, the Finish due at
+        // `finish_at`, the events the game saw until the finish
+        let session = |finish_at: u64| {
+            let chan = NetChan::new();
+            let config = WorkerConfig {
+                // no budget left after the first packet of a tick
+                tick_budget: 0,
+                offline: OfflineConfig {
+                    start_delay: 1000,
+                    latency: 0,
+                },
+                ..WorkerConfig::default()
+            };
+            let mut worker = NetWorker::offline(chan.clone(), config).unwrap();
+            worker.start().unwrap();
+            let finish = NetMessage::finish(0, NetFinishCause::ServerError);
+            match &mut worker.kcp {
+                Transport::Null(server) => server.inject(finish_at, &finish).unwrap(),
+                Transport::Kcp(_) => unreachable!(),
+            };
+            let mut ticks = 0;
+            let err = loop {
+                let current = ticks * KCP_INTERVAL;
+                worker.kcp.update_kcp(current);
+                ticks += 1;
+                if let Err(err) = worker.tick(current, SystemTime::now()) {
+                    break err;
+                }
+            };
+            worker.begin_finish(err, false);
+            let mut events = Vec::new();
+            chan.recv_events(&mut events);
+            let cause = chan.finish_info().unwrap().cause;
+            assert_eq!(cause, NetFinishCause::ServerError);
+            return (ticks, events);
+        };
+
+        // in one buffer: a single clean finish
+        let (ticks, events) = session(0);
+        assert_eq!(ticks, 1);
+        assert!(events.is_empty(), "{:?}", events);
+
+        // in two ticks: Waiting was real and is reported first
+        let (ticks, events) = session(KCP_INTERVAL);
+        assert_eq!(ticks, 2);
+        assert!(matches!(
+            events[..],
+            [NetEvent::State {
+                conv: OFFLINE_CONV,
+                state: NetPlayerState::Waiting,
+                ..
+            }]
+        ));
+    }
+
+    #[test]
+    fn test_net_worker_recv_into() {
+        let server = MockServer::start(1).unwrap();
+        let chan = NetChan::new();
+        let mut worker =
+            NetWorker::new(server.addr(), 6666, "room", "player", "", chan.clone()).unwrap();
+        worker.start().unwrap();
+        drive(&mut worker, || chan.start_info().is_some());
+
+        let mut buffer = vec![0; KCP_MAX_PACKET];
+        assert_eq!(worker.kcp.recv_into(&mut buffer).unwrap(), None);
+
+        // relayed back to us as is, exactly KCP_MAX_PACKET long
+        let mut packet = Vec::new();
+        NetMessage::command(1, 6666).encode(&mut packet).unwrap();
+        packet.resize(KCP_MAX_PACKET, 0xaa);
+        worker.kcp.send_kcp(&packet).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let err = loop {
+            assert!(Instant::now() < deadline, "timeout");
+            let current = worker.current();
+            worker.kcp.update_kcp(current);
+            let until = SystemTime::now() + Duration::from_millis(KCP_INTERVAL);
+            worker.kcp.update_udp(until).unwrap();
+            match worker.kcp.recv_into(&mut buffer[..KCP_MAX_PACKET - 1]) {
+                Ok(None) => continue,
+                Ok(Some(len)) => panic!("{} bytes into a short buffer", len),
+                Err(err) => break err,
+            };
+        };
+        assert!(matches!(
+            err.downcast_ref::<KCPError>(),
+            Some(KCPError::Oversized(KCP_MAX_PACKET))
+        ));
+
+        // still queued, and fits exactly
+        let len = worker.kcp.recv_into(&mut buffer).unwrap();
+        assert_eq!(len, Some(KCP_MAX_PACKET));
+        assert_eq!(buffer, packet);
+        assert_eq!(worker.kcp.recv_into(&mut buffer).unwrap(), None);
+    }
+
+    #[test]
+    fn test_net_worker_timer_jitter() {
+        let addr = SocketAddr::from(([138, 128, 196, 233], 33303));
+        let worker = |conv, timer_jitter| {
+            let config = WorkerConfig {
+                timer_jitter,
+                ..WorkerConfig::default()
+            };
+            return NetWorker::with_config(
+                addr,
+                conv,
+                "room",
+                "player",
+                "",
+                NetChan::new(),
+                config,
+            )
+            .unwrap();
+        };
+        // the ticks over 10s of a simulated clock
+        let ticks = |worker: &NetWorker| {
+            let mut ticks = vec![0];
+            while *ticks.last().unwrap() < 10_000 {
+                ticks.push(worker.next_tick(*ticks.last().unwrap()));
+            }
+            return ticks;
+        };
+
+        // aligned without jitter, as before
+        let a = ticks(&worker(6666, 0));
+        let b = ticks(&worker(8888, 0));
+        assert_eq!(a, b);
+        assert!(a.iter().all(|tick| tick % KCP_INTERVAL == 0));
+
+        // apart, on the same cadence, and the same on every run
+        let a = ticks(&worker(6666, TIMER_JITTER_MAX));
+        let b = ticks(&worker(8888, TIMER_JITTER_MAX));
+        assert_eq!(a, ticks(&worker(6666, TIMER_JITTER_MAX)));
+        let (a_offset, b_offset) = (a[1] % KCP_INTERVAL, b[1] % KCP_INTERVAL);
+        assert_ne!(a_offset, b_offset);
+        for (ticks, offset) in [(&a, a_offset), (&b, b_offset)] {
+            assert!(offset < KCP_INTERVAL * TIMER_JITTER_MAX / 100);
+            for pair in ticks[1..].windows(2) {
+                assert_eq!(pair[1] - pair[0], KCP_INTERVAL);
+                assert_eq!(pair[1] % KCP_INTERVAL, offset);
+            }
+        }
+
+        let config = WorkerConfig {
+            timer_jitter: TIMER_JITTER_MAX + 1,
+            ..WorkerConfig::default()
+        };
+        let err = NetWorker::with_config(addr, 6666, "room", "player", "", NetChan::new(), config)
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err.downcast_ref::<ConfigError>(),
+            Some(ConfigError::InvalidField {
+                field: "timer_jitter",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_net_worker_early_input() {
+        let addr = SocketAddr::from(([138, 128, 196, 233], 33303));
+        let config = |early_input| WorkerConfig {
+            early_input,
+            ..WorkerConfig::default()
+        };
+
+        let chan = NetChan::new();
+        let drop = config(EarlyInputPolicy::Drop { warn: true });
+        let mut worker =
+            NetWorker::with_config(addr, 6666, "", "", "", chan.clone(), drop).unwrap();
+        chan.send_input(1, &[Command::Aaa(1, 1)], &[]).unwrap();
+        chan.send_input(2, &[], &[]).unwrap();
+        worker.handle_input().unwrap();
+        assert_eq!(worker.output.stats.dropped_inputs, 1);
+        assert_eq!(
+            worker.output.events,
+            vec![NetEvent::Warning(NetWarning::InputDropped { frame: 1 })]
+        );
+        // counted whatever the policy once stopped, without a warning
+        worker.state = NetPlayerState::Stopped;
+        chan.send_input(3, &[], &[1; 8]).unwrap();
+        worker.handle_input().unwrap();
+        assert_eq!(worker.output.stats.dropped_inputs, 2);
+        assert!(worker.output.events.is_empty());
+
+        let chan = NetChan::new();
+        let buffer = config(EarlyInputPolicy::Buffer { max_frames: 1 });
+        let mut worker =
+            NetWorker::with_config(addr, 6666, "", "", "", chan.clone(), buffer).unwrap();
+        chan.send_input(1, &[], &[]).unwrap();
+        chan.send_input(2, &[Command::Aaa(2, 2)], &[]).unwrap();
+        let err = worker.handle_input().unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<KCPError>(),
+            Some(KCPError::Unexpected)
+        ));
+
+        // sent in order right after Start
+        let server = MockServer::start(1).unwrap();
+        let chan = NetChan::new();
+        let buffer = config(EarlyInputPolicy::Buffer { max_frames: 3 });
+        let mut worker = NetWorker::with_config(
+            server.addr(),
+            6666,
+            "room",
+            "player",
+            "secret",
+            chan.clone(),
+            buffer,
+        )
+        .unwrap();
+        for frame in 1..=3 {
+            chan.send_input(frame, &[Command::Aaa(frame as i32, 0)], &[])
+                .unwrap();
+        }
+        worker.start().unwrap();
+        drive(&mut worker, || sent_frames(&server, 6666).len() == 3);
+        assert_eq!(sent_frames(&server, 6666), vec![1, 2, 3]);
+        assert_eq!(worker.frame, 3);
+        assert!(chan.start_info().is_some());
+        assert!(worker.early_inputs.is_empty());
+    }
+
+    #[test]
+    fn test_net_worker_cancel_pending_inputs() {
+        let config = WorkerConfig {
+            early_input: EarlyInputPolicy::Buffer { max_frames: 4 },
+            ..WorkerConfig::default()
+        };
+        let chan = NetChan::new();
+        let handle = GameHandle::new(6666, chan.clone());
+        let mut worker = NetWorker::with_config(
+            SocketAddr::from(([138, 128, 196, 233], 33303)),
+            6666,
+            "",
+            "",
+            "",
+            chan.clone(),
+            config,
+        )
+        .unwrap();
+        for frame in 1..=3 {
+            handle
+                .send_input(frame, &[Command::Aaa(frame as i32, 0)], &[])
+                .unwrap();
+        }
+        worker.handle_input().unwrap();
+        assert_eq!(worker.early_inputs.len(), 3);
+        handle.send_input(4, &[], &[]).unwrap();
+
+        // parked and queued frames alike, dropped by the next tick
+        let cancel = handle.cancel_pending_inputs(2);
+        assert_eq!(
+            cancel,
+            CancelResult {
+                cancelled: 3,
+                already_sent: 0,
+            }
+        );
+        assert_eq!(worker.chan.take_cancel(), Some(2));
+        assert_eq!(worker.chan.take_cancel(), None);
+        worker.cancel_early_inputs(2);
+        handle.send_input(2, &[], &[]).unwrap();
+        worker.handle_input().unwrap();
+        let frames: Vec<u32> = worker.early_inputs.iter().map(|input| input.0).collect();
+        assert_eq!(frames, vec![1, 2]);
+
+        // a cancel before the release at Start still drops them
+        assert_eq!(handle.cancel_pending_inputs(2).cancelled, 1);
+        worker.state = NetPlayerState::Running;
+        worker.release_early_inputs().unwrap();
+        assert_eq!(worker.frame, 1);
+        assert_eq!(worker.kcp.waitsnd(), KCP_FRAME_SEGMENTS);
+
+        // sent frames stay sent
+        handle.send_input(2, &[], &[]).unwrap();
+        worker.handle_input().unwrap();
+        handle.send_input(3, &[], &[]).unwrap();
+        assert_eq!(
+            handle.cancel_pending_inputs(2),
+            CancelResult {
+                cancelled: 1,
+                already_sent: 1,
+            }
+        );
+        assert_eq!(worker.chan.take_cancel(), None);
+        assert_eq!(worker.frame, 2);
+    }
+
+    #[test]
+    fn test_net_worker_self_state() {
+        let addr = SocketAddr::from(([138, 128, 196, 233], 33303));
+        let kicked = |self_state: SelfStatePolicy, state: NetPlayerState| {
+            let config = WorkerConfig {
+                self_state,
+                ..WorkerConfig::default()
+            };
+            let mut worker =
+                NetWorker::with_config(addr, 6666, "", "", "", NetChan::new(), config).unwrap();
+            fake_wall(&mut worker);
+            worker.state = NetPlayerState::Running;
+            worker.kcp_buffer.clear();
+            NetMessage::state(6666, state)
+                .encode(&mut worker.kcp_buffer)
+                .unwrap();
+            worker.handle_output_impl().unwrap();
+            return worker;
+        };
+        let stopped = NetEvent::State {
+            conv: 6666,
+            state: NetPlayerState::Stopped,
+            frame: 0,
+        };
+
+        // the default only takes the server's Stopped
+        assert_eq!(
+            WorkerConfig::default().self_state,
+            SelfStatePolicy::ApplyStopped
+        );
+        for policy in [SelfStatePolicy::Apply, SelfStatePolicy::ApplyStopped] {
+            let mut worker = kicked(policy, NetPlayerState::Stopped);
+            assert_eq!(worker.state, NetPlayerState::Stopped);
+            assert_eq!(worker.output.states[&6666], NetPlayerState::Stopped);
+            assert_eq!(worker.output.events, vec![stopped.clone()]);
+
+            // and times out once the server goes quiet
+            worker.handle_timeout().unwrap();
+            step_wall(0, (UPDATE_TIMEOUT as i64 + 1) * 1000);
+            assert!(worker.handle_timeout().is_err());
+
+            let worker = kicked(policy, NetPlayerState::Running);
+            assert_eq!(worker.state, NetPlayerState::Running);
+            assert!(worker.output.events.is_empty());
+        }
+
+        let worker = kicked(SelfStatePolicy::Ignore, NetPlayerState::Stopped);
+        assert_eq!(worker.state, NetPlayerState::Running);
+        assert!(worker.output.states.is_empty());
+        assert!(worker.output.events.is_empty());
+
+        let worker = kicked(SelfStatePolicy::Verify, NetPlayerState::Stopped);
+        assert_eq!(worker.state, NetPlayerState::Running);
+        assert!(worker.output.states.is_empty());
+        assert_eq!(
+            worker.output.events,
+            vec![NetEvent::Warning(NetWarning::StateDiverged {
+                local: NetPlayerState::Running,
+                server: NetPlayerState::Stopped,
+            })]
+        );
+        let worker = kicked(SelfStatePolicy::Verify, NetPlayerState::Background);
+        assert!(worker.output.events.is_empty());
+    }
+
+    #[test]
+    fn test_net_worker_stopped_packets() {
+        let addr = SocketAddr::from(([138, 128, 196, 233], 33303));
+        let stopped = |stopped_keepalive: bool| {
+            let config = WorkerConfig {
+                stopped_keepalive,
+                ..WorkerConfig::default()
+            };
+            let chan = NetChan::new();
+            let mut worker =
+                NetWorker::with_config(addr, 6666, "", "", "", chan.clone(), config).unwrap();
+            fake_wall(&mut worker);
+            worker.state = NetPlayerState::Running;
+            worker.set_self_state(NetPlayerState::Stopped);
+            for frame in 1..=5 {
+                relay(&mut worker, 7777, frame, &[Command::Aaa(7, 7)]);
+            }
+            worker.kcp_buffer.clear();
+            NetMessage::state(7777, NetPlayerState::Stopped)
+                .encode(&mut worker.kcp_buffer)
+                .unwrap();
+            worker.handle_output_impl().unwrap();
+            return (chan, worker);
+        };
+
+        let (chan, mut worker) = stopped(true);
+        let ignored = worker.output.stats.ignored_packets;
+        assert_eq!(ignored.count(NetType::Command), 5);
+        assert_eq!(ignored.count(NetType::State), 1);
+        assert_eq!(ignored.total(), 6);
+        assert!(ignored.bytes() > 0);
+        assert!(worker.output.commands.is_empty());
+        assert!(worker
+            .output
+            .events
+            .iter()
+            .all(|event| !matches!(event, NetEvent::State { conv: 7777, .. })));
+
+        // the server still talks to us
+        let quiet = (UPDATE_TIMEOUT as i64 + 1) * 1000;
+        step_wall(0, quiet);
+        worker.heard_at = worker.current();
+        worker.handle_timeout().unwrap();
+        step_wall(0, quiet);
+        let err = worker.handle_timeout().unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<KCPError>(),
+            Some(KCPError::Timeout)
+        ));
+        worker.begin_finish(err, false);
+        let info = chan.finish_info().unwrap();
+        assert_eq!(info.ignored_packets, ignored);
+
+        let (_, mut worker) = stopped(false);
+        step_wall(0, quiet);
+        worker.heard_at = worker.current();
+        assert!(worker.handle_timeout().is_err());
+    }
+
+    #[test]
+    fn test_net_worker_deterministic_delivery() {
+        let run = || {
+            let addr = SocketAddr::from(([138, 128, 196, 233], 33303));
+            let chan = NetChan::new();
+            let mut worker = NetWorker::new(addr, 6666, "", "", "", chan.clone()).unwrap();
+            let mut handle = GameHandle::new(6666, chan);
+            worker.state = NetPlayerState::Running;
+            let state = |worker: &mut NetWorker, conv: u32, state: NetPlayerState| {
+                worker.kcp_buffer.clear();
+                NetMessage::state(conv, state)
+                    .encode(&mut worker.kcp_buffer)
+                    .unwrap();
+                worker.handle_output_impl().unwrap();
+            };
+            relay(&mut worker, 8888, 1, &[Command::Aaa(8, 1)]);
+            relay(&mut worker, 7777, 1, &[Command::Aaa(7, 1)]);
+            state(&mut worker, 9999, NetPlayerState::Running);
+            relay(&mut worker, 7777, 2, &[Command::Aaa(7, 2)]);
+            relay(
+                &mut worker,
+                8888,
+                2,
+                &[Command::Aaa(8, 2), Command::Aaa(8, 3)],
+            );
+            state(&mut worker, 8888, NetPlayerState::Stopped);
+            relay(&mut worker, 7777, 3, &[Command::Aaa(7, 3)]);
+            worker.exchange();
+
+            let mut events = Vec::new();
+            handle.poll(&mut events);
+            events.retain(|event| !matches!(event, NetEvent::Stats(_)));
+            return events;
+        };
+
+        let events = run();
+        let kinds: Vec<_> = events
+            .iter()
+            .map(|event| match event {
+                NetEvent::Commands { frame, commands } => {
+                    let convs: Vec<_> = commands.iter().map(|command| command.conv).collect();
+                    format!("commands {} {:?}", frame, convs)
+                }
+                NetEvent::State { conv, frame, .. } => format!("state {} {}", conv, frame),
+                event => panic!("unexpected {:?}", event),
+            })
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                "commands 1 [7777, 8888]",
+                "state 9999 1",
+                "commands 2 [7777, 8888, 8888]",
+                "state 8888 2",
+                "commands 3 [7777]",
+            ]
+        );
+        for _ in 0..8 {
+            assert_eq!(format!("{:?}", run()), format!("{:?}", events));
+        }
+    }
+
+    #[test]
+    fn test_net_worker_warnings() {
+        let addr = SocketAddr::from(([138, 128, 196, 233], 33303));
+        let chan = NetChan::new();
+        let mut handle = GameHandle::new(6666, chan.clone());
+        let mut worker = NetWorker::new(addr, 6666, "", "", "", chan.clone()).unwrap();
+        worker.state = NetPlayerState::Running;
+        fake_wall(&mut worker);
+        worker.paused = Some(1);
+        let input = |worker: &mut NetWorker, frame: u32| {
+            chan.send_input(frame, &[Command::Aaa(1, 1)], &[]).unwrap();
+            worker.handle_input().unwrap();
+        };
+        let paused = |frame: u32| NetEvent::Warning(NetWarning::InputPaused { frame, paused: 1 });
+
+        relay(&mut worker, 7777, 1, &[Command::Aaa(7, 1)]);
+        input(&mut worker, 2);
+        input(&mut worker, 3);
+        worker.set_state(7777, NetPlayerState::Stopped);
+        for frame in 4..=9 {
+            input(&mut worker, frame);
+        }
+        assert_eq!(worker.output.stats.suppressed_warnings, 4);
+        worker.exchange();
+        let mut events = Vec::new();
+        handle.poll(&mut events);
+        events.retain(|event| !matches!(event, NetEvent::Stats(_)));
+        assert_eq!(
+            events,
+            vec![
+                paused(2),
+                paused(3),
+                NetEvent::Commands {
+                    frame: 1,
+                    commands: vec![CommandEx {
+                        conv: 7777,
+                        frame: 1,
+                        command: Command::Aaa(7, 1),
+                        stamps: None,
+                    }],
+                },
+                NetEvent::State {
+                    conv: 7777,
+                    state: NetPlayerState::Stopped,
+                    frame: 1,
+                },
+                paused(4),
+                paused(5),
+            ]
+        );
+
+        // a new window after WARNING_INTERVAL
+        step_wall(0, WARNING_INTERVAL as i64);
+        input(&mut worker, 10);
+        worker.exchange();
+        events.clear();
+        handle.poll(&mut events);
+        events.retain(|event| !matches!(event, NetEvent::Stats(_)));
+        assert_eq!(events, vec![paused(10)]);
+        assert_eq!(chan.stats().suppressed_warnings, 4);
+    }
+
+    #[test]
+    fn test_net_worker_first_frame() {
+        let addr = SocketAddr::from(([138, 128, 196, 233], 33303));
+        let chan = NetChan::new();
+        let mut worker = NetWorker::new(addr, 6666, "", "", "", chan.clone()).unwrap();
+        worker.state = NetPlayerState::Running;
+        assert!(chan.send_input(0, &[], &[]).is_err());
+        chan.send_input(1, &[Command::Aaa(1, 1)], &[]).unwrap();
+        worker.handle_input().unwrap();
+        assert_eq!(worker.frame, 1);
+
+        // resumed sessions continue after the last frame sent
+        let mut state = SessionState::new(addr, 6666, "room", "player");
+        state.resume_token = vec![1];
+        state.last_frame = 5;
+        let resumed = |frame: u32| {
+            let chan = NetChan::new();
+            let mut worker = NetWorker::new(addr, 6666, "", "", "", chan.clone()).unwrap();
+            worker.resume(&state).unwrap();
+            worker.state = NetPlayerState::Running;
+            chan.send_input(frame, &[], &[]).unwrap();
+            return worker.handle_input().map(|_| worker.frame);
+        };
+        let err = resumed(5).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<KCPError>(),
+            Some(KCPError::InvalidFrame)
+        ));
+        assert_eq!(resumed(6).unwrap(), 6);
+    }
+
+    #[test]
+    fn test_net_worker_start_first_frame() {
+        let addr = SocketAddr::from(([138, 128, 196, 233], 33303));
+        let start = |first_frame: u32, resumed: Option<u32>| {
+            let chan = NetChan::new();
+            let mut worker = NetWorker::new(addr, 6666, "", "", "", chan.clone()).unwrap();
+            if let Some(last_frame) = resumed {
+                let mut state = SessionState::new(addr, 6666, "room", "player");
+                state.resume_token = vec![1];
+                state.last_frame = last_frame;
+                worker.resume(&state).unwrap();
+            }
+            worker.state = NetPlayerState::Waiting;
+            worker.kcp_buffer.clear();
+            NetMessage::start_at(first_frame)
+                .encode(&mut worker.kcp_buffer)
+                .unwrap();
+            worker.handle_output_impl().unwrap();
+            worker.exchange();
+            return (chan, worker);
+        };
+        let input = |chan: &NetChan, worker: &mut NetWorker, frame: u32| {
+            chan.send_input(frame, &[Command::Aaa(1, 1)], &[]).unwrap();
+            return worker.handle_input().map(|_| worker.frame);
+        };
+
+        // legacy servers start at 1
+        let (chan, mut worker) = start(0, None);
+        assert_eq!(chan.start_info().unwrap().first_frame, 1);
+        assert_eq!(input(&chan, &mut worker, 1).unwrap(), 1);
+
+        let (chan, mut worker) = start(100, None);
+        assert_eq!(chan.start_info().unwrap().first_frame, 100);
+        let err = input(&chan, &mut worker, 99).unwrap_err();
+        let err = err.downcast::<KCPError>().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "input frame 99 is before the first frame 100"
+        );
+        let (chan, mut worker) = start(100, None);
+        assert_eq!(input(&chan, &mut worker, 100).unwrap(), 100);
+        assert_eq!(input(&chan, &mut worker, 101).unwrap(), 101);
+
+        // the resumed session is further along
+        let (chan, mut worker) = start(100, Some(250));
+        assert_eq!(chan.start_info().unwrap().first_frame, 251);
+        let err = input(&chan, &mut worker, 99).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<KCPError>(),
+            Some(KCPError::BeforeFirstFrame {
+                frame: 99,
+                first_frame: 100
+            })
+        ));
+        let (chan, mut worker) = start(100, Some(250));
+        let err = input(&chan, &mut worker, 250).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<KCPError>(),
+            Some(KCPError::InvalidFrame)
+        ));
+        let (chan, mut worker) = start(100, Some(250));
+        assert_eq!(input(&chan, &mut worker, 251).unwrap(), 251);
+
+        // and a resume before the first frame starts at it
+        let (chan, mut worker) = start(100, Some(50));
+        assert_eq!(chan.start_info().unwrap().first_frame, 100);
+        assert_eq!(input(&chan, &mut worker, 100).unwrap(), 100);
+    }
+
+    #[test]
+    fn test_net_worker_pause() {
+        let addr = SocketAddr::from(([138, 128, 196, 233], 33303));
+        let chan = NetChan::new();
+        let mut worker = NetWorker::new(addr, 6666, "", "", "", chan.clone()).unwrap();
+        worker.state = NetPlayerState::Running;
+        let server = |worker: &mut NetWorker, msg: NetMessage| {
+            worker.kcp_buffer.clear();
+            msg.encode(&mut worker.kcp_buffer).unwrap();
+            worker.handle_output_impl().unwrap();
+        };
+        let input = |worker: &mut NetWorker, frame: u32| {
+            chan.send_input(frame, &[Command::Aaa(1, 1)], &[]).unwrap();
+            worker.handle_input().unwrap();
+            return worker.output.stats.sent_frame;
+        };
+
+        assert_eq!(input(&mut worker, 1), 1);
+        server(&mut worker, NetMessage::pause(2));
+        assert_eq!(worker.output.stats.paused, Some(2));
+        assert_eq!(input(&mut worker, 2), 2);
+        assert_eq!(input(&mut worker, 3), 2);
+        assert_eq!(input(&mut worker, 4), 2);
+        assert_eq!(worker.output.stats.dropped_inputs, 2);
+
+        // the game replays from the resume frame
+        server(&mut worker, NetMessage::resume(3));
+        assert_eq!(worker.output.stats.paused, None);
+        assert_eq!(input(&mut worker, 3), 3);
+        assert_eq!(input(&mut worker, 4), 4);
+
+        // every handle_input() hands the events so far to the chan
+        worker.exchange();
+        let mut events = Vec::new();
+        chan.recv_events(&mut events);
+        events.retain(|event| !matches!(event, NetEvent::State { .. }));
+        assert_eq!(
+            events,
+            vec![
+                NetEvent::Paused { frame: 2 },
+                NetEvent::Warning(NetWarning::InputPaused {
+                    frame: 3,
+                    paused: 2
+                }),
+                NetEvent::Warning(NetWarning::InputPaused {
+                    frame: 4,
+                    paused: 2
+                }),
+                NetEvent::Resumed { frame: 3 },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_net_worker_resume_bounds() {
+        let addr = SocketAddr::from(([138, 128, 196, 233], 33303));
+        let chan = NetChan::new();
+        let mut worker = NetWorker::new(addr, 6666, "", "", "", chan.clone()).unwrap();
+        worker.state = NetPlayerState::Running;
+        worker.first_frame = 100;
+        worker.frame = 120;
+        let server = |worker: &mut NetWorker, msg: NetMessage| {
+            worker.kcp_buffer.clear();
+            msg.encode(&mut worker.kcp_buffer).unwrap();
+            return worker.handle_output_impl();
+        };
+
+        // before the first frame and at 0 replay from the first frame
+        server(&mut worker, NetMessage::resume(50)).unwrap();
+        assert_eq!(worker.frame, 99);
+        server(&mut worker, NetMessage::resume(0)).unwrap();
+        assert_eq!(worker.frame, 99);
+        worker
+            .output
+            .events
+            .retain(|event| matches!(event, NetEvent::Resumed { .. }));
+        assert_eq!(
+            worker.output.events,
+            vec![
+                NetEvent::Resumed { frame: 100 },
+                NetEvent::Resumed { frame: 100 },
+            ]
+        );
+
+        // not before the last acked frame
+        worker.frame = 120;
+        worker.ack_frame(110);
+        assert!(server(&mut worker, NetMessage::resume(105)).is_err());
+        assert!(server(&mut worker, NetMessage::resume(110)).is_err());
+        assert_eq!(worker.frame, 120);
+        server(&mut worker, NetMessage::resume(111)).unwrap();
+        assert_eq!(worker.frame, 110);
+    }
+
+    #[test]
+    fn test_net_worker_application() {
+        let addr = SocketAddr::from(([138, 128, 196, 233], 33303));
+        let reserved = WorkerConfig {
+            application_types: vec![APPLICATION_TYPES - 1],
+            ..WorkerConfig::default()
+        };
+        assert!(NetWorker::with_config(addr, 6666, "", "", "", NetChan::new(), reserved).is_err());
+
+        let config = WorkerConfig {
+            application_types: vec![200],
+            ..WorkerConfig::default()
+        };
+        let chan = NetChan::new();
+        let mut worker =
+            NetWorker::with_config(addr, 6666, "", "", "", chan.clone(), config).unwrap();
+        let server = |worker: &mut NetWorker, msg: NetMessage| {
+            worker.kcp_buffer.clear();
+            msg.encode(&mut worker.kcp_buffer).unwrap();
+            return worker.handle_output_impl();
+        };
+
+        // before the Accept as well as mid-match, unregistered types are
+        // dropped and counted in either
+        server(&mut worker, NetMessage::Application(201, b"?".to_vec())).unwrap();
+        assert_eq!(worker.output.stats.undecodable_packets, 1);
+        server(&mut worker, NetMessage::Application(200, b"lobby".to_vec())).unwrap();
+        server(&mut worker, NetMessage::accept()).unwrap();
+        worker.state = NetPlayerState::Running;
+        server(&mut worker, NetMessage::Application(200, Vec::new())).unwrap();
+        server(&mut worker, NetMessage::Application(201, b"?".to_vec())).unwrap();
+        assert_eq!(worker.output.stats.undecodable_packets, 2);
+        // the category decides, not the state alone
+        let err = server(&mut worker, NetMessage::start()).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<KCPError>(),
+            Some(KCPError::UnexpectedPacket)
+        ));
+
+        worker.exchange();
+        let mut events = Vec::new();
+        chan.recv_events(&mut events);
+        events.retain(|event| matches!(event, NetEvent::Application { .. }));
+        assert_eq!(
+            events,
+            vec![
+                NetEvent::Application {
+                    kind: 200,
+                    payload: b"lobby".to_vec(),
+                },
+                NetEvent::Application {
+                    kind: 200,
+                    payload: Vec::new(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_net_worker_unassigned_conv() {
+        let addr = SocketAddr::from(([138, 128, 196, 233], 33303));
+        for (conv, reason) in [(0, "not assigned"), (UNRELIABLE_CONV, "reserved")] {
+            let err = NetWorker::new(addr, conv, "", "", "", NetChan::new())
+                .err()
+                .unwrap();
+            assert_eq!(
+                err.downcast_ref::<ConfigError>(),
+                Some(&ConfigError::InvalidField {
+                    field: "conv",
+                    reason
+                })
+            );
         }
-        return Ok(());
+
+        let mut worker = NetWorker::new(addr, 6666, "", "", "", NetChan::new()).unwrap();
+        worker.state = NetPlayerState::Running;
+        worker.set_state(0, NetPlayerState::Running);
+        worker.set_state(6666, NetPlayerState::Running);
+        worker.set_state(7777, NetPlayerState::Running);
+        assert_eq!(worker.output.states.len(), 1);
+        assert_eq!(
+            worker.output.events,
+            vec![NetEvent::State {
+                conv: 7777,
+                state: NetPlayerState::Running,
+                frame: 0
+            }]
+        );
+
+        // unstamped commands are still delivered, just not tracked
+        relay(&mut worker, 0, 1, &[Command::Aaa(0, 1)]);
+        assert_eq!(worker.output.commands.len(), 1);
+        assert_eq!(worker.output.stats.lag.len(), 0);
     }
 
-    #[context("CommandEncoder::handle_timeout()")]
-    fn handle_timeout(&mut self) -> Result<()> {
-        match self.state {
-            NetPlayerState::Initing => {
-                let dura = self.started_at.elapsed().unwrap_or(Duration::ZERO);
-                if dura.as_secs() > CONNECT_TIMEOUT {
-                    return Err(KCPError::Timeout.into());
-                }
-            }
-            NetPlayerState::Waiting => {
-                let dura = self.started_at.elapsed().unwrap_or(Duration::ZERO);
-                if dura.as_secs() > START_TIMEOUT {
-                    return Err(KCPError::Timeout.into());
-                }
-            }
-            NetPlayerState::Running => {}
-            NetPlayerState::Stopped => {
-                let dura = self.stopped_at.elapsed().unwrap_or(Duration::ZERO);
-                if dura.as_secs() > UPDATE_TIMEOUT {
-                    return Err(KCPError::Timeout.into());
-                }
+    #[test]
+    fn test_net_worker_redundant_states() {
+        let addr = SocketAddr::from(([138, 128, 196, 233], 33303));
+        let state = |worker: &mut NetWorker, state: NetPlayerState| {
+            worker.kcp_buffer.clear();
+            NetMessage::state(7777, state)
+                .encode(&mut worker.kcp_buffer)
+                .unwrap();
+            worker.handle_output_impl().unwrap();
+        };
+        let run = |forward_redundant_states: bool| {
+            let config = WorkerConfig {
+                forward_redundant_states,
+                ..WorkerConfig::default()
+            };
+            let mut worker =
+                NetWorker::with_config(addr, 6666, "", "", "", NetChan::new(), config).unwrap();
+            worker.state = NetPlayerState::Running;
+            relay(&mut worker, 7777, 1, &[Command::Aaa(7, 1)]);
+            for _ in 0..3 {
+                state(&mut worker, NetPlayerState::Running);
             }
+            state(&mut worker, NetPlayerState::Stopped);
+            let states: Vec<_> = worker
+                .output
+                .events
+                .iter()
+                .filter_map(|event| match event {
+                    NetEvent::State { state, .. } => Some(*state),
+                    _ => None,
+                })
+                .collect();
+            return (worker, states);
         };
-        return Ok(());
+
+        let (worker, states) = run(false);
+        assert_eq!(
+            states,
+            vec![NetPlayerState::Running, NetPlayerState::Stopped]
+        );
+        assert_eq!(worker.output.stats.redundant_states, 2);
+        assert_eq!(worker.output.states[&7777], NetPlayerState::Stopped);
+        assert!(worker.output.stats.lag.get(7777).unwrap().stopped);
+
+        let (worker, states) = run(true);
+        assert_eq!(states.len(), 4);
+        assert_eq!(worker.output.stats.redundant_states, 0);
     }
 
-    fn set_state(&mut self, conv: u32, state: NetPlayerState) {
-        if conv != self.conv {
-            self.chan.send_output_states(conv, state);
+    #[test]
+    fn test_net_worker_lag() {
+        let mut worker = NetWorker::new(
+            SocketAddr::from(([138, 128, 196, 233], 33303)),
+            6666,
+            "",
+            "",
+            "",
+            NetChan::new(),
+        )
+        .unwrap();
+        worker.state = NetPlayerState::Running;
+        assert_eq!(worker.output.stats.slowest_conv(), None);
+
+        // 1 keeps up, 2 stalls at 3, 3 stalls at 5
+        for frame in 1..=8 {
+            relay(&mut worker, 1, frame, &[]);
+            if frame <= 3 {
+                relay(&mut worker, 2, frame, &[]);
+            }
+            if frame <= 5 {
+                relay(&mut worker, 3, frame, &[Command::Aaa(3, 3)]);
+            }
+        }
+        let stats = worker.output.stats;
+        assert_eq!(stats.lag.len(), 3);
+        assert_eq!(stats.lag.get(1).unwrap().last_frame, 8);
+        assert_eq!(stats.lag.get(2).unwrap().last_frame, 3);
+        assert_eq!(stats.lag.get(3).unwrap().last_frame, 5);
+        assert!(stats.lag.get(3).unwrap().last_seen >= stats.lag.get(2).unwrap().last_seen);
+        assert_eq!(stats.slowest_conv(), Some(2));
+
+        // a stopped conv isn't lagging, a reordered packet doesn't go back
+        worker.set_state(2, NetPlayerState::Stopped);
+        relay(&mut worker, 3, 4, &[]);
+        assert_eq!(worker.output.stats.lag.get(3).unwrap().last_frame, 5);
+        assert!(worker.output.stats.lag.get(2).unwrap().stopped);
+        assert_eq!(worker.output.stats.slowest_conv(), Some(3));
+        relay(&mut worker, 3, 9, &[]);
+        assert_eq!(worker.output.stats.slowest_conv(), Some(1));
+
+        // bounded by PLAYERS_CAP
+        for conv in 100..200 {
+            relay(&mut worker, conv, 1, &[]);
         }
+        assert_eq!(worker.lag.len(), PLAYERS_CAP);
+        assert_eq!(worker.output.stats.lag.len(), PLAYERS_CAP);
     }
 
-    fn set_self_state(&mut self, state: NetPlayerState) {
-        self.state = state;
-        self.chan.send_output_states(self.conv, state);
+    #[test]
+    fn test_net_worker_late_commands() {
+        let worker = |late_commands| {
+            let config = WorkerConfig {
+                late_commands,
+                ..WorkerConfig::default()
+            };
+            let mut worker = NetWorker::with_config(
+                SocketAddr::from(([138, 128, 196, 233], 33303)),
+                6666,
+                "",
+                "",
+                "",
+                NetChan::new(),
+                config,
+            )
+            .unwrap();
+            worker.state = NetPlayerState::Running;
+            return worker;
+        };
+        // 7 resends frame 1 between 2 and 3, 8 is on time
+        let stream = |worker: &mut NetWorker| {
+            try_relay(worker, 7, 1, &[Command::Aaa(7, 1)])?;
+            try_relay(worker, 7, 2, &[Command::Aaa(7, 2)])?;
+            try_relay(worker, 8, 1, &[Command::Aaa(8, 1)])?;
+            try_relay(worker, 7, 1, &[Command::Aaa(7, 1), Command::Aaa(7, 0)])?;
+            try_relay(worker, 7, 3, &[Command::Aaa(7, 3)])?;
+            return Ok::<_, Error>(());
+        };
+        let delivered = |worker: &NetWorker| {
+            let batches = worker.output.commands.iter();
+            return batches.map(|b| (b.conv, b.frame)).collect::<Vec<_>>();
+        };
+        let in_order = vec![(7, 1), (7, 2), (8, 1), (7, 3)];
+
+        let mut dropping = worker(LateCommandPolicy::Drop);
+        stream(&mut dropping).unwrap();
+        assert_eq!(delivered(&dropping), in_order);
+        assert_eq!(dropping.output.stats.late_commands, 2);
+        assert!(dropping.output.events.is_empty());
+
+        let mut flagging = worker(LateCommandPolicy::DeliverFlagged);
+        stream(&mut flagging).unwrap();
+        assert_eq!(delivered(&flagging), in_order);
+        assert_eq!(flagging.output.stats.late_commands, 0);
+        assert_eq!(
+            flagging.output.events,
+            vec![NetEvent::LateCommands {
+                conv: 7,
+                frame: 1,
+                commands: vec![Command::Aaa(7, 1), Command::Aaa(7, 0)],
+            }]
+        );
+
+        let mut strict = worker(LateCommandPolicy::Fatal);
+        let err = stream(&mut strict).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<KCPError>(),
+            Some(KCPError::LateCommands(1))
+        ));
+        assert_eq!(delivered(&strict), vec![(7, 1), (7, 2), (8, 1)]);
     }
 
-    fn is_message_command(bytes: &[u8]) -> bool {
-        if bytes.len() < KCP_MIN_PACKET {
-            return false;
-        }
-        return NetType::from_i32(bytes[0] as i32) == Some(NetType::Command);
+    #[test]
+    fn test_net_worker_frame_acked() {
+        let config = WorkerConfig {
+            frame_acked_events: true,
+            ..WorkerConfig::default()
+        };
+        let acked = |chan: &NetChan| {
+            let mut events = Vec::new();
+            chan.recv_events(&mut events);
+            return events
+                .into_iter()
+                .filter_map(|event| match event {
+                    NetEvent::FrameAcked { frame } => Some(frame),
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+        };
+
+        // newest only, reported at most every FRAME_ACK_INTERVAL
+        let addr = SocketAddr::from(([138, 128, 196, 233], 33303));
+        let chan = NetChan::new();
+        let mut worker =
+            NetWorker::with_config(addr, 6666, "", "", "", chan.clone(), config.clone()).unwrap();
+        worker.report_acked(0);
+        worker.ack_frame(5);
+        worker.ack_frame(4);
+        worker.report_acked(0);
+        worker.ack_frame(6);
+        worker.report_acked(FRAME_ACK_INTERVAL - 1);
+        worker.report_acked(FRAME_ACK_INTERVAL);
+        worker.report_acked(FRAME_ACK_INTERVAL * 2);
+        worker.exchange();
+        assert_eq!(acked(&chan), vec![5, 6]);
+        assert_eq!(chan.stats().last_acked_frame, Some(6));
+        drop(worker);
+
+        let session = |auth: MockAuth| {
+            let server = MockServer::start_with_auth(1, auth).unwrap();
+            let chan = NetChan::new();
+            let mut worker = NetWorker::with_config(
+                server.addr(),
+                6666,
+                "room",
+                "player",
+                "",
+                chan.clone(),
+                config.clone(),
+            )
+            .unwrap();
+            drive(&mut worker, || chan.start_info().is_some());
+            for frame in 1..=3 {
+                chan.send_input(frame, &[Command::Aaa(frame as i32, 0)], &[])
+                    .unwrap();
+            }
+            drive(&mut worker, || server.records().commands.len() == 3);
+            return (server, chan, worker);
+        };
+
+        // the echo of our own commands
+        let (_server, chan, mut worker) = session(MockAuth::default());
+        drive(&mut worker, || chan.stats().last_acked_frame == Some(3));
+        let frames = acked(&chan);
+        assert!(!frames.is_empty());
+        assert!(frames.windows(2).all(|pair| pair[0] < pair[1]));
+
+        // a server that doesn't echo but acknowledges
+        let auth = MockAuth {
+            no_echo: true,
+            acks: true,
+            ..MockAuth::default()
+        };
+        let (_server, chan, mut worker) = session(auth);
+        drive(&mut worker, || chan.stats().last_acked_frame == Some(3));
+
+        // neither, nothing is guessed
+        let auth = MockAuth {
+            no_echo: true,
+            ..MockAuth::default()
+        };
+        let (_server, chan, mut worker) = session(auth);
+        let until = Instant::now() + Duration::from_millis(200);
+        drive(&mut worker, || Instant::now() > until);
+        assert_eq!(chan.stats().last_acked_frame, None);
+        assert!(acked(&chan).is_empty());
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    use crate::codec::{Command, CommandEx};
-    use crate::message::{NetAccept, NetConnect, NetFinish, NetStart};
-    use std::collections::HashMap;
+    #[test]
+    fn test_net_worker_chan_attached_once() {
+        let addr = SocketAddr::from(([138, 128, 196, 233], 33303));
+        let chan = NetChan::new();
+        let barrier = Arc::new(Barrier::new(2));
+        let threads: Vec<_> = (0..2)
+            .map(|_| {
+                let (chan, barrier) = (chan.clone(), barrier.clone());
+                return thread::spawn(move || {
+                    barrier.wait();
+                    let worker = NetWorker::new(addr, 6666, "", "", "", chan);
+                    // held until both tried
+                    barrier.wait();
+                    return match worker {
+                        Ok(_) => None,
+                        Err(err) => Some(err.downcast::<ConfigError>().unwrap()),
+                    };
+                });
+            })
+            .collect();
+        let errors: Vec<_> = threads
+            .into_iter()
+            .map(|thread| thread.join().unwrap())
+            .collect();
+        assert_eq!(errors.iter().filter(|err| err.is_none()).count(), 1);
+        assert!(errors.contains(&Some(ConfigError::ChanAlreadyAttached)));
+
+        // released when the worker drops, and when creating it fails
+        assert!(!chan.is_attached());
+        let worker = NetWorker::new(addr, 6666, "", "", "", chan.clone()).unwrap();
+        assert!(chan.is_attached());
+        drop(worker);
+        let config = WorkerConfig {
+            timer_jitter: TIMER_JITTER_MAX + 1,
+            ..WorkerConfig::default()
+        };
+        assert!(NetWorker::with_config(addr, 6666, "", "", "", chan.clone(), config).is_err());
+        assert!(!chan.is_attached());
+    }
 
     #[test]
-    fn test_net_worker_input() {
+    fn test_net_worker_conv_echo() {
         let chan = NetChan::new();
-        let mut worker = NetWorker::new(
+        let mut worker = NetWorker::with_config(
             SocketAddr::from(([138, 128, 196, 233], 33303)),
             6666,
             "",
             "",
             "",
             chan.clone(),
+            WorkerConfig::default(),
         )
         .unwrap();
+        worker.state = NetPlayerState::Running;
+        chan.send_input(1, &[Command::Aaa(1, 1)], &[1; 8]).unwrap();
+        chan.send_input(2, &[Command::Aaa(2, 2)], &[2; 8]).unwrap();
+        worker.handle_input().unwrap();
+        assert_eq!(worker.frame, 2);
 
-        chan.send_input(1, &[], &[1, 2, 3]).unwrap();
-        let err = worker.handle_input().unwrap_err();
-        assert_eq!(
-            err.downcast::<KCPError>().unwrap().to_string(),
-            "unexpected error"
-        );
+        // stamped on the wire
+        match NetMessage::decode(worker.cmd_encoder.command_bytes()).unwrap() {
+            (NetMessage::Command(cmd), _) => assert_eq!((cmd.conv, cmd.frame), (6666, 2)),
+            (msg, _) => panic!("unexpected {:?}", msg),
+        };
+        match NetMessage::decode(worker.cmd_encoder.hash_bytes()).unwrap() {
+            (NetMessage::Hash(hash), _) => assert_eq!((hash.conv, hash.frame), (6666, 2)),
+            (msg, _) => panic!("unexpected {:?}", msg),
+        };
 
-        worker.state = NetPlayerState::Waiting;
-        chan.send_input(2, &[], &[1, 2, 3]).unwrap();
-        let err = worker.handle_input().unwrap_err();
+        // ours for frames we never sent
+        relay(&mut worker, 6666, 5, &[Command::Aaa(5, 5)]);
+        let mut hash = NetHash::default();
+        hash.conv = 6666;
+        hash.frame = 6;
+        hash.hash = vec![0; 8];
+        worker.kcp_buffer.clear();
+        NetMessage::Hash(hash)
+            .encode(&mut worker.kcp_buffer)
+            .unwrap();
+        worker.handle_output_impl().unwrap();
+        assert!(worker.output.commands.is_empty());
+        assert_eq!(worker.output.stats.last_acked_frame, None);
         assert_eq!(
-            err.downcast::<KCPError>().unwrap().to_string(),
-            "unexpected error"
+            worker.output.events,
+            vec![
+                NetEvent::Warning(NetWarning::SpoofedConv { frame: 5 }),
+                NetEvent::Warning(NetWarning::SpoofedConv { frame: 6 }),
+            ]
         );
+        assert_eq!(worker.summary.warnings[9], 2);
+
+        // the echo of what we sent
+        worker.output.events.clear();
+        relay(&mut worker, 6666, 2, &[Command::Aaa(2, 2)]);
+        assert_eq!(worker.output.stats.last_acked_frame, Some(2));
+        assert_eq!(worker.output.commands.len(), 1);
+        assert!(worker.output.events.is_empty());
+    }
 
+    #[test]
+    fn test_net_worker_roster_hints() {
+        let config = WorkerConfig {
+            roster: Some(RosterLimits {
+                window: 200,
+                per_conv: 2,
+                total: 3,
+            }),
+            ..WorkerConfig::default()
+        };
+        let mut worker = NetWorker::with_config(
+            SocketAddr::from(([138, 128, 196, 233], 33303)),
+            6666,
+            "",
+            "",
+            "",
+            NetChan::new(),
+            config,
+        )
+        .unwrap();
         worker.state = NetPlayerState::Running;
-        chan.send_input(0, &[], &[]).unwrap();
-        let err = worker.handle_input().unwrap_err();
-        assert_eq!(
-            err.downcast::<KCPError>().unwrap().to_string(),
-            "invalid frame"
-        );
+        worker.set_state(1, NetPlayerState::Running);
+        let delivered = |worker: &NetWorker| {
+            let batches = worker.output.commands.iter();
+            return batches.map(|b| (b.conv, b.frame)).collect::<Vec<_>>();
+        };
+        let warnings = |worker: &mut NetWorker| {
+            let events = worker.output.events.drain(..);
+            return events
+                .filter_map(|event| match event {
+                    NetEvent::Warning(NetWarning::UnknownConv { conv, batches }) => {
+                        Some((conv, batches))
+                    }
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+        };
 
-        chan.send_input(3, &[Command::Bbb(1.0, 1.0, 1.0)], &[9, 0, 9, 0])
-            .unwrap();
-        worker.handle_input().unwrap();
-        worker.kcp.update_kcp(0);
-        assert_eq!(worker.kcp.output_queue().len(), 1);
+        // known convs and our own pass, 2 joins ahead of its State
+        worker.frame = 1;
+        relay(&mut worker, 1, 1, &[Command::Aaa(1, 1)]);
+        relay(&mut worker, 6666, 1, &[Command::Aaa(6, 1)]);
+        relay(&mut worker, 2, 1, &[Command::Aaa(2, 1)]);
+        relay(&mut worker, 2, 2, &[Command::Aaa(2, 2)]);
+        assert_eq!(delivered(&worker), vec![(1, 1), (6666, 1)]);
+        assert_eq!(worker.output.stats.held_hints, 2);
+        assert!(worker.output.stats.lag.get(2).is_none());
 
-        chan.game_over().unwrap();
-        let err = worker.handle_input().unwrap_err();
-        assert_eq!(err.downcast::<KCPError>().unwrap().to_string(), "game over");
+        // released in order with its State
+        worker.set_state(2, NetPlayerState::Running);
+        assert_eq!(delivered(&worker), vec![(1, 1), (6666, 1), (2, 1), (2, 2)]);
+        assert_eq!(worker.output.stats.released_hints, 2);
+        assert_eq!(worker.output.stats.held_hints, 0);
+        assert_eq!(worker.output.stats.lag.get(2).unwrap().last_frame, 2);
+        relay(&mut worker, 2, 3, &[Command::Aaa(2, 3)]);
+        assert_eq!(delivered(&worker).last(), Some(&(2, 3)));
+        assert!(warnings(&mut worker).is_empty());
+
+        // bounded per conv and over all convs
+        worker.output.commands.clear();
+        for frame in 1..=3 {
+            relay(&mut worker, 3, frame, &[Command::Aaa(3, frame as i32)]);
+        }
+        relay(&mut worker, 4, 1, &[Command::Aaa(4, 1)]);
+        relay(&mut worker, 5, 1, &[Command::Aaa(5, 1)]);
+        assert!(worker.output.commands.is_empty());
+        assert_eq!(worker.output.stats.held_hints, 3);
+        assert_eq!(worker.output.stats.discarded_hints, 2);
+        assert_eq!(warnings(&mut worker), vec![(3, 1), (5, 1)]);
+
+        // no State within the window
+        worker.expire_hints(10_000);
+        assert_eq!(worker.output.stats.held_hints, 0);
+        assert_eq!(worker.output.stats.discarded_hints, 5);
+        assert_eq!(warnings(&mut worker), vec![(3, 2), (4, 1)]);
+        worker.set_state(3, NetPlayerState::Running);
+        assert!(worker.output.commands.is_empty());
+        assert_eq!(worker.output.stats.released_hints, 2);
     }
 
     #[test]
-    fn test_net_worker_output() {
+    fn test_net_worker_tick_exchange() {
         let chan = NetChan::new();
         let mut worker = NetWorker::new(
             SocketAddr::from(([138, 128, 196, 233], 33303)),
@@ -351,71 +6141,209 @@ mod test {
             chan.clone(),
         )
         .unwrap();
-        worker.handle_output().unwrap();
+        worker.state = NetPlayerState::Running;
 
-        let mut commands = Vec::<CommandEx>::new();
-        let mut states = HashMap::<u32, NetPlayerState>::new();
+        let mut ce = CommandEncoder::new(0);
+        for frame in 1..=10 {
+            ce.commands().push(Command::Aaa(frame as i32, 0));
+            ce.commands().push(Command::Aaa(frame as i32, 1));
+            ce.encode(frame).unwrap();
+            worker.kcp_buffer.clear();
+            worker.kcp_buffer.extend_from_slice(ce.command_bytes());
+            worker.handle_output_impl().unwrap();
+        }
+        chan.send_input(1, &[Command::Aaa(0, 0)], &[1, 2]).unwrap();
+        chan.send_input(2, &[], &[3, 4]).unwrap();
 
-        worker.kcp_buffer.clear();
-        NetMessage::Accept(NetAccept::default())
-            .encode(&mut worker.kcp_buffer)
-            .unwrap();
-        worker.handle_output_impl().unwrap();
-        assert_eq!(worker.state, NetPlayerState::Waiting);
-        chan.recv_output(&mut commands, &mut states).unwrap();
-        assert_eq!(states[&worker.conv], NetPlayerState::Waiting);
+        let before = chan.metrics();
+        worker.handle_input().unwrap();
+        let after = chan.metrics();
+        assert_eq!(after.output_appends - before.output_appends, 1);
+        assert_eq!(after.locks - before.locks, 1);
+        assert_eq!(worker.inputs.len(), 2);
+        assert_eq!(worker.frame, 2);
 
-        worker.kcp_buffer.clear();
-        NetMessage::Start(NetStart::default())
-            .encode(&mut worker.kcp_buffer)
-            .unwrap();
-        worker.handle_output_impl().unwrap();
-        assert_eq!(worker.state, NetPlayerState::Running);
+        let mut commands = Vec::<CommandEx>::new();
+        let mut states = BTreeMap::<u32, NetPlayerState>::new();
         chan.recv_output(&mut commands, &mut states).unwrap();
-        assert_eq!(states[&worker.conv], NetPlayerState::Running);
+        assert_eq!(commands.len(), 20);
+        assert_eq!(commands[19].frame, 10);
+        assert_eq!(commands[19].command, Command::Aaa(10, 1));
+    }
+
+    #[test]
+    fn test_net_worker_steady_state_allocations() {
+        let chan = NetChan::new();
+        let mut worker = NetWorker::new(
+            SocketAddr::from(([138, 128, 196, 233], 33303)),
+            6666,
+            "",
+            "",
+            "",
+            chan.clone(),
+        )
+        .unwrap();
+        worker.state = NetPlayerState::Running;
 
-        worker.kcp_buffer.clear();
         let mut ce = CommandEncoder::new(0);
-        ce.commands().push(Command::Bbb(1.0, 1.0, 1.0));
-        ce.encode(10).unwrap();
-        worker.kcp_buffer.extend_from_slice(ce.command_bytes());
-        worker.handle_output_impl().unwrap();
-        chan.recv_output(&mut commands, &mut states).unwrap();
-        assert_eq!(commands[0].command, Command::Bbb(1.0, 1.0, 1.0));
-        assert_eq!(commands[0].frame, 10);
+        let mut packets = Vec::new();
+        for conv in [7777, 8888] {
+            ce.commands().push(Command::Aaa(conv as i32, 0));
+            ce.encode(1).unwrap();
+            packets.push(ce.command_bytes().to_vec());
+        }
 
-        for state in [
-            NetPlayerState::Initing,
-            NetPlayerState::Waiting,
-            NetPlayerState::Running,
-        ] {
-            worker.state = state;
-            worker.kcp_buffer.clear();
-            NetMessage::Finish(NetFinish::default())
-                .encode(&mut worker.kcp_buffer)
+        // 2 packets in, a hash and a command packet out
+        let mut commands = Vec::<CommandEx>::with_capacity(COMMANDS_CAP);
+        let mut states = BTreeMap::<u32, NetPlayerState>::new();
+        let mut tick = |worker: &mut NetWorker, frame: u32| {
+            chan.send_input(frame, &[Command::Aaa(1, 2)], &[1, 2, 3, 4])
                 .unwrap();
-            let err = worker.handle_output_impl().unwrap_err();
-            assert_eq!(
-                err.downcast::<KCPError>().unwrap().to_string(),
-                "remote finished"
-            );
+            for packet in packets.iter() {
+                worker.kcp_buffer.clear();
+                worker.kcp_buffer.extend_from_slice(packet);
+                worker.handle_output_impl().unwrap();
+            }
+            worker.exchange();
+            let input = &worker.inputs[0];
+            let (ce_commands, ce_hash) = worker.cmd_encoder.buffers();
+            ce_commands.extend(input.commands.iter().cloned());
+            ce_hash.extend_from_slice(&input.hash);
+            worker.cmd_encoder.encode(frame).unwrap();
+
+            commands.clear();
+            chan.recv_output(&mut commands, &mut states).unwrap();
+            assert_eq!(commands.len(), 2);
+        };
+
+        for frame in 1..10 {
+            tick(&mut worker, frame);
+        }
+        let before = allocations();
+        for frame in 10..110 {
+            tick(&mut worker, frame);
         }
+        assert_eq!(allocations(), before);
+    }
 
-        for state in [
-            NetPlayerState::Initing,
-            NetPlayerState::Waiting,
-            NetPlayerState::Running,
-        ] {
-            worker.state = state;
-            worker.kcp_buffer.clear();
-            NetMessage::Connect(NetConnect::default())
-                .encode(&mut worker.kcp_buffer)
-                .unwrap();
-            let err = worker.handle_output_impl().unwrap_err();
-            assert_eq!(
-                err.downcast::<KCPError>().unwrap().to_string(),
-                "unexpected packet"
-            );
+    #[test]
+    fn test_net_worker_buffer_pool() {
+        // 6 commands don't fit inline, in and out
+        let frame_commands: Vec<Command> = (0..6).map(|idx| Command::Aaa(idx, 0)).collect();
+        let mut ce = CommandEncoder::new(0);
+        let mut packets = Vec::new();
+        for conv in [7777, 8888] {
+            ce.commands().extend(frame_commands.iter().cloned());
+            ce.encode(1).unwrap();
+            packets.push(ce.command_bytes().to_vec());
+        }
+
+        // allocations of the global allocator over 100 steady frames
+        let run = |chan: NetChan| {
+            let mut worker = NetWorker::new(
+                SocketAddr::from(([138, 128, 196, 233], 33303)),
+                6666,
+                "",
+                "",
+                "",
+                chan.clone(),
+            )
+            .unwrap();
+            worker.state = NetPlayerState::Running;
+            let mut commands = Vec::<CommandEx>::with_capacity(COMMANDS_CAP);
+            let mut states = BTreeMap::<u32, NetPlayerState>::new();
+            let mut tick = |worker: &mut NetWorker, frame: u32| {
+                chan.send_input(frame, &frame_commands, &[1, 2, 3, 4])
+                    .unwrap();
+                for packet in packets.iter() {
+                    worker.kcp_buffer.clear();
+                    worker.kcp_buffer.extend_from_slice(packet);
+                    worker.handle_output_impl().unwrap();
+                }
+                worker.exchange();
+                let input = &worker.inputs[0];
+                worker.cmd_encoder.reserve_commands(input.commands.len());
+                let (ce_commands, ce_hash) = worker.cmd_encoder.buffers();
+                ce_commands.extend(input.commands.iter().cloned());
+                ce_hash.extend_from_slice(&input.hash);
+                worker.cmd_encoder.encode(frame).unwrap();
+
+                commands.clear();
+                chan.recv_output(&mut commands, &mut states).unwrap();
+                assert_eq!(commands.len(), 12);
+            };
+            for frame in 1..10 {
+                tick(&mut worker, frame);
+            }
+            let before = allocations();
+            for frame in 10..110 {
+                tick(&mut worker, frame);
+            }
+            let allocated = allocations() - before;
+            return (worker, allocated);
+        };
+
+        let (_, allocated) = run(NetChan::new());
+        assert!(allocated >= 100);
+
+        // whatever goes back to the pool is checked to have come from it
+        let pool = Arc::new(TaggedPool::new(16, KCP_MAX_PACKET, 16));
+        let shared: Arc<dyn BufferPool> = pool.clone();
+        let chan = NetChan::with_pool(InputLimits::default(), OutputLimits::default(), shared);
+        let (worker, allocated) = run(chan);
+        assert_eq!(allocated, 0);
+        assert!(pool.acquired() > 100 * 4);
+        assert!(pool.tagged(worker.cmd_encoder.hash_bytes().as_ptr()));
+        assert!(pool.tagged(worker.cmd_encoder.command_bytes().as_ptr()));
+    }
+
+    // 16 players sending 32 commands each for a frame, stored and handed to
+    // the game once per packet instead of once per command
+    #[test]
+    fn test_net_worker_command_batches() {
+        let chan = NetChan::new();
+        let mut worker = NetWorker::new(
+            SocketAddr::from(([138, 128, 196, 233], 33303)),
+            6666,
+            "",
+            "",
+            "",
+            chan.clone(),
+        )
+        .unwrap();
+        worker.state = NetPlayerState::Running;
+        let commands: Vec<_> = (0..32).map(|x| Command::Aaa(x, 0)).collect();
+        for conv in 1..=16 {
+            relay(&mut worker, conv, 1, &commands);
         }
+        assert_eq!(worker.output.commands.len(), 16);
+        assert!(worker.output.commands.iter().all(|batch| batch.len() == 32));
+
+        let flat_bytes = 16 * 32 * mem::size_of::<CommandEx>();
+        let batch_bytes: usize = worker
+            .output
+            .commands
+            .iter()
+            .map(|batch| {
+                let heap = match batch.commands.spilled() {
+                    true => batch.commands.capacity() * mem::size_of::<Command>(),
+                    false => 0,
+                };
+                return mem::size_of::<CommandBatch>() + heap;
+            })
+            .sum();
+        assert!(batch_bytes < flat_bytes);
+
+        // draining moves the batches, not the commands
+        worker.exchange();
+        let moved = 16 * mem::size_of::<CommandBatch>();
+        assert!(moved * 4 < flat_bytes);
+        let mut batches = Vec::new();
+        let mut events = Vec::new();
+        chan.drain_batches(&mut batches, &mut events);
+        let flat: Vec<_> = CommandBatch::flatten(&batches).collect();
+        assert_eq!(flat.len(), 16 * 32);
+        assert_eq!(flat[32].conv, 2);
+        assert_eq!(flat[32].command, Command::Aaa(0, 0));
     }
 }